@@ -0,0 +1,122 @@
+//! Benchmarks of the core paths: compiling, evaluating, and building and
+//! deduplicating the cache. Each one runs with the JIT on and off, the two
+//! ways the search can evaluate statements.
+//!
+//! Run with `cargo bench`, or e.g. `cargo bench -- eval_grid` for one group.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use expr_rust::jit::jit_compile_expr;
+use expr_rust::{build_cache, naive_eval, run_bytecode, Cache, Context, Expr, NumT, SearchConfig};
+use std::hint::black_box;
+use std::sync::Arc;
+
+fn config(use_jit: bool) -> SearchConfig {
+    SearchConfig { use_jit, use_multithread: false, ..SearchConfig::default() }
+}
+
+fn backend(use_jit: bool) -> &'static str {
+    if use_jit { "jit" } else { "no_jit" }
+}
+
+fn statements(ctx: &Context, max_len: usize) -> Vec<Arc<Expr>> {
+    (1..=max_len).filter_map(|n| ctx.statements(n)).flat_map(|c| c.iter().cloned()).collect()
+}
+
+/// Run each statement once from every point of the -4..=4 grid
+fn eval_grid(stmts: &[Arc<Expr>], eval: impl Fn(&Expr, &mut NumT, &mut NumT) -> NumT) -> NumT {
+    let mut sum: NumT = 0;
+    for e in stmts {
+        for x0 in -4..=4 {
+            for y0 in -4..=4 {
+                let (mut x, mut y) = (x0, y0);
+                sum = sum.wrapping_add(eval(e, &mut x, &mut y));
+            }
+        }
+    }
+    sum
+}
+
+fn bench_jit_compile(c: &mut Criterion) {
+    let stmts = statements(&build_cache(&config(false), 5), 5);
+    c.bench_function("jit_compile/statements_to_5", |b| {
+        b.iter(|| stmts.iter().map(|e| jit_compile_expr(e).code_bytes().len()).sum::<usize>())
+    });
+}
+
+fn bench_eval_grid(c: &mut Criterion) {
+    let mut group = c.benchmark_group("eval_grid");
+    for use_jit in [true, false] {
+        let stmts = statements(&build_cache(&config(use_jit), 5), 5);
+        if use_jit {
+            group.bench_function("jit", |b| {
+                b.iter(|| eval_grid(&stmts, |e, x, y| unsafe { e.jit.as_ref().unwrap().func()(x, y) }))
+            });
+        } else {
+            group.bench_function("bytecode", |b| {
+                b.iter(|| eval_grid(&stmts, |e, x, y| run_bytecode(e.bytecode.as_ref().unwrap(), x, y).unwrap().unwrap_or(0)))
+            });
+        }
+        group.bench_function(format!("naive/{}", backend(use_jit)), |b| {
+            b.iter(|| eval_grid(&stmts, |e, x, y| naive_eval(e, x, y, &mut false)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_build_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build_cache");
+    group.sample_size(10);
+    for use_jit in [true, false] {
+        let config = config(use_jit);
+        group.bench_function(format!("length_7/{}", backend(use_jit)), |b| {
+            b.iter(|| build_cache(&config, black_box(7)))
+        });
+    }
+    group.finish();
+}
+
+/// The multithreaded length-7 build in pools of 1 to 16 threads, for how the
+/// sharded dedup scales. Only as far as the machine has cores means anything.
+fn bench_build_cache_threads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build_cache_threads");
+    group.sample_size(10);
+    let config = SearchConfig { use_multithread: true, ..config(true) };
+    for threads in [1, 2, 4, 8, 16] {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        group.bench_function(format!("length_7/{}", threads), |b| {
+            b.iter(|| pool.install(|| build_cache(&config, black_box(7))))
+        });
+    }
+    group.finish();
+}
+
+/// Hashing each statement into an empty cache, then again into the full one
+/// so every insert also compares against its equal. Statements, since their
+/// fingerprint comes from the Jit when there is one
+fn bench_cache_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache_insert");
+    for use_jit in [true, false] {
+        let ctx = build_cache(&config(use_jit), 5);
+        let exprs: Vec<Arc<Expr>> = ctx.statements(5).unwrap().iter().cloned().collect();
+        group.bench_function(format!("length_5/{}", backend(use_jit)), |b| {
+            b.iter_batched(
+                || exprs.clone(),
+                |exprs| {
+                    let mut cache = Cache::new();
+                    for e in &exprs {
+                        cache.insert(e.clone());
+                    }
+                    for e in exprs {
+                        cache.insert(e);
+                    }
+                    cache.len()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_jit_compile, bench_eval_grid, bench_build_cache, bench_build_cache_threads, bench_cache_insert);
+criterion_main!(benches);
@@ -0,0 +1,168 @@
+//! Shorthand for building expression trees by hand, e.g. for tests or seeds.
+//!
+//! ```
+//! use expr_rust::{build, Operator};
+//!
+//! // x*2+y
+//! let e = build::bin(build::bin(build::x(), Operator::Mul, build::lit(2)), Operator::Add, build::y());
+//! assert_eq!(e.op, Operator::Add);
+//! ```
+
+use crate::{jit, Expr, Mode, NumT, Operator};
+use std::sync::Arc;
+
+/// Builds nodes like the free functions below, JIT-compiling each one when `jit` is set
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Builder {
+    pub jit: bool,
+}
+
+impl Builder {
+    pub fn new(jit: bool) -> Self {
+        Self { jit }
+    }
+
+    pub fn lit(&self, n: NumT) -> Arc<Expr> {
+        self.node(None, None, None, n, Mode::Plain, Operator::Literal)
+    }
+
+    pub fn x(&self) -> Arc<Expr> {
+        self.node(None, None, None, 0, Mode::Plain, Operator::Var)
+    }
+
+    pub fn y(&self) -> Arc<Expr> {
+        self.node(None, None, None, 0, Mode::Plain, Operator::VarY)
+    }
+
+    /// Binary operators, and assignments with `left` as the target
+    pub fn bin(&self, left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+        self.node(Some(left), None, Some(right), 0, Mode::Plain, op)
+    }
+
+    /// `left**right`, reduced mod `modulus`
+    pub fn pow_mod(&self, left: Arc<Expr>, right: Arc<Expr>, modulus: NumT) -> Arc<Expr> {
+        assert!(modulus > 0, "the modulus must be positive, got {}", modulus);
+        self.node(Some(left), None, Some(right), 0, Mode::PowMod(modulus), Operator::Pow)
+    }
+
+    /// `+`, `-`, `*` or their assignments, clamping at i64::MIN/MAX
+    pub fn saturating(&self, left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+        assert!(Expr::can_saturate(op), "{:?} can't saturate", op);
+        self.node(Some(left), None, Some(right), 0, Mode::Saturating, op)
+    }
+
+    /// `/`, `%` or their assignments, rounding the Euclidean way
+    pub fn euclidean(&self, left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+        assert!(Expr::can_be_euclidean(op), "{:?} isn't a division", op);
+        self.node(Some(left), None, Some(right), 0, Mode::Euclidean, op)
+    }
+
+    /// A comparison, `<?`, `>?`, `>>`, `/`, `%` or assignment form of these,
+    /// reading its operands as u64
+    pub fn unsigned(&self, left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+        assert!(Expr::can_be_unsigned(op), "{:?} can't be unsigned", op);
+        self.node(Some(left), None, Some(right), 0, Mode::Unsigned, op)
+    }
+
+    /// Unary, inc/dec and `Parens` nodes
+    pub fn un(&self, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+        self.node(None, None, Some(right), 0, Mode::Plain, op)
+    }
+
+    pub fn ternary(&self, cond: Arc<Expr>, then: Arc<Expr>, other: Arc<Expr>) -> Arc<Expr> {
+        self.node(Some(cond), Some(then), Some(other), 0, Mode::Plain, Operator::Ternary)
+    }
+
+    fn node(
+        &self,
+        left: Option<Arc<Expr>>,
+        mid: Option<Arc<Expr>>,
+        right: Option<Arc<Expr>>,
+        literal: NumT,
+        mode: Mode,
+        op: Operator,
+    ) -> Arc<Expr> {
+        let mut e = Expr { left, mid, right, literal, mode, op, jit: None, bytecode: None };
+        if self.jit && e.is_jittable() {
+            e.jit = Some(Arc::new(jit::jit_compile_expr(&e)));
+        }
+        Arc::new(e)
+    }
+}
+
+pub fn lit(n: NumT) -> Arc<Expr> {
+    Builder::default().lit(n)
+}
+
+pub fn x() -> Arc<Expr> {
+    Builder::default().x()
+}
+
+pub fn y() -> Arc<Expr> {
+    Builder::default().y()
+}
+
+pub fn bin(left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+    Builder::default().bin(left, op, right)
+}
+
+pub fn pow_mod(left: Arc<Expr>, right: Arc<Expr>, modulus: NumT) -> Arc<Expr> {
+    Builder::default().pow_mod(left, right, modulus)
+}
+
+pub fn saturating(left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+    Builder::default().saturating(left, op, right)
+}
+
+pub fn euclidean(left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+    Builder::default().euclidean(left, op, right)
+}
+
+pub fn unsigned(left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+    Builder::default().unsigned(left, op, right)
+}
+
+pub fn un(op: Operator, right: Arc<Expr>) -> Arc<Expr> {
+    Builder::default().un(op, right)
+}
+
+pub fn ternary(cond: Arc<Expr>, then: Arc<Expr>, other: Arc<Expr>) -> Arc<Expr> {
+    Builder::default().ternary(cond, then, other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naive_eval;
+
+    #[test]
+    fn test_build_shapes() {
+        let e = ternary(x(), un(Operator::Neg, y()), bin(x(), Operator::Add, lit(3)));
+        assert_eq!(e.op, Operator::Ternary);
+        assert_eq!(e.mid.as_ref().unwrap().op, Operator::Neg);
+        assert_eq!(e.right.as_ref().unwrap().right.as_ref().unwrap().literal, 3);
+        assert!(e.jit.is_none());
+    }
+
+    #[test]
+    fn test_builder_jit_flag() {
+        let b = Builder::new(true);
+        let e = b.bin(b.x(), Operator::Mul, b.lit(5));
+        let (r, mut x, mut y) = e.jit.as_ref().expect("compiled").call(7, 0);
+        assert_eq!(r, 35);
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 35);
+    }
+
+    #[test]
+    fn test_builder_pow_mod_not_compiled() {
+        let b = Builder::new(true);
+        let e = b.bin(b.pow_mod(b.x(), b.lit(3), 7), Operator::Add, b.lit(1));
+        assert!(e.jit.is_none());
+        assert!(e.left.as_ref().unwrap().left.as_ref().unwrap().jit.is_some());
+
+        let (mut x, mut y) = (4, 0);
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 2);
+    }
+}
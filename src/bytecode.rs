@@ -0,0 +1,567 @@
+//! Flat postfix bytecode for expressions, and a stack machine that runs it.
+//!
+//! This is what the search runs when a statement has no Jit. It computes the
+//! same values as `naive_eval` without chasing `Arc`s or re-checking each
+//! node's targets on every call.
+//!
+//! Each instruction is an `Operator` byte, followed by its operands:
+//! - `Literal`: the value as 8 little-endian bytes
+//! - assignments and `++`/`--`: the target, `Var` or `VarY`
+//! - `Pow`: its modulus as 8 bytes, or `0` for a plain power
+//! - `Ternary` is lowered to the jumps below, and `Parens` emits nothing
+//!
+//! A saturating node is its operator's instruction behind a `SATURATE` byte,
+//! a Euclidean one behind a `EUCLID` byte and an unsigned one behind `UNSIGN`.
+
+use std::fmt;
+use crate::{euclidean_op, gcd, pow_mod, pow_negative_exp, saturating_op, unsigned_op, Expr, NumT, Operator};
+
+/// Pop the condition; jump to the 2-byte offset that follows if it's zero
+const JUMP_IF_ZERO: u8 = 0xF8;
+/// Jump to the 2-byte offset that follows
+const JUMP: u8 = 0xF9;
+/// A node whose target isn't a variable, which stops the run
+const FATAL: u8 = 0xFA;
+/// The `+`, `-` or `*` instruction that follows clamps instead of wrapping
+const SATURATE: u8 = 0xFB;
+/// The `/` or `%` instruction that follows rounds the Euclidean way
+const EUCLID: u8 = 0xFC;
+/// The comparison, `>>`, `/` or `%` instruction that follows reads its operands as u64
+const UNSIGN: u8 = 0xFD;
+
+/// Values `run_bytecode` can hold at once; `to_bytecode` checks code stays within it
+const STACK_SIZE: usize = 32;
+
+/// Compile `e` to bytecode for `run_bytecode`
+pub fn to_bytecode(e: &Expr) -> Vec<u8> {
+    let mut c = Compiler { code: Vec::new(), depth: 0 };
+    c.expr(e);
+    c.code
+}
+
+struct Compiler {
+    code: Vec<u8>,
+    depth: usize,
+}
+
+impl Compiler {
+    fn push(&mut self) {
+        self.depth += 1;
+        assert!(self.depth <= STACK_SIZE, "expression too deep for run_bytecode");
+    }
+
+    fn expr(&mut self, e: &Expr) {
+        if e.has_bad_target() {
+            self.code.push(FATAL);
+            self.push();
+            return;
+        }
+        let left = e.left.as_deref();
+        let right = e.right.as_deref();
+
+        match e.op {
+            Operator::Literal => {
+                self.code.push(Operator::Literal as u8);
+                self.code.extend_from_slice(&e.literal.to_le_bytes());
+                self.push();
+            }
+            Operator::Var | Operator::VarY => {
+                self.code.push(e.op as u8);
+                self.push();
+            }
+            Operator::Parens => self.expr(right.unwrap()),
+            Operator::Ternary => {
+                self.expr(left.unwrap());
+                self.depth -= 1;
+                let to_other = self.jump(JUMP_IF_ZERO);
+                self.expr(e.mid.as_ref().unwrap());
+                self.depth -= 1;
+                let to_end = self.jump(JUMP);
+                self.patch(to_other);
+                self.expr(right.unwrap());
+                self.patch(to_end);
+            }
+            _ if e.is_incdec() => {
+                self.code.extend_from_slice(&[e.op as u8, right.unwrap().op as u8]);
+                self.push();
+            }
+            _ if e.is_assignment() => {
+                self.expr(right.unwrap());
+                self.prefix(e);
+                self.code.extend_from_slice(&[e.op as u8, left.unwrap().op as u8]);
+            }
+            // Unary operators only have a right operand
+            _ if left.is_none() => {
+                self.expr(right.unwrap());
+                self.code.push(e.op as u8);
+            }
+            _ => {
+                self.expr(left.unwrap());
+                self.expr(right.unwrap());
+                self.prefix(e);
+                self.code.push(e.op as u8);
+                if e.op == Operator::Pow {
+                    self.code.extend_from_slice(&e.pow_modulus().unwrap_or(0).to_le_bytes());
+                }
+                self.depth -= 1;
+            }
+        }
+    }
+
+    fn prefix(&mut self, e: &Expr) {
+        if e.is_saturating() {
+            self.code.push(SATURATE);
+        } else if e.is_euclidean() {
+            self.code.push(EUCLID);
+        } else if e.is_unsigned() {
+            self.code.push(UNSIGN);
+        }
+    }
+
+    fn jump(&mut self, opcode: u8) -> usize {
+        self.code.extend_from_slice(&[opcode, 0, 0]);
+        self.code.len() - 2
+    }
+
+    // Point the jump whose offset is at `at` to the end of the code so far
+    fn patch(&mut self, at: usize) {
+        let target = u16::try_from(self.code.len()).expect("bytecode too long for a jump");
+        self.code[at..at + 2].copy_from_slice(&target.to_le_bytes());
+    }
+}
+
+/// Why `run_bytecode` couldn't run some code, with the byte offset it stopped at.
+/// `to_bytecode` never produces any of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BytecodeError {
+    /// The byte at `at` isn't an instruction, or isn't a target where one belongs
+    UnknownOpcode { byte: u8, at: usize },
+    /// The instruction at `at` has operands that run past the end of the code
+    Truncated { at: usize },
+    /// The instruction at `at` pops more values than the stack holds
+    StackUnderflow { at: usize },
+    /// The instruction at `at` pushes past `STACK_SIZE` values
+    StackOverflow { at: usize },
+    /// The jump at `at` goes to `target`, which isn't forward within the code
+    BadJump { target: usize, at: usize },
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BytecodeError::UnknownOpcode { byte, at } => write!(f, "unknown opcode {:#04x} at byte {}", byte, at),
+            BytecodeError::Truncated { at } => write!(f, "instruction at byte {} runs past the end of the code", at),
+            BytecodeError::StackUnderflow { at } => write!(f, "stack underflow at byte {}", at),
+            BytecodeError::StackOverflow { at } => write!(f, "stack overflow at byte {}", at),
+            BytecodeError::BadJump { target, at } => write!(f, "jump at byte {} to {} isn't forward within the code", at, target),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+fn read_u8(code: &[u8], pc: usize, at: usize) -> Result<u8, BytecodeError> {
+    code.get(pc).copied().ok_or(BytecodeError::Truncated { at })
+}
+
+fn read_num(code: &[u8], pc: usize, at: usize) -> Result<NumT, BytecodeError> {
+    let bytes = code.get(pc..pc + 8).ok_or(BytecodeError::Truncated { at })?;
+    Ok(NumT::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// The jump offset at `pc`, which must land past it and no further than the end
+fn read_jump(code: &[u8], pc: usize, at: usize) -> Result<usize, BytecodeError> {
+    let bytes = code.get(pc..pc + 2).ok_or(BytecodeError::Truncated { at })?;
+    let target = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+    if target < pc + 2 || target > code.len() {
+        return Err(BytecodeError::BadJump { target, at });
+    }
+    Ok(target)
+}
+
+// The variable named by the target byte at `pc`
+fn read_target<'a>(code: &[u8], pc: usize, at: usize, x: &'a mut NumT, y: &'a mut NumT) -> Result<&'a mut NumT, BytecodeError> {
+    match read_u8(code, pc, at)? {
+        b if b == Operator::Var as u8 => Ok(x),
+        b if b == Operator::VarY as u8 => Ok(y),
+        byte => Err(BytecodeError::UnknownOpcode { byte, at: pc }),
+    }
+}
+
+fn need(sp: usize, n: usize, at: usize) -> Result<(), BytecodeError> {
+    if sp < n { Err(BytecodeError::StackUnderflow { at }) } else { Ok(()) }
+}
+
+fn room(sp: usize, at: usize) -> Result<(), BytecodeError> {
+    if sp == STACK_SIZE { Err(BytecodeError::StackOverflow { at }) } else { Ok(()) }
+}
+
+// Whether `op` may follow the `SATURATE`, `EUCLID` or `UNSIGN` byte `prefix`
+fn takes_prefix(prefix: u8, op: Operator) -> bool {
+    use Operator::*;
+    match prefix {
+        SATURATE => matches!(op, Add | AddEq | Sub | SubEq | Mul | MulEq),
+        EUCLID => matches!(op, Div | DivEq | Mod | ModEq),
+        _ => matches!(op, Lt | Leq | Gt | Geq | Min | Max | BitShr | BitShrEq | Div | DivEq | Mod | ModEq),
+    }
+}
+
+/// Run code from `to_bytecode`, returning its value or None if it's fatal.
+/// Like the JIT, a fatal run stops right away, so x and y are then partly updated.
+/// Code that doesn't come from `to_bytecode` may be malformed, which is an error.
+pub fn run_bytecode(code: &[u8], x: &mut NumT, y: &mut NumT) -> Result<Option<NumT>, BytecodeError> {
+    let mut stack = [0 as NumT; STACK_SIZE];
+    let mut sp = 0;
+    let mut pc = 0;
+
+    while pc < code.len() {
+        let at = pc;
+        let op = code[pc];
+        pc += 1;
+        match op {
+            JUMP_IF_ZERO => {
+                need(sp, 1, at)?;
+                sp -= 1;
+                let target = read_jump(code, pc, at)?;
+                pc = if stack[sp] == 0 { target } else { pc + 2 };
+                continue;
+            }
+            JUMP => {
+                pc = read_jump(code, pc, at)?;
+                continue;
+            }
+            FATAL => return Ok(None),
+            SATURATE | EUCLID | UNSIGN => {
+                let apply: fn(Operator, NumT, NumT) -> Option<NumT> = match op {
+                    SATURATE => |op, l, r| Some(saturating_op(op, l, r)),
+                    EUCLID => euclidean_op,
+                    _ => unsigned_op,
+                };
+                let byte = read_u8(code, pc, at)?;
+                let prefixed = Operator::from_u8(byte).filter(|&o| takes_prefix(op, o));
+                let op = prefixed.ok_or(BytecodeError::UnknownOpcode { byte, at: pc })?;
+                pc += 1;
+                let value = if (op as u8) < Operator::Comma as u8 {
+                    need(sp, 1, at)?;
+                    let target = read_target(code, pc, at, x, y)?;
+                    pc += 1;
+                    let Some(v) = apply(op, *target, stack[sp - 1]) else { return Ok(None) };
+                    *target = v;
+                    v
+                } else {
+                    need(sp, 2, at)?;
+                    sp -= 1;
+                    let Some(v) = apply(op, stack[sp - 1], stack[sp]) else { return Ok(None) };
+                    v
+                };
+                stack[sp - 1] = value;
+                continue;
+            }
+            _ => {}
+        }
+        let op = Operator::from_u8(op).ok_or(BytecodeError::UnknownOpcode { byte: op, at })?;
+
+        match op {
+            Operator::Literal => {
+                room(sp, at)?;
+                stack[sp] = read_num(code, pc, at)?;
+                sp += 1;
+                pc += 8;
+            }
+            Operator::Var | Operator::VarY => {
+                room(sp, at)?;
+                stack[sp] = if op == Operator::Var { *x } else { *y };
+                sp += 1;
+            }
+            Operator::PreInc | Operator::PreDec | Operator::PostInc | Operator::PostDec => {
+                room(sp, at)?;
+                let target = read_target(code, pc, at, x, y)?;
+                pc += 1;
+                let old = *target;
+                *target = if matches!(op, Operator::PreInc | Operator::PostInc) {
+                    old.wrapping_add(1)
+                } else {
+                    old.wrapping_sub(1)
+                };
+                stack[sp] = if matches!(op, Operator::PreInc | Operator::PreDec) { *target } else { old };
+                sp += 1;
+            }
+            Operator::Neg | Operator::BitNot | Operator::Not | Operator::PopCount => {
+                need(sp, 1, at)?;
+                let v = stack[sp - 1];
+                stack[sp - 1] = match op {
+                    Operator::Neg => v.wrapping_neg(),
+                    Operator::BitNot => !v,
+                    Operator::Not => (v == 0) as NumT,
+                    _ => v.count_ones() as NumT,
+                };
+            }
+            // Lowered to jumps and dropped by to_bytecode
+            Operator::Ternary | Operator::Parens => return Err(BytecodeError::UnknownOpcode { byte: op as u8, at }),
+            _ if (op as u8) < Operator::Comma as u8 => {
+                need(sp, 1, at)?;
+                let target = read_target(code, pc, at, x, y)?;
+                pc += 1;
+                let Some(v) = assign_op(op, *target, stack[sp - 1]) else { return Ok(None) };
+                *target = v;
+                stack[sp - 1] = v;
+            }
+            _ => {
+                need(sp, 2, at)?;
+                let modulus = if op == Operator::Pow {
+                    let m = read_num(code, pc, at)?;
+                    pc += 8;
+                    m
+                } else {
+                    0
+                };
+                sp -= 1;
+                let Some(v) = binary_op(op, stack[sp - 1], stack[sp], modulus) else { return Ok(None) };
+                stack[sp - 1] = v;
+            }
+        }
+    }
+    need(sp, 1, code.len())?;
+    Ok(Some(stack[0]))
+}
+
+// `t op= r` for a plain assignment, None if it's fatal
+fn assign_op(op: Operator, t: NumT, r: NumT) -> Option<NumT> {
+    Some(match op {
+        Operator::AssignEq => r,
+        Operator::BitOrEq => t | r,
+        Operator::BitXorEq => t ^ r,
+        Operator::BitAndEq => t & r,
+        Operator::BitShlEq => t.wrapping_shl(r as u32),
+        Operator::BitShrEq => t.wrapping_shr(r as u32),
+        Operator::AddEq => t.wrapping_add(r),
+        Operator::SubEq => t.wrapping_sub(r),
+        Operator::MulEq => t.wrapping_mul(r),
+        Operator::DivEq => t.checked_div(r)?,
+        Operator::ModEq => t.checked_rem(r)?,
+        _ => unreachable!(),
+    })
+}
+
+// `l op r` for a plain binary operator, None if it's fatal; `modulus` is Pow's
+fn binary_op(op: Operator, l: NumT, r: NumT, modulus: NumT) -> Option<NumT> {
+    Some(match op {
+        Operator::Comma => r,
+        Operator::Or => (l != 0 || r != 0) as NumT,
+        Operator::And => (l != 0 && r != 0) as NumT,
+        Operator::BitOr => l | r,
+        Operator::BitXor => l ^ r,
+        Operator::BitAnd => l & r,
+        Operator::Eq => (l == r) as NumT,
+        Operator::Neq => (l != r) as NumT,
+        Operator::Lt => (l < r) as NumT,
+        Operator::Leq => (l <= r) as NumT,
+        Operator::Gt => (l > r) as NumT,
+        Operator::Geq => (l >= r) as NumT,
+        Operator::Min => l.min(r),
+        Operator::Max => l.max(r),
+        Operator::BitShl => l.wrapping_shl(r as u32),
+        Operator::BitShr => l.wrapping_shr(r as u32),
+        Operator::LShr => (l as u64).wrapping_shr(r as u32) as NumT,
+        Operator::Add => l.wrapping_add(r),
+        Operator::Sub => l.wrapping_sub(r),
+        Operator::Mul => l.wrapping_mul(r),
+        Operator::Div => l.checked_div(r)?,
+        Operator::Mod => l.checked_rem(r)?,
+        Operator::Gcd => gcd(l, r),
+        Operator::Pow if modulus > 0 => pow_mod(l, r, modulus)?,
+        Operator::Pow if r < 0 => pow_negative_exp(l, r)?,
+        Operator::Pow => l.checked_pow(u32::try_from(r).ok()?)?,
+        _ => unreachable!(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{self, bin, lit, ternary, un, x, y};
+    use crate::naive_eval;
+
+    // Bytecode agrees with naive_eval on value, x, y and fatality over a grid
+    fn check(e: &Expr) {
+        let code = to_bytecode(e);
+        for x0 in -4..=4 {
+            for y0 in -4..=4 {
+                let (mut x, mut y, mut fatal) = (x0, y0, false);
+                let expected = naive_eval(e, &mut x, &mut y, &mut fatal);
+                let (mut bx, mut by) = (x0, y0);
+                match run_bytecode(&code, &mut bx, &mut by).unwrap() {
+                    Some(v) => {
+                        assert!(!fatal, "x={} y={}: naive is fatal", x0, y0);
+                        assert_eq!((v, bx, by), (expected, x, y), "x={} y={}", x0, y0);
+                    }
+                    None => assert!(fatal, "x={} y={}: bytecode is fatal", x0, y0),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytecode_operators() {
+        let binary = [
+            Operator::Comma, Operator::Or, Operator::And, Operator::BitOr, Operator::BitXor,
+            Operator::BitAnd, Operator::Eq, Operator::Neq, Operator::Lt, Operator::Leq,
+            Operator::Gt, Operator::Geq, Operator::Min, Operator::Max, Operator::BitShl,
+            Operator::BitShr, Operator::LShr, Operator::Add, Operator::Sub, Operator::Mul,
+            Operator::Div, Operator::Mod, Operator::Pow, Operator::Gcd,
+        ];
+        for op in binary {
+            check(&bin(x(), op, bin(y(), Operator::Sub, lit(1))));
+        }
+        for op in [Operator::Neg, Operator::BitNot, Operator::Not, Operator::PopCount, Operator::Parens] {
+            check(&un(op, bin(x(), Operator::Mul, y())));
+        }
+        check(&lit(NumT::MIN));
+    }
+
+    #[test]
+    fn test_bytecode_side_effects() {
+        let assign = [
+            Operator::AssignEq, Operator::BitOrEq, Operator::BitXorEq, Operator::BitAndEq,
+            Operator::BitShlEq, Operator::BitShrEq, Operator::AddEq, Operator::SubEq,
+            Operator::MulEq, Operator::DivEq, Operator::ModEq,
+        ];
+        for op in assign {
+            check(&bin(x(), op, bin(y(), Operator::Add, un(Operator::PostInc, x()))));
+            check(&bin(y(), op, x()));
+        }
+        for op in [Operator::PreInc, Operator::PreDec, Operator::PostInc, Operator::PostDec] {
+            check(&bin(un(op, y()), Operator::Sub, un(op, y())));
+        }
+    }
+
+    #[test]
+    fn test_bytecode_ternary_runs_one_branch() {
+        // x?y++:--x, then the comma reads both
+        let e = ternary(x(), un(Operator::PostInc, y()), un(Operator::PreDec, x()));
+        check(&e);
+        check(&bin(e, Operator::Comma, bin(x(), Operator::Mul, y())));
+        // Nested in both branches
+        let inner = ternary(y(), lit(3), un(Operator::PostDec, y()));
+        check(&ternary(bin(x(), Operator::Lt, lit(0)), inner.clone(), bin(inner, Operator::Add, x())));
+    }
+
+    #[test]
+    fn test_bytecode_fatal() {
+        // ++(x+1) has no variable to write
+        check(&un(Operator::PreInc, bin(x(), Operator::Add, lit(1))));
+        check(&bin(lit(2), Operator::AssignEq, x()));
+        let (mut x0, mut y0) = (1, 0);
+        assert_eq!(run_bytecode(&to_bytecode(&bin(x(), Operator::DivEq, y())), &mut x0, &mut y0), Ok(None));
+    }
+
+    #[test]
+    fn test_bytecode_pow_mod() {
+        check(&build::pow_mod(x(), bin(y(), Operator::Add, lit(40)), 7));
+        check(&build::pow_mod(bin(x(), Operator::Sub, lit(1)), y(), 1000));
+    }
+
+    #[test]
+    fn test_bytecode_saturating() {
+        let big = lit(NumT::MAX / 2);
+        for op in [Operator::Add, Operator::Sub, Operator::Mul] {
+            check(&build::saturating(bin(x(), Operator::Mul, big.clone()), op, bin(y(), Operator::Mul, big.clone())));
+        }
+        for op in [Operator::AddEq, Operator::SubEq, Operator::MulEq] {
+            check(&bin(build::saturating(x(), op, bin(y(), Operator::Mul, big.clone())), Operator::Comma, x()));
+        }
+        let (mut x0, mut y0) = (NumT::MAX, 0);
+        let code = to_bytecode(&build::saturating(x(), Operator::AddEq, lit(1)));
+        assert_eq!(run_bytecode(&code, &mut x0, &mut y0), Ok(Some(NumT::MAX)));
+        assert_eq!(x0, NumT::MAX);
+    }
+
+    #[test]
+    fn test_bytecode_euclidean() {
+        // The grid has negative dividends and divisors of both signs
+        for op in [Operator::Div, Operator::Mod] {
+            check(&build::euclidean(x(), op, y()));
+            check(&build::euclidean(lit(NumT::MIN), op, y()));
+        }
+        for op in [Operator::DivEq, Operator::ModEq] {
+            check(&bin(build::euclidean(x(), op, y()), Operator::Comma, x()));
+        }
+        let (mut x0, mut y0) = (-7, 2);
+        let code = to_bytecode(&build::euclidean(x(), Operator::ModEq, y()));
+        assert_eq!(run_bytecode(&code, &mut x0, &mut y0), Ok(Some(1)));
+    }
+
+    #[test]
+    fn test_bytecode_unsigned() {
+        for op in [Operator::Lt, Operator::Leq, Operator::Gt, Operator::Geq, Operator::Min, Operator::Max, Operator::BitShr, Operator::Div, Operator::Mod] {
+            check(&build::unsigned(x(), op, y()));
+            check(&build::unsigned(lit(NumT::MIN), op, y()));
+        }
+        for op in [Operator::BitShrEq, Operator::DivEq, Operator::ModEq] {
+            check(&bin(build::unsigned(x(), op, y()), Operator::Comma, x()));
+        }
+        let (mut x0, mut y0) = (-1, 2);
+        let code = to_bytecode(&build::unsigned(x(), Operator::DivEq, y()));
+        assert_eq!(run_bytecode(&code, &mut x0, &mut y0), Ok(Some(NumT::MAX)));
+    }
+
+    #[test]
+    fn test_bytecode_layout() {
+        // x+=y*3 in postfix: y 3 * +=x
+        let code = to_bytecode(&bin(x(), Operator::AddEq, bin(y(), Operator::Mul, lit(3))));
+        let mut expected = vec![Operator::VarY as u8, Operator::Literal as u8];
+        expected.extend_from_slice(&3i64.to_le_bytes());
+        expected.extend_from_slice(&[Operator::Mul as u8, Operator::AddEq as u8, Operator::Var as u8]);
+        assert_eq!(code, expected);
+    }
+
+    fn run(code: &[u8]) -> Result<Option<NumT>, BytecodeError> {
+        let (mut x0, mut y0) = (1, 2);
+        run_bytecode(code, &mut x0, &mut y0)
+    }
+
+    #[test]
+    fn test_bytecode_unknown_opcode() {
+        let (v, m) = (Operator::Var as u8, Operator::Mul as u8);
+        assert_eq!(run(&[v, 0xEE]), Err(BytecodeError::UnknownOpcode { byte: 0xEE, at: 1 }));
+        // A target that isn't a variable, and an operator UNSIGN can't take
+        assert_eq!(run(&[v, Operator::AddEq as u8, 0x15]), Err(BytecodeError::UnknownOpcode { byte: 0x15, at: 2 }));
+        assert_eq!(run(&[v, v, UNSIGN, m]), Err(BytecodeError::UnknownOpcode { byte: m, at: 3 }));
+        assert_eq!(
+            BytecodeError::UnknownOpcode { byte: 0xEE, at: 1 }.to_string(),
+            "unknown opcode 0xee at byte 1"
+        );
+    }
+
+    #[test]
+    fn test_bytecode_truncated() {
+        let code = to_bytecode(&lit(7));
+        assert_eq!(run(&code[..5]), Err(BytecodeError::Truncated { at: 0 }));
+        let code = to_bytecode(&bin(x(), Operator::Pow, y()));
+        assert_eq!(run(&code[..code.len() - 1]), Err(BytecodeError::Truncated { at: 2 }));
+        assert_eq!(run(&[Operator::PreInc as u8]), Err(BytecodeError::Truncated { at: 0 }));
+    }
+
+    #[test]
+    fn test_bytecode_stack_underflow() {
+        assert_eq!(run(&[Operator::Var as u8, Operator::Add as u8]), Err(BytecodeError::StackUnderflow { at: 1 }));
+        assert_eq!(run(&[Operator::Neg as u8]), Err(BytecodeError::StackUnderflow { at: 0 }));
+        // Code that leaves nothing to return
+        assert_eq!(run(&[]), Err(BytecodeError::StackUnderflow { at: 0 }));
+    }
+
+    #[test]
+    fn test_bytecode_stack_overflow() {
+        let code = vec![Operator::Var as u8; STACK_SIZE + 1];
+        assert_eq!(run(&code), Err(BytecodeError::StackOverflow { at: STACK_SIZE }));
+        assert_eq!(run(&code[..STACK_SIZE]), Ok(Some(1)));
+    }
+
+    #[test]
+    fn test_bytecode_bad_jump() {
+        // A jump back to itself would never stop
+        assert_eq!(run(&[JUMP, 0, 0]), Err(BytecodeError::BadJump { target: 0, at: 0 }));
+        assert_eq!(run(&[Operator::Var as u8, JUMP, 9, 0]), Err(BytecodeError::BadJump { target: 9, at: 1 }));
+        assert_eq!(run(&[Operator::Var as u8, JUMP, 4, 0]), Ok(Some(1)));
+    }
+}
@@ -0,0 +1,333 @@
+//! Exporting an expression as a C function.
+//!
+//! The operators C has are emitted as themselves wherever C defines them the
+//! crate's way, so a C compiler can check the crate's semantics: `/` and `%`
+//! behind a guard for their fatal inputs, comparisons, bitwise operators and
+//! `>>` on a signed value. Wraparound and `<<` go through `uint64_t`, as
+//! signed overflow is undefined in C, and shift counts are masked to 6 bits
+//! as the crate does. The rest (`**`, `%%`, `#`, saturating and Euclidean
+//! nodes) call the helpers in `C_PRELUDE`. Each node is its own statement,
+//! so side effects happen in `naive_eval`'s order.
+
+use crate::{format_expression, Expr, NumT, Operator};
+
+/// What the functions `to_c` emits need in front of them
+pub const C_PRELUDE: &str = r#"#include <stdint.h>
+
+#define FATAL do { *fatal = 1; return 0; } while (0)
+
+static int64_t wrap_add(int64_t a, int64_t b) { return (int64_t)((uint64_t)a + (uint64_t)b); }
+static int64_t wrap_sub(int64_t a, int64_t b) { return (int64_t)((uint64_t)a - (uint64_t)b); }
+static int64_t wrap_mul(int64_t a, int64_t b) { return (int64_t)((uint64_t)a * (uint64_t)b); }
+
+static int64_t sat_add(int64_t a, int64_t b) {
+    int64_t r;
+    return __builtin_add_overflow(a, b, &r) ? (b < 0 ? INT64_MIN : INT64_MAX) : r;
+}
+
+static int64_t sat_sub(int64_t a, int64_t b) {
+    int64_t r;
+    return __builtin_sub_overflow(a, b, &r) ? (b < 0 ? INT64_MAX : INT64_MIN) : r;
+}
+
+static int64_t sat_mul(int64_t a, int64_t b) {
+    int64_t r;
+    return __builtin_mul_overflow(a, b, &r) ? ((a < 0) != (b < 0) ? INT64_MIN : INT64_MAX) : r;
+}
+
+/* Euclidean: the remainder is never negative. Fatal where / is */
+static int ediv(int64_t a, int64_t b, int64_t *out) {
+    if (b == 0 || (a == INT64_MIN && b == -1)) return 0;
+    int64_t q = a / b;
+    *out = a % b < 0 ? (b > 0 ? q - 1 : q + 1) : q;
+    return 1;
+}
+
+static int emod(int64_t a, int64_t b, int64_t *out) {
+    if (b == 0 || (a == INT64_MIN && b == -1)) return 0;
+    int64_t r = a % b;
+    *out = r < 0 ? (b < 0 ? r - b : r + b) : r;
+    return 1;
+}
+
+/* Fatal on overflow, for an exponent past 2**32-1 and for 0**-n */
+static int ipow(int64_t a, int64_t b, int64_t *out) {
+    if (b < 0) {
+        if (a == 0) return 0;
+        *out = a == 1 ? 1 : a == -1 ? (b % 2 == 0 ? 1 : -1) : 0;
+        return 1;
+    }
+    if (b > 0xFFFFFFFFLL) return 0;
+    int64_t acc = 1;
+    if (b == 0) { *out = 1; return 1; }
+    for (;;) {
+        if (b & 1) {
+            if (__builtin_mul_overflow(acc, a, &acc)) return 0;
+            if (b == 1) { *out = acc; return 1; }
+        }
+        b >>= 1;
+        if (__builtin_mul_overflow(a, a, &a)) return 0;
+    }
+}
+
+static int ipow_mod(int64_t a, int64_t b, int64_t m, int64_t *out) {
+    if (b < 0) {
+        if (!ipow(a, b, out)) return 0;
+        *out %= m;
+        return 1;
+    }
+    __int128 r = 1 % m, base = (__int128)a % m;
+    for (; b > 0; b >>= 1) {
+        if (b & 1) r = r * base % m;
+        base = base * base % m;
+    }
+    *out = (int64_t)r;
+    return 1;
+}
+
+static int64_t gcd(int64_t a, int64_t b) {
+    uint64_t ua = a < 0 ? 0 - (uint64_t)a : (uint64_t)a, ub = b < 0 ? 0 - (uint64_t)b : (uint64_t)b;
+    while (ub != 0) {
+        uint64_t t = ua % ub;
+        ua = ub;
+        ub = t;
+    }
+    return (int64_t)ua;
+}
+
+static int64_t popcount(int64_t a) {
+    int64_t n = 0;
+    for (uint64_t u = (uint64_t)a; u != 0; u &= u - 1) n++;
+    return n;
+}
+"#;
+
+/// `int64_t name(int64_t *x, int64_t *y, int *fatal)` running `e` like
+/// `naive_eval`: it returns the value and leaves x and y as `e` does, or sets
+/// `*fatal` and returns 0. Compile it after `C_PRELUDE`.
+pub fn to_c(e: &Expr, name: &str) -> String {
+    let mut w = Lowering { lines: Vec::new(), indent: 1, temps: 0 };
+    let value = w.lower(e);
+    let mut out = format!("/* {} */\nint64_t {}(int64_t *x, int64_t *y, int *fatal) {{\n", format_expression(e, &['x', 'y']), name);
+    for line in &w.lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(&format!("    return {};\n}}\n", value));
+    out
+}
+
+/// `n` as a C expression; `-9223372036854775808` would be the negation of
+/// a literal too large for any type
+fn literal(n: NumT) -> String {
+    if n == NumT::MIN {
+        "INT64_MIN".to_string()
+    } else {
+        format!("INT64_C({})", n)
+    }
+}
+
+struct Lowering {
+    lines: Vec<String>,
+    indent: usize,
+    temps: usize,
+}
+
+impl Lowering {
+    fn line(&mut self, s: String) {
+        self.lines.push(format!("{}{}", "    ".repeat(self.indent), s));
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("t{}", self.temps)
+    }
+
+    /// Store `value` in a fresh temporary, so later writes to x or y can't change it
+    fn bind(&mut self, value: String) -> String {
+        let t = self.temp();
+        self.line(format!("int64_t {} = {};", t, value));
+        t
+    }
+
+    /// A helper that reports failure, e.g. `ipow(a, b, &t)`, into a fresh
+    /// temporary, fatal where it fails
+    fn checked(&mut self, call: impl FnOnce(&str) -> String) -> String {
+        let t = self.temp();
+        self.line(format!("int64_t {};", t));
+        let call = call(&t);
+        self.line(format!("if (!{}) FATAL;", call));
+        t
+    }
+
+    fn var(e: &Expr) -> &'static str {
+        if e.op == Operator::Var { "*x" } else { "*y" }
+    }
+
+    /// `l / r` or `l % r` for a division node or its assignment, after the
+    /// guard for its fatal inputs
+    fn divide(&mut self, e: &Expr, l: &str, r: &str) -> String {
+        let div = matches!(e.op, Operator::Div | Operator::DivEq);
+        if e.is_euclidean() {
+            let helper = if div { "ediv" } else { "emod" };
+            return self.checked(|t| format!("{}({}, {}, &{})", helper, l, r, t));
+        }
+        let sym = if div { "/" } else { "%" };
+        if e.is_unsigned() {
+            self.line(format!("if ({} == 0) FATAL;", r));
+            format!("(int64_t)((uint64_t){} {} (uint64_t){})", l, sym, r)
+        } else {
+            self.line(format!("if ({} == 0 || ({} == INT64_MIN && {} == -1)) FATAL;", r, l, r));
+            format!("{} {} {}", l, sym, r)
+        }
+    }
+
+    /// `+`, `-` or `*` for a node or its assignment, wrapping or saturating
+    fn arith(e: &Expr, l: &str, r: &str) -> String {
+        let name = match e.op {
+            Operator::Add | Operator::AddEq => "add",
+            Operator::Sub | Operator::SubEq => "sub",
+            _ => "mul",
+        };
+        let kind = if e.is_saturating() { "sat" } else { "wrap" };
+        format!("{}_{}({}, {})", kind, name, l, r)
+    }
+
+    /// Emit the statements evaluating `e`, in `naive_eval`'s order, and return
+    /// the C expression holding its value
+    fn lower(&mut self, e: &Expr) -> String {
+        if e.has_bad_target() {
+            self.line("FATAL;".to_string());
+            return "0".to_string();
+        }
+
+        match e.op {
+            Operator::Literal => return literal(e.literal),
+            Operator::Var | Operator::VarY => return self.bind(Self::var(e).to_string()),
+            Operator::Ternary => {
+                let cond = self.lower(e.left.as_ref().unwrap());
+                let t = self.temp();
+                self.line(format!("int64_t {};", t));
+                self.line(format!("if ({} != 0) {{", cond));
+                self.indent += 1;
+                let then = self.lower(e.mid.as_ref().unwrap());
+                self.line(format!("{} = {};", t, then));
+                self.indent -= 1;
+                self.line("} else {".to_string());
+                self.indent += 1;
+                let other = self.lower(e.right.as_ref().unwrap());
+                self.line(format!("{} = {};", t, other));
+                self.indent -= 1;
+                self.line("}".to_string());
+                return t;
+            }
+            _ => {}
+        }
+
+        if e.is_assignment() {
+            let target = Self::var(e.left.as_ref().unwrap());
+            let r = self.lower(e.right.as_ref().unwrap());
+            let value = match e.op {
+                Operator::BitShrEq if e.is_unsigned() => format!("(int64_t)((uint64_t){} >> ({} & 63))", target, r),
+                Operator::AssignEq => r,
+                Operator::BitOrEq => format!("{} | {}", target, r),
+                Operator::BitXorEq => format!("{} ^ {}", target, r),
+                Operator::BitAndEq => format!("{} & {}", target, r),
+                Operator::BitShlEq => format!("(int64_t)((uint64_t){} << ({} & 63))", target, r),
+                Operator::BitShrEq => format!("{} >> ({} & 63)", target, r),
+                Operator::AddEq | Operator::SubEq | Operator::MulEq => Self::arith(e, target, &r),
+                Operator::DivEq | Operator::ModEq => self.divide(e, target, &r),
+                _ => unreachable!(),
+            };
+            self.line(format!("{} = {};", target, value));
+            return self.bind(target.to_string());
+        }
+
+        if e.is_incdec() {
+            let target = Self::var(e.right.as_ref().unwrap());
+            let step = if matches!(e.op, Operator::PreInc | Operator::PostInc) { "add" } else { "sub" };
+            let update = format!("{} = wrap_{}({}, 1);", target, step, target);
+            return if matches!(e.op, Operator::PreInc | Operator::PreDec) {
+                self.line(update);
+                self.bind(target.to_string())
+            } else {
+                let t = self.bind(target.to_string());
+                self.line(update);
+                t
+            };
+        }
+
+        let l = e.left.as_ref().map(|left| self.lower(left)).unwrap_or_default();
+        let r = e.right.as_ref().map(|right| self.lower(right)).unwrap_or_default();
+        let value = match e.op {
+            Operator::Comma | Operator::Parens => return r,
+            Operator::Lt if e.is_unsigned() => format!("(int64_t)((uint64_t){} < (uint64_t){})", l, r),
+            Operator::Leq if e.is_unsigned() => format!("(int64_t)((uint64_t){} <= (uint64_t){})", l, r),
+            Operator::Gt if e.is_unsigned() => format!("(int64_t)((uint64_t){} > (uint64_t){})", l, r),
+            Operator::Geq if e.is_unsigned() => format!("(int64_t)((uint64_t){} >= (uint64_t){})", l, r),
+            Operator::Min if e.is_unsigned() => format!("(uint64_t){} < (uint64_t){} ? {} : {}", l, r, l, r),
+            Operator::Max if e.is_unsigned() => format!("(uint64_t){} > (uint64_t){} ? {} : {}", l, r, l, r),
+            Operator::BitShr if e.is_unsigned() => format!("(int64_t)((uint64_t){} >> ({} & 63))", l, r),
+            Operator::Or => format!("(int64_t)({} || {})", l, r),
+            Operator::And => format!("(int64_t)({} && {})", l, r),
+            Operator::BitOr => format!("{} | {}", l, r),
+            Operator::BitXor => format!("{} ^ {}", l, r),
+            Operator::BitAnd => format!("{} & {}", l, r),
+            Operator::Eq => format!("(int64_t)({} == {})", l, r),
+            Operator::Neq => format!("(int64_t)({} != {})", l, r),
+            Operator::Lt => format!("(int64_t)({} < {})", l, r),
+            Operator::Leq => format!("(int64_t)({} <= {})", l, r),
+            Operator::Gt => format!("(int64_t)({} > {})", l, r),
+            Operator::Geq => format!("(int64_t)({} >= {})", l, r),
+            Operator::Min => format!("{} < {} ? {} : {}", l, r, l, r),
+            Operator::Max => format!("{} > {} ? {} : {}", l, r, l, r),
+            Operator::BitShl => format!("(int64_t)((uint64_t){} << ({} & 63))", l, r),
+            Operator::BitShr => format!("{} >> ({} & 63)", l, r),
+            Operator::LShr => format!("(int64_t)((uint64_t){} >> ({} & 63))", l, r),
+            Operator::Add | Operator::Sub | Operator::Mul => Self::arith(e, &l, &r),
+            Operator::Div | Operator::Mod => self.divide(e, &l, &r),
+            Operator::Pow => match e.pow_modulus() {
+                Some(m) => self.checked(|t| format!("ipow_mod({}, {}, {}, &{})", l, r, literal(m), t)),
+                None => self.checked(|t| format!("ipow({}, {}, &{})", l, r, t)),
+            },
+            Operator::Gcd => format!("gcd({}, {})", l, r),
+            Operator::Neg => format!("wrap_sub(0, {})", r),
+            Operator::BitNot => format!("~{}", r),
+            Operator::Not => format!("(int64_t)!{}", r),
+            Operator::PopCount => format!("popcount({})", r),
+            _ => unreachable!("{:?} is handled above", e.op),
+        };
+        self.bind(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{bin, lit, ternary, un, x, y};
+
+    #[test]
+    fn test_to_c_shape() {
+        // x+=y*3
+        let c = to_c(&bin(x(), Operator::AddEq, bin(y(), Operator::Mul, lit(3))), "step");
+        assert_eq!(
+            c,
+            "/* x+=y*3 */\n\
+             int64_t step(int64_t *x, int64_t *y, int *fatal) {\n    \
+             int64_t t1 = *y;\n    \
+             int64_t t2 = wrap_mul(t1, INT64_C(3));\n    \
+             *x = wrap_add(*x, t2);\n    \
+             int64_t t3 = *x;\n    \
+             return t3;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_to_c_guards() {
+        let c = to_c(&bin(x(), Operator::Mod, lit(NumT::MIN)), "f");
+        assert!(c.contains("if (INT64_MIN == 0 || (t1 == INT64_MIN && INT64_MIN == -1)) FATAL;"));
+        // Only the branch taken runs, and a bad target is fatal where it runs
+        let c = to_c(&ternary(x(), un(Operator::PreInc, lit(1)), y()), "f");
+        assert!(c.contains("if (t1 != 0) {\n        FATAL;\n        t2 = 0;\n    } else {"));
+    }
+}
@@ -0,0 +1,46 @@
+//! Catalogs of every distinct behavior at a length, for studying the space
+//! of expressions rather than searching it for a target.
+
+use crate::config::SearchConfig;
+use crate::generate::build_cache;
+use crate::{fingerprint, format_expression};
+use std::io::{self, Write};
+
+/// Generate the expressions of length `n` and write one line per class,
+/// `<expression>\t<fingerprint>`, sorted by the printed form. Classes are
+/// only merged within a length, so one a shorter expression already has is
+/// listed again. Returns the number of lines written.
+pub fn dump_catalog<W: Write>(config: &SearchConfig, n: usize, writer: &mut W) -> io::Result<usize> {
+    let ctx = build_cache(config, n);
+    let mut lines: Vec<String> = ctx.expressions(n).into_iter().flatten()
+        .map(|e| format!("{}\t{}", format_expression(e, &['x', 'y']), fingerprint(e)))
+        .collect();
+    lines.sort();
+    for line in &lines {
+        writeln!(writer, "{}", line)?;
+    }
+    Ok(lines.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::HashSet;
+
+    #[test]
+    fn test_dump_catalog() {
+        let mut out = Vec::new();
+        assert_eq!(dump_catalog(&SearchConfig::default(), 1, &mut out).unwrap(), 5);
+        let text = String::from_utf8(out).unwrap();
+        let exprs: Vec<&str> = text.lines().map(|l| l.split('\t').next().unwrap()).collect();
+        assert_eq!(exprs, ["1", "2", "3", "x", "y"]);
+
+        // One line per class, so no two share a fingerprint
+        let mut out = Vec::new();
+        let count = dump_catalog(&SearchConfig::default(), 3, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let prints: HashSet<&str> = text.lines().map(|l| l.split('\t').nth(1).unwrap()).collect();
+        assert_eq!(prints.len(), count);
+        assert!(prints.iter().all(|p| p.len() == 48));
+    }
+}
@@ -0,0 +1,321 @@
+//! Saving a search's progress to a file and resuming from it.
+//!
+//! A checkpoint holds the cached expressions and statements, the last fully
+//! searched length and the pairs reported so far. Trees are stored as one
+//! table of nodes, children before parents, so subtrees the caches share are
+//! written once and shared again on loading. Statements are rebuilt through
+//! `make_expr`, which compiles them just as generation did and gives each
+//! node the mode its config does, so modes aren't stored. (Bytecode won't
+//! do for this: it drops `Parens` and lowers ternaries to jumps.)
+
+use crate::{make_expr, make_ternary, sample_grid, Cache, Context, Expr, NumT, Operator, SearchConfig};
+use hashbrown::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"EXPRCKPT";
+const VERSION: u32 = 2;
+/// Child index of a node without that child
+const NONE: u32 = u32::MAX;
+
+/// What `resume_from` loads
+pub struct Checkpoint {
+    pub ctx: Context,
+    /// Every length up to this one was searched
+    pub length: usize,
+    pub solutions: Vec<FoundPair>,
+}
+
+/// A pair the search reported, to report again on resuming
+#[derive(Debug, Clone)]
+pub struct FoundPair {
+    pub length: usize,
+    pub e_x: Arc<Expr>,
+    pub e_y: Arc<Expr>,
+    pub init_x: NumT,
+    pub init_y: NumT,
+}
+
+/// The generator settings a checkpoint's caches depend on, the grid they
+/// were deduplicated over included. Threads and the JIT don't change what's
+/// generated, so resuming may switch them.
+fn fingerprint(config: &SearchConfig, target: &str) -> String {
+    let mut config = config.clone();
+    config.use_jit = false;
+    config.use_multithread = false;
+    format!("{:?}\n{:?}\n{}", config, sample_grid(), target)
+}
+
+/// Write `ctx`'s caches, `length` and `solutions` to `path`. `target`
+/// is whatever else the solutions depend on (the sequence, seeds and so on),
+/// checked along with `config` on resuming. Written to a temporary file and
+/// renamed, so a crash mid-write leaves the previous checkpoint intact.
+pub fn save_checkpoint(
+    path: &Path,
+    config: &SearchConfig,
+    target: &str,
+    ctx: &Context,
+    length: usize,
+    solutions: &[FoundPair],
+) -> io::Result<()> {
+    let mut w = Writer { out: Vec::new(), ids: HashMap::new(), nodes: Vec::new() };
+    let var_ids: Vec<u32> = ctx.var_expressions().iter().map(|e| w.node(e)).collect();
+    let mut caches = Vec::new();
+    // Lengths past the cache are searched without one
+    for n in (1..).take_while(|&n| ctx.expressions(n).is_some()) {
+        let mut ids = |cache: Option<&Cache>| cache.into_iter().flatten().map(|e| w.node(e)).collect::<Vec<_>>();
+        caches.push((ids(ctx.expressions(n)), ids(ctx.statements(n)), ctx.pruned(n)));
+    }
+    let pairs: Vec<(u32, u32)> = solutions.iter().map(|s| (w.node(&s.e_x), w.node(&s.e_y))).collect();
+
+    let nodes = std::mem::take(&mut w.nodes);
+    w.out.extend_from_slice(MAGIC);
+    w.u32(VERSION);
+    w.bytes(fingerprint(config, target).as_bytes());
+    w.u64(length as u64);
+    w.u32(nodes.len() as u32);
+    for (op, literal, children) in nodes {
+        w.out.push(op as u8);
+        w.out.extend_from_slice(&literal.to_le_bytes());
+        children.iter().for_each(|&c| w.u32(c));
+    }
+    w.ids(&var_ids);
+    w.u32(caches.len() as u32);
+    for (exprs, stmts, pruned) in &caches {
+        w.ids(exprs);
+        w.ids(stmts);
+        w.u64(*pruned as u64);
+    }
+    w.u32(solutions.len() as u32);
+    for (s, (e_x, e_y)) in solutions.iter().zip(pairs) {
+        w.u64(s.length as u64);
+        w.u32(e_x);
+        w.u32(e_y);
+        w.u64(s.init_x as u64);
+        w.u64(s.init_y as u64);
+    }
+
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, &w.out)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Load a checkpoint written by `save_checkpoint` with the same `config` and
+/// `target`, to continue from the length after `Checkpoint::length`
+pub fn resume_from(path: &Path, config: &SearchConfig, target: &str) -> io::Result<Checkpoint> {
+    let data = std::fs::read(path)?;
+    let mut r = Reader { data: &data, at: 0 };
+    if r.take(MAGIC.len())? != MAGIC || r.u32()? != VERSION {
+        return Err(invalid("not a checkpoint, or from another version"));
+    }
+    if r.take_bytes()? != fingerprint(config, target).as_bytes() {
+        return Err(invalid("checkpoint was made with a different config or target"));
+    }
+    let length = r.u64()? as usize;
+
+    let mut nodes: Vec<Arc<Expr>> = Vec::new();
+    for _ in 0..r.u32()? {
+        let op = r.u8()?;
+        let op = Operator::from_u8(op).ok_or_else(|| invalid("unknown operator"))?;
+        let literal = r.u64()? as NumT;
+        let mut child = || -> io::Result<Option<Arc<Expr>>> {
+            match r.u32()? {
+                NONE => Ok(None),
+                // Children come first, so anything else is corrupt
+                id => nodes.get(id as usize).cloned().map(Some).ok_or_else(|| invalid("bad child index")),
+            }
+        };
+        let (left, mid, right) = (child()?, child()?, child()?);
+        let e = match (op, left, mid, right) {
+            (Operator::Ternary, Some(cond), Some(then), Some(other)) => make_ternary(config, &cond, &then, &other),
+            (_, left, None, right) => make_expr(config, left, right, literal, op),
+            _ => return Err(invalid("malformed node")),
+        };
+        nodes.push(Arc::new(e));
+    }
+
+    let mut ctx = Context::new();
+    for e in r.ids(&nodes)? {
+        ctx.push_var_expression(e);
+    }
+    for n in 1..=r.u32()? as usize {
+        ctx.insert_expressions(n, r.ids(&nodes)?.into_iter().collect());
+        ctx.insert_statements(n, r.ids(&nodes)?.into_iter().collect());
+        ctx.set_pruned(n, r.u64()? as usize);
+    }
+    let mut solutions = Vec::new();
+    for _ in 0..r.u32()? {
+        let length = r.u64()? as usize;
+        let e_x = r.node(&nodes)?;
+        let e_y = r.node(&nodes)?;
+        let (init_x, init_y) = (r.u64()? as NumT, r.u64()? as NumT);
+        solutions.push(FoundPair { length, e_x, e_y, init_x, init_y });
+    }
+    if r.at != data.len() {
+        return Err(invalid("trailing data"));
+    }
+    Ok(Checkpoint { ctx, length, solutions })
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+type Node = (Operator, NumT, [u32; 3]);
+
+struct Writer {
+    out: Vec<u8>,
+    ids: HashMap<*const Expr, u32>,
+    nodes: Vec<Node>,
+}
+
+impl Writer {
+    /// The index of `e` in the node table, adding it and its children first
+    fn node(&mut self, e: &Expr) -> u32 {
+        if let Some(&id) = self.ids.get(&(e as *const Expr)) {
+            return id;
+        }
+        let mut children = [NONE; 3];
+        for (slot, child) in children.iter_mut().zip([&e.left, &e.mid, &e.right]) {
+            if let Some(child) = child {
+                *slot = self.node(child);
+            }
+        }
+        let id = self.nodes.len() as u32;
+        self.nodes.push((e.op, e.literal, children));
+        self.ids.insert(e as *const Expr, id);
+        id
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.u32(b.len() as u32);
+        self.out.extend_from_slice(b);
+    }
+
+    fn ids(&mut self, ids: &[u32]) {
+        self.u32(ids.len() as u32);
+        ids.iter().for_each(|&id| self.u32(id));
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.data.get(self.at..self.at + len).ok_or_else(|| invalid("checkpoint is truncated"))?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn take_bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn node(&mut self, nodes: &[Arc<Expr>]) -> io::Result<Arc<Expr>> {
+        let id = self.u32()? as usize;
+        nodes.get(id).cloned().ok_or_else(|| invalid("bad node index"))
+    }
+
+    /// A list of node indices, as the nodes they refer to
+    fn ids(&mut self, nodes: &[Arc<Expr>]) -> io::Result<Vec<Arc<Expr>>> {
+        (0..self.u32()?).map(|_| self.node(nodes)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_cache;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("expr_checkpoint_{}_{}", std::process::id(), name))
+    }
+
+    fn printed(cache: Option<&Cache>) -> Vec<String> {
+        let mut v: Vec<String> = cache.into_iter().flatten().map(|e| crate::format_expression(e, &['x', 'y'])).collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let config = SearchConfig::default();
+        let ctx = build_cache(&config, 4);
+        let path = temp_path("round_trip");
+        // x+=y, sharing its leaves with the caches, and a cached statement
+        let vars = ctx.var_expressions();
+        let e_x = Arc::new(make_expr(&config, Some(vars[0].clone()), Some(vars[1].clone()), 0, Operator::AddEq));
+        let e_y = ctx.statements(3).unwrap().iter().next().unwrap().clone();
+        let solutions = vec![FoundPair { length: 3, e_x, e_y, init_x: 0, init_y: -1 }];
+        // Searched to 6, the last two lengths without a cache
+        save_checkpoint(&path, &config, "fib", &ctx, 6, &solutions).unwrap();
+        let cp = resume_from(&path, &config, "fib").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(cp.length, 6);
+        assert!(cp.ctx.expressions(5).is_none());
+        let pair = &cp.solutions[0];
+        assert_eq!((pair.length, pair.init_x, pair.init_y), (3, 0, -1));
+        assert_eq!(crate::format_expression(&pair.e_x, &['x', 'y']), "x+=y");
+        assert!(pair.e_x.jit.is_some());
+        assert!(Arc::ptr_eq(&pair.e_x.left.clone().unwrap(), &cp.ctx.var_expressions()[0]));
+        for n in 1..=4 {
+            assert_eq!(printed(cp.ctx.expressions(n)), printed(ctx.expressions(n)));
+            assert_eq!(printed(cp.ctx.statements(n)), printed(ctx.statements(n)));
+            assert_eq!(cp.ctx.pruned(n), ctx.pruned(n));
+        }
+        assert!(cp.ctx.statements(3).unwrap().iter().all(|e| e.jit.is_some()));
+        assert_eq!(cp.ctx.var_expressions()[0].op, Operator::Var);
+        // Generation carries on from the loaded caches
+        let mut resumed = cp.ctx;
+        resumed.gen_expressions(&config, 5);
+        assert_eq!(printed(resumed.expressions(5)), printed(build_cache(&config, 5).expressions(5)));
+    }
+
+    #[test]
+    fn test_checkpoint_rejects_mismatch() {
+        let config = SearchConfig::default();
+        let ctx = build_cache(&config, 2);
+        let path = temp_path("mismatch");
+        save_checkpoint(&path, &config, "fib", &ctx, 2, &[]).unwrap();
+
+        // Threads and the JIT may change, the generator may not
+        let mut other = config.clone();
+        other.use_multithread = !other.use_multithread;
+        other.use_jit = !other.use_jit;
+        assert!(resume_from(&path, &other, "fib").is_ok());
+        other.literals.push(7);
+        assert!(resume_from(&path, &other, "fib").is_err());
+        assert!(resume_from(&path, &config, "squares").is_err());
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert_eq!(resume_from(&path, &config, "fib").err().unwrap().kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+//! Generator settings, the library-side counterpart of the binary's `params.rs`.
+
+use crate::{category, Expr, Mode, NumT, OpCategory, Operator};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BinaryOp {
+    pub op: Operator,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UnaryOp {
+    pub op: Operator,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AssignOp {
+    pub op: Operator,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IncDecOp {
+    pub op: Operator,
+}
+
+/// What `+`, `-` and `*` (and `+=`, `-=`, `*=`) do on overflow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Arithmetic {
+    /// Wrap around, like two's complement hardware
+    #[default]
+    Wrapping,
+    /// Clamp at i64::MIN/MAX
+    Saturating,
+}
+
+/// How `/` and `%` (and `/=`, `%=`) round
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DivMode {
+    /// Toward zero, with `%` taking the dividend's sign, like C
+    #[default]
+    Truncating,
+    /// `%` is never negative, and `/` rounds so that `a == b*(a/b) + a%b`
+    Euclidean,
+}
+
+/// What the generator builds and how. `Default` matches the binary's shipped `params.rs`.
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    pub use_jit: bool, // Compile statements to machine code for the search to run
+    pub verify_jit: bool, // Panic if a compiled statement's fingerprint differs from naive_eval's
+    pub use_multithread: bool,
+    pub literals: Vec<NumT>,
+    pub use_parens: bool,
+    pub right_groupings: bool, // With use_parens off, still build x-(y-z) for operators of the same class
+    pub use_ternary: bool,
+    pub use_min_max: bool, // Enables the non-C <? and >? operators
+    pub use_lshr: bool, // Enables >>>, a logical shift right like JavaScript's
+    pub use_popcount: bool, // Enables the non-C #x, the number of set bits in x
+    pub use_gcd: bool, // Enables the non-C x%%y, the greatest common divisor
+    pub use_comma: bool, // Statements like x=a,y=b that update both variables
+    pub own_var_only: bool, // Statements only write their own variable: no y++ or y+=1 inside x's, no commas
+    pub prune_const_expr: bool, // Skip constant-only expressions since we can easily find them
+    pub prune_grid_const: bool, // Skip expressions like x-x that equal one of the literals over the whole grid
+    pub max_literal_abs: Option<NumT>, // Skip expressions with a constant part like 99*99 beyond this magnitude
+    pub exclude: Vec<Arc<Expr>>, // Expressions and statements to leave out, with all their equivalents
+    pub seed_expressions: Vec<Arc<Expr>>, // Extra building blocks, cached at their printed length
+    pub pow_modulus: Option<NumT>, // Pow reduces mod this (must be positive), so it never overflows
+    pub arithmetic: Arithmetic,
+    pub div_mode: DivMode,
+    pub unsigned: bool, // Comparisons, >>, / and % treat values as u64 (see Expr::can_be_unsigned)
+    pub binary_operators: Vec<BinaryOp>,
+    pub unary_operators: Vec<UnaryOp>,
+    pub assign_operators: Vec<AssignOp>,
+    pub incdec_operators: Vec<IncDecOp>,
+}
+
+impl SearchConfig {
+    pub fn binary_ops_by_len(&self, len: usize) -> impl Iterator<Item = &BinaryOp> {
+        let use_min_max = self.use_min_max;
+        let use_lshr = self.use_lshr;
+        let use_gcd = self.use_gcd;
+        self.binary_operators.iter()
+            .filter(move |o| use_min_max || !matches!(o.op, Operator::Min | Operator::Max))
+            .filter(move |o| use_lshr || o.op != Operator::LShr)
+            .filter(move |o| use_gcd || o.op != Operator::Gcd)
+            .filter(move |o| o.len == len)
+    }
+
+    pub fn unary_ops(&self) -> impl Iterator<Item = &UnaryOp> {
+        let use_popcount = self.use_popcount;
+        self.unary_operators.iter().filter(move |o| use_popcount || o.op != Operator::PopCount)
+    }
+
+    /// Check the operator tables: each op is in the right table, and a
+    /// declared `len` matches the op's printed width, or every expression
+    /// using it would be generated at the wrong length
+    pub fn validate(&self) -> Result<(), String> {
+        let check = |op: Operator, len: usize, kind: &str, fits: bool| {
+            if !fits {
+                return Err(format!("{:?} is not a {} operator", op, kind));
+            }
+            if len != op.symbol().len() {
+                return Err(format!("{:?} has len {} but `{}` is {} long", op, len, op.symbol(), op.symbol().len()));
+            }
+            Ok(())
+        };
+        for o in &self.binary_operators {
+            check(o.op, o.len, "binary", is_binary(o.op))?;
+        }
+        for o in &self.assign_operators {
+            check(o.op, o.len, "assignment", is_assign(o.op))?;
+        }
+        for o in &self.unary_operators {
+            check(o.op, o.op.symbol().len(), "unary", is_unary(o.op))?;
+        }
+        for o in &self.incdec_operators {
+            check(o.op, o.op.symbol().len(), "increment/decrement", is_incdec(o.op))?;
+        }
+        Ok(())
+    }
+
+    /// Add the operator named `name` (as in `Operator::from_name`) to its
+    /// table, or remove it, so a search can be narrowed without recompiling
+    pub fn set_operator_enabled(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let op = Operator::from_name(name).ok_or_else(|| format!("unknown operator {}", name))?;
+        let len = op.symbol().len();
+        if is_binary(op) {
+            self.binary_operators.retain(|o| o.op != op);
+            if enabled {
+                self.binary_operators.push(BinaryOp { op, len });
+            }
+        } else if is_assign(op) {
+            self.assign_operators.retain(|o| o.op != op);
+            if enabled {
+                self.assign_operators.push(AssignOp { op, len });
+            }
+        } else if is_unary(op) {
+            self.unary_operators.retain(|o| o.op != op);
+            if enabled {
+                self.unary_operators.push(UnaryOp { op });
+            }
+        } else if is_incdec(op) {
+            self.incdec_operators.retain(|o| o.op != op);
+            if enabled {
+                self.incdec_operators.push(IncDecOp { op });
+            }
+        } else {
+            return Err(format!("{:?} is not in an operator table", op));
+        }
+        Ok(())
+    }
+
+    /// The mode a generated node with operator `op` computes in
+    pub fn op_mode(&self, op: Operator) -> Mode {
+        match op {
+            Operator::Pow => match self.pow_modulus {
+                Some(m) => {
+                    assert!(m > 0, "pow_modulus must be positive, got {}", m);
+                    Mode::PowMod(m)
+                }
+                None => Mode::Plain,
+            },
+            _ if self.arithmetic == Arithmetic::Saturating && Expr::can_saturate(op) => Mode::Saturating,
+            // Unsigned division is already Euclidean, so it takes precedence
+            _ if self.unsigned && Expr::can_be_unsigned(op) => Mode::Unsigned,
+            _ if self.div_mode == DivMode::Euclidean && Expr::can_be_euclidean(op) => Mode::Euclidean,
+            _ => Mode::Plain,
+        }
+    }
+}
+
+/// `,` and `?:` are built by the generator itself, not from the table
+fn is_binary(op: Operator) -> bool {
+    category(op) == OpCategory::Binary && !matches!(op, Operator::Comma | Operator::Ternary)
+}
+
+fn is_assign(op: Operator) -> bool {
+    category(op) == OpCategory::Assignment
+}
+
+fn is_unary(op: Operator) -> bool {
+    category(op) == OpCategory::Unary
+}
+
+fn is_incdec(op: Operator) -> bool {
+    category(op) == OpCategory::IncDec
+}
+
+impl Default for SearchConfig {
+    #[rustfmt::skip]
+    fn default() -> Self {
+        Self {
+            use_jit: true,
+            verify_jit: false,
+            use_multithread: true,
+            literals: vec![1, 2, 3],
+            use_parens: true,
+            right_groupings: false,
+            use_ternary: true,
+            use_min_max: false,
+            use_lshr: false,
+            use_popcount: false,
+            use_gcd: false,
+            use_comma: false,
+            own_var_only: false,
+            prune_const_expr: true,
+            prune_grid_const: false,
+            max_literal_abs: None,
+            exclude: Vec::new(),
+            seed_expressions: Vec::new(),
+            pow_modulus: None,
+            arithmetic: Arithmetic::Wrapping,
+            div_mode: DivMode::Truncating,
+            unsigned: false,
+            binary_operators: vec![
+                BinaryOp { op: Operator::BitOr, len: 1 },
+                BinaryOp { op: Operator::BitXor, len: 1 },
+                BinaryOp { op: Operator::BitAnd, len: 1 },
+                BinaryOp { op: Operator::Lt, len: 1 },
+                BinaryOp { op: Operator::Gt, len: 1 },
+                BinaryOp { op: Operator::Add, len: 1 },
+                BinaryOp { op: Operator::Sub, len: 1 },
+                BinaryOp { op: Operator::Mul, len: 1 },
+                BinaryOp { op: Operator::Div, len: 1 },
+                BinaryOp { op: Operator::Mod, len: 1 },
+                BinaryOp { op: Operator::Eq, len: 2 },
+                BinaryOp { op: Operator::Neq, len: 2 },
+                BinaryOp { op: Operator::Leq, len: 2 },
+                BinaryOp { op: Operator::Geq, len: 2 },
+                BinaryOp { op: Operator::BitShl, len: 2 },
+                BinaryOp { op: Operator::BitShr, len: 2 },
+                BinaryOp { op: Operator::Or, len: 2 },
+                BinaryOp { op: Operator::And, len: 2 },
+                BinaryOp { op: Operator::Min, len: 2 },
+                BinaryOp { op: Operator::Max, len: 2 },
+                BinaryOp { op: Operator::LShr, len: 3 },
+                BinaryOp { op: Operator::Gcd, len: 2 },
+            ],
+            unary_operators: vec![
+                UnaryOp { op: Operator::Neg },
+                UnaryOp { op: Operator::BitNot },
+                UnaryOp { op: Operator::Not },
+                UnaryOp { op: Operator::PopCount },
+            ],
+            assign_operators: vec![
+                AssignOp { op: Operator::AssignEq, len: 1 },
+                AssignOp { op: Operator::AddEq, len: 2 },
+                AssignOp { op: Operator::SubEq, len: 2 },
+                AssignOp { op: Operator::MulEq, len: 2 },
+                AssignOp { op: Operator::DivEq, len: 2 },
+                AssignOp { op: Operator::ModEq, len: 2 },
+                AssignOp { op: Operator::BitOrEq, len: 2 },
+                AssignOp { op: Operator::BitXorEq, len: 2 },
+                AssignOp { op: Operator::BitAndEq, len: 2 },
+                AssignOp { op: Operator::BitShlEq, len: 3 },
+                AssignOp { op: Operator::BitShrEq, len: 3 },
+            ],
+            incdec_operators: vec![
+                IncDecOp { op: Operator::PreInc },
+                IncDecOp { op: Operator::PreDec },
+                IncDecOp { op: Operator::PostInc },
+                IncDecOp { op: Operator::PostDec },
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_valid() {
+        assert_eq!(SearchConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_len_mismatch() {
+        let mut config = SearchConfig::default();
+        config.binary_operators.push(BinaryOp { op: Operator::Pow, len: 1 });
+        assert_eq!(config.validate(), Err("Pow has len 1 but `**` is 2 long".to_string()));
+
+        let mut config = SearchConfig::default();
+        config.assign_operators[9].len = 2;
+        assert!(config.validate().unwrap_err().starts_with("BitShlEq has len 2"));
+    }
+
+    #[test]
+    fn test_validate_wrong_table() {
+        let mut config = SearchConfig::default();
+        config.binary_operators.push(BinaryOp { op: Operator::AddEq, len: 2 });
+        assert_eq!(config.validate(), Err("AddEq is not a binary operator".to_string()));
+    }
+
+    #[test]
+    fn test_set_operator_enabled() {
+        let mut config = SearchConfig::default();
+        config.set_operator_enabled("Mod", false).unwrap();
+        config.set_operator_enabled("ModEq", false).unwrap();
+        config.set_operator_enabled("PostInc", false).unwrap();
+        assert!(config.binary_operators.iter().all(|o| o.op != Operator::Mod));
+        assert!(config.assign_operators.iter().all(|o| o.op != Operator::ModEq));
+        assert_eq!(config.incdec_operators.len(), 3);
+
+        // Enabling picks up the printed width, and doesn't duplicate
+        config.set_operator_enabled("Pow", true).unwrap();
+        config.set_operator_enabled("Pow", true).unwrap();
+        let pows: Vec<_> = config.binary_operators.iter().filter(|o| o.op == Operator::Pow).collect();
+        assert_eq!(pows.len(), 1);
+        assert_eq!(pows[0].len, 2);
+        assert_eq!(config.validate(), Ok(()));
+
+        assert_eq!(config.set_operator_enabled("Plus", true), Err("unknown operator Plus".to_string()));
+        assert!(config.set_operator_enabled("Ternary", true).is_err());
+    }
+}
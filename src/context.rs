@@ -0,0 +1,134 @@
+//! Per-length caches of generated expressions and statements.
+
+use crate::{generate, structural_cmp, Expr, SearchConfig};
+use hashbrown::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Semantically distinct expressions (or statements) of one length
+pub type Cache = HashSet<Arc<Expr>>;
+
+/// Add `e` to `cache`. If an equivalent one is already there, keep whichever
+/// is first by `structural_cmp`, so the cache comes out the same however
+/// generation was scheduled.
+pub fn insert_canonical(cache: &mut Cache, e: Arc<Expr>) {
+    let kept = cache.get_or_insert(e.clone());
+    if !Arc::ptr_eq(kept, &e) && structural_cmp(&e, kept).is_lt() {
+        cache.replace(e);
+    }
+}
+
+/// Everything generated so far, keyed by printed length
+pub struct Context {
+    expressions: HashMap<usize, Cache>,
+    statements: HashMap<usize, Cache>,
+    var_expressions: Vec<Arc<Expr>>,
+    pruned: HashMap<usize, usize>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self {
+            expressions: HashMap::new(),
+            statements: HashMap::new(),
+            var_expressions: Vec::new(),
+            pruned: HashMap::new(),
+        }
+    }
+
+    /// Expressions of length `n`, if that length has been generated
+    pub fn expressions(&self, n: usize) -> Option<&Cache> {
+        self.expressions.get(&n)
+    }
+
+    /// Assignment statements of length `n`, if that length has been generated
+    pub fn statements(&self, n: usize) -> Option<&Cache> {
+        self.statements.get(&n)
+    }
+
+    /// The variable leaves, x first and then y
+    pub fn var_expressions(&self) -> &[Arc<Expr>] {
+        &self.var_expressions
+    }
+
+    pub fn insert_expressions(&mut self, n: usize, cache: Cache) {
+        self.expressions.insert(n, cache);
+    }
+
+    pub fn insert_statements(&mut self, n: usize, cache: Cache) {
+        self.statements.insert(n, cache);
+    }
+
+    pub fn push_var_expression(&mut self, var: Arc<Expr>) {
+        self.var_expressions.push(var);
+    }
+
+    /// How many expressions of length `n` `prune_grid_const` dropped
+    pub fn pruned(&self, n: usize) -> usize {
+        self.pruned.get(&n).copied().unwrap_or(0)
+    }
+
+    pub fn set_pruned(&mut self, n: usize, count: usize) {
+        self.pruned.insert(n, count);
+    }
+
+    /// Generate and cache the expressions of length `n` (see `generate::gen_expressions`)
+    pub fn gen_expressions(&mut self, config: &SearchConfig, n: usize) {
+        generate::gen_expressions(self, config, n);
+    }
+
+    /// Generate and cache the statements of length `n` (see `generate::gen_statements`)
+    pub fn gen_statements(&mut self, config: &SearchConfig, n: usize) {
+        generate::gen_statements(self, config, n);
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build, Operator};
+
+    #[test]
+    fn test_context_starts_empty() {
+        let ctx = Context::new();
+        assert!(ctx.expressions(1).is_none());
+        assert!(ctx.statements(1).is_none());
+        assert!(ctx.var_expressions().is_empty());
+    }
+
+    #[test]
+    fn test_context_insert_and_read() {
+        let mut ctx = Context::new();
+        ctx.push_var_expression(build::x());
+        ctx.push_var_expression(build::y());
+
+        let mut cache = Cache::new();
+        cache.insert(build::lit(1));
+        cache.insert(build::lit(2));
+        ctx.insert_expressions(1, cache);
+
+        assert_eq!(ctx.expressions(1).map(|c| c.len()), Some(2));
+        assert!(ctx.expressions(2).is_none());
+        assert_eq!(ctx.var_expressions()[1].op, Operator::VarY);
+    }
+
+    #[test]
+    fn test_insert_canonical_keeps_least() {
+        // x+y and y+x are one class; x+y comes first as Var sorts before VarY
+        let xy = build::bin(build::x(), Operator::Add, build::y());
+        let yx = build::bin(build::y(), Operator::Add, build::x());
+        for order in [[&xy, &yx], [&yx, &xy]] {
+            let mut cache = Cache::new();
+            for e in order {
+                insert_canonical(&mut cache, e.clone());
+            }
+            assert_eq!(cache.len(), 1);
+            assert!(Arc::ptr_eq(cache.iter().next().unwrap(), &xy));
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Graphviz export of expression trees.
+
+use crate::{write_node, Expr, Operator};
+use hashbrown::HashMap;
+
+/// A Graphviz digraph of `e`, one node per operator, literal or variable with
+/// edges to its children labeled L, M (a ternary's middle) and R. Subtrees
+/// shared through the same `Arc` are drawn once, showing the DAG the cache
+/// builds rather than a tree.
+pub fn to_dot(e: &Expr, var_names: &[char]) -> String {
+    let mut dot = Dot { var_names, ids: HashMap::new(), out: String::from("digraph expr {\n") };
+    dot.node(e);
+    dot.out.push_str("}\n");
+    dot.out
+}
+
+struct Dot<'a> {
+    var_names: &'a [char],
+    ids: HashMap<*const Expr, usize>,
+    out: String,
+}
+
+impl Dot<'_> {
+    fn node(&mut self, e: &Expr) -> usize {
+        if let Some(&id) = self.ids.get(&(e as *const Expr)) {
+            return id;
+        }
+        let id = self.ids.len();
+        self.ids.insert(e as *const Expr, id);
+        self.out.push_str(&format!("    n{} [label=\"{}\"];\n", id, self.label(e)));
+
+        for (child, edge) in [(&e.left, "L"), (&e.mid, "M"), (&e.right, "R")] {
+            if let Some(child) = child {
+                let child_id = self.node(child);
+                self.out.push_str(&format!("    n{} -> n{} [label=\"{}\"];\n", id, child_id, edge));
+            }
+        }
+        id
+    }
+
+    fn label(&self, e: &Expr) -> String {
+        match e.op {
+            Operator::PreInc => "++_".to_string(),
+            Operator::PreDec => "--_".to_string(),
+            Operator::PostInc => "_++".to_string(),
+            Operator::PostDec => "_--".to_string(),
+            Operator::Parens => "( )".to_string(),
+            Operator::Ternary => "?:".to_string(),
+            _ => {
+                let mut s = String::new();
+                write_node(&mut s, e, self.var_names);
+                s
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{bin, lit, ternary, un, x, y};
+
+    #[test]
+    fn test_to_dot_tree() {
+        // x*2+-y
+        let e = bin(bin(x(), Operator::Mul, lit(2)), Operator::Add, un(Operator::Neg, y()));
+        assert_eq!(
+            to_dot(&e, &['x', 'y']),
+            "digraph expr {\n\
+             \x20   n0 [label=\"+\"];\n\
+             \x20   n1 [label=\"*\"];\n\
+             \x20   n2 [label=\"x\"];\n\
+             \x20   n1 -> n2 [label=\"L\"];\n\
+             \x20   n3 [label=\"2\"];\n\
+             \x20   n1 -> n3 [label=\"R\"];\n\
+             \x20   n0 -> n1 [label=\"L\"];\n\
+             \x20   n4 [label=\"-\"];\n\
+             \x20   n5 [label=\"y\"];\n\
+             \x20   n4 -> n5 [label=\"R\"];\n\
+             \x20   n0 -> n4 [label=\"R\"];\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_to_dot_shared_subtree() {
+        // Both operands are the same Arc, so it's one node with two edges
+        let sum = bin(x(), Operator::Add, lit(1));
+        let e = bin(sum.clone(), Operator::Mul, sum);
+        let dot = to_dot(&e, &['x', 'y']);
+        assert_eq!(dot.matches("[label=\"+\"]").count(), 1);
+        assert!(dot.contains("n0 -> n1 [label=\"L\"]"));
+        assert!(dot.contains("n0 -> n1 [label=\"R\"]"));
+    }
+
+    #[test]
+    fn test_to_dot_labels() {
+        let e = ternary(un(Operator::PostInc, x()), un(Operator::Parens, y()), lit(-3));
+        let dot = to_dot(&e, &['y', 'x']);
+        assert!(dot.contains("n0 [label=\"?:\"]"));
+        assert!(dot.contains("[label=\"_++\"]"));
+        assert!(dot.contains("[label=\"( )\"]"));
+        assert!(dot.contains("[label=\"-3\"]"));
+        assert!(dot.contains("n0 -> n3 [label=\"M\"]"));
+        // Names follow var_names, so x prints as y here
+        assert!(dot.contains("n2 [label=\"y\"]"));
+    }
+}
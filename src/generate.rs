@@ -0,0 +1,815 @@
+//! Bottom-up generation of semantically distinct expressions and statements.
+
+use crate::config::SearchConfig;
+use crate::context::{insert_canonical, Cache, Context};
+use crate::{bytecode, fingerprint, is_associative, is_atomic, jit, leading_sign, precedence_class, printed_len, Expr, Mode, NumT, Operator};
+use rayon::prelude::*;
+use std::sync::Arc;
+
+// =============================================================================
+// EXPRESSION CONSTRUCTION
+// =============================================================================
+
+/// A node of `op` in the mode `config` gives it. `literal` is the value of
+/// a `Literal` node, and 0 for anything else.
+pub fn make_expr(config: &SearchConfig, left: Option<Arc<Expr>>, right: Option<Arc<Expr>>, literal: NumT, op: Operator) -> Expr {
+    finish_expr(config, Expr { left, mid: None, right, literal, mode: config.op_mode(op), op, jit: None, bytecode: None })
+}
+
+pub fn make_ternary(config: &SearchConfig, cond: &Arc<Expr>, then: &Arc<Expr>, other: &Arc<Expr>) -> Expr {
+    let e = Expr {
+        left: Some(cond.clone()),
+        mid: Some(then.clone()),
+        right: Some(other.clone()),
+        literal: 0,
+        mode: Mode::Plain,
+        op: Operator::Ternary,
+        jit: None,
+        bytecode: None,
+    };
+    finish_expr(config, e)
+}
+
+/// Only statements get a Jit, as they're what the search runs. Expressions are
+/// only ever their children, and a page of code for each would pin hundreds of
+/// megabytes by length 7; their dedup fingerprint falls back to naive_eval.
+/// Statements that can't have a Jit get bytecode instead.
+fn finish_expr(config: &SearchConfig, mut e: Expr) -> Expr {
+    let is_statement = e.is_assignment() || e.op == Operator::Comma;
+    if !is_statement || e.jit.is_some() {
+        return e;
+    }
+    if config.use_jit && e.is_jittable() {
+        let jit = jit::jit_compile_expr(&e);
+        e.jit = Some(Arc::new(jit));
+        if config.verify_jit {
+            verify_jit(&e);
+        }
+    } else {
+        e.bytecode = Some(bytecode::to_bytecode(&e).into());
+    }
+    e
+}
+
+/// Panic if `e`'s Jit and naive_eval disagree anywhere on the grid, so a
+/// miscompiled operator shows up as the statement is built rather than as
+/// solutions missing from the search
+fn verify_jit(e: &Expr) {
+    let naive = e.without_code();
+    if fingerprint(e) != fingerprint(&naive) {
+        panic!("JIT and naive_eval disagree on {}", crate::format_expression(e, &['x', 'y']));
+    }
+}
+
+fn cache_expression(cache: &mut Cache, e: Expr) {
+    insert_canonical(cache, Arc::new(e));
+}
+
+/// Dedup expressions into per-task shards in parallel, then merge the shards
+/// into `cache`. Hashing is the expensive grid fingerprint, so this keeps it off
+/// the single thread that a plain `par_extend` funnels every insert through.
+fn extend_sharded<I>(cache: &mut Cache, exprs: I)
+where
+    I: ParallelIterator<Item = Expr>,
+{
+    let merged = exprs
+        .fold(Cache::new, |mut shard, e| {
+            cache_expression(&mut shard, e);
+            shard
+        })
+        .reduce(Cache::new, |a, b| {
+            let (mut big, small) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+            small.into_iter().for_each(|e| insert_canonical(&mut big, e));
+            big
+        });
+
+    if cache.is_empty() {
+        *cache = merged;
+    } else {
+        merged.into_iter().for_each(|e| insert_canonical(cache, e));
+    }
+}
+
+pub fn make_statement(config: &SearchConfig, var: &Arc<Expr>, expr: &Arc<Expr>, op: Operator) -> Expr {
+    make_expr(config, Some(var.clone()), Some(expr.clone()), 0, op)
+}
+
+// =============================================================================
+// EXPRESSION GENERATION
+// =============================================================================
+
+/// Generate and cache the expressions of length `n`; shorter lengths must already be cached.
+/// Nothing has length 0, so that caches nothing.
+pub fn gen_expressions(ctx: &mut Context, config: &SearchConfig, n: usize) {
+    if n == 0 {
+        return;
+    }
+
+    let mut en = Cache::new();
+
+    // Length 1: variables
+    if n == 1 {
+        let vars = [Operator::Var, Operator::VarY];
+        for &op in &vars {
+            let e = make_expr(config, None, None, 0, op);
+            let arc = Arc::new(e);
+            en.insert(arc.clone());
+            ctx.push_var_expression(arc);
+        }
+    }
+
+    // Literals go at their printed length, so -5 costs 2 like 10 does
+    for &lit in config.literals.iter().filter(|lit| lit.to_string().len() == n) {
+        cache_expression(&mut en, make_expr(config, None, None, lit, Operator::Literal));
+    }
+
+    // Length 3: increment/decrement operators
+    if n == 3 {
+        if let Some(exprs_1) = ctx.expressions(1) {
+            for e_r in exprs_1 {
+                if e_r.is_lvalue() {
+                    for incdec in &config.incdec_operators {
+                        cache_expression(&mut en, make_expr(config, None, Some(e_r.clone()), 0, incdec.op));
+                    }
+                }
+            }
+        }
+    }
+
+    // Generate binary expressions
+    {
+        let expressions_ref = &*ctx;
+
+        // Generate binary ops for a given left operand of length n_l
+        let gen_binary_ops = |e_l: &Arc<Expr>, n_l: usize, op_len: usize| -> Vec<Expr> {
+            let mut local_exprs = Vec::new();
+            // A right operand starting with the operator's own sign takes a space
+            for spaced in [false, true] {
+                let n_r = n.saturating_sub(n_l + op_len + spaced as usize);
+                if n_r < 1 { continue; }
+            
+                if let Some(exprs_r) = expressions_ref.expressions(n_r) {
+                    for e_r in exprs_r {
+                        if config.prune_const_expr && e_l.op == Operator::Literal && e_r.op == Operator::Literal {
+                            continue;
+                        }
+
+                        let class_l = precedence_class(e_l.op);
+                        let class_r = precedence_class(e_r.op);
+                        let sign = leading_sign(e_r);
+
+                        // Check each enabled binary operator
+                        for bin_op in config.binary_ops_by_len(op_len) {
+                            let needs_space = match bin_op.op {
+                                Operator::Add => sign == Some('+'),
+                                Operator::Sub => sign == Some('-'),
+                                _ => false,
+                            };
+                            if needs_space != spaced {
+                                continue;
+                            }
+                            // Operands group left to right without parens: the left
+                            // may share the operator's class, the right must bind
+                            // tighter. || and && are below | and not generated.
+                            // Comparisons chain as in C, x<y<2 being (x<y)<2: a 0
+                            // or 1 against 2, which golfed C relies on, so they stay.
+                            let class = precedence_class(bin_op.op);
+                            let can_apply = class >= precedence_class(Operator::BitOr)
+                                && class_l >= class
+                                && class_r > class
+                                && (class != precedence_class(Operator::Mul) || !(e_r.op == Operator::Literal && e_r.literal == 1));
+                        
+                            if can_apply {
+                                local_exprs.push(make_expr(config, Some(e_l.clone()), Some(e_r.clone()), 0, bin_op.op));
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Without Parens nodes, a right operand of the operator's own class
+            // (x-(y-z), x*(y/z)) is only reachable with its parens added here
+            let n_r = n.saturating_sub(n_l + op_len + 2);
+            if config.right_groupings && !config.use_parens && n_r >= 3 {
+                if let Some(exprs_r) = expressions_ref.expressions(n_r) {
+                    let class_l = precedence_class(e_l.op);
+                    for bin_op in config.binary_ops_by_len(op_len) {
+                        let class = precedence_class(bin_op.op);
+                        if class < precedence_class(Operator::BitOr) || class_l < class {
+                            continue;
+                        }
+                        // x+(y+z) is x+y+z again
+                        let regroups = |e_r: &&Arc<Expr>| {
+                            precedence_class(e_r.op) == class && !(e_r.op == bin_op.op && is_associative(bin_op.op))
+                        };
+                        for e_r in exprs_r.iter().filter(regroups) {
+                            let parens = Arc::new(make_expr(config, None, Some(e_r.clone()), 0, Operator::Parens));
+                            local_exprs.push(make_expr(config, Some(e_l.clone()), Some(parens), 0, bin_op.op));
+                        }
+                    }
+                }
+            }
+            local_exprs
+        };
+
+        // Left operands paired with their length, so parallel work splits per
+        // operand rather than per length (there are only n-2 length splits)
+        let lefts: Vec<(usize, &Arc<Expr>)> = (1..n)
+            .filter_map(|n_l| expressions_ref.expressions(n_l).map(|s| (n_l, s)))
+            .flat_map(|(n_l, s)| s.iter().map(move |e_l| (n_l, e_l)))
+            .collect();
+
+        // 1- to 3-byte operators (>>> is the only 3-byte one)
+        for op_len in [1, 2, 3] {
+            if n <= op_len + 1 {
+                continue;
+            }
+            let gen_for = |&(n_l, e_l): &(usize, &Arc<Expr>)| gen_binary_ops(e_l, n_l, op_len);
+
+            if config.use_multithread {
+                extend_sharded(&mut en, lefts.par_iter().flat_map_iter(gen_for));
+            } else {
+                lefts.iter().flat_map(gen_for).for_each(|e| cache_expression(&mut en, e));
+            }
+        }
+
+        // Ternary: cond?a:b needs at least one char per operand plus '?' and ':'
+        if config.use_ternary && n > 4 {
+            let gen_ternary_ops = |n_c: usize| -> Vec<Expr> {
+                let mut local_exprs = Vec::new();
+                let Some(exprs_c) = expressions_ref.expressions(n_c) else { return local_exprs; };
+
+                for n_m in 1..(n - 2 - n_c) {
+                    let n_r = n - 2 - n_c - n_m;
+                    if let (Some(exprs_m), Some(exprs_r)) = (expressions_ref.expressions(n_m), expressions_ref.expressions(n_r)) {
+                        for e_c in exprs_c {
+                            // Condition must bind tighter than ?: and shouldn't be constant
+                            if precedence_class(e_c.op) <= precedence_class(Operator::Ternary)
+                                || (config.prune_const_expr && e_c.op == Operator::Literal)
+                            {
+                                continue;
+                            }
+                            for e_m in exprs_m {
+                                for e_r in exprs_r {
+                                    // Else branch may itself be a ternary (right-associative)
+                                    let else_ok = e_r.op == Operator::Ternary
+                                        || precedence_class(e_r.op) > precedence_class(Operator::Ternary);
+                                    if !else_ok || Arc::ptr_eq(e_m, e_r) {
+                                        continue;
+                                    }
+                                    local_exprs.push(make_ternary(config, e_c, e_m, e_r));
+                                }
+                            }
+                        }
+                    }
+                }
+                local_exprs
+            };
+
+            if config.use_multithread {
+                extend_sharded(&mut en, (1..(n - 3)).into_par_iter().flat_map_iter(gen_ternary_ops));
+            } else {
+                (1..(n - 3)).flat_map(gen_ternary_ops).for_each(|e| cache_expression(&mut en, e));
+            }
+        }
+
+        // Unary operators; - before another leading minus takes a space (- -x)
+        for (n_r, spaced) in [(n - 1, false), (n.saturating_sub(2), true)] {
+            if let Some(exprs_r) = expressions_ref.expressions(n_r) {
+                for e_r in exprs_r {
+                    if precedence_class(e_r.op) >= precedence_class(Operator::Neg) {
+                        let minus = leading_sign(e_r) == Some('-');
+                        for unary_op in config.unary_ops() {
+                            if (unary_op.op == Operator::Neg && minus) == spaced {
+                                cache_expression(&mut en, make_expr(config, None, Some(e_r.clone()), 0, unary_op.op));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Parentheses
+        if config.use_parens && n > 2 {
+            if let Some(exprs_r) = expressions_ref.expressions(n - 2) {
+                // Parens around an atom would print as the bare atom
+                for e_r in exprs_r.iter().filter(|e_r| !is_atomic(e_r.op)) {
+                    cache_expression(&mut en, make_expr(config, None, Some(e_r.clone()), 0, Operator::Parens));
+                }
+            }
+        }
+    }
+    
+    // A disguised constant like x-x can always give way to a literal no longer than it
+    if config.prune_grid_const {
+        let before = en.len();
+        en.retain(|e| e.op == Operator::Literal || !is_disguised_literal(config, e, n));
+        ctx.set_pruned(n, before - en.len());
+    }
+
+    // Constants are folded over the grid as for prune_grid_const. They only
+    // get here whole, so longer expressions never have a part over the cap.
+    if let Some(cap) = config.max_literal_abs {
+        en.retain(|e| e.grid_constant().is_none_or(|c| c.unsigned_abs() <= cap.unsigned_abs()));
+    }
+
+    // Seeds skip the pruning above, they're there because they were asked for
+    for seed in config.seed_expressions.iter().filter(|e| printed_len(e) == n) {
+        insert_canonical(&mut en, seed.clone());
+    }
+
+    drop_excluded(config, &mut en);
+    ctx.insert_expressions(n, en);
+}
+
+/// Leave out whatever `config.exclude` lists, by value over the grid, so
+/// excluding x+1 drops 1+x and x- -1 too. Longer expressions built on a
+/// dropped one go with it, unless they're also reachable some other way.
+fn drop_excluded(config: &SearchConfig, cache: &mut Cache) {
+    if config.exclude.is_empty() {
+        return;
+    }
+    let excluded: Cache = config.exclude.iter().cloned().collect();
+    cache.retain(|e| !excluded.contains(e));
+}
+
+fn is_disguised_literal(config: &SearchConfig, e: &Expr, n: usize) -> bool {
+    e.grid_constant()
+        .is_some_and(|c| config.literals.contains(&c) && c.to_string().len() <= n)
+}
+
+// =============================================================================
+// STATEMENT GENERATION
+// =============================================================================
+
+/// Generate and cache the statements of length `n` from the expressions already cached
+pub fn gen_statements(ctx: &mut Context, config: &SearchConfig, n: usize) {
+    if ctx.expressions(n).is_none() {
+        return;
+    }
+    
+    let mut sn = Cache::new();
+    let e_l = if !ctx.var_expressions().is_empty() { 
+        ctx.var_expressions()[0].clone() 
+    } else { 
+        return; 
+    };
+
+    let expressions_ref = &*ctx;
+
+    // Comma: x<op>=a,y<op>=b updates both variables in a single step
+    if config.use_comma && !config.own_var_only && n > 2 && ctx.var_expressions().len() > 1 {
+        let var_y = &ctx.var_expressions()[1];
+        let statements_ref = &*ctx;
+
+        let gen_comma_stmts = |n_l: usize| -> Vec<Expr> {
+            let mut local_stmts = Vec::new();
+            let n_r = n - 1 - n_l;
+
+            if let (Some(stmts_l), Some(stmts_r)) = (statements_ref.statements(n_l), statements_ref.statements(n_r)) {
+                // Retarget the right-hand statements at y
+                let stmts_y: Vec<Arc<Expr>> = stmts_r.iter()
+                    .filter(|s| s.is_assignment())
+                    .map(|s| Arc::new(make_statement(config, var_y, s.right.as_ref().unwrap(), s.op)))
+                    .collect();
+
+                for s_l in stmts_l.iter().filter(|s| s.is_assignment()) {
+                    for s_y in &stmts_y {
+                        local_stmts.push(make_expr(config, Some(s_l.clone()), Some(s_y.clone()), 0, Operator::Comma));
+                    }
+                }
+            }
+            local_stmts
+        };
+
+        if config.use_multithread {
+            extend_sharded(&mut sn, (1..(n - 1)).into_par_iter().flat_map_iter(gen_comma_stmts));
+        } else {
+            (1..(n - 1)).flat_map(gen_comma_stmts).for_each(|e| cache_expression(&mut sn, e));
+        }
+    }
+
+    // Generate statements for each assignment operator length
+    for assign_op in &config.assign_operators {
+        let expr_len = n.saturating_sub(assign_op.len);
+        if expr_len < 1 { continue; }
+        
+        if let Some(exprs_r) = expressions_ref.expressions(expr_len) {
+            if config.use_multithread && exprs_r.len() > 100 && assign_op.len == 1 {
+                // Parallel for large sets with 1-byte operator
+                extend_sharded(&mut sn, exprs_r.par_iter().map(|e_r| {
+                    make_statement(config, &e_l, e_r, assign_op.op)
+                }));
+            } else {
+                for e_r in exprs_r {
+                    cache_expression(&mut sn, make_statement(config, &e_l, e_r, assign_op.op));
+                }
+            }
+        }
+    }
+
+    // y's statement runs with the variables swapped, so not writing y here
+    // also keeps it off x
+    if config.own_var_only {
+        sn.retain(|s| !s.writes_var(Operator::VarY));
+    }
+
+    drop_excluded(config, &mut sn);
+    ctx.insert_statements(n, sn);
+}
+
+// =============================================================================
+// CACHE BUILDING
+// =============================================================================
+
+/// Generate expressions and statements for every length up to `max_len`, as
+/// the binary's cached phase does
+pub fn build_cache(config: &SearchConfig, max_len: usize) -> Context {
+    let mut ctx = Context::new();
+    for n in 1..=max_len {
+        gen_expressions(&mut ctx, config, n);
+        gen_statements(&mut ctx, config, n);
+    }
+    ctx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(ctx: &Context, n: usize) -> (usize, usize) {
+        (
+            ctx.expressions(n).map(|c| c.len()).unwrap_or(0),
+            ctx.statements(n).map(|c| c.len()).unwrap_or(0),
+        )
+    }
+
+    // Every cached expression and statement of length n, as sorted source text
+    fn printed(ctx: &Context, n: usize) -> (Vec<String>, Vec<String>) {
+        let text = |cache: Option<&Cache>| {
+            let mut v: Vec<String> = cache.into_iter().flatten().map(|e| crate::format_expression(e, &['x', 'y'])).collect();
+            v.sort();
+            v
+        };
+        (text(ctx.expressions(n)), text(ctx.statements(n)))
+    }
+
+    #[test]
+    fn test_build_cache_leaves() {
+        let ctx = build_cache(&SearchConfig::default(), 1);
+        // x, y and the literals 1, 2, 3; no statement fits in one character
+        assert_eq!(counts(&ctx, 1), (5, 0));
+        assert_eq!(ctx.var_expressions().len(), 2);
+        assert_eq!(ctx.var_expressions()[0].op, Operator::Var);
+    }
+
+    #[test]
+    fn test_gen_length_zero() {
+        let config = SearchConfig::default();
+        let mut ctx = Context::new();
+        ctx.gen_expressions(&config, 0);
+        ctx.gen_statements(&config, 0);
+        assert!(ctx.expressions(0).is_none() && ctx.statements(0).is_none());
+        assert!(ctx.var_expressions().is_empty());
+    }
+
+    #[test]
+    fn test_build_cache_matches_stepwise() {
+        let config = SearchConfig::default();
+        let built = build_cache(&config, 4);
+
+        let mut ctx = Context::new();
+        for n in 1..=4 {
+            ctx.gen_expressions(&config, n);
+            ctx.gen_statements(&config, n);
+            assert_eq!(printed(&ctx, n), printed(&built, n), "length {}", n);
+        }
+    }
+
+    #[test]
+    fn test_build_cache_serial_matches_parallel() {
+        // Each class keeps the same representative whatever order it's found in
+        let parallel = build_cache(&SearchConfig::default(), 4);
+        let serial = build_cache(&SearchConfig { use_multithread: false, ..SearchConfig::default() }, 4);
+        for n in 1..=4 {
+            assert_eq!(printed(&parallel, n), printed(&serial, n), "length {}", n);
+        }
+    }
+
+    #[test]
+    fn test_only_statements_compiled() {
+        let ctx = build_cache(&SearchConfig::default(), 4);
+        for n in 1..=4 {
+            assert!(ctx.expressions(n).unwrap().iter().all(|e| e.jit.is_none()));
+            assert!(ctx.statements(n).unwrap().iter().all(|s| s.jit.is_some() && s.bytecode.is_none()));
+        }
+    }
+
+    #[test]
+    fn test_verify_jit() {
+        let config = SearchConfig { verify_jit: true, ..SearchConfig::default() };
+        let ctx = build_cache(&config, 4);
+        assert!(ctx.statements(4).unwrap().iter().all(|s| s.jit.is_some()));
+    }
+
+    #[test]
+    #[should_panic(expected = "JIT and naive_eval disagree on x+=1")]
+    fn test_verify_jit_catches_mismatch() {
+        use crate::build::{bin, lit, x};
+        // x+=1 running the code compiled for x-=1
+        let wrong = jit::jit_compile_expr(&bin(x(), Operator::SubEq, lit(1)));
+        let e = Expr { jit: Some(Arc::new(wrong)), ..bin(x(), Operator::AddEq, lit(1)).without_code() };
+        verify_jit(&e);
+    }
+
+    #[test]
+    fn test_statements_get_bytecode_without_jit() {
+        let config = SearchConfig { use_jit: false, ..SearchConfig::default() };
+        let ctx = build_cache(&config, 4);
+        for n in 1..=4 {
+            assert!(ctx.expressions(n).unwrap().iter().all(|e| e.bytecode.is_none()));
+            assert!(ctx.statements(n).unwrap().iter().all(|s| s.jit.is_none() && s.bytecode.is_some()));
+        }
+    }
+
+    #[test]
+    fn test_pow_modulus_generates_naive_pow_mod() {
+        let mut config = SearchConfig { pow_modulus: Some(5), ..SearchConfig::default() };
+        config.binary_operators.push(crate::BinaryOp { op: Operator::Pow, len: 2 });
+        let ctx = build_cache(&config, 4);
+        let pows: Vec<_> = ctx.expressions(4).unwrap().iter().filter(|e| e.op == Operator::Pow).collect();
+        assert!(!pows.is_empty());
+        assert!(pows.iter().all(|e| e.mode == Mode::PowMod(5) && e.jit.is_none()));
+    }
+
+    #[test]
+    fn test_saturating_arithmetic_marks_nodes() {
+        let config = SearchConfig { arithmetic: crate::Arithmetic::Saturating, ..SearchConfig::default() };
+        let ctx = build_cache(&config, 3);
+        for n in 1..=3 {
+            for e in ctx.expressions(n).unwrap().iter().chain(ctx.statements(n).unwrap()) {
+                assert_eq!(e.is_saturating(), Expr::can_saturate(e.op), "{:?}", e.op);
+            }
+        }
+        let stmts = ctx.statements(3).unwrap();
+        assert!(stmts.iter().any(|s| s.op == Operator::MulEq && s.jit.is_some()));
+
+        // A generated node is the same tree as one built by hand in its mode
+        use crate::build::{bin, saturating, x, y};
+        use crate::structural_eq;
+        let generated_sum = |ctx: &Context| {
+            let is = |c: &Option<Arc<Expr>>, op| c.as_ref().is_some_and(|c| c.op == op);
+            ctx.expressions(3).unwrap().iter().find(|e| e.op == Operator::Add && is(&e.left, Operator::Var) && is(&e.right, Operator::VarY)).unwrap().clone()
+        };
+        let sum = generated_sum(&ctx);
+        assert!(structural_eq(&sum, &saturating(x(), Operator::Add, y())));
+        assert!(!structural_eq(&sum, &bin(x(), Operator::Add, y())));
+        let sum = generated_sum(&build_cache(&SearchConfig::default(), 3));
+        assert!(structural_eq(&sum, &bin(x(), Operator::Add, y())));
+    }
+
+    #[test]
+    fn test_euclidean_division_marks_nodes() {
+        let config = SearchConfig { div_mode: crate::DivMode::Euclidean, ..SearchConfig::default() };
+        let ctx = build_cache(&config, 3);
+        for n in 1..=3 {
+            for e in ctx.expressions(n).unwrap().iter().chain(ctx.statements(n).unwrap()) {
+                assert_eq!(e.is_euclidean(), Expr::can_be_euclidean(e.op), "{:?}", e.op);
+            }
+        }
+        // Whichever x%y-like expression was kept, it isn't negative at x=-7
+        let exprs = ctx.expressions(3).unwrap();
+        let rem = exprs.iter().find(|e| e.op == Operator::Mod).unwrap();
+        let (mut x, mut y, mut fatal) = (-7, 2, false);
+        assert!(crate::naive_eval(rem, &mut x, &mut y, &mut fatal) >= 0);
+    }
+
+    #[test]
+    fn test_unsigned_marks_nodes() {
+        use crate::build::{bin, unsigned, x, y};
+        let config = SearchConfig { unsigned: true, ..SearchConfig::default() };
+        let ctx = build_cache(&config, 3);
+        for n in 1..=3 {
+            for e in ctx.expressions(n).unwrap().iter().chain(ctx.statements(n).unwrap()) {
+                assert_eq!(e.is_unsigned(), Expr::can_be_unsigned(e.op), "{:?}", e.op);
+                // Modes aren't kept in the literal any more
+                assert!(e.op == Operator::Literal || e.literal == 0, "{:?}", e);
+            }
+        }
+        // -1 is the largest u64, so the cached x<y is the unsigned one
+        let exprs = ctx.expressions(3).unwrap();
+        let cached = |e: Arc<Expr>| exprs.iter().any(|c| **c == *e);
+        assert!(cached(unsigned(x(), Operator::Lt, y())));
+        assert!(!cached(bin(x(), Operator::Lt, y())));
+    }
+
+    #[test]
+    fn test_negative_literals_at_printed_length() {
+        let config = SearchConfig { literals: vec![1, -1, -5, 10], ..SearchConfig::default() };
+        let ctx = build_cache(&config, 2);
+        let literals = |n: usize| -> Vec<NumT> {
+            let mut v: Vec<NumT> = ctx.expressions(n).unwrap().iter()
+                .filter(|e| e.op == Operator::Literal)
+                .map(|e| e.literal)
+                .collect();
+            v.sort();
+            v
+        };
+        assert_eq!(literals(1), vec![1]);
+        assert_eq!(literals(2), vec![-5, -1, 10]);
+    }
+
+    #[test]
+    fn test_prune_grid_const() {
+        let plain = build_cache(&SearchConfig::default(), 4);
+        let pruned = build_cache(&SearchConfig { prune_grid_const: true, ..SearchConfig::default() }, 4);
+        assert_eq!(plain.pruned(3), 0);
+        assert!(pruned.pruned(3) > 0);
+        for n in 1..=4 {
+            // Exact only while classes don't depend on representatives
+            if n <= 3 {
+                assert_eq!(counts(&pruned, n).0 + pruned.pruned(n), counts(&plain, n).0, "length {}", n);
+            }
+            assert!(pruned.expressions(n).unwrap().iter()
+                .all(|e| e.op == Operator::Literal || e.grid_constant().is_none_or(|c| !matches!(c, 1..=3))));
+        }
+    }
+
+    #[test]
+    fn test_max_literal_abs() {
+        let config = SearchConfig {
+            literals: vec![99],
+            prune_const_expr: false,
+            max_literal_abs: Some(100),
+            ..SearchConfig::default()
+        };
+        let ctx = build_cache(&config, 5);
+        let (exprs, _) = printed(&ctx, 5);
+        // 99+99 is 198, over the cap like 99*99, where 99|99 is 99
+        assert!(!exprs.contains(&"99+99".to_string()));
+        assert!(!exprs.contains(&"99*99".to_string()));
+        assert!(ctx.expressions(5).unwrap().iter().all(|e| e.grid_constant().is_none_or(|c| c.abs() <= 100)));
+        assert!(exprs.contains(&"99|99".to_string()));
+        assert!(exprs.contains(&"x*x*x".to_string()));
+        let (uncapped, _) = printed(&build_cache(&SearchConfig { max_literal_abs: None, ..config }, 5), 5);
+        assert!(uncapped.contains(&"99*99".to_string()));
+        assert!(uncapped.contains(&"99+99".to_string()));
+    }
+
+    #[test]
+    fn test_seed_expressions() {
+        use crate::build::lit;
+        let config = SearchConfig { seed_expressions: vec![lit(42)], ..SearchConfig::default() };
+        let ctx = build_cache(&config, 4);
+        assert!(ctx.expressions(2).unwrap().iter().any(|e| e.op == Operator::Literal && e.literal == 42));
+        let has_child_42 = |n: usize| {
+            ctx.expressions(n).unwrap().iter()
+                .any(|e| [&e.left, &e.right].into_iter().flatten().any(|c| c.op == Operator::Literal && c.literal == 42))
+        };
+        assert!(has_child_42(3)); // -42, ~42
+        assert!(has_child_42(4)); // x+42
+        assert!(!build_cache(&SearchConfig::default(), 4).expressions(4).unwrap().iter()
+            .any(|e| crate::format_expression(e, &['x', 'y']).contains("42")));
+    }
+
+    #[test]
+    fn test_exclude_drops_equivalents() {
+        use crate::build::{bin, lit, x};
+        let x_plus_1 = bin(x(), Operator::Add, lit(1));
+        let plain = build_cache(&SearchConfig::default(), 5);
+        let excluded = build_cache(&SearchConfig { exclude: vec![x_plus_1.clone()], ..SearchConfig::default() }, 5);
+        let one_plus_x = bin(lit(1), Operator::Add, x());
+        let minus_minus = bin(x(), Operator::Sub, lit(-1));
+        assert!(plain.expressions(3).unwrap().contains(&one_plus_x));
+        assert!(plain.expressions(5).unwrap().contains(&minus_minus));
+        for n in 1..=5 {
+            let exprs = excluded.expressions(n).unwrap();
+            assert!(!exprs.contains(&x_plus_1) && !exprs.contains(&one_plus_x) && !exprs.contains(&minus_minus), "length {}", n);
+        }
+        // x+=1 isn't x+1: it also writes x
+        assert!(excluded.statements(4).unwrap().contains(&bin(x(), Operator::AddEq, lit(1))));
+    }
+
+    #[test]
+    #[should_panic(expected = "pow_modulus must be positive")]
+    fn test_pow_modulus_zero_rejected() {
+        SearchConfig { pow_modulus: Some(0), ..SearchConfig::default() }.op_mode(Operator::Pow);
+    }
+
+    #[test]
+    fn test_binary_ops_by_len_min_max_gate() {
+        let mut config = SearchConfig::default();
+        assert!(!config.binary_ops_by_len(2).any(|o| o.op == Operator::Min));
+        config.use_min_max = true;
+        assert!(config.binary_ops_by_len(2).any(|o| o.op == Operator::Min));
+        assert!(config.binary_ops_by_len(1).all(|o| o.len == 1));
+    }
+
+    #[test]
+    fn test_unary_ops_popcount_gate() {
+        let mut config = SearchConfig::default();
+        assert!(!config.unary_ops().any(|o| o.op == Operator::PopCount));
+        config.use_popcount = true;
+        let ctx = build_cache(&config, 2);
+        assert!(ctx.expressions(2).unwrap().iter().any(|e| e.op == Operator::PopCount));
+    }
+
+    #[test]
+    fn test_comparison_chains() {
+        use crate::build::{bin, lit, x, y};
+        let ctx = build_cache(&SearchConfig::default(), 5);
+        let exprs = ctx.expressions(5).unwrap();
+        let chain = |e: &Arc<Expr>| precedence_class(e.op) == precedence_class(Operator::Lt)
+            && precedence_class(e.left.as_ref().unwrap().op) == precedence_class(Operator::Lt);
+        assert!(exprs.iter().any(chain));
+        // Never the other way round, which would print as the same text
+        assert!(!exprs.iter().any(|e| precedence_class(e.op) == precedence_class(Operator::Lt)
+            && precedence_class(e.right.as_ref().unwrap().op) == precedence_class(Operator::Lt)));
+        // Unlike maths, x>y>0 doesn't need y>0: it's (x>y)>0, just x>y
+        let e = bin(bin(x(), Operator::Gt, y()), Operator::Gt, lit(0));
+        assert_eq!(crate::format_expression(&e, &['x', 'y']), "x>y>0");
+        for (xv, yv, expected) in [(2, 1, 1), (1, 2, 0), (-3, -4, 1)] {
+            let mut fatal = false;
+            assert_eq!(crate::naive_eval(&e, &mut { xv }, &mut { yv }, &mut fatal), expected);
+        }
+    }
+
+    #[test]
+    fn test_right_groupings() {
+        use crate::build::{bin, lit, un, x, y};
+        use crate::BinaryOp;
+        // A small operator set keeps length 7, the shortest x/(y/2), quick to build
+        let config = SearchConfig {
+            literals: vec![2],
+            use_parens: false,
+            use_ternary: false,
+            binary_operators: [Operator::Add, Operator::Sub, Operator::Mul, Operator::Div].map(|op| BinaryOp { op, len: 1 }).to_vec(),
+            unary_operators: Vec::new(),
+            incdec_operators: Vec::new(),
+            ..SearchConfig::default()
+        };
+        let grouped = SearchConfig { right_groupings: true, ..config.clone() };
+        let (ctx, ctx_grouped) = (build_cache(&config, 7), build_cache(&grouped, 7));
+        for n in 1..7 {
+            assert_eq!(counts(&ctx, n), counts(&ctx_grouped, n));
+        }
+        let has = |ctx: &Context, e: Arc<Expr>| ctx.expressions(7).unwrap().iter().any(|c| **c == *e);
+        // Truncation makes both differ from every parens-free expression
+        for (outer, inner) in [(Operator::Div, Operator::Div), (Operator::Mul, Operator::Div)] {
+            let e = bin(x(), outer, un(Operator::Parens, bin(y(), inner, lit(2))));
+            assert!(!has(&ctx, e.clone()), "{:?} {:?}", outer, inner);
+            assert!(has(&ctx_grouped, e), "{:?} {:?}", outer, inner);
+        }
+    }
+
+    #[test]
+    fn test_own_var_only() {
+        use crate::build::{bin, lit, un, x, y};
+        use crate::BinaryOp;
+        let config = SearchConfig {
+            literals: vec![1],
+            use_parens: false,
+            use_ternary: false,
+            use_comma: true,
+            binary_operators: vec![BinaryOp { op: Operator::Add, len: 1 }],
+            unary_operators: Vec::new(),
+            ..SearchConfig::default()
+        };
+        let own = SearchConfig { own_var_only: true, ..config.clone() };
+        let (ctx, ctx_own) = (build_cache(&config, 7), build_cache(&own, 7));
+        let has = |ctx: &Context, e: Arc<Expr>| (1..=7).any(|n| ctx.statements(n).is_some_and(|s| s.contains(&e)));
+        // x+=y++ and x=1,y=1
+        let bumps_y = bin(x(), Operator::AddEq, un(Operator::PostInc, y()));
+        let comma = bin(bin(x(), Operator::AssignEq, lit(1)), Operator::Comma, bin(y(), Operator::AssignEq, lit(1)));
+        for e in [bumps_y, comma] {
+            assert!(e.writes_var(Operator::VarY));
+            assert!(has(&ctx, e.clone()));
+            assert!(!has(&ctx_own, e));
+        }
+        for n in 1..=7 {
+            assert!(ctx_own.statements(n).is_none_or(|s| s.iter().all(|s| !s.writes_var(Operator::VarY))));
+        }
+        // Writing x, even with ++, and reading y are still fine
+        assert!(has(&ctx_own, bin(x(), Operator::AddEq, un(Operator::PostInc, x()))));
+        assert!(has(&ctx_own, bin(x(), Operator::AddEq, y())));
+    }
+
+    #[test]
+    fn test_binary_ops_by_len_gcd_gate() {
+        let mut config = SearchConfig::default();
+        assert!(!config.binary_ops_by_len(2).any(|o| o.op == Operator::Gcd));
+        config.use_gcd = true;
+        let ctx = build_cache(&config, 4);
+        assert!(ctx.expressions(4).unwrap().iter().any(|e| e.op == Operator::Gcd));
+    }
+
+    #[test]
+    fn test_binary_ops_by_len_lshr_gate() {
+        let mut config = SearchConfig::default();
+        assert!(!config.binary_ops_by_len(3).any(|o| o.op == Operator::LShr));
+        config.use_lshr = true;
+        assert!(config.binary_ops_by_len(3).any(|o| o.op == Operator::LShr));
+        let ctx = build_cache(&config, 5);
+        assert!(ctx.expressions(5).unwrap().iter().any(|e| e.op == Operator::LShr));
+    }
+}
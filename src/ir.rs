@@ -0,0 +1,451 @@
+//! A three-address form of expressions, which the JIT backends compile.
+//!
+//! `lower` flattens a tree into a list of instructions over numbered virtual
+//! registers, with labels and branches where `?:` picks one side. The
+//! backends then only need to know how to emit each instruction, not how
+//! the tree's operators nest.
+//!
+//! Every value gets a virtual register of its own, written once, except
+//! that both sides of a `?:` are copied into the same one. The backends
+//! place them in machine registers and stack slots with
+//! `regalloc::allocate`.
+//!
+//! Compound assignments become a `Load` of their target, the `BinOp` of
+//! their plain operator and a `Store`, and `++`/`--` an increment or
+//! decrement between a `Load` and a `Store`.
+//!
+//! A child that was already compiled isn't lowered again: the IR its `Jit`
+//! kept is spliced in, renumbered past the registers and labels in use.
+
+use crate::{Expr, Mode, NumT, Operator};
+
+/// A virtual register
+pub type VReg = usize;
+
+/// A branch target, placed by the `IrInst::Label` with the same number
+pub type LabelId = usize;
+
+/// One instruction. Variables are named by `Operator::Var` or `VarY`. The
+/// operands of `BinOp`, `UnOp` and `Branch` are never read again, so a
+/// backend may clobber them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IrInst {
+    /// dst = value
+    Const { dst: VReg, value: NumT },
+    /// dst = var
+    Load { dst: VReg, var: Operator },
+    /// var = src
+    Store { var: Operator, src: VReg },
+    /// dst = lhs op rhs in its node's mode, for a binary operator other than
+    /// `,` and the assignments. Fatal where naive_eval is, e.g. for `/0`.
+    BinOp { op: Operator, mode: Mode, dst: VReg, lhs: VReg, rhs: VReg },
+    /// dst = op src, for `-`, `~`, `!` and `#`. `PreInc` and `PreDec` stand
+    /// for src + 1 and src - 1, wrapping.
+    UnOp { op: Operator, dst: VReg, src: VReg },
+    /// dst = src
+    Copy { dst: VReg, src: VReg },
+    /// Go to `target` if cond is 0
+    Branch { cond: VReg, target: LabelId },
+    /// Go to `target`
+    Jump { target: LabelId },
+    Label(LabelId),
+    /// Return 0 right away, as where a node writes to a non-variable
+    Fatal,
+    /// Return src
+    Return { src: VReg },
+}
+
+impl IrInst {
+    /// The virtual registers this reads
+    pub fn reads(&self) -> impl Iterator<Item = VReg> {
+        let (a, b) = match *self {
+            IrInst::BinOp { lhs, rhs, .. } => (Some(lhs), Some(rhs)),
+            IrInst::Store { src, .. } | IrInst::UnOp { src, .. } | IrInst::Copy { src, .. } | IrInst::Return { src } => (Some(src), None),
+            IrInst::Branch { cond, .. } => (Some(cond), None),
+            _ => (None, None),
+        };
+        a.into_iter().chain(b)
+    }
+
+    /// This with every register moved up by `vregs` and label by `labels`
+    fn renumbered(&self, vregs: VReg, labels: LabelId) -> IrInst {
+        match *self {
+            IrInst::Const { dst, value } => IrInst::Const { dst: dst + vregs, value },
+            IrInst::Load { dst, var } => IrInst::Load { dst: dst + vregs, var },
+            IrInst::Store { var, src } => IrInst::Store { var, src: src + vregs },
+            IrInst::BinOp { op, mode, dst, lhs, rhs } => IrInst::BinOp { op, mode, dst: dst + vregs, lhs: lhs + vregs, rhs: rhs + vregs },
+            IrInst::UnOp { op, dst, src } => IrInst::UnOp { op, dst: dst + vregs, src: src + vregs },
+            IrInst::Copy { dst, src } => IrInst::Copy { dst: dst + vregs, src: src + vregs },
+            IrInst::Branch { cond, target } => IrInst::Branch { cond: cond + vregs, target: target + labels },
+            IrInst::Jump { target } => IrInst::Jump { target: target + labels },
+            IrInst::Label(label) => IrInst::Label(label + labels),
+            IrInst::Fatal => IrInst::Fatal,
+            IrInst::Return { src } => IrInst::Return { src: src + vregs },
+        }
+    }
+
+    /// The virtual register this writes, if any
+    pub fn writes(&self) -> Option<VReg> {
+        match *self {
+            IrInst::Const { dst, .. } | IrInst::Load { dst, .. } | IrInst::BinOp { dst, .. }
+                | IrInst::UnOp { dst, .. } | IrInst::Copy { dst, .. } => Some(dst),
+            _ => None,
+        }
+    }
+}
+
+/// `e` as instructions ending in a `Return` of its value. Modular `Pow`
+/// isn't lowered, see `Expr::is_jittable`.
+pub fn lower(e: &Expr) -> Vec<IrInst> {
+    let mut l = Lowering { code: Vec::new(), vregs: 0, labels: 0 };
+    let src = l.expr(e);
+    l.code.push(IrInst::Return { src });
+    l.code
+}
+
+/// The number of virtual registers `code` uses
+pub fn vreg_count(code: &[IrInst]) -> usize {
+    code.iter().flat_map(|inst| inst.reads().chain(inst.writes())).map(|r| r + 1).max().unwrap_or(0)
+}
+
+/// The number of labels in `code`, numbered from 0
+pub fn label_count(code: &[IrInst]) -> usize {
+    code.iter().filter(|inst| matches!(inst, IrInst::Label(_))).count()
+}
+
+/// The plain operator a compound assignment applies, e.g. `+` for `+=`
+fn assigned_op(op: Operator) -> Operator {
+    match op {
+        Operator::BitOrEq => Operator::BitOr,
+        Operator::BitXorEq => Operator::BitXor,
+        Operator::BitAndEq => Operator::BitAnd,
+        Operator::BitShlEq => Operator::BitShl,
+        Operator::BitShrEq => Operator::BitShr,
+        Operator::AddEq => Operator::Add,
+        Operator::SubEq => Operator::Sub,
+        Operator::MulEq => Operator::Mul,
+        Operator::DivEq => Operator::Div,
+        Operator::ModEq => Operator::Mod,
+        _ => unreachable!("{:?} isn't a compound assignment", op),
+    }
+}
+
+struct Lowering {
+    code: Vec<IrInst>,
+    vregs: VReg,
+    labels: LabelId,
+}
+
+impl Lowering {
+    fn vreg(&mut self) -> VReg {
+        self.vregs += 1;
+        self.vregs - 1
+    }
+
+    fn label(&mut self) -> LabelId {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    // Emit code computing e, returning the register its value ends up in
+    fn expr(&mut self, e: &Expr) -> VReg {
+        if let Some(jit) = &e.jit {
+            return self.splice(jit.lowered());
+        }
+        if e.has_bad_target() {
+            // Nothing after this runs, so the register is never written
+            self.code.push(IrInst::Fatal);
+            return self.vreg();
+        }
+        let left = e.left.as_deref();
+        let right = e.right.as_deref();
+        match e.op {
+            Operator::Literal => {
+                let dst = self.vreg();
+                self.code.push(IrInst::Const { dst, value: e.literal });
+                dst
+            }
+            Operator::Var | Operator::VarY => {
+                let dst = self.vreg();
+                self.code.push(IrInst::Load { dst, var: e.op });
+                dst
+            }
+            Operator::Parens => self.expr(right.unwrap()),
+            Operator::Comma => {
+                // Left only runs for its side effects
+                self.expr(left.unwrap());
+                self.expr(right.unwrap())
+            }
+            Operator::Ternary => {
+                // Each branch copies its value into the same register
+                let other = self.label();
+                let end = self.label();
+                let cond = self.expr(left.unwrap());
+                self.code.push(IrInst::Branch { cond, target: other });
+                let dst = self.vreg();
+                let src = self.expr(e.mid.as_ref().unwrap());
+                self.code.push(IrInst::Copy { dst, src });
+                self.code.push(IrInst::Jump { target: end });
+                self.code.push(IrInst::Label(other));
+                let src = self.expr(right.unwrap());
+                self.code.push(IrInst::Copy { dst, src });
+                self.code.push(IrInst::Label(end));
+                dst
+            }
+            _ if e.is_incdec() => {
+                let var = right.unwrap().op;
+                let inc = matches!(e.op, Operator::PreInc | Operator::PostInc);
+                let (step, undo) = if inc { (Operator::PreInc, Operator::PreDec) } else { (Operator::PreDec, Operator::PreInc) };
+                let old = self.vreg();
+                self.code.push(IrInst::Load { dst: old, var });
+                let new = self.unop(step, old);
+                self.code.push(IrInst::Store { var, src: new });
+                if matches!(e.op, Operator::PostInc | Operator::PostDec) {
+                    // The old value again, rather than keep it live across the store
+                    self.unop(undo, new)
+                } else {
+                    new
+                }
+            }
+            Operator::AssignEq => {
+                let src = self.expr(right.unwrap());
+                self.code.push(IrInst::Store { var: left.unwrap().op, src });
+                src
+            }
+            _ if e.is_assignment() => {
+                // The target is read after the right side, which may change it
+                let rhs = self.expr(right.unwrap());
+                let var = left.unwrap().op;
+                let lhs = self.vreg();
+                self.code.push(IrInst::Load { dst: lhs, var });
+                let dst = self.vreg();
+                self.code.push(IrInst::BinOp { op: assigned_op(e.op), mode: e.mode, dst, lhs, rhs });
+                self.code.push(IrInst::Store { var, src: dst });
+                dst
+            }
+            // Unary operators only have a right operand
+            _ if left.is_none() => {
+                let src = self.expr(right.unwrap());
+                self.unop(e.op, src)
+            }
+            _ => {
+                assert!(!e.is_pow_mod(), "modular Pow must be evaluated with naive_eval");
+                let lhs = self.expr(left.unwrap());
+                let rhs = self.expr(right.unwrap());
+                let dst = self.vreg();
+                self.code.push(IrInst::BinOp { op: e.op, mode: e.mode, dst, lhs, rhs });
+                dst
+            }
+        }
+    }
+
+    // Emit a child's already lowered code, less its Return, returning the
+    // register that held its value
+    fn splice(&mut self, code: &[IrInst]) -> VReg {
+        let (vregs, labels) = (self.vregs, self.labels);
+        let Some((IrInst::Return { src }, body)) = code.split_last() else {
+            unreachable!("lowered code ends in a Return");
+        };
+        self.code.extend(body.iter().map(|inst| inst.renumbered(vregs, labels)));
+        self.vregs += vreg_count(code);
+        self.labels += label_count(code);
+        src + vregs
+    }
+
+    fn unop(&mut self, op: Operator, src: VReg) -> VReg {
+        let dst = self.vreg();
+        self.code.push(IrInst::UnOp { op, dst, src });
+        dst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{self, bin, lit, ternary, un, x, y};
+    use crate::naive_eval;
+
+    fn var<'a>(v: Operator, x: &'a mut NumT, y: &'a mut NumT) -> &'a mut NumT {
+        if v == Operator::Var { x } else { y }
+    }
+
+    // Run `code` the way a backend's output would, None where it's fatal
+    fn run(code: &[IrInst], x: &mut NumT, y: &mut NumT) -> Option<NumT> {
+        let mut regs = vec![0; vreg_count(code)];
+        let mut pc = 0;
+        loop {
+            let inst = &code[pc];
+            pc += 1;
+            match *inst {
+                IrInst::Const { dst, value } => regs[dst] = value,
+                IrInst::Load { dst, var: v } => regs[dst] = *var(v, x, y),
+                IrInst::Store { var: v, src } => *var(v, x, y) = regs[src],
+                IrInst::BinOp { op, mode, dst, lhs, rhs } => {
+                    let node = Expr { mode, ..(*bin(lit(regs[lhs]), op, lit(regs[rhs]))).clone() };
+                    let mut fatal = false;
+                    regs[dst] = naive_eval(&node, &mut 0, &mut 0, &mut fatal);
+                    if fatal {
+                        return None;
+                    }
+                }
+                IrInst::UnOp { op, dst, src } => {
+                    regs[dst] = match op {
+                        Operator::PreInc => regs[src].wrapping_add(1),
+                        Operator::PreDec => regs[src].wrapping_sub(1),
+                        _ => naive_eval(&un(op, lit(regs[src])), &mut 0, &mut 0, &mut false),
+                    };
+                }
+                IrInst::Copy { dst, src } => regs[dst] = regs[src],
+                IrInst::Branch { cond, target } => {
+                    if regs[cond] == 0 {
+                        pc = code.iter().position(|i| *i == IrInst::Label(target)).unwrap();
+                    }
+                }
+                IrInst::Jump { target } => pc = code.iter().position(|i| *i == IrInst::Label(target)).unwrap(),
+                IrInst::Label(_) => {}
+                IrInst::Fatal => return None,
+                IrInst::Return { src } => return Some(regs[src]),
+            }
+        }
+    }
+
+    // The lowered code agrees with naive_eval on value, x, y and fatality over a grid
+    fn check(e: &Expr) {
+        let code = lower(e);
+        for x0 in -4..=4 {
+            for y0 in -4..=4 {
+                let (mut x, mut y, mut fatal) = (x0, y0, false);
+                let expected = naive_eval(e, &mut x, &mut y, &mut fatal);
+                let (mut ix, mut iy) = (x0, y0);
+                match run(&code, &mut ix, &mut iy) {
+                    Some(v) => {
+                        assert!(!fatal, "x={} y={}: naive is fatal", x0, y0);
+                        assert_eq!((v, ix, iy), (expected, x, y), "x={} y={}", x0, y0);
+                    }
+                    None => assert!(fatal, "x={} y={}: IR is fatal", x0, y0),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_lower_layout() {
+        // x+=y*3: the right side, then x read, updated and written back
+        let code = lower(&bin(x(), Operator::AddEq, bin(y(), Operator::Mul, lit(3))));
+        let plain = Mode::Plain;
+        assert_eq!(code, [
+            IrInst::Load { dst: 0, var: Operator::VarY },
+            IrInst::Const { dst: 1, value: 3 },
+            IrInst::BinOp { op: Operator::Mul, mode: plain, dst: 2, lhs: 0, rhs: 1 },
+            IrInst::Load { dst: 3, var: Operator::Var },
+            IrInst::BinOp { op: Operator::Add, mode: plain, dst: 4, lhs: 3, rhs: 2 },
+            IrInst::Store { var: Operator::Var, src: 4 },
+            IrInst::Return { src: 4 },
+        ]);
+        assert_eq!(vreg_count(&code), 5);
+        assert_eq!(label_count(&code), 0);
+    }
+
+    #[test]
+    fn test_lower_operators() {
+        let binary = [
+            Operator::Comma, Operator::Or, Operator::And, Operator::BitOr, Operator::BitXor,
+            Operator::BitAnd, Operator::Eq, Operator::Neq, Operator::Lt, Operator::Leq,
+            Operator::Gt, Operator::Geq, Operator::Min, Operator::Max, Operator::BitShl,
+            Operator::BitShr, Operator::LShr, Operator::Add, Operator::Sub, Operator::Mul,
+            Operator::Div, Operator::Mod, Operator::Pow, Operator::Gcd,
+        ];
+        for op in binary {
+            check(&bin(x(), op, bin(y(), Operator::Sub, lit(1))));
+        }
+        for op in [Operator::Neg, Operator::BitNot, Operator::Not, Operator::PopCount, Operator::Parens] {
+            check(&un(op, bin(x(), Operator::Mul, y())));
+        }
+        for op in [Operator::Add, Operator::AddEq] {
+            check(&bin(build::saturating(x(), op, lit(NumT::MAX)), Operator::Comma, x()));
+        }
+        for op in [Operator::Mod, Operator::ModEq] {
+            check(&bin(build::euclidean(x(), op, y()), Operator::Comma, x()));
+            check(&bin(build::unsigned(x(), op, y()), Operator::Comma, x()));
+        }
+    }
+
+    #[test]
+    fn test_lower_side_effects() {
+        let assign = [
+            Operator::AssignEq, Operator::BitOrEq, Operator::BitXorEq, Operator::BitAndEq,
+            Operator::BitShlEq, Operator::BitShrEq, Operator::AddEq, Operator::SubEq,
+            Operator::MulEq, Operator::DivEq, Operator::ModEq,
+        ];
+        for op in assign {
+            // The right side changes the target before it's read
+            check(&bin(x(), op, bin(y(), Operator::Add, un(Operator::PostInc, x()))));
+            check(&bin(y(), op, x()));
+        }
+        for op in [Operator::PreInc, Operator::PreDec, Operator::PostInc, Operator::PostDec] {
+            check(&bin(un(op, y()), Operator::Sub, un(op, y())));
+            check(&bin(bin(x(), Operator::AssignEq, lit(NumT::MAX)), Operator::Comma, un(op, x())));
+        }
+        check(&un(Operator::PostInc, x()));
+    }
+
+    #[test]
+    fn test_lower_ternary() {
+        // x?y++:--x, then the comma reads both
+        let e = ternary(x(), un(Operator::PostInc, y()), un(Operator::PreDec, x()));
+        check(&e);
+        check(&bin(e.clone(), Operator::Comma, bin(x(), Operator::Mul, y())));
+        let inner = ternary(y(), lit(3), un(Operator::PostDec, y()));
+        let nested = ternary(bin(x(), Operator::Lt, lit(0)), inner.clone(), bin(inner, Operator::Add, x()));
+        check(&nested);
+        assert_eq!(label_count(&lower(&nested)), 6);
+    }
+
+    #[test]
+    fn test_lower_writes_registers_once() {
+        let writes = |e: &Expr| {
+            let code = lower(e);
+            let mut writes = vec![0; vreg_count(&code)];
+            for dst in code.iter().filter_map(IrInst::writes) {
+                writes[dst] += 1;
+            }
+            writes
+        };
+        // x+(y+(x+y)): four loads and three sums
+        let e = bin(x(), Operator::Add, bin(y(), Operator::Add, bin(x(), Operator::Add, y())));
+        assert_eq!(writes(&e), [1; 7]);
+        assert_eq!(writes(&bin(un(Operator::PostInc, x()), Operator::Comma, y())), [1; 4]);
+        // Only the ternary's value is written by both branches
+        let w = writes(&ternary(x(), un(Operator::Neg, y()), lit(2)));
+        assert_eq!(w.iter().filter(|&&n| n == 2).count(), 1);
+        assert!(w.iter().all(|&n| n == 1 || n == 2));
+    }
+
+    #[test]
+    fn test_lower_reuses_compiled_children() {
+        // Statements joined by a comma, as gen_statements pairs them: each
+        // side's kept IR is spliced in, as if the whole had been lowered afresh
+        let stmts = |b: build::Builder| {
+            let ternary = b.bin(b.x(), Operator::AssignEq, b.ternary(b.y(), b.lit(3), b.un(Operator::PostDec, b.y())));
+            let add = b.bin(b.y(), Operator::AddEq, b.bin(b.x(), Operator::Mul, b.lit(2)));
+            let bad = b.bin(b.lit(2), Operator::AssignEq, b.x());
+            [b.bin(ternary.clone(), Operator::Comma, add.clone()), b.bin(add, Operator::Comma, ternary), b.bin(bad, Operator::Comma, b.x())]
+        };
+        for (compiled, fresh) in stmts(build::Builder::new(true)).iter().zip(stmts(build::Builder::new(false))) {
+            assert!(compiled.left.as_ref().unwrap().jit.is_some());
+            let code = lower(&compiled.without_code());
+            assert_eq!(code, lower(&fresh));
+            assert_eq!(code, compiled.jit.as_ref().unwrap().lowered());
+            check(compiled);
+        }
+    }
+
+    #[test]
+    fn test_lower_fatal() {
+        // ++(x+1) has no variable to write
+        let code = lower(&bin(lit(1), Operator::Add, un(Operator::PreInc, bin(x(), Operator::Add, lit(1)))));
+        assert!(code.contains(&IrInst::Fatal));
+        check(&bin(lit(2), Operator::AssignEq, x()));
+        check(&bin(x(), Operator::DivEq, y()));
+    }
+}
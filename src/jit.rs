@@ -1,7 +1,12 @@
+use std::cell::RefCell;
 use std::mem;
 use std::ptr;
+use std::sync::Arc;
 use libc::{mmap, munmap, PROT_READ, PROT_WRITE, PROT_EXEC, MAP_PRIVATE, MAP_ANON, MAP_FAILED};
-use crate::{Expr, Operator, NumT};
+use crate::ir::{self, IrInst};
+use crate::regalloc::{self, Loc};
+use crate::visit::{map, visit};
+use crate::{is_associative, naive_eval, Expr, Mode, NumT};
 
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 const MAP_JIT: libc::c_int = 0x0800;
@@ -65,10 +70,25 @@ impl JitMemory {
     }
 
     pub fn flush(&self) {
+        self.flush_range(0, self.size);
+    }
+
+    /// Make `len` bytes written at `start` visible to instruction fetch
+    fn flush_range(&self, start: usize, len: usize) {
         #[cfg(any(target_os = "macos", target_os = "ios"))]
         unsafe {
-             sys_icache_invalidate(self.ptr as *mut _, self.size);
+             sys_icache_invalidate(self.ptr.add(start) as *mut _, len);
+        }
+        #[cfg(all(target_arch = "aarch64", not(any(target_os = "macos", target_os = "ios"))))]
+        unsafe {
+            extern "C" {
+                fn __clear_cache(start: *mut libc::c_char, end: *mut libc::c_char);
+            }
+            let start = self.ptr.add(start) as *mut libc::c_char;
+            __clear_cache(start, start.add(len));
         }
+        #[cfg(not(any(target_os = "macos", target_os = "ios", target_arch = "aarch64")))]
+        let _ = (start, len);
     }
 }
 
@@ -88,18 +108,72 @@ pub use aarch64::*;
 
 pub type JitFunc = unsafe extern "C" fn(*mut NumT, *mut NumT) -> NumT;
 
+/// A branch target in the code being emitted. Jumps reserved before the label
+/// is bound are remembered and back-patched by `bind_label`; jumps reserved
+/// after it go straight to the bound offset.
+#[derive(Debug, Default)]
+struct Label {
+    bound: Option<usize>,
+    fixups: Vec<usize>,
+}
+
+/// Executable memory that finalized functions are packed into, one chunk at a
+/// time per thread. A function is usually well under 100 bytes, so giving each
+/// its own mmap'd page wasted almost all of it. Every `Jit` holds its chunk,
+/// which is unmapped once all the functions in it are dropped.
+struct CodeArena {
+    chunk: Option<Arc<JitMemory>>,
+    used: usize,
+}
+
+const ARENA_CHUNK_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    static ARENA: RefCell<CodeArena> = const { RefCell::new(CodeArena { chunk: None, used: 0 }) };
+}
+
+impl CodeArena {
+    /// Copy `code` into executable memory, returning its chunk and offset there
+    fn place(&mut self, code: &[u8]) -> (Arc<JitMemory>, usize) {
+        // Cache-line aligned, so no function shares a line with code already run
+        let mut start = (self.used + 63) & !63;
+        let chunk = match &self.chunk {
+            Some(chunk) if start + code.len() <= chunk.size => chunk.clone(),
+            _ => {
+                let chunk = Arc::new(JitMemory::new(ARENA_CHUNK_SIZE.max(code.len())));
+                self.chunk = Some(chunk.clone());
+                start = 0;
+                chunk
+            }
+        };
+        chunk.make_writable();
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), chunk.ptr.add(start), code.len());
+        }
+        chunk.flush_range(start, code.len());
+        chunk.make_executable();
+        self.used = start + code.len();
+        (chunk, start)
+    }
+}
+
 pub struct Jit {
-    memory: JitMemory,
+    buf: Vec<u8>, // Code is emitted here, then moved to `code` by finalize
+    code: Option<(Arc<JitMemory>, usize)>, // Chunk and offset of the finalized function
     offset: usize,
-    regind: usize,
+    frame: usize, // Bytes of stack the function reserves for spills, 0 if it has none
+    lowered: Vec<IrInst>, // The IR compile emitted, which parents lower splices in
     cached_func: Option<JitFunc>,
+    #[cfg(target_arch = "x86_64")]
+    near_jumps: bool, // Forward jumps use rel32, set when a rel8 one didn't fit
+    #[cfg(target_arch = "x86_64")]
+    popcnt: bool, // The CPU has popcnt, else PopCount counts bits with shifts and masks
 }
 
 impl std::fmt::Debug for Jit {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Jit")
             .field("offset", &self.offset)
-            .field("regind", &self.regind)
             .field("has_func", &self.cached_func.is_some())
             .finish()
     }
@@ -108,60 +182,192 @@ impl std::fmt::Debug for Jit {
 impl Jit {
     pub fn new(size: usize) -> Self {
         Jit {
-            memory: JitMemory::new(size),
+            buf: vec![0; size],
+            code: None,
             offset: 0,
-            regind: 0,
+            frame: 0,
+            lowered: Vec::new(),
             cached_func: None,
+            #[cfg(target_arch = "x86_64")]
+            near_jumps: false,
+            #[cfg(target_arch = "x86_64")]
+            popcnt: std::arch::is_x86_feature_detected!("popcnt"),
+        }
+    }
+
+    /// The machine code emitted so far
+    pub fn code_bytes(&self) -> &[u8] {
+        match &self.code {
+            Some((chunk, start)) => unsafe { std::slice::from_raw_parts(chunk.ptr.add(*start), self.offset) },
+            None => &self.buf[..self.offset],
         }
     }
 
+    /// The IR the code was compiled from, ending in its `Return`
+    pub fn lowered(&self) -> &[IrInst] {
+        &self.lowered
+    }
+
     pub fn func(&self) -> JitFunc {
         self.cached_func.expect("JIT not finalized - call finalize() first")
     }
 
-    /// Finalize the JIT code - flush caches and make executable.
+    /// Run from x and y, returning the value and the final x and y. The
+    /// search's hot loops call `func` on their own x and y instead.
+    pub fn call(&self, mut x: NumT, mut y: NumT) -> (NumT, NumT, NumT) {
+        // Safe: finalized code only touches the two locals it's handed
+        let r = unsafe { self.func()(&mut x, &mut y) };
+        (r, x, y)
+    }
+
+    /// Finalize the JIT code - move it into executable memory and free the buffer.
     /// Must be called after compile() and before func().
     pub fn finalize(&mut self) {
-        self.memory.flush();
-        self.memory.make_executable();
-        self.cached_func = Some(unsafe { mem::transmute(self.memory.ptr) });
+        let (chunk, start) = ARENA.with(|arena| arena.borrow_mut().place(&self.buf[..self.offset]));
+        self.cached_func = Some(unsafe { mem::transmute::<*mut u8, JitFunc>(chunk.ptr.add(start)) });
+        self.code = Some((chunk, start));
+        self.buf = Vec::new();
     }
 
     #[cfg(target_arch = "x86_64")]
     fn emit_u8(&mut self, b: u8) {
-        unsafe {
-            *self.memory.ptr.add(self.offset) = b;
+        self.emit_bytes(&[b]);
+    }
+
+    fn emit_bytes(&mut self, bytes: &[u8]) {
+        if self.offset + bytes.len() > self.buf.len() {
+            // Big expressions outgrow the first guess at the size
+            let size = (self.offset + bytes.len()).max(2 * self.buf.len());
+            self.buf.resize(size, 0);
         }
-        self.offset += 1;
+        self.buf[self.offset..self.offset + bytes.len()].copy_from_slice(bytes);
+        self.offset += bytes.len();
     }
 
     fn emit_u32(&mut self, val: u32) {
-        unsafe {
-            ptr::copy_nonoverlapping(&val as *const u32 as *const u8, self.memory.ptr.add(self.offset), 4);
-        }
-        self.offset += 4;
+        self.emit_bytes(&val.to_le_bytes());
     }
 
     #[cfg(target_arch = "x86_64")]
     fn emit_u64(&mut self, val: u64) {
-        unsafe {
-            ptr::copy_nonoverlapping(&val as *const u64 as *const u8, self.memory.ptr.add(self.offset), 8);
-        }
-        self.offset += 8;
+        self.emit_bytes(&val.to_le_bytes());
     }
 }
 
+/// Modular `Pow` isn't supported, see `Expr::is_jittable`
 pub fn jit_compile_expr(e: &Expr) -> Jit {
+    debug_assert!(e.is_jittable(), "modular Pow must be evaluated with naive_eval");
     let size = 4096; 
     let mut jit = Jit::new(size);
-    jit.compile(e);
+    if has_chain(e) {
+        jit.compile(&rebalance(e));
+    } else {
+        jit.compile(e);
+    }
     jit.finalize();
     jit
 }
 
+/// `e` as a plain function of the starting x and y, returning its value and
+/// the final x and y. It runs `e`'s own Jit if it has one, a freshly compiled
+/// one otherwise, and naive_eval for what the JIT can't compile (modular
+/// `Pow`). Like the JIT, the value is 0 where `e` is fatal.
+pub fn compile_fn(e: &Expr) -> impl Fn(NumT, NumT) -> (NumT, NumT, NumT) {
+    let jit = match &e.jit {
+        Some(jit) => Some(jit.clone()),
+        None => e.is_jittable().then(|| Arc::new(jit_compile_expr(e))),
+    };
+    // Only kept when there's no Jit to run; the copy shares e's children
+    let naive = jit.is_none().then(|| e.clone());
+    move |mut x, mut y| match (&jit, &naive) {
+        (Some(jit), _) => jit.call(x, y),
+        (None, Some(e)) => {
+            let mut fatal = false;
+            let r = naive_eval(e, &mut x, &mut y, &mut fatal);
+            (if fatal { 0 } else { r }, x, y)
+        }
+        (None, None) => unreachable!(),
+    }
+}
+
+/// An associative operator whose chains `rebalance` may reshape. Saturating
+/// nodes clamp at every step, so they don't reassociate.
+fn chains(e: &Expr) -> bool {
+    is_associative(e.op) && !e.is_saturating()
+}
+
+/// Whether `c` continues `n`'s chain: the same operator in the same mode,
+/// as an unsigned `<?` under a signed one is a different operator
+fn continues(n: &Expr, c: &Expr) -> bool {
+    c.op == n.op && c.mode == n.mode && chains(c)
+}
+
+/// Whether `n` joins a chain of three or more operands, like `a+(b+c)`
+fn is_chain(n: &Expr) -> bool {
+    chains(n) && [&n.left, &n.right].into_iter().flatten().any(|c| continues(n, c))
+}
+
+/// Whether some chain has three or more operands
+fn has_chain(e: &Expr) -> bool {
+    let mut found = false;
+    visit(e, &mut |n: &Expr| found |= is_chain(n));
+    found
+}
+
+/// `e` with each chain of an associative operator, like `((a+b)+c)+d` or
+/// `a+(b+(c+d))`, rebuilt balanced as `(a+b)+(c+d)`. A chain of n operands
+/// then nests about log2(n) deep rather than n, and the IR, which holds a
+/// binary node's left value in a register while it computes the right,
+/// needs about log2(n)+1 registers for it however it nested. Operands keep
+/// their order, and with it their side effects and which step is fatal first.
+fn rebalance(e: &Expr) -> Arc<Expr> {
+    map(e, &|n: &Expr| {
+        if !is_chain(n) {
+            return None;
+        }
+        // Children are rebuilt first; flattening them again is cheap
+        let mut operands = Vec::new();
+        for side in [&n.left, &n.right].into_iter().flatten() {
+            chain_operands(side, n, &mut operands);
+        }
+        Some(balanced(n, &operands))
+    })
+}
+
+/// `operands` joined by `n`'s operator, the larger half on the left so the
+/// left value held across the right side needs no more registers than it
+fn balanced(n: &Expr, operands: &[Arc<Expr>]) -> Arc<Expr> {
+    if let [operand] = operands {
+        return operand.clone();
+    }
+    let (left, right) = operands.split_at(operands.len().div_ceil(2));
+    Arc::new(Expr {
+        left: Some(balanced(n, left)),
+        mid: None,
+        right: Some(balanced(n, right)),
+        literal: n.literal,
+        mode: n.mode,
+        op: n.op,
+        jit: None,
+        bytecode: None,
+    })
+}
+
+/// The operands of the chain `e` continues from `n`, left to right
+fn chain_operands(e: &Arc<Expr>, n: &Expr, out: &mut Vec<Arc<Expr>>) {
+    if !continues(n, e) {
+        out.push(e.clone());
+        return;
+    }
+    for side in [&e.left, &e.right].into_iter().flatten() {
+        chain_operands(side, n, out);
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 mod x86_64 {
     use super::*;
+    use crate::regalloc::Allocation;
     use crate::{Expr, Operator};
 
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -174,6 +380,48 @@ mod x86_64 {
     const CALL_REGS: [Reg; 6] = [Reg::RDI, Reg::RSI, Reg::RDX, Reg::RCX, Reg::R8, Reg::R9];
     const FREE_REGS: [Reg; 4] = [Reg::R8, Reg::R9, Reg::R10, Reg::R11];
 
+    // Branch kinds the fixup layer can emit in rel8 or rel32 form
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Jump {
+        Je,
+        Jne,
+        Js,
+        Jns,
+        Ja,
+        // Signed overflow after add, sub or imul
+        Jo,
+        Jno,
+        Jmp,
+    }
+
+    impl Jump {
+        fn short(self) -> fn(&mut Jit, i8) {
+            match self {
+                Jump::Je => Jit::jes,
+                Jump::Jne => Jit::jnes,
+                Jump::Js => Jit::jss,
+                Jump::Jns => Jit::jnss,
+                Jump::Ja => Jit::jas,
+                Jump::Jo => Jit::jos,
+                Jump::Jno => Jit::jnos,
+                Jump::Jmp => Jit::jmps,
+            }
+        }
+
+        fn near(self) -> fn(&mut Jit, i32) {
+            match self {
+                Jump::Je => Jit::je32,
+                Jump::Jne => Jit::jne32,
+                Jump::Js => Jit::js32,
+                Jump::Jns => Jit::jns32,
+                Jump::Ja => Jit::ja32,
+                Jump::Jo => Jit::jo32,
+                Jump::Jno => Jit::jno32,
+                Jump::Jmp => Jit::jmp32,
+            }
+        }
+    }
+
     impl Jit {
         fn rex(&mut self, w: u8, r: Reg, x: u8, b: Reg) {
             let r_val = r as u8;
@@ -186,12 +434,19 @@ mod x86_64 {
         }
 
         // Instructions
-        fn movir(&mut self, i0: i64, r1: Reg) {
-            if i0 >= i32::MIN as i64 && i0 <= i32::MAX as i64 {
+        pub(super) fn movir(&mut self, i0: i64, r1: Reg) {
+            if i0 >= 0 && i0 <= i32::MAX as i64 {
+                 // mov r32, imm32 (zero-extends into the full register)
                  let r1_val = r1 as u8;
                  if Self::reg_hi(r1) { self.emit_u8(0x41); }
                  self.emit_u8(0xB8 | (r1_val & 7));
                  self.emit_u32(i0 as u32);
+            } else if i0 >= i32::MIN as i64 && i0 < 0 {
+                 // mov r64, imm32 (sign-extends)
+                 self.rex(1, Reg::RAX, 0, r1);
+                 self.emit_u8(0xC7);
+                 self.emit_u8(0xC0 | (r1 as u8 & 7));
+                 self.emit_u32(i0 as u32);
             } else {
                  let r1_val = r1 as u8;
                  self.rex(1, Reg::RAX, 0, r1);
@@ -206,10 +461,10 @@ mod x86_64 {
             self.emit_u8(((r1 as u8 & 7) << 3) | (r0 as u8 & 7));
         }
 
-        fn movr(&mut self, r0: Reg, r1: Reg) {
+        pub(super) fn movr(&mut self, r0: Reg, r1: Reg) {
             self.rex(1, r0, 0, r1);
             self.emit_u8(0x89);
-            self.emit_u8(((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
+            self.emit_u8(0xC0 | ((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
         }
 
         fn movrm(&mut self, r0: Reg, r1: Reg) {
@@ -218,25 +473,6 @@ mod x86_64 {
             self.emit_u8(((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
         }
 
-        fn mov0m(&mut self, r0: Reg) {
-            self.rex(1, Reg::RAX, 0, r0);
-            self.emit_u8(0xC7);
-            self.emit_u8(r0 as u8 & 7);
-            self.emit_u32(0);
-        }
-
-        fn incm(&mut self, r0: Reg) {
-            self.rex(1, Reg::RAX, 0, r0);
-            self.emit_u8(0xFF);
-            self.emit_u8(r0 as u8 & 7);
-        }
-
-        fn decm(&mut self, r0: Reg) {
-            self.rex(1, Reg::RAX, 0, r0);
-            self.emit_u8(0xFF);
-            self.emit_u8(0x08 | (r0 as u8 & 7));
-        }
-
         fn negr(&mut self, r0: Reg) {
             self.rex(1, Reg::RAX, 0, r0);
             self.emit_u8(0xF7);
@@ -249,10 +485,10 @@ mod x86_64 {
             self.emit_u8(0xD0 | (r0 as u8 & 7));
         }
 
-        fn testr(&mut self, r0: Reg, r1: Reg) {
+        pub(super) fn testr(&mut self, r0: Reg, r1: Reg) {
             self.rex(1, r0, 0, r1);
             self.emit_u8(0x85);
-            self.emit_u8(((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
+            self.emit_u8(0xC0 | ((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
         }
 
         fn seter(&mut self, r0: Reg) {
@@ -291,6 +527,30 @@ mod x86_64 {
             self.emit_u8(0xC0 | (r0 as u8 & 7));
         }
 
+        fn setbr(&mut self, r0: Reg) {
+            if (r0 as u8) >= 4 { self.rex(0, Reg::RAX, 0, r0); }
+            self.emit_u8(0x0F); self.emit_u8(0x92);
+            self.emit_u8(0xC0 | (r0 as u8 & 7));
+        }
+
+        fn setbe(&mut self, r0: Reg) {
+            if (r0 as u8) >= 4 { self.rex(0, Reg::RAX, 0, r0); }
+            self.emit_u8(0x0F); self.emit_u8(0x96);
+            self.emit_u8(0xC0 | (r0 as u8 & 7));
+        }
+
+        fn setar(&mut self, r0: Reg) {
+            if (r0 as u8) >= 4 { self.rex(0, Reg::RAX, 0, r0); }
+            self.emit_u8(0x0F); self.emit_u8(0x97);
+            self.emit_u8(0xC0 | (r0 as u8 & 7));
+        }
+
+        fn setaer(&mut self, r0: Reg) {
+            if (r0 as u8) >= 4 { self.rex(0, Reg::RAX, 0, r0); }
+            self.emit_u8(0x0F); self.emit_u8(0x93);
+            self.emit_u8(0xC0 | (r0 as u8 & 7));
+        }
+
         fn andir(&mut self, i0: i32, r1: Reg) {
             self.rex(1, Reg::RAX, 0, r1);
             if i0 as i8 as i32 == i0 {
@@ -314,22 +574,17 @@ mod x86_64 {
             self.emit_u8(0xC0 | (r0 as u8) << 3 | (r1 as u8 & 7));
         }
 
-        fn orrm(&mut self, r0: Reg, r1: Reg) {
-            self.rex(1, r0, 0, r1);
-            self.emit_u8(0x09);
-            self.emit_u8(((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
-        }
-
         fn xorr(&mut self, r0: Reg, r1: Reg) {
             self.rex(1, r0, 0, r1);
             self.emit_u8(0x31);
             self.emit_u8(0xC0 | (r0 as u8) << 3 | (r1 as u8 & 7));
         }
 
-        fn xorrm(&mut self, r0: Reg, r1: Reg) {
-            self.rex(1, r0, 0, r1);
+        // xor r32, r32: zeroes the whole register, and is 2 bytes for RAX
+        fn zeror(&mut self, r0: Reg) {
+            if (r0 as u8) >= 8 { self.emit_u8(0x45); }
             self.emit_u8(0x31);
-            self.emit_u8(((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
+            self.emit_u8(0xC0 | ((r0 as u8 & 7) << 3) | (r0 as u8 & 7));
         }
 
         fn andr(&mut self, r0: Reg, r1: Reg) {
@@ -338,42 +593,6 @@ mod x86_64 {
             self.emit_u8(0xC0 | (r0 as u8) << 3 | (r1 as u8 & 7));
         }
 
-        fn andrm(&mut self, r0: Reg, r1: Reg) {
-            self.rex(1, r0, 0, r1);
-            self.emit_u8(0x21);
-            self.emit_u8(((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
-        }
-
-        fn salcm(&mut self, r0: Reg) {
-            self.rex(1, Reg::RAX, 0, r0);
-            self.emit_u8(0xD3);
-            self.emit_u8(0x20 | (r0 as u8 & 7));
-        }
-
-        fn sarcm(&mut self, r0: Reg) {
-            self.rex(1, Reg::RAX, 0, r0);
-            self.emit_u8(0xD3);
-            self.emit_u8(0x38 | (r0 as u8 & 7));
-        }
-
-        fn addrm(&mut self, r0: Reg, r1: Reg) {
-            self.rex(1, r0, 0, r1);
-            self.emit_u8(0x01);
-            self.emit_u8(((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
-        }
-
-        fn subrm(&mut self, r0: Reg, r1: Reg) {
-            self.rex(1, r0, 0, r1);
-            self.emit_u8(0x29);
-            self.emit_u8(((r0 as u8 & 7) << 3) | (r1 as u8 & 7));
-        }
-
-        fn imulmr(&mut self, r0: Reg, r1: Reg) {
-            self.rex(1, r1, 0, r0);
-            self.emit_u8(0x0F); self.emit_u8(0xAF);
-            self.emit_u8(((r1 as u8 & 7) << 3) | (r0 as u8 & 7));
-        }
-
         fn cmpr(&mut self, r0: Reg, r1: Reg) {
             self.rex(1, r0, 0, r1);
             self.emit_u8(0x39);
@@ -397,12 +616,49 @@ mod x86_64 {
             }
         }
 
+        fn cmovlr(&mut self, r0: Reg, r1: Reg) {
+            self.rex(1, r1, 0, r0);
+            self.emit_u8(0x0F); self.emit_u8(0x4C);
+            self.emit_u8(0xC0 | ((r1 as u8 & 7) << 3) | (r0 as u8 & 7));
+        }
+
+        fn cmovgr(&mut self, r0: Reg, r1: Reg) {
+            self.rex(1, r1, 0, r0);
+            self.emit_u8(0x0F); self.emit_u8(0x4F);
+            self.emit_u8(0xC0 | ((r1 as u8 & 7) << 3) | (r0 as u8 & 7));
+        }
+
+        fn cmovbr(&mut self, r0: Reg, r1: Reg) {
+            self.rex(1, r1, 0, r0);
+            self.emit_u8(0x0F); self.emit_u8(0x42);
+            self.emit_u8(0xC0 | ((r1 as u8 & 7) << 3) | (r0 as u8 & 7));
+        }
+
+        fn cmovar(&mut self, r0: Reg, r1: Reg) {
+            self.rex(1, r1, 0, r0);
+            self.emit_u8(0x0F); self.emit_u8(0x47);
+            self.emit_u8(0xC0 | ((r1 as u8 & 7) << 3) | (r0 as u8 & 7));
+        }
+
+        fn shrir(&mut self, i0: u8, r1: Reg) {
+            self.rex(1, Reg::RAX, 0, r1);
+            self.emit_u8(0xC1);
+            self.emit_u8(0xE8 | (r1 as u8 & 7));
+            self.emit_u8(i0);
+        }
+
         fn salc(&mut self, r0: Reg) {
             self.rex(1, Reg::RAX, 0, r0);
             self.emit_u8(0xD3);
             self.emit_u8(0xE0 | (r0 as u8 & 7));
         }
 
+        fn shrc(&mut self, r0: Reg) {
+            self.rex(1, Reg::RAX, 0, r0);
+            self.emit_u8(0xD3);
+            self.emit_u8(0xE8 | (r0 as u8 & 7));
+        }
+
         fn sarc(&mut self, r0: Reg) {
             self.rex(1, Reg::RAX, 0, r0);
             self.emit_u8(0xD3);
@@ -427,11 +683,24 @@ mod x86_64 {
             self.emit_u8(0xC0 | (r1 as u8) << 3 | (r0 as u8 & 7));
         }
 
+        fn popcntr(&mut self, r0: Reg, r1: Reg) {
+            self.emit_u8(0xF3);
+            self.rex(1, r1, 0, r0);
+            self.emit_u8(0x0F); self.emit_u8(0xB8);
+            self.emit_u8(0xC0 | ((r1 as u8 & 7) << 3) | (r0 as u8 & 7));
+        }
+
         fn cqto(&mut self) {
             self.emit_u8(0x48);
             self.emit_u8(0x99);
         }
 
+        fn divr(&mut self, r0: Reg) {
+            self.rex(1, Reg::RAX, 0, r0);
+            self.emit_u8(0xF7);
+            self.emit_u8(0xF0 | (r0 as u8 & 7));
+        }
+
         fn idivr(&mut self, r0: Reg) {
             self.rex(1, Reg::RAX, 0, r0);
             self.emit_u8(0xF7);
@@ -442,6 +711,45 @@ mod x86_64 {
             self.emit_u8(0xC3);
         }
 
+        // Return, first restoring RBX and R12 and freeing the frame if the
+        // function spills
+        fn leave(&mut self) {
+            if self.frame > 0 {
+                self.movsr(0, Reg::RBX);
+                self.movsr(8, Reg::R12);
+                self.addspi(self.frame as i32);
+            }
+            self.ret();
+        }
+
+        // r1 = [rsp + i0]
+        fn movsr(&mut self, i0: i32, r1: Reg) {
+            self.rex(1, r1, 0, Reg::RSP);
+            self.emit_u8(0x8B);
+            self.emit_u8(0x84 | ((r1 as u8 & 7) << 3));
+            self.emit_u8(0x24);
+            self.emit_u32(i0 as u32);
+        }
+
+        // [rsp + i1] = r0
+        fn movrs(&mut self, r0: Reg, i1: i32) {
+            self.rex(1, r0, 0, Reg::RSP);
+            self.emit_u8(0x89);
+            self.emit_u8(0x84 | ((r0 as u8 & 7) << 3));
+            self.emit_u8(0x24);
+            self.emit_u32(i1 as u32);
+        }
+
+        fn subspi(&mut self, i0: i32) {
+            self.emit_bytes(&[0x48, 0x81, 0xEC]);
+            self.emit_u32(i0 as u32);
+        }
+
+        fn addspi(&mut self, i0: i32) {
+            self.emit_bytes(&[0x48, 0x81, 0xC4]);
+            self.emit_u32(i0 as u32);
+        }
+
         fn jes(&mut self, i0: i8) {
             self.emit_u8(0x74);
             self.emit_u8(i0 as u8);
@@ -452,205 +760,566 @@ mod x86_64 {
             self.emit_u8(i0 as u8);
         }
 
-        pub fn compile(&mut self, e: &Expr) {
-            self.regind = 0;
-            self.gen_expr(e);
-            // Move result to RAX
-            self.movr(FREE_REGS[self.regind - 1], Reg::RAX);
-            self.ret();
+        fn jmps(&mut self, i0: i8) {
+            self.emit_u8(0xEB);
+            self.emit_u8(i0 as u8);
         }
 
-        // gen_expr logic same as before
-        fn gen_expr(&mut self, e: &Expr) {
-            match e.op {
-                Operator::Literal => {
-                    self.movir(e.literal, FREE_REGS[self.regind]);
-                    self.regind += 1;
-                    return;
-                }
-                Operator::Var | Operator::VarY => {
-                    let reg_idx = (e.op as usize) & 0xF;
-                    self.movmr(CALL_REGS[reg_idx], FREE_REGS[self.regind]);
-                    self.regind += 1;
-                    return;
-                }
+        fn jss(&mut self, i0: i8) {
+            self.emit_u8(0x78);
+            self.emit_u8(i0 as u8);
+        }
 
-                 Operator::PreInc => {
-                     let target = e.right.as_ref().unwrap();
-                     let reg_idx = (target.op as usize) & 0xF;
-                     self.incm(CALL_REGS[reg_idx]);
-                     self.movmr(CALL_REGS[reg_idx], FREE_REGS[self.regind]);
-                     self.regind += 1;
-                     return;
-                }
-                Operator::PreDec => {
-                     let target = e.right.as_ref().unwrap();
-                     let reg_idx = (target.op as usize) & 0xF;
-                     self.decm(CALL_REGS[reg_idx]);
-                     self.movmr(CALL_REGS[reg_idx], FREE_REGS[self.regind]);
-                     self.regind += 1;
-                     return;
-                }
-                Operator::PostInc => {
-                     let target = e.right.as_ref().unwrap();
-                     let reg_idx = (target.op as usize) & 0xF;
-                     self.movmr(CALL_REGS[reg_idx], FREE_REGS[self.regind]);
-                     self.regind += 1;
-                     self.incm(CALL_REGS[reg_idx]);
-                     return;
+        fn jnss(&mut self, i0: i8) {
+            self.emit_u8(0x79);
+            self.emit_u8(i0 as u8);
+        }
+
+        fn jas(&mut self, i0: i8) {
+            self.emit_u8(0x77);
+            self.emit_u8(i0 as u8);
+        }
+
+        fn jos(&mut self, i0: i8) {
+            self.emit_u8(0x70);
+            self.emit_u8(i0 as u8);
+        }
+
+        fn jnos(&mut self, i0: i8) {
+            self.emit_u8(0x71);
+            self.emit_u8(i0 as u8);
+        }
+
+        fn je32(&mut self, i0: i32) {
+            self.emit_u8(0x0F);
+            self.emit_u8(0x84);
+            self.emit_u32(i0 as u32);
+        }
+
+        fn jne32(&mut self, i0: i32) {
+            self.emit_u8(0x0F);
+            self.emit_u8(0x85);
+            self.emit_u32(i0 as u32);
+        }
+
+        fn jmp32(&mut self, i0: i32) {
+            self.emit_u8(0xE9);
+            self.emit_u32(i0 as u32);
+        }
+
+        fn js32(&mut self, i0: i32) {
+            self.emit_u8(0x0F);
+            self.emit_u8(0x88);
+            self.emit_u32(i0 as u32);
+        }
+
+        fn jns32(&mut self, i0: i32) {
+            self.emit_u8(0x0F);
+            self.emit_u8(0x89);
+            self.emit_u32(i0 as u32);
+        }
+
+        fn ja32(&mut self, i0: i32) {
+            self.emit_u8(0x0F);
+            self.emit_u8(0x87);
+            self.emit_u32(i0 as u32);
+        }
+
+        fn jo32(&mut self, i0: i32) {
+            self.emit_u8(0x0F);
+            self.emit_u8(0x80);
+            self.emit_u32(i0 as u32);
+        }
+
+        fn jno32(&mut self, i0: i32) {
+            self.emit_u8(0x0F);
+            self.emit_u8(0x81);
+            self.emit_u32(i0 as u32);
+        }
+
+        // Point the jump emitted at `at` to the current offset. A short jump
+        // that doesn't reach flags the function for re-emission with near jumps
+        fn patch_jump(&mut self, at: usize) {
+            let (disp_at, len) = match self.buf[at] {
+                0x0F => (at + 2, 6),
+                0xE9 => (at + 1, 5),
+                _ => (at + 1, 2),
+            };
+            let disp = self.offset - (at + len);
+            if len > 2 {
+                self.buf[disp_at..disp_at + 4].copy_from_slice(&(disp as u32).to_le_bytes());
+            } else if disp <= i8::MAX as usize {
+                self.buf[disp_at] = disp as u8;
+            } else {
+                self.near_jumps = true;
+            }
+        }
+
+        // Emit a `jump` to `label`, short when the displacement is known to fit
+        fn reserve_jump(&mut self, jump: Jump, label: &mut Label) {
+            match label.bound {
+                Some(target) => {
+                    let disp = target as isize - (self.offset + 2) as isize;
+                    if disp >= i8::MIN as isize {
+                        (jump.short())(self, disp as i8);
+                    } else {
+                        let len = if jump == Jump::Jmp { 5 } else { 6 };
+                        let disp = target as isize - (self.offset + len) as isize;
+                        (jump.near())(self, disp as i32);
+                    }
                 }
-                Operator::PostDec => {
-                     let target = e.right.as_ref().unwrap();
-                     let reg_idx = (target.op as usize) & 0xF;
-                     self.movmr(CALL_REGS[reg_idx], FREE_REGS[self.regind]);
-                     self.regind += 1;
-                     self.decm(CALL_REGS[reg_idx]);
-                     return;
+                None => {
+                    label.fixups.push(self.offset);
+                    if self.near_jumps {
+                        (jump.near())(self, 0);
+                    } else {
+                        (jump.short())(self, 0);
+                    }
                 }
-                _ => {}
-            }
-
-            if e.left.is_none() || e.is_assignment() {
-                self.gen_expr(e.right.as_ref().unwrap());
-                let rr = FREE_REGS[self.regind - 1];
-                
-                if let Some(ref left) = e.left {
-                     let target_idx = (left.op as usize) & 0xF;
-                     let target_reg = CALL_REGS[target_idx];
-                     
-                     match e.op {
-                        Operator::AssignEq => self.movrm(rr, target_reg),
-                        Operator::BitOrEq => self.orrm(rr, target_reg),
-                        Operator::BitXorEq => self.xorrm(rr, target_reg),
-                        Operator::BitAndEq => self.andrm(rr, target_reg),
-                        Operator::BitShlEq => {
-                            self.movr(rr, Reg::RCX);
-                            self.salcm(target_reg);
-                        },
-                        Operator::BitShrEq => {
-                            self.movr(rr, Reg::RCX);
-                            self.sarcm(target_reg);
-                        },
-                        Operator::AddEq => self.addrm(rr, target_reg),
-                        Operator::SubEq => self.subrm(rr, target_reg),
-                        Operator::MulEq => {
-                            self.imulmr(target_reg, rr);
-                            self.movrm(rr, target_reg);
-                        },
-                        Operator::DivEq => {
-                            self.testr(rr, rr);
-                            self.jes(24);
-                            self.movmr(target_reg, Reg::RAX);
-                            self.movir(i64::MIN, Reg::RDX);
-                            self.cmpr(Reg::RDX, Reg::RAX);
-                            self.jnes(14);
-                            self.cmpi(-1, rr);
-                            self.jnes(8);
-                            self.mov0m(target_reg);
-                            self.ret();
-                            self.cqto();
-                            self.idivr(rr);
-                            self.movrm(Reg::RAX, target_reg);
-                        },
-                        Operator::ModEq => {
-                            self.testr(rr, rr);
-                            self.jes(24);
-                            self.movmr(target_reg, Reg::RAX);
-                            self.movir(i64::MIN, Reg::RDX);
-                            self.cmpr(Reg::RDX, Reg::RAX);
-                            self.jnes(14);
-                            self.cmpi(-1, rr);
-                            self.jnes(8);
-                            self.mov0m(target_reg);
-                            self.ret();
-                            self.cqto();
-                            self.idivr(rr);
-                            self.movrm(Reg::RDX, target_reg);
-                        },
-                        _ => {}
-                     }
-                } else {
-                    match e.op {
-                        Operator::Neg => self.negr(rr),
-                        Operator::BitNot => self.notr(rr),
-                        Operator::Not => {
-                            self.testr(rr, rr);
-                            self.seter(rr);
-                            self.andir(1, rr);
-                        },
-                        Operator::Parens => {}, // Just pass through - value already in rr
-                        _ => {}
+            }
+        }
+
+        fn bind_label(&mut self, label: &mut Label) {
+            label.bound = Some(self.offset);
+            for at in label.fixups.drain(..) {
+                self.patch_jump(at);
+            }
+        }
+
+        fn incr(&mut self, r0: Reg) {
+            self.rex(1, Reg::RAX, 0, r0);
+            self.emit_u8(0xFF);
+            self.emit_u8(0xC0 | (r0 as u8 & 7));
+        }
+
+        fn decr(&mut self, r0: Reg) {
+            self.rex(1, Reg::RAX, 0, r0);
+            self.emit_u8(0xFF);
+            self.emit_u8(0xC8 | (r0 as u8 & 7));
+        }
+
+        // rax:rdx = rl / rr, the Euclidean way if `euclid`
+        fn gen_div(&mut self, rl: Reg, rr: Reg, euclid: bool) {
+            let mut fatal = Label::default();
+            self.testr(rr, rr);
+            self.reserve_jump(Jump::Je, &mut fatal);
+            self.gen_div_fatal(rl, rr, &mut fatal);
+            self.movr(rl, Reg::RAX);
+            self.cqto();
+            self.idivr(rr);
+            if euclid {
+                self.gen_euclid(rr);
+            }
+        }
+
+        // rl = rl <cc> rr as 0 or 1. setcc only writes the low byte, so it
+        // goes into rax zeroed beforehand; the xor clobbers the flags, so it
+        // can't zero rl, which cmp still reads
+        fn gen_compare(&mut self, rl: Reg, rr: Reg, set: fn(&mut Jit, Reg)) {
+            self.zeror(Reg::RAX);
+            self.cmpr(rr, rl);
+            set(self, Reg::RAX);
+            self.movr(Reg::RAX, rl);
+        }
+
+        // r = number of set bits in r, for CPUs without popcnt: sums of
+        // 2, 4 then 8 bits, and a multiply adds up the bytes into the top one.
+        // Clobbers RAX and RDX
+        fn gen_popcount(&mut self, r: Reg) {
+            self.movr(r, Reg::RAX);
+            self.shrir(1, Reg::RAX);
+            self.movir(0x5555_5555_5555_5555, Reg::RDX);
+            self.andr(Reg::RDX, Reg::RAX);
+            self.subr(Reg::RAX, r);
+            self.movir(0x3333_3333_3333_3333, Reg::RDX);
+            self.movr(r, Reg::RAX);
+            self.shrir(2, Reg::RAX);
+            self.andr(Reg::RDX, Reg::RAX);
+            self.andr(Reg::RDX, r);
+            self.addr(Reg::RAX, r);
+            self.movr(r, Reg::RAX);
+            self.shrir(4, Reg::RAX);
+            self.addr(Reg::RAX, r);
+            self.movir(0x0F0F_0F0F_0F0F_0F0F, Reg::RDX);
+            self.andr(Reg::RDX, r);
+            self.movir(0x0101_0101_0101_0101, Reg::RDX);
+            self.imulr(Reg::RDX, r);
+            self.shrir(56, r);
+        }
+
+        fn gen_abs(&mut self, r: Reg) {
+            let mut done = Label::default();
+            self.testr(r, r);
+            self.reserve_jump(Jump::Jns, &mut done);
+            self.negr(r);
+            self.bind_label(&mut done);
+        }
+
+        // rl = gcd(|rl|, |rr|) by Euclid's loop, dividing as u64 so that
+        // |i64::MIN| works. Clobbers rr, RAX and RDX
+        fn gen_gcd(&mut self, rl: Reg, rr: Reg) {
+            let mut top = Label::default();
+            let mut done = Label::default();
+            self.gen_abs(rl);
+            self.gen_abs(rr);
+            self.bind_label(&mut top);
+            self.testr(rr, rr);
+            self.reserve_jump(Jump::Je, &mut done);
+            self.movr(rl, Reg::RAX);
+            self.zeror(Reg::RDX);
+            self.divr(rr);
+            self.movr(rr, rl);
+            self.movr(Reg::RDX, rr);
+            self.reserve_jump(Jump::Jmp, &mut top);
+            self.bind_label(&mut done);
+        }
+
+        // rl = rl ** rr by squaring, the way naive_eval's checked_pow goes:
+        // for each bit of rr from the bottom, multiply rl into RAX if it's
+        // set, then square rl if any bits are left. Where naive_eval is fatal
+        // (an overflow, 0 to a negative power, an exponent past u32) this
+        // returns 0 from the whole function. Clobbers RAX, RCX, RDX and rr
+        fn gen_pow(&mut self, rl: Reg, rr: Reg) {
+            let mut negative = Label::default();
+            let mut positive = Label::default();
+            let mut top = Label::default();
+            let mut even = Label::default();
+            let mut done = Label::default();
+            let mut zero = Label::default();
+            let mut fatal = Label::default();
+            let mut end = Label::default();
+
+            self.testr(rr, rr);
+            self.reserve_jump(Jump::Js, &mut negative);
+            self.movir(u32::MAX as i64, Reg::RDX);
+            self.cmpr(Reg::RDX, rr);
+            self.reserve_jump(Jump::Ja, &mut fatal);
+
+            self.bind_label(&mut positive);
+            self.movir(1, Reg::RAX);
+            self.testr(rr, rr);
+            self.reserve_jump(Jump::Je, &mut done);
+
+            self.bind_label(&mut top);
+            self.movr(rr, Reg::RCX);
+            self.andir(1, Reg::RCX);
+            self.reserve_jump(Jump::Je, &mut even);
+            self.imulr(rl, Reg::RAX);
+            self.reserve_jump(Jump::Jo, &mut fatal);
+            self.bind_label(&mut even);
+            self.shrir(1, rr);
+            self.reserve_jump(Jump::Je, &mut done);
+            self.imulr(rl, rl);
+            self.reserve_jump(Jump::Jo, &mut fatal);
+            self.reserve_jump(Jump::Jmp, &mut top);
+
+            self.bind_label(&mut done);
+            self.movr(Reg::RAX, rl);
+            self.reserve_jump(Jump::Jmp, &mut end);
+
+            // negative: 1 and -1 to the power of rr's parity, 0 is fatal and
+            // any other base rounds to 0
+            self.bind_label(&mut negative);
+            self.movr(rl, Reg::RAX);
+            self.incr(Reg::RAX);
+            self.cmpi(2, Reg::RAX);
+            self.reserve_jump(Jump::Ja, &mut zero);
+            self.testr(rl, rl);
+            self.reserve_jump(Jump::Je, &mut fatal);
+            self.andir(1, rr);
+            self.reserve_jump(Jump::Jmp, &mut positive);
+
+            self.bind_label(&mut zero);
+            self.xorr(rl, rl);
+            self.reserve_jump(Jump::Jmp, &mut end);
+
+            self.bind_label(&mut fatal);
+            self.xorr(Reg::RAX, Reg::RAX);
+            self.leave();
+            self.bind_label(&mut end);
+        }
+
+        // rax:rdx = dividend / rr as u64. Only /0 is fatal, there's no
+        // MIN/-1 to trap on
+        fn gen_udiv(&mut self, dividend: Reg, rr: Reg) {
+            let mut divide = Label::default();
+            self.testr(rr, rr);
+            self.reserve_jump(Jump::Jne, &mut divide);
+            self.zeror(Reg::RAX);
+            self.leave();
+            self.bind_label(&mut divide);
+            self.movr(dividend, Reg::RAX);
+            self.zeror(Reg::RDX);
+            self.divr(rr);
+        }
+
+        // rl = rl op rr for a node in Mode::Unsigned, clobbering RAX, RCX and RDX
+        fn gen_unsigned(&mut self, op: Operator, rl: Reg, rr: Reg) {
+            match op {
+                Operator::Lt => self.gen_compare(rl, rr, Jit::setbr),
+                Operator::Leq => self.gen_compare(rl, rr, Jit::setbe),
+                Operator::Gt => self.gen_compare(rl, rr, Jit::setar),
+                Operator::Geq => self.gen_compare(rl, rr, Jit::setaer),
+                Operator::Min => {
+                    self.cmpr(rr, rl);
+                    self.cmovar(rr, rl);
+                },
+                Operator::Max => {
+                    self.cmpr(rr, rl);
+                    self.cmovbr(rr, rl);
+                },
+                Operator::BitShr => {
+                    self.movr(rr, Reg::RCX);
+                    self.shrc(rl);
+                },
+                Operator::Div => {
+                    self.gen_udiv(rl, rr);
+                    self.movr(Reg::RAX, rl);
+                },
+                Operator::Mod => {
+                    self.gen_udiv(rl, rr);
+                    self.movr(Reg::RDX, rl);
+                },
+                _ => unreachable!("{:?} can't be unsigned", op),
+            }
+        }
+
+        // Turn idiv's truncated rax:rdx into the Euclidean quotient and
+        // remainder: a negative remainder gains |rr|, and the quotient
+        // steps one toward -rr's sign to match
+        fn gen_euclid(&mut self, rr: Reg) {
+            let mut done = Label::default();
+            let mut negative = Label::default();
+            self.testr(Reg::RDX, Reg::RDX);
+            self.reserve_jump(Jump::Jns, &mut done);
+            self.testr(rr, rr);
+            self.reserve_jump(Jump::Js, &mut negative);
+            self.addr(rr, Reg::RDX);
+            self.decr(Reg::RAX);
+            self.reserve_jump(Jump::Jmp, &mut done);
+            self.bind_label(&mut negative);
+            self.subr(rr, Reg::RDX);
+            self.incr(Reg::RAX);
+            self.bind_label(&mut done);
+        }
+
+        // x/0 (jumps already reserved to `fatal`) and MIN/-1 return 0 from the
+        // whole function without touching any variable, like naive_eval
+        fn gen_div_fatal(&mut self, dividend: Reg, rr: Reg, fatal: &mut Label) {
+            let mut divide = Label::default();
+            self.movir(i64::MIN, Reg::RDX);
+            self.cmpr(Reg::RDX, dividend);
+            self.reserve_jump(Jump::Jne, &mut divide);
+            self.cmpi(-1, rr);
+            self.reserve_jump(Jump::Jne, &mut divide);
+            self.bind_label(fatal);
+            self.xorr(Reg::RAX, Reg::RAX);
+            self.leave();
+            self.bind_label(&mut divide);
+        }
+
+        // rl = rl op rr for a saturating Add, Sub or Mul, clobbering RAX and RCX.
+        // The bound is MAX, or MIN (MAX + 1) when the true result is negative:
+        // an overflowing sum or difference has rl's sign, a product rl^rr's.
+        fn gen_saturating(&mut self, op: Operator, rl: Reg, rr: Reg) {
+            self.movr(rl, Reg::RCX);
+            if matches!(op, Operator::Mul | Operator::MulEq) {
+                self.xorr(rr, Reg::RCX);
+            }
+            self.shrir(63, Reg::RCX);
+            self.movir(NumT::MAX, Reg::RAX);
+            self.addr(Reg::RCX, Reg::RAX);
+            match op {
+                Operator::Add | Operator::AddEq => self.addr(rr, rl),
+                Operator::Sub | Operator::SubEq => self.subr(rr, rl),
+                _ => self.imulr(rr, rl),
+            }
+            let mut fits = Label::default();
+            self.reserve_jump(Jump::Jno, &mut fits);
+            self.movr(Reg::RAX, rl);
+            self.bind_label(&mut fits);
+        }
+
+        pub fn compile(&mut self, e: &Expr) {
+            let code = ir::lower(e);
+            let alloc = regalloc::allocate(&code, FREE_REGS.len());
+            self.near_jumps = false;
+            self.emit_function(&code, &alloc);
+            if self.near_jumps {
+                // A forward branch outgrew rel8, start over with rel32 ones
+                self.emit_function(&code, &alloc);
+            }
+            self.lowered = code;
+        }
+
+        // A function that spills keeps RBX and R12 at the bottom of its
+        // frame and uses them for spilled operands, its slots sit above
+        fn emit_function(&mut self, code: &[IrInst], alloc: &Allocation) {
+            self.offset = 0;
+            self.frame = if alloc.slots > 0 { 8 * (alloc.slots + 2) } else { 0 };
+            if self.frame > 0 {
+                self.subspi(self.frame as i32);
+                self.movrs(Reg::RBX, 0);
+                self.movrs(Reg::R12, 8);
+            }
+            let loc = |v: usize| alloc.locs[v];
+            let mut labels: Vec<Label> = (0..ir::label_count(code)).map(|_| Label::default()).collect();
+            for inst in code {
+                match *inst {
+                    IrInst::Const { dst, value } => {
+                        let rd = Self::target(loc(dst));
+                        self.movir(value, rd);
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::Load { dst, var } => {
+                        let rd = Self::target(loc(dst));
+                        self.movmr(var_ptr(var), rd);
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::Store { var, src } => {
+                        let rs = self.operand(loc(src), Reg::RBX);
+                        self.movrm(rs, var_ptr(var));
+                    }
+                    IrInst::BinOp { op, mode, dst, lhs, rhs } => {
+                        let rd = Self::target(loc(dst));
+                        let rl = self.operand(loc(lhs), rd);
+                        let rr = self.operand(loc(rhs), Reg::R12);
+                        self.gen_binop(op, mode, rd, rl, rr);
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::UnOp { op, dst, src } => {
+                        let rd = Self::target(loc(dst));
+                        let rs = self.operand(loc(src), rd);
+                        self.gen_unop(op, rd, rs);
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::Copy { dst, src } => {
+                        let rd = Self::target(loc(dst));
+                        let rs = self.operand(loc(src), rd);
+                        if rs != rd {
+                            self.movr(rs, rd);
+                        }
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::Branch { cond, target } => {
+                        let rc = self.operand(loc(cond), Reg::RBX);
+                        self.testr(rc, rc);
+                        self.reserve_jump(Jump::Je, &mut labels[target]);
+                    }
+                    IrInst::Jump { target } => self.reserve_jump(Jump::Jmp, &mut labels[target]),
+                    IrInst::Label(label) => self.bind_label(&mut labels[label]),
+                    IrInst::Fatal => {
+                        // Writing to a non-variable is fatal: return 0, nothing written
+                        self.xorr(Reg::RAX, Reg::RAX);
+                        self.leave();
+                    }
+                    IrInst::Return { src } => {
+                        let rs = self.operand(loc(src), Reg::RBX);
+                        self.movr(rs, Reg::RAX);
+                        self.leave();
                     }
                 }
-                return;
             }
+        }
 
-            self.gen_expr(e.left.as_ref().unwrap());
-            self.gen_expr(e.right.as_ref().unwrap());
-            
-            self.regind -= 1;
-            let rr = FREE_REGS[self.regind];
-            let rl = FREE_REGS[self.regind - 1];
+        // The register a value at `loc` is computed in: its own, or RBX to
+        // be spilled from
+        fn target(loc: Loc) -> Reg {
+            match loc {
+                Loc::Reg(i) => FREE_REGS[i],
+                Loc::Slot(_) => Reg::RBX,
+            }
+        }
 
-            match e.op {
+        // The register holding the value at `loc`, loaded into `scratch` if it's spilled
+        fn operand(&mut self, loc: Loc, scratch: Reg) -> Reg {
+            match loc {
+                Loc::Reg(i) => FREE_REGS[i],
+                Loc::Slot(s) => {
+                    self.movsr(slot_disp(s), scratch);
+                    scratch
+                }
+            }
+        }
+
+        // Write r back to `loc` if that's a stack slot
+        fn spill(&mut self, loc: Loc, r: Reg) {
+            if let Loc::Slot(s) = loc {
+                self.movrs(r, slot_disp(s));
+            }
+        }
+
+        // rd = op rs, where PreInc and PreDec add and subtract 1
+        fn gen_unop(&mut self, op: Operator, rd: Reg, rs: Reg) {
+            if rd != rs {
+                self.movr(rs, rd);
+            }
+            match op {
+                Operator::Neg => self.negr(rd),
+                Operator::BitNot => self.notr(rd),
+                Operator::Not => {
+                    self.zeror(Reg::RAX);
+                    self.testr(rd, rd);
+                    self.seter(Reg::RAX);
+                    self.movr(Reg::RAX, rd);
+                },
+                Operator::PopCount if self.popcnt => self.popcntr(rd, rd),
+                Operator::PopCount => self.gen_popcount(rd),
+                Operator::PreInc => self.incr(rd),
+                Operator::PreDec => self.decr(rd),
+                _ => unreachable!("{:?} isn't a unary IR op", op),
+            }
+        }
+
+        // rd = rl op rr. rd may be rl but not rr, which some ops clobber
+        fn gen_binop(&mut self, op: Operator, mode: Mode, rd: Reg, rl: Reg, rr: Reg) {
+            if rd != rl {
+                debug_assert_ne!(rd, rr);
+                self.movr(rl, rd);
+            }
+            let rl = rd;
+            match op {
                 Operator::Or => {
                     // rl || rr -> (rl | rr) != 0
                     self.orr(rr, rl);           // rl |= rr
-                    self.xorr(Reg::RAX, Reg::RAX); // zero rax
+                    self.zeror(Reg::RAX);       // zero rax
                     self.testr(rl, rl);         // test result
                     self.setner(Reg::RAX);      // al = (result != 0)
                     self.movr(Reg::RAX, rl);    // rl = rax (0 or 1)
                 },
                 Operator::And => {
                     // rl && rr -> (rl != 0) && (rr != 0)
-                    // Byte layout:
-                    // 0-2: testr rl, rl (3 bytes)
-                    // 3-4: jz +13 (2 bytes) -> to xor at byte 18
-                    // 5-7: testr rr, rr (3 bytes)
-                    // 8-9: jz +8 (2 bytes) -> to xor at byte 18
-                    // 10-15: movir 1, rl (6 bytes for R8-R15)
-                    // 16-17: jmp +3 (2 bytes) -> skip xor
-                    // 18-20: xor rl, rl (3 bytes)
+                    let mut zero = Label::default();
+                    let mut done = Label::default();
                     self.testr(rl, rl);
-                    self.jes(13);
+                    self.reserve_jump(Jump::Je, &mut zero);
                     self.testr(rr, rr);
-                    self.jes(8);
+                    self.reserve_jump(Jump::Je, &mut zero);
                     self.movir(1, rl);
-                    self.emit_u8(0xEB); self.emit_u8(3);
+                    self.reserve_jump(Jump::Jmp, &mut done);
+                    self.bind_label(&mut zero);
                     self.xorr(rl, rl);
+                    self.bind_label(&mut done);
                 },
                 Operator::BitOr => self.orr(rr, rl),
                 Operator::BitXor => self.xorr(rr, rl),
                 Operator::BitAnd => self.andr(rr, rl),
-                Operator::Eq => {
-                    self.cmpr(rr, rl);
-                    self.seter(rl);
-                    self.andir(1, rl);
-                },
-                Operator::Neq => {
-                    self.cmpr(rr, rl);
-                    self.setner(rl);
-                    self.andir(1, rl);
-                },
-                Operator::Lt => {
+                _ if mode == Mode::Unsigned => self.gen_unsigned(op, rl, rr),
+                Operator::Eq => self.gen_compare(rl, rr, Jit::seter),
+                Operator::Neq => self.gen_compare(rl, rr, Jit::setner),
+                Operator::Lt => self.gen_compare(rl, rr, Jit::setlr),
+                Operator::Gt => self.gen_compare(rl, rr, Jit::setgr),
+                Operator::Leq => self.gen_compare(rl, rr, Jit::setler),
+                Operator::Geq => self.gen_compare(rl, rr, Jit::setger),
+                Operator::Min => {
+                    // rl = rl > rr ? rr : rl
                     self.cmpr(rr, rl);
-                    self.setlr(rl);
-                    self.andir(1, rl);
+                    self.cmovgr(rr, rl);
                 },
-                Operator::Gt => {
+                Operator::Max => {
+                    // rl = rl < rr ? rr : rl
                     self.cmpr(rr, rl);
-                    self.setgr(rl);
-                    self.andir(1, rl);
-                },
-                Operator::Leq => {
-                    self.cmpr(rr, rl);
-                    self.setler(rl);
-                    self.andir(1, rl);
-                },
-                Operator::Geq => {
-                    self.cmpr(rr, rl);
-                    self.setger(rl);
-                    self.andir(1, rl);
+                    self.cmovlr(rr, rl);
                 },
                 Operator::BitShl => {
                     self.movr(rr, Reg::RCX);
@@ -660,76 +1329,38 @@ mod x86_64 {
                     self.movr(rr, Reg::RCX);
                     self.sarc(rl);
                 },
+                Operator::LShr => {
+                    self.movr(rr, Reg::RCX);
+                    self.shrc(rl);
+                },
+                _ if mode == Mode::Saturating => self.gen_saturating(op, rl, rr),
                 Operator::Add => self.addr(rr, rl),
                 Operator::Sub => self.subr(rr, rl),
                 Operator::Mul => self.imulr(rr, rl),
                 Operator::Div => {
-                    self.testr(rr, rr);
-                    self.jes(21);
-                    self.movir(i64::MIN, Reg::RDX);
-                    self.cmpr(Reg::RDX, rl);
-                    self.jnes(10);
-                    self.cmpi(-1, rr);
-                    self.jnes(4);
-                    self.xorr(Reg::RAX, Reg::RAX);
-                    self.ret();
-                    self.movr(rl, Reg::RAX);
-                    self.cqto();
-                    self.idivr(rr);
+                    self.gen_div(rl, rr, mode == Mode::Euclidean);
                     self.movr(Reg::RAX, rl);
                 },
                 Operator::Mod => {
-                    self.testr(rr, rr);
-                    self.jes(21);
-                    self.movir(i64::MIN, Reg::RDX);
-                    self.cmpr(Reg::RDX, rl);
-                    self.jnes(10);
-                    self.cmpi(-1, rr);
-                    self.jnes(4);
-                    self.xorr(Reg::RAX, Reg::RAX);
-                    self.ret();
-                    self.movr(rl, Reg::RAX);
-                    self.cqto();
-                    self.idivr(rr);
+                    self.gen_div(rl, rr, mode == Mode::Euclidean);
                     self.movr(Reg::RDX, rl);
                 },
-                Operator::Pow => {
-                  
-                    // test rr, rr (3 bytes)
-                    self.testr(rr, rr);
-                    // js negative: +24 bytes from here to xor (3+5+3+2+4+3+2+3+2=27, we're at 5, target at 29, so +24)
-                    self.emit_u8(0x78); self.emit_u8(24);
-                    
-                    // mov rax, 1 (5 bytes) - result accumulator
-                    self.movir(1, Reg::RAX);
-                    
-                    // test rr, rr (3 bytes)
-                    self.testr(rr, rr);
-                    // jz done: +9 bytes (from offset 15 to mov at 24)
-                    self.jes(9);
-                    
-                    // loop: imul rax, rl (4 bytes)
-                    self.imulr(rl, Reg::RAX);
-                    // dec rr (3 bytes)
-                    self.rex(1, Reg::RAX, 0, rr);
-                    self.emit_u8(0xFF);
-                    self.emit_u8(0xC8 | (rr as u8 & 7));
-                    // jnz loop: -9 bytes (from 24 back to 15)
-                    self.jnes(-9i8);
-                    
-                    // done: mov rl, rax (3 bytes)
-                    self.movr(Reg::RAX, rl);
-                    // jmp end: +3 bytes to skip xor
-                    self.emit_u8(0xEB); self.emit_u8(3);
-                    
-                    // negative: xor rl, rl (3 bytes) - return 0
-                    self.xorr(rl, rl);
-                    // end:
-                },
-                _ => {}
+                Operator::Gcd => self.gen_gcd(rl, rr),
+                Operator::Pow => self.gen_pow(rl, rr),
+                _ => unreachable!("{:?} isn't a binary IR op", op),
             }
         }
     }
+
+    // The register holding the address of `var`
+    fn var_ptr(var: Operator) -> Reg {
+        CALL_REGS[(var as usize) & 0xF]
+    }
+
+    // Where stack slot `s` is from RSP, above the saved RBX and R12
+    fn slot_disp(s: usize) -> i32 {
+        i32::try_from(16 + 8 * s).expect("too many spill slots")
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -746,6 +1377,7 @@ mod aarch64 {
         X24=24, X25=25, X26=26, X27=27, X28=28, FP=29, LR=30, XZR=31
     }
 
+    // X18 is the platform register on Apple arm64, so no code here writes it
     const CALL_REGS: [Reg; 2] = [Reg::X0, Reg::X1]; // Only need pointers for var indices 0 and 1
     const FREE_REGS: [Reg; 7] = [Reg::X9, Reg::X10, Reg::X11, Reg::X12, Reg::X13, Reg::X14, Reg::X15];
 
@@ -761,12 +1393,44 @@ mod aarch64 {
             self.inst(0xD65F03C0);
         }
 
+        // Return, first freeing the frame if the function spills
+        fn leave(&mut self) {
+            if self.frame > 0 {
+                self.move_sp(self.frame, false);
+            }
+            self.ret();
+        }
+
+        // SUB SP, SP, #bytes if `down`, else ADD, as a shifted immediate for
+        // bits 12-23 and a plain one for bits 0-11
+        fn move_sp(&mut self, bytes: usize, down: bool) {
+            assert!(bytes < 1 << 24, "stack frame too large");
+            let base = if down { 0xD10003FF } else { 0x910003FF };
+            for (imm, shift) in [(bytes >> 12, 1 << 22), (bytes & 0xFFF, 0)] {
+                if imm > 0 {
+                    self.inst(base | shift | ((imm as u32) << 10));
+                }
+            }
+        }
+
+        // LDR Xt, [SP, #8*slot]
+        fn ldr_slot(&mut self, rt: Reg, slot: usize) {
+            assert!(slot < 4096, "too many spill slots");
+            self.inst(0xF94003E0 | ((slot as u32) << 10) | (rt as u32));
+        }
+
+        // STR Xt, [SP, #8*slot]
+        fn str_slot(&mut self, rt: Reg, slot: usize) {
+            assert!(slot < 4096, "too many spill slots");
+            self.inst(0xF90003E0 | ((slot as u32) << 10) | (rt as u32));
+        }
+
         // MOV immediate (handle wide)
         // Simple movz/movn logic
         fn mov_imm(&mut self, dest: Reg, imm: i64) {
             let rd = dest as u32;
             // If positive and fits in 16 bits
-            if imm >= 0 && imm <= 0xFFFF {
+            if (0..=0xFFFF).contains(&imm) {
                 self.inst(0xD2800000 | ((imm as u32) << 5) | rd); // MOVZ
                 return;
             }
@@ -811,6 +1475,71 @@ mod aarch64 {
             self.inst(0xCB000000 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
         }
         
+        // ADDS Rd, Rn, Rm (sets V on signed overflow)
+        fn adds(&mut self, rd: Reg, rn: Reg, rm: Reg) {
+            self.inst(0xAB000000 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // SUBS Rd, Rn, Rm (sets V on signed overflow)
+        fn subs(&mut self, rd: Reg, rn: Reg, rm: Reg) {
+            self.inst(0xEB000000 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // ADDS Rd, Rn, #imm12 (shift 0)
+        pub(super) fn adds_imm(&mut self, rd: Reg, rn: Reg, imm: u32) {
+            assert!(imm <= 0xFFF, "ADDS immediate {} doesn't fit in 12 bits", imm);
+            self.inst(0xB1000000 | (imm << 10) | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // SUBS Rd, Rn, #imm12 (shift 0)
+        pub(super) fn subs_imm(&mut self, rd: Reg, rn: Reg, imm: u32) {
+            assert!(imm <= 0xFFF, "SUBS immediate {} doesn't fit in 12 bits", imm);
+            self.inst(0xF1000000 | (imm << 10) | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // SMULH Rd, Rn, Rm: high 64 bits of the 128-bit signed product
+        fn smulh(&mut self, rd: Reg, rn: Reg, rm: Reg) {
+            self.inst(0x9B407C00 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // MUL Rd, Rn, Rm setting V when the product overflows, like adds and
+        // subs do, clobbering X16. There's no flag-setting multiply: the
+        // product fits iff its high half is the low half's sign, and CCMP
+        // turns the NE of that check into V.
+        fn muls(&mut self, rd: Reg, rn: Reg, rm: Reg) {
+            self.smulh(Reg::X16, rn, rm);
+            self.mul(rd, rn, rm);
+            // CMP X16, Rd, ASR #63
+            self.inst(0xEB80FC1F | ((rd as u32) << 16) | ((Reg::X16 as u32) << 5));
+            // CCMP XZR, XZR, #0 (nzcv = V), EQ
+            self.inst(0xFA5F03E1);
+        }
+
+        // ASR Rd, Rn, #63 (SBFM Rd, Rn, #63, #63): 0 or -1 by Rn's sign
+        fn asr63(&mut self, rd: Reg, rn: Reg) {
+            self.inst(0x937FFC00 | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // TST Rn, #1
+        pub(super) fn tst1(&mut self, rn: Reg) {
+            self.inst(0xF240001F | ((rn as u32) << 5));
+        }
+
+        // AND Rd, Rn, #1
+        pub(super) fn and1(&mut self, rd: Reg, rn: Reg) {
+            self.inst(0x92400000 | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // LSR Rd, Rn, #1 (UBFM Rd, Rn, #1, #63)
+        pub(super) fn lsr1(&mut self, rd: Reg, rn: Reg) {
+            self.inst(0xD341FC00 | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // EOR Rd, Rn, #0x7FFFFFFFFFFFFFFF
+        fn eor_max(&mut self, rd: Reg, rn: Reg) {
+            self.inst(0xD240F800 | ((rn as u32) << 5) | (rd as u32));
+        }
+
         // MUL Rd, Rn, Rm
         fn mul(&mut self, rd: Reg, rn: Reg, rm: Reg) {
             self.inst(0x9B007C00 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
@@ -821,6 +1550,11 @@ mod aarch64 {
             self.inst(0x9AC00C00 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
         }
 
+        // UDIV Rd, Rn, Rm
+        fn udiv(&mut self, rd: Reg, rn: Reg, rm: Reg) {
+            self.inst(0x9AC00800 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
+        }
+
         // AND Rd, Rn, Rm
         fn and(&mut self, rd: Reg, rn: Reg, rm: Reg) {
             self.inst(0x8A000000 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
@@ -857,16 +1591,39 @@ mod aarch64 {
              self.inst(0x9AC02800 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
         }
 
+        // LSRV Rd, Rn, Rm (Logical Shift Right Variable)
+        fn lsrv(&mut self, rd: Reg, rn: Reg, rm: Reg) {
+             self.inst(0x9AC02400 | ((rm as u32) << 16) | ((rn as u32) << 5) | (rd as u32));
+        }
+
+        // Rd = number of set bits in Rn, through V0: FMOV D0, Rn; CNT V0.8B, V0.8B
+        // counts each byte; ADDV B0, V0.8B sums them; FMOV Rd, D0. NEON is
+        // always there on AArch64, so there's no fallback
+        pub(super) fn popcount(&mut self, rd: Reg, rn: Reg) {
+            self.inst(0x9E670000 | ((rn as u32) << 5));
+            self.inst(0x0E205800);
+            self.inst(0x0E31B800);
+            self.inst(0x9E660000 | (rd as u32));
+        }
+
         // CMP Rn, Rm (SUBS XZR, Rn, Rm)
         fn cmp(&mut self, rn: Reg, rm: Reg) {
             self.inst(0xEB00001F | ((rm as u32) << 16) | ((rn as u32) << 5));
         }
         
-        // CMP Rn, #imm (SUBS XZR, Rn, #imm) - simplified, supports imm12
-        fn cmpi(&mut self, rn: Reg, imm: u16) {
-            // SUBS XZR, Rn, #imm (shift 0)
-            // 111 10001 00 imm12 Rn 11111
-            self.inst(0xF100001F | ((imm as u32) << 10) | ((rn as u32) << 5));
+        // CMP Rn, #imm. The immediate field is only 12 bits, so a negative
+        // one down to -4095 goes through CMN, and anything else is moved
+        // into X17 (clobbering it) and compared as a register
+        pub(super) fn cmpi(&mut self, rn: Reg, imm: i64) {
+            if (0..=0xFFF).contains(&imm) {
+                self.subs_imm(Reg::XZR, rn, imm as u32);
+            } else if (-0xFFF..0).contains(&imm) {
+                // CMN Rn, #-imm
+                self.adds_imm(Reg::XZR, rn, -imm as u32);
+            } else {
+                self.mov_imm(Reg::X17, imm);
+                self.cmp(rn, Reg::X17);
+            }
         }
 
         // CSET Rd, cond (CSINC Rd, XZR, XZR, cond_inv)
@@ -896,6 +1653,77 @@ mod aarch64 {
              let imm26 = (offset_instrs & 0x3FFFFFF) as u32;
              self.inst(0x14000000 | imm26);
         }
+
+        fn b_eq(&mut self, offset_instrs: i32) {
+            self.b_cond(0, offset_instrs);
+        }
+
+        fn b_ne(&mut self, offset_instrs: i32) {
+            self.b_cond(1, offset_instrs);
+        }
+
+        #[allow(dead_code)]
+        fn b_gt(&mut self, offset_instrs: i32) {
+            self.b_cond(12, offset_instrs);
+        }
+
+        fn b_ge(&mut self, offset_instrs: i32) {
+            self.b_cond(10, offset_instrs);
+        }
+
+        fn b_lt(&mut self, offset_instrs: i32) {
+            self.b_cond(11, offset_instrs);
+        }
+
+        fn b_hi(&mut self, offset_instrs: i32) {
+            self.b_cond(8, offset_instrs);
+        }
+
+        // Signed overflow, as left by adds, subs or muls
+        #[allow(dead_code)]
+        fn b_vs(&mut self, offset_instrs: i32) {
+            self.b_cond(6, offset_instrs);
+        }
+
+        fn b_vc(&mut self, offset_instrs: i32) {
+            self.b_cond(7, offset_instrs);
+        }
+
+        // Point the B or B.cond emitted (with offset 0) at `at` to the current offset
+        fn patch_branch(&mut self, at: usize) {
+            let delta = ((self.offset - at) / 4) as u32;
+            unsafe {
+                let p = self.buf.as_mut_ptr().add(at) as *mut u32;
+                let inst = p.read_unaligned();
+                let inst = if inst & 0xFC000000 == 0x14000000 {
+                    inst | (delta & 0x3FFFFFF)
+                } else {
+                    inst | ((delta & 0x7FFFF) << 5)
+                };
+                p.write_unaligned(inst);
+            }
+        }
+
+        // Emit `branch` (B or one of the B.cond wrappers above) to `label`
+        fn reserve_jump(&mut self, branch: fn(&mut Jit, i32), label: &mut Label) {
+            match label.bound {
+                Some(target) => {
+                    let delta = (target as isize - self.offset as isize) / 4;
+                    branch(self, delta as i32);
+                }
+                None => {
+                    label.fixups.push(self.offset);
+                    branch(self, 0);
+                }
+            }
+        }
+
+        fn bind_label(&mut self, label: &mut Label) {
+            label.bound = Some(self.offset);
+            for at in label.fixups.drain(..) {
+                self.patch_branch(at);
+            }
+        }
         
         // Mov 0 to register (alias MOV Rd, XZR) -> ORR Rd, XZR, XZR
         fn mov0(&mut self, rd: Reg) {
@@ -908,150 +1736,328 @@ mod aarch64 {
             self.inst(0x9A800000 | ((rm as u32) << 16) | (cond << 12) | ((rn as u32) << 5) | (rd as u32));
         }
 
-        pub fn compile(&mut self, e: &Expr) {
-            self.regind = 0;
-            self.gen_expr(e);
-            // Move result to X0 (RAX equiv)
-            self.orr(Reg::X0, FREE_REGS[self.regind - 1], Reg::XZR); // MOV X0, Res
-            self.ret();
+        // rd = rd op rm for a saturating Add, Sub or Mul, clobbering X16 and
+        // X17. X17 is the bound: MAX, or MIN when the true result
+        // is negative; an overflowing sum or difference has rd's sign, a product rd^rm's.
+        fn gen_saturating(&mut self, op: Operator, rd: Reg, rm: Reg) {
+            let is_mul = matches!(op, Operator::Mul | Operator::MulEq);
+            if is_mul {
+                self.eor(Reg::X17, rd, rm);
+                self.asr63(Reg::X17, Reg::X17);
+            } else {
+                self.asr63(Reg::X17, rd);
+            }
+            self.eor_max(Reg::X17, Reg::X17);
+            match op {
+                Operator::Add | Operator::AddEq => self.adds(rd, rd, rm),
+                Operator::Sub | Operator::SubEq => self.subs(rd, rd, rm),
+                _ => self.muls(rd, rd, rm),
+            }
+            let mut fits = Label::default();
+            self.reserve_jump(Jit::b_vc, &mut fits);
+            self.orr(rd, Reg::X17, Reg::XZR); // MOV Rd, X17
+            self.bind_label(&mut fits);
+        }
+
+        // Turn a truncated quotient q and remainder r of a division by rm into
+        // the Euclidean ones: a negative r gains |rm|, and q steps one toward
+        // -rm's sign to match. Clobbers X2, which the function's two pointers leave free.
+        fn gen_euclid(&mut self, q: Reg, r: Reg, rm: Reg) {
+            let mut done = Label::default();
+            let mut negative = Label::default();
+            self.cmp(r, Reg::XZR);
+            self.reserve_jump(Jit::b_ge, &mut done);
+            self.mov_imm(Reg::X2, 1);
+            self.cmp(rm, Reg::XZR);
+            self.reserve_jump(Jit::b_lt, &mut negative);
+            self.add(r, r, rm);
+            self.sub(q, q, Reg::X2);
+            self.reserve_jump(Jit::b, &mut done);
+            self.bind_label(&mut negative);
+            self.sub(r, r, rm);
+            self.add(q, q, Reg::X2);
+            self.bind_label(&mut done);
+        }
+
+        // X17 = rn / rm and X16 = rn % rm as u64. /0 returns 0 like every
+        // other fatal path
+        fn gen_udiv(&mut self, rn: Reg, rm: Reg) {
+            let mut div = Label::default();
+            self.cmp(rm, Reg::XZR);
+            self.reserve_jump(Jit::b_ne, &mut div);
+            self.mov0(Reg::X0);
+            self.leave();
+            self.bind_label(&mut div);
+            self.udiv(Reg::X17, rn, rm);
+            self.mul(Reg::X16, Reg::X17, rm);
+            self.sub(Reg::X16, rn, Reg::X16);
+        }
+
+        fn gen_abs(&mut self, r: Reg) {
+            self.neg(Reg::X17, r);
+            self.cmp(r, Reg::XZR);
+            self.csel(r, Reg::X17, r, 11); // LT ? -r : r
+        }
+
+        // rl = gcd(|rl|, |rr|) by Euclid's loop, dividing as u64 so that
+        // |i64::MIN| works. Clobbers rr, X16 and X17
+        fn gen_gcd(&mut self, rl: Reg, rr: Reg) {
+            let mut top = Label::default();
+            let mut done = Label::default();
+            self.gen_abs(rl);
+            self.gen_abs(rr);
+            self.bind_label(&mut top);
+            self.cmp(rr, Reg::XZR);
+            self.reserve_jump(Jit::b_eq, &mut done);
+            self.udiv(Reg::X17, rl, rr);
+            self.mul(Reg::X16, Reg::X17, rr);
+            self.sub(Reg::X16, rl, Reg::X16);
+            self.orr(rl, rr, Reg::XZR);
+            self.orr(rr, Reg::X16, Reg::XZR);
+            self.reserve_jump(Jit::b, &mut top);
+            self.bind_label(&mut done);
+        }
+
+        // rl = rl ** rr by squaring, as on x86_64: X17 gathers rl for each
+        // set bit of rr from the bottom, and rl is squared while bits are
+        // left. Where naive_eval is fatal (an overflow, 0 to a negative power,
+        // an exponent past u32) this returns 0 from the whole function.
+        // Clobbers rr, X16 and X17
+        fn gen_pow(&mut self, rl: Reg, rr: Reg) {
+            let mut negative = Label::default();
+            let mut positive = Label::default();
+            let mut top = Label::default();
+            let mut even = Label::default();
+            let mut done = Label::default();
+            let mut zero = Label::default();
+            let mut fatal = Label::default();
+            let mut end = Label::default();
+
+            self.cmp(rr, Reg::XZR);
+            self.reserve_jump(Jit::b_lt, &mut negative);
+            self.cmpi(rr, u32::MAX as i64);
+            self.reserve_jump(Jit::b_hi, &mut fatal);
+
+            self.bind_label(&mut positive);
+            self.mov_imm(Reg::X17, 1);
+            self.cmp(rr, Reg::XZR);
+            self.reserve_jump(Jit::b_eq, &mut done);
+
+            self.bind_label(&mut top);
+            self.tst1(rr);
+            self.reserve_jump(Jit::b_eq, &mut even);
+            self.muls(Reg::X17, Reg::X17, rl);
+            self.reserve_jump(Jit::b_vs, &mut fatal);
+            self.bind_label(&mut even);
+            self.lsr1(rr, rr);
+            self.cmp(rr, Reg::XZR);
+            self.reserve_jump(Jit::b_eq, &mut done);
+            self.muls(rl, rl, rl);
+            self.reserve_jump(Jit::b_vs, &mut fatal);
+            self.reserve_jump(Jit::b, &mut top);
+
+            self.bind_label(&mut done);
+            self.orr(rl, Reg::X17, Reg::XZR);
+            self.reserve_jump(Jit::b, &mut end);
+
+            // negative: 1 and -1 to the power of rr's parity, 0 is fatal and
+            // any other base rounds to 0
+            self.bind_label(&mut negative);
+            self.adds_imm(Reg::X17, rl, 1);
+            self.cmpi(Reg::X17, 2);
+            self.reserve_jump(Jit::b_hi, &mut zero);
+            self.cmp(rl, Reg::XZR);
+            self.reserve_jump(Jit::b_eq, &mut fatal);
+            self.and1(rr, rr);
+            self.reserve_jump(Jit::b, &mut positive);
+
+            self.bind_label(&mut zero);
+            self.mov0(rl);
+            self.reserve_jump(Jit::b, &mut end);
+
+            self.bind_label(&mut fatal);
+            self.mov0(Reg::X0);
+            self.leave();
+            self.bind_label(&mut end);
+        }
+
+        // rl = rl op rr for a node in Mode::Unsigned
+        fn gen_unsigned(&mut self, op: Operator, rl: Reg, rr: Reg) {
+            match op {
+                Operator::Lt => { self.cmp(rl, rr); self.cset(rl, 3); }, // LO
+                Operator::Gt => { self.cmp(rl, rr); self.cset(rl, 8); }, // HI
+                Operator::Leq => { self.cmp(rl, rr); self.cset(rl, 9); }, // LS
+                Operator::Geq => { self.cmp(rl, rr); self.cset(rl, 2); }, // HS
+                Operator::Min => { self.cmp(rl, rr); self.csel(rl, rl, rr, 3); }, // LO ? rl : rr
+                Operator::Max => { self.cmp(rl, rr); self.csel(rl, rl, rr, 8); }, // HI ? rl : rr
+                Operator::BitShr => self.lsrv(rl, rl, rr),
+                Operator::Div => { self.gen_udiv(rl, rr); self.orr(rl, Reg::X17, Reg::XZR); },
+                Operator::Mod => { self.gen_udiv(rl, rr); self.orr(rl, Reg::X16, Reg::XZR); },
+                _ => unreachable!("{:?} can't be unsigned", op),
+            }
         }
 
-        fn gen_expr(&mut self, e: &Expr) {
-             match e.op {
-                Operator::Literal => {
-                    self.mov_imm(FREE_REGS[self.regind], e.literal);
-                    self.regind += 1;
-                    return;
+        // rl = rl / rr or rl % rr, the Euclidean way if `euclid`. x/0 and
+        // MIN/-1 return 0 from the whole function, like naive_eval
+        fn gen_div(&mut self, op: Operator, rl: Reg, rr: Reg, euclid: bool) {
+            let mut fatal = Label::default();
+            let mut div = Label::default();
+            self.cmp(rr, Reg::XZR);
+            self.reserve_jump(Jit::b_eq, &mut fatal);
+            self.mov_imm(Reg::X17, -1);
+            self.cmp(rr, Reg::X17);
+            self.reserve_jump(Jit::b_ne, &mut div);
+            self.mov_imm(Reg::X17, i64::MIN);
+            self.cmp(rl, Reg::X17);
+            self.reserve_jump(Jit::b_ne, &mut div);
+            self.bind_label(&mut fatal);
+            self.mov0(Reg::X0);
+            self.leave();
+            self.bind_label(&mut div);
+
+            self.sdiv(Reg::X17, rl, rr);
+            if euclid {
+                self.mul(Reg::X16, Reg::X17, rr);
+                self.sub(Reg::X16, rl, Reg::X16);
+                self.gen_euclid(Reg::X17, Reg::X16, rr);
+                let result = if op == Operator::Mod { Reg::X16 } else { Reg::X17 };
+                self.orr(rl, result, Reg::XZR);
+            } else if op == Operator::Mod {
+                // a - (a/b)*b
+                self.mul(Reg::X16, Reg::X17, rr);
+                self.sub(rl, rl, Reg::X16);
+            } else {
+                self.orr(rl, Reg::X17, Reg::XZR);
+            }
+        }
+
+        pub fn compile(&mut self, e: &Expr) {
+            let code = ir::lower(e);
+            let alloc = regalloc::allocate(&code, FREE_REGS.len());
+            self.offset = 0;
+            // Slots sit from SP up; SP has to stay 16-byte aligned
+            self.frame = (8 * alloc.slots).next_multiple_of(16);
+            if self.frame > 0 {
+                self.move_sp(self.frame, true);
+            }
+            let loc = |v: usize| alloc.locs[v];
+            let mut labels: Vec<Label> = (0..ir::label_count(&code)).map(|_| Label::default()).collect();
+            for inst in &code {
+                match *inst {
+                    IrInst::Const { dst, value } => {
+                        let rd = Self::target(loc(dst));
+                        self.mov_imm(rd, value);
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::Load { dst, var } => {
+                        let rd = Self::target(loc(dst));
+                        self.ldr(rd, var_ptr(var));
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::Store { var, src } => {
+                        let rs = self.operand(loc(src), Reg::X3);
+                        self.str(rs, var_ptr(var));
+                    }
+                    IrInst::BinOp { op, mode, dst, lhs, rhs } => {
+                        let rd = Self::target(loc(dst));
+                        let rl = self.operand(loc(lhs), rd);
+                        let rr = self.operand(loc(rhs), Reg::X4);
+                        self.gen_binop(op, mode, rd, rl, rr);
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::UnOp { op, dst, src } => {
+                        let rd = Self::target(loc(dst));
+                        let rs = self.operand(loc(src), rd);
+                        self.gen_unop(op, rd, rs);
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::Copy { dst, src } => {
+                        let rd = Self::target(loc(dst));
+                        let rs = self.operand(loc(src), rd);
+                        if rs != rd {
+                            self.orr(rd, rs, Reg::XZR);
+                        }
+                        self.spill(loc(dst), rd);
+                    }
+                    IrInst::Branch { cond, target } => {
+                        let rc = self.operand(loc(cond), Reg::X3);
+                        self.cmp(rc, Reg::XZR);
+                        self.reserve_jump(Jit::b_eq, &mut labels[target]);
+                    }
+                    IrInst::Jump { target } => self.reserve_jump(Jit::b, &mut labels[target]),
+                    IrInst::Label(label) => self.bind_label(&mut labels[label]),
+                    IrInst::Fatal => {
+                        // Writing to a non-variable is fatal: return 0, nothing written
+                        self.mov0(Reg::X0);
+                        self.leave();
+                    }
+                    IrInst::Return { src } => {
+                        let rs = self.operand(loc(src), Reg::X3);
+                        self.orr(Reg::X0, rs, Reg::XZR); // MOV X0, Res
+                        self.leave();
+                    }
                 }
-                Operator::Var | Operator::VarY => {
-                    let reg_idx = (e.op as usize) & 0xF;
-                    // Load from pointer in CALL_REGS[idx]
-                    self.ldr(FREE_REGS[self.regind], CALL_REGS[reg_idx]);
-                    self.regind += 1;
-                    return;
+            }
+            self.lowered = code;
+        }
+
+        // The register a value at `loc` is computed in: its own, or X3 to
+        // be spilled from. X3 and X4 are free, the function only takes two pointers
+        fn target(loc: Loc) -> Reg {
+            match loc {
+                Loc::Reg(i) => FREE_REGS[i],
+                Loc::Slot(_) => Reg::X3,
+            }
+        }
+
+        // The register holding the value at `loc`, loaded into `scratch` if it's spilled
+        fn operand(&mut self, loc: Loc, scratch: Reg) -> Reg {
+            match loc {
+                Loc::Reg(i) => FREE_REGS[i],
+                Loc::Slot(s) => {
+                    self.ldr_slot(scratch, s);
+                    scratch
                 }
+            }
+        }
+
+        // Write r back to `loc` if that's a stack slot
+        fn spill(&mut self, loc: Loc, r: Reg) {
+            if let Loc::Slot(s) = loc {
+                self.str_slot(r, s);
+            }
+        }
+
+        // rd = op rs, where PreInc and PreDec add and subtract 1
+        fn gen_unop(&mut self, op: Operator, rd: Reg, rs: Reg) {
+            match op {
+                Operator::Neg => self.neg(rd, rs),
+                Operator::BitNot => self.mvn(rd, rs),
+                Operator::Not => {
+                     self.cmp(rs, Reg::XZR);
+                     self.cset(rd, 0); // EQ -> 1, else 0.
+                },
+                Operator::PopCount => self.popcount(rd, rs),
                 Operator::PreInc => {
-                     let target = e.right.as_ref().unwrap();
-                     let reg_idx = (target.op as usize) & 0xF;
-                     let ptr = CALL_REGS[reg_idx];
-                     let tmp = FREE_REGS[self.regind];
-                     self.ldr(tmp, ptr);
-                     self.mov_imm(Reg::X16, 1);
-                     self.add(tmp, tmp, Reg::X16);
-                     self.str(tmp, ptr);
-                     self.regind += 1;
-                     return;
-                }
+                    self.mov_imm(Reg::X16, 1);
+                    self.add(rd, rs, Reg::X16);
+                },
                 Operator::PreDec => {
-                     let target = e.right.as_ref().unwrap();
-                     let reg_idx = (target.op as usize) & 0xF;
-                     let ptr = CALL_REGS[reg_idx];
-                     let tmp = FREE_REGS[self.regind];
-                     self.ldr(tmp, ptr);
-                     self.mov_imm(Reg::X16, 1);
-                     self.sub(tmp, tmp, Reg::X16);
-                     self.str(tmp, ptr);
-                     self.regind += 1;
-                     return;
-                }
-                Operator::PostInc => {
-                     let target = e.right.as_ref().unwrap();
-                     let reg_idx = (target.op as usize) & 0xF;
-                     let ptr = CALL_REGS[reg_idx];
-                     let tmp = FREE_REGS[self.regind];
-                     self.ldr(tmp, ptr); // Load value
-                     // Increment memory
-                     let tmp2 = Reg::X16;
-                     self.mov_imm(tmp2, 1);
-                     self.add(tmp2, tmp, tmp2);
-                     self.str(tmp2, ptr);
-                     self.regind += 1;
-                     return;
-                }
-                Operator::PostDec => {
-                     let target = e.right.as_ref().unwrap();
-                     let reg_idx = (target.op as usize) & 0xF;
-                     let ptr = CALL_REGS[reg_idx];
-                     let tmp = FREE_REGS[self.regind];
-                     self.ldr(tmp, ptr);
-                     let tmp2 = Reg::X16;
-                     self.mov_imm(tmp2, 1);
-                     self.sub(tmp2, tmp, tmp2);
-                     self.str(tmp2, ptr);
-                     self.regind += 1;
-                     return;
-                }
-                _ => {}
-            }
-
-            if e.left.is_none() || e.is_assignment() {
-                self.gen_expr(e.right.as_ref().unwrap());
-                let rr = FREE_REGS[self.regind - 1];
-                
-                if let Some(ref left) = e.left {
-                     let target_idx = (left.op as usize) & 0xF;
-                     let ptr = CALL_REGS[target_idx];
-                     let tmp = Reg::X16; // Temp for loading target val
-                     
-                     match e.op {
-                        Operator::AssignEq => self.str(rr, ptr),
-                        Operator::BitOrEq => { self.ldr(tmp, ptr); self.orr(tmp, tmp, rr); self.str(tmp, ptr); self.orr(rr, tmp, Reg::XZR); }, // result is new value
-                        Operator::BitXorEq => { self.ldr(tmp, ptr); self.eor(tmp, tmp, rr); self.str(tmp, ptr); self.orr(rr, tmp, Reg::XZR); },
-                        Operator::BitAndEq => { self.ldr(tmp, ptr); self.and(tmp, tmp, rr); self.str(tmp, ptr); self.orr(rr, tmp, Reg::XZR); },
-                        Operator::BitShlEq => { self.ldr(tmp, ptr); self.lslv(tmp, tmp, rr); self.str(tmp, ptr); self.orr(rr, tmp, Reg::XZR); },
-                        Operator::BitShrEq => { self.ldr(tmp, ptr); self.asrv(tmp, tmp, rr); self.str(tmp, ptr); self.orr(rr, tmp, Reg::XZR); },
-                        Operator::AddEq => { self.ldr(tmp, ptr); self.add(tmp, tmp, rr); self.str(tmp, ptr); self.orr(rr, tmp, Reg::XZR); },
-                        Operator::SubEq => { self.ldr(tmp, ptr); self.sub(tmp, tmp, rr); self.str(tmp, ptr); self.orr(rr, tmp, Reg::XZR); },
-                        Operator::MulEq => { self.ldr(tmp, ptr); self.mul(tmp, tmp, rr); self.str(tmp, ptr); self.orr(rr, tmp, Reg::XZR); },
-                        Operator::DivEq | Operator::ModEq => {
-                            // if rr == 0: return 0 but do NOT modify target (matches naive_eval)
-                            self.cmp(rr, Reg::XZR);
-                            self.b_cond(1, 3); // NE -> skip EQ block (2 instrs)
-                            self.mov0(Reg::X0); // Return 0
-                            self.ret();
-                            
-                            // Overflow check? (min / -1)
-                            // For simplicity skip overflow check to save space/time or implement simple one
-                            
-                            self.ldr(tmp, ptr);
-                            self.sdiv(Reg::X17, tmp, rr); // X17 = result div
-                            
-                            if matches!(e.op, Operator::ModEq) {
-                                // Mod: a - (a/b)*b
-                                self.mul(Reg::X18, Reg::X17, rr);
-                                self.sub(tmp, tmp, Reg::X18); // tmp = mod result
-                            } else {
-                                self.orr(tmp, Reg::X17, Reg::XZR); // tmp = div result
-                            }
-                            self.str(tmp, ptr);
-                            self.orr(rr, tmp, Reg::XZR);
-                        },
-                        _ => {}
-                     }
-                } else {
-                    // Unary
-                    match e.op {
-                        Operator::Neg => self.neg(rr, rr),
-                        Operator::BitNot => self.mvn(rr, rr),
-                        Operator::Not => {
-                             self.cmp(rr, Reg::XZR);
-                             self.cset(rr, 0); // EQ -> 1, else 0.
-                        },
-                        Operator::Parens => {}, // Just pass through
-                        _ => {}
-                    }
-                }
-                return;
+                    self.mov_imm(Reg::X16, 1);
+                    self.sub(rd, rs, Reg::X16);
+                },
+                _ => unreachable!("{:?} isn't a unary IR op", op),
             }
+        }
 
-            self.gen_expr(e.left.as_ref().unwrap());
-            self.gen_expr(e.right.as_ref().unwrap());
-            
-            self.regind -= 1;
-            let rr = FREE_REGS[self.regind];
-            let rl = FREE_REGS[self.regind - 1];
-
-            match e.op {
+        // rd = rl op rr. rd may be rl but not rr, which some ops clobber
+        fn gen_binop(&mut self, op: Operator, mode: Mode, rd: Reg, rl: Reg, rr: Reg) {
+            if rd != rl {
+                debug_assert_ne!(rd, rr);
+                self.orr(rd, rl, Reg::XZR);
+            }
+            let rl = rd;
+            match op {
                 Operator::Or => {
                     // rl || rr -> simplified: just use bitwise or and check != 0
                     self.orr(rl, rl, rr);
@@ -1071,136 +2077,46 @@ mod aarch64 {
                 Operator::BitOr => self.orr(rl, rl, rr),
                 Operator::BitXor => self.eor(rl, rl, rr),
                 Operator::BitAnd => self.and(rl, rl, rr),
+                _ if mode == Mode::Unsigned => self.gen_unsigned(op, rl, rr),
                 Operator::Eq => { self.cmp(rl, rr); self.cset(rl, 0); }, // EQ
                 Operator::Neq => { self.cmp(rl, rr); self.cset(rl, 1); }, // NE
                 Operator::Lt => { self.cmp(rl, rr); self.cset(rl, 11); }, // LT
                 Operator::Gt => { self.cmp(rl, rr); self.cset(rl, 12); }, // GT
                 Operator::Leq => { self.cmp(rl, rr); self.cset(rl, 13); }, // LE
                 Operator::Geq => { self.cmp(rl, rr); self.cset(rl, 10); }, // GE
+                Operator::Min => { self.cmp(rl, rr); self.csel(rl, rl, rr, 11); }, // LT ? rl : rr
+                Operator::Max => { self.cmp(rl, rr); self.csel(rl, rl, rr, 12); }, // GT ? rl : rr
                 Operator::BitShl => self.lslv(rl, rl, rr),
                 Operator::BitShr => self.asrv(rl, rl, rr),
+                Operator::LShr => self.lsrv(rl, rl, rr),
+                _ if mode == Mode::Saturating => self.gen_saturating(op, rl, rr),
                 Operator::Add => self.add(rl, rl, rr),
                 Operator::Sub => self.sub(rl, rl, rr),
                 Operator::Mul => self.mul(rl, rl, rr),
-                Operator::Div | Operator::Mod => {
-                    self.cmp(rr, Reg::XZR);
-                    if matches!(e.op, Operator::Mod) {
-                        self.b_cond(1, 3); // NE -> +3 to div
-                        self.mov0(rl);
-                        self.b(4); // -> +4 to end
-                        // div:
-                        self.sdiv(Reg::X17, rl, rr);
-                        self.mul(Reg::X18, Reg::X17, rr);
-                        self.sub(rl, rl, Reg::X18);
-                        // end:
-                    } else {
-                        self.b_cond(1, 3); // NE -> +3 to div
-                        self.mov0(rl);
-                        self.b(2); // -> +2 to end
-                        // div:
-                        self.sdiv(rl, rl, rr);
-                        // end:
-                    }
-                },
-                Operator::Pow => {
-                    self.mov_imm(Reg::X17, 1);           // 0: result = 1
-                    self.cmp(rl, Reg::X17);              // 1: base == 1?
-                    self.b_cond(0, 14);                  // 2: EQ -> end (16)
-                    self.orr(Reg::X18, rr, Reg::XZR);    // 3: X18 = rr
-                    self.cmp(rr, Reg::XZR);              // 4
-                    self.b_cond(12, 6);                  // 5: GT -> loop (11)
-                    self.b_cond(0, 10);                  // 6: EQ -> end (16)
-                    // neg exp: check base==-1
-                    self.mov_imm(Reg::X16, -1i64);       // 7
-                    self.cmp(rl, Reg::X16);              // 8
-                    self.b_cond(1, 6);                   // 9: NE -> set_zero (15)
-                    // base==-1, neg exp: negate X18 and compute
-                    self.neg(Reg::X18, Reg::X18);        // 10: X18 = -rr (now positive)
-                    // loop:
-                    self.mul(Reg::X17, Reg::X17, rl);    // 11
-                    self.inst(0xF1000400 | (18 << 5) | 18); // 12: SUBS X18, X18, 1
-                    self.b_cond(12, -2);                 // 13: GT -> loop (11)
-                    self.b(2);                           // 14: -> end (16)
-                    // set_zero:
-                    self.mov0(Reg::X17);                 // 15
-                    // end:
-                    self.orr(rl, Reg::X17, Reg::XZR);    // 16
-                },
-                _ => {}
+                Operator::Div | Operator::Mod => self.gen_div(op, rl, rr, mode == Mode::Euclidean),
+                Operator::Gcd => self.gen_gcd(rl, rr),
+                Operator::Pow => self.gen_pow(rl, rr),
+                _ => unreachable!("{:?} isn't a binary IR op", op),
             }
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Arc;
-    use crate::{naive_eval, NumT};
-
-    // Helper function to create a literal expression
-    fn lit(val: NumT) -> Arc<Expr> {
-        Arc::new(Expr {
-            left: None,
-            right: None,
-            literal: val,
-            op: Operator::Literal,
-            jit: None,
-        })
-    }
-
-    // Helper function to create a variable x expression
-    fn var_x() -> Arc<Expr> {
-        Arc::new(Expr {
-            left: None,
-            right: None,
-            literal: 0,
-            op: Operator::Var,
-            jit: None,
-        })
-    }
-
-    // Helper function to create a variable y expression
-    fn var_y() -> Arc<Expr> {
-        Arc::new(Expr {
-            left: None,
-            right: None,
-            literal: 0,
-            op: Operator::VarY,
-            jit: None,
-        })
-    }
-
-    // Helper function to create a binary expression
-    fn binary(left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Expr {
-        Expr {
-            left: Some(left),
-            right: Some(right),
-            literal: 0,
-            op,
-            jit: None,
-        }
-    }
 
-    // Helper function to create a unary expression
-    fn unary(op: Operator, right: Arc<Expr>) -> Expr {
-        Expr {
-            left: None,
-            right: Some(right),
-            literal: 0,
-            op,
-            jit: None,
-        }
+    // The register holding the address of `var`
+    fn var_ptr(var: Operator) -> Reg {
+        CALL_REGS[(var as usize) & 0xF]
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::build::{self, bin, lit, ternary, un};
+    use crate::{naive_eval, NumT, Operator};
 
     // Helper to compare JIT with naive_eval
     fn compare_jit_naive(e: &Expr, x_val: NumT, y_val: NumT) -> bool {
-        let jit = jit_compile_expr(e);
-        let f = jit.func();
-
-        let mut x_jit = x_val;
-        let mut y_jit = y_val;
-        let jit_result = unsafe { f(&mut x_jit, &mut y_jit) };
+        let (jit_result, x_jit, y_jit) = jit_compile_expr(e).call(x_val, y_val);
 
         let mut x_naive = x_val;
         let mut y_naive = y_val;
@@ -1229,6 +2145,209 @@ mod tests {
         }
     }
 
+    // ==================== Every Operator ====================
+
+    // Each operator's smallest tree, and its flavored ones, over the extreme
+    // values: the same value and writes as naive_eval, or 0 where that's fatal
+    #[test]
+    fn test_jit_every_operator() {
+        use crate::reference::{minimal_expr, EXTREMES};
+        let mut exprs: Vec<Arc<Expr>> = Operator::all().map(minimal_expr).collect();
+        for op in Operator::all() {
+            let (l, r) = (build::x(), build::y());
+            if Expr::can_saturate(op) {
+                exprs.push(build::saturating(l.clone(), op, r.clone()));
+            }
+            if Expr::can_be_euclidean(op) {
+                exprs.push(build::euclidean(l.clone(), op, r.clone()));
+            }
+            if Expr::can_be_unsigned(op) {
+                exprs.push(build::unsigned(l, op, r));
+            }
+        }
+        for e in &exprs {
+            let jit = jit_compile_expr(e);
+            for &x0 in EXTREMES {
+                for &y0 in EXTREMES {
+                    let (mut x, mut y, mut fatal) = (x0, y0, false);
+                    let r = naive_eval(e, &mut x, &mut y, &mut fatal);
+                    let (jit_r, jit_x, jit_y) = jit.call(x0, y0);
+                    let at = format!("{} at x={}, y={}", crate::format_expression(e, &['x', 'y']), x0, y0);
+                    if fatal {
+                        assert_eq!(jit_r, 0, "{}", at);
+                    } else {
+                        assert_eq!((jit_r, jit_x, jit_y), (r, x, y), "{}", at);
+                    }
+                }
+            }
+        }
+    }
+
+    // ==================== Branch Layout Tests ====================
+
+    // Branchy arms patch their jumps at emit time; pin the x86 bytes of x op y
+    // so a change to an intervening instruction can't silently move a target
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_jit_branch_code_bytes() {
+        let expected = [
+            (Operator::And, "4c8b074c8b0e4d85c0740d4d85c9740841b801000000eb034d31c04c89c0c3"),
+            (Operator::Pow, "4c8b074c8b0e4d85c9783b48baffffffff000000004939d17748b8010000004d85c9741d4c89c94883e1017406490fafc0702f49c1e90174084d0fafc07023ebe34989c0eb204c89c048ffc04883f802770b4d85c0740b4983e101ebbd4d31c0eb044831c0c34c89c0c3"),
+            (Operator::Div, "4c8b074c8b0e4d85c9741548ba00000000000000804939d0750a4983f9ff75044831c0c34c89c0489949f7f94989c04c89c0c3"),
+            (Operator::Mod, "4c8b074c8b0e4d85c9741548ba00000000000000804939d0750a4983f9ff75044831c0c34c89c0489949f7f94989d04c89c0c3"),
+            (Operator::DivEq, "4c8b064c8b0f4d85c0741548ba00000000000000804939d1750a4983f8ff75044831c0c34c89c8489949f7f84989c14c890f4c89c8c3"),
+            (Operator::ModEq, "4c8b064c8b0f4d85c0741548ba00000000000000804939d1750a4983f8ff75044831c0c34c89c8489949f7f84989d14c890f4c89c8c3"),
+        ];
+        for (op, hex) in expected {
+            let jit = jit_compile_expr(&bin(build::x(), op, build::y()));
+            let bytes: String = jit.code_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+            assert_eq!(bytes, hex, "{:?}", op);
+        }
+    }
+
+    // Saturating ops skip the clamp with jno, routed through the fixup layer
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_jit_saturating_code_bytes() {
+        let expected = [
+            (Operator::Add, "4c8b074c8b0e4c89c148c1e93f48b8ffffffffffffff7f4801c84d01c871034989c04c89c0c3"),
+            (Operator::Mul, "4c8b074c8b0e4c89c14c31c948c1e93f48b8ffffffffffffff7f4801c84d0fafc171034989c04c89c0c3"),
+        ];
+        for (op, hex) in expected {
+            let jit = jit_compile_expr(&build::saturating(build::x(), op, build::y()));
+            let bytes: String = jit.code_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+            assert_eq!(bytes, hex, "{:?}", op);
+        }
+    }
+
+    // Comparisons set a zeroed rax and copy it over, rather than setcc into
+    // the left operand's register and masking off its upper bytes with and
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_jit_compare_code_bytes() {
+        let jit = jit_compile_expr(&bin(build::x(), Operator::Lt, build::y()));
+        let bytes: String = jit.code_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(bytes, "4c8b074c8b0e31c04d39c80f9cc04989c04c89c0c3");
+    }
+
+    #[test]
+    fn test_jit_compare_large_operands() {
+        // Left operands with set upper bytes must still give exactly 0 or 1
+        let big = bin(build::x(), Operator::Mul, build::lit(1 << 40));
+        for op in [Operator::Eq, Operator::Neq, Operator::Lt, Operator::Gt, Operator::Leq, Operator::Geq] {
+            test_expr_range(&bin(big.clone(), op, build::y()), -4..=4);
+        }
+        test_expr_range(&build::un(Operator::Not, big), -4..=4);
+    }
+
+    #[test]
+    fn test_compile_fn() {
+        let step = bin(build::x(), Operator::AddEq, build::y());
+        let f = compile_fn(&step);
+        assert_eq!(f(2, 3), (5, 5, 3));
+        assert_eq!(f(-1, 1), (0, 0, 1));
+        // Reuses the Jit the expression already has
+        let compiled = build::Builder::new(true).bin(build::x(), Operator::AddEq, build::y());
+        assert_eq!(compile_fn(&compiled)(2, 3), (5, 5, 3));
+        // Fatal is 0, whichever way it runs
+        assert_eq!(compile_fn(&bin(build::x(), Operator::Div, build::y()))(7, 0).0, 0);
+        let pow = build::pow_mod(build::x(), build::y(), 7);
+        let f = compile_fn(&pow);
+        assert_eq!(f(3, 4), (4, 3, 4));
+        assert_eq!(f(3, -1).0, 0);
+    }
+
+    #[test]
+    fn test_jit_call() {
+        // y=x-- leaves both changed
+        let jit = jit_compile_expr(&bin(build::y(), Operator::AssignEq, un(Operator::PostDec, build::x())));
+        let (mut x, mut y) = (4, 9);
+        let r = unsafe { jit.func()(&mut x, &mut y) };
+        assert_eq!(jit.call(4, 9), (r, x, y));
+        assert_eq!(jit.call(4, 9), (4, 3, 4));
+    }
+
+    // Immediates past imm12 must not spill into the register fields
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_cmpi_imm12_range() {
+        let words = |imm: i64| -> Vec<u32> {
+            let mut jit = Jit::new(64);
+            jit.cmpi(Reg::X9, imm);
+            jit.code_bytes().chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect()
+        };
+        assert_eq!(words(4095), [0xF13FFD3F]); // CMP X9, #4095
+        assert_eq!(words(-1), [0xB100053F]); // CMN X9, #1
+        assert_eq!(words(5000), [0xD2827111, 0xEB11013F]); // MOVZ X17, #5000; CMP X9, X17
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_flag_setting_imm_encodings() {
+        let word = |emit: fn(&mut Jit)| {
+            let mut jit = Jit::new(64);
+            emit(&mut jit);
+            u32::from_le_bytes(jit.code_bytes().try_into().unwrap())
+        };
+        assert_eq!(word(|j| j.subs_imm(Reg::X16, Reg::X16, 1)), 0xF1000610); // SUBS X16, X16, #1
+        assert_eq!(word(|j| j.adds_imm(Reg::X9, Reg::X10, 7)), 0xB1001D49);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_bit_imm_encodings() {
+        let word = |emit: fn(&mut Jit)| {
+            let mut jit = Jit::new(64);
+            emit(&mut jit);
+            u32::from_le_bytes(jit.code_bytes().try_into().unwrap())
+        };
+        assert_eq!(word(|j| j.tst1(Reg::X10)), 0xF240015F); // TST X10, #1
+        assert_eq!(word(|j| j.and1(Reg::X9, Reg::X10)), 0x92400149); // AND X9, X10, #1
+        assert_eq!(word(|j| j.lsr1(Reg::X9, Reg::X10)), 0xD341FD49); // LSR X9, X10, #1
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_popcount_encoding() {
+        let mut jit = Jit::new(64);
+        jit.popcount(Reg::X9, Reg::X8);
+        let words: Vec<u32> = jit.code_bytes().chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+        assert_eq!(words, [0x9E670100, 0x0E205800, 0x0E31B800, 0x9E660009]);
+    }
+
+    // Register-to-register mov and test take mod=11, not a memory operand
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_reg_reg_encodings() {
+        let bytes = |emit: fn(&mut Jit)| {
+            let mut jit = Jit::new(64);
+            emit(&mut jit);
+            jit.code_bytes().to_vec()
+        };
+        assert_eq!(bytes(|j| j.movr(Reg::RAX, Reg::RCX)), [0x48, 0x89, 0xC1]); // mov rcx, rax
+        assert_eq!(bytes(|j| j.movr(Reg::R9, Reg::RAX)), [0x4C, 0x89, 0xC8]); // mov rax, r9
+        assert_eq!(bytes(|j| j.testr(Reg::R8, Reg::R8)), [0x4D, 0x85, 0xC0]); // test r8, r8
+    }
+
+    // Negative immediates that fit in 32 bits are sign-extended, the rest
+    // of the imm32 range zero-extended
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_movir_encodings() {
+        let bytes = |imm: i64, r: Reg| {
+            let mut jit = Jit::new(64);
+            jit.movir(imm, r);
+            jit.code_bytes().to_vec()
+        };
+        assert_eq!(bytes(5, Reg::R8), [0x41, 0xB8, 5, 0, 0, 0]); // mov r8d, 5
+        assert_eq!(bytes(-1, Reg::RAX), [0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]); // mov rax, -1
+        assert_eq!(bytes(-2, Reg::R10), [0x49, 0xC7, 0xC2, 0xFE, 0xFF, 0xFF, 0xFF]); // mov r10, -2
+        assert_eq!(bytes(1 << 32, Reg::RAX), [0x48, 0xB8, 0, 0, 0, 0, 1, 0, 0, 0]); // movabs rax, 1<<32
+        for value in [-1, -123, i32::MIN as NumT, i32::MAX as NumT, 1 << 32] {
+            assert_eq!(jit_compile_expr(&lit(value)).call(0, 0).0, value);
+        }
+    }
+
     // ==================== JIT Memory Tests ====================
 
     #[test]
@@ -1244,16 +2363,37 @@ mod tests {
         assert_eq!(mem.size % 4096, 0);
     }
 
+    #[test]
+    fn test_jit_functions_share_arena_chunk() {
+        let a = jit_compile_expr(&bin(build::x(), Operator::AddEq, build::y()));
+        let b = jit_compile_expr(&bin(build::y(), Operator::AssignEq, build::lit(3)));
+        let (chunk_a, start_a) = a.code.as_ref().unwrap();
+        let (chunk_b, start_b) = b.code.as_ref().unwrap();
+        assert!(Arc::ptr_eq(chunk_a, chunk_b));
+        assert!(start_b >= &(start_a + a.offset));
+        assert_eq!(start_b % 64, 0);
+        assert!(a.buf.is_empty());
+
+        // a is still callable after b was written next to it
+        let (_, x, y) = a.call(2, 5);
+        assert_eq!(x, 7);
+        drop(a);
+        assert_eq!(b.call(x, y).2, 3);
+    }
+
     // ==================== Basic Literal Tests ====================
 
     #[test]
     fn test_jit_literal() {
         let e = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: 42,
+            mode: Mode::Plain,
             op: Operator::Literal,
             jit: None,
+            bytecode: None,
         };
         test_expr_range(&e, -4..=4);
     }
@@ -1262,10 +2402,13 @@ mod tests {
     fn test_jit_literal_negative() {
         let e = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: -123,
+            mode: Mode::Plain,
             op: Operator::Literal,
             jit: None,
+            bytecode: None,
         };
         test_expr_range(&e, -4..=4);
     }
@@ -1274,10 +2417,13 @@ mod tests {
     fn test_jit_literal_large() {
         let e = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: 1_000_000_000,
+            mode: Mode::Plain,
             op: Operator::Literal,
             jit: None,
+            bytecode: None,
         };
         test_expr_range(&e, -2..=2);
     }
@@ -1288,10 +2434,13 @@ mod tests {
     fn test_jit_var_x() {
         let e = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: 0,
+            mode: Mode::Plain,
             op: Operator::Var,
             jit: None,
+            bytecode: None,
         };
         test_expr_range(&e, -4..=4);
     }
@@ -1300,10 +2449,13 @@ mod tests {
     fn test_jit_var_y() {
         let e = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: 0,
+            mode: Mode::Plain,
             op: Operator::VarY,
             jit: None,
+            bytecode: None,
         };
         test_expr_range(&e, -4..=4);
     }
@@ -1312,37 +2464,37 @@ mod tests {
 
     #[test]
     fn test_jit_add() {
-        let e = binary(var_x(), Operator::Add, var_y());
+        let e = bin(build::x(), Operator::Add, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_add_literal() {
-        let e = binary(var_x(), Operator::Add, lit(10));
+        let e = bin(build::x(), Operator::Add, lit(10));
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_sub() {
-        let e = binary(var_x(), Operator::Sub, var_y());
+        let e = bin(build::x(), Operator::Sub, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_mul() {
-        let e = binary(var_x(), Operator::Mul, var_y());
+        let e = bin(build::x(), Operator::Mul, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_div() {
-        let e = binary(var_x(), Operator::Div, var_y());
+        let e = bin(build::x(), Operator::Div, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_mod() {
-        let e = binary(var_x(), Operator::Mod, var_y());
+        let e = bin(build::x(), Operator::Mod, build::y());
         test_expr_range(&e, -4..=4);
     }
 
@@ -1350,51 +2502,169 @@ mod tests {
 
     #[test]
     fn test_jit_eq() {
-        let e = binary(var_x(), Operator::Eq, var_y());
+        let e = bin(build::x(), Operator::Eq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_neq() {
-        let e = binary(var_x(), Operator::Neq, var_y());
+        let e = bin(build::x(), Operator::Neq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_lt() {
-        let e = binary(var_x(), Operator::Lt, var_y());
+        let e = bin(build::x(), Operator::Lt, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_leq() {
-        let e = binary(var_x(), Operator::Leq, var_y());
+        let e = bin(build::x(), Operator::Leq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_gt() {
-        let e = binary(var_x(), Operator::Gt, var_y());
+        let e = bin(build::x(), Operator::Gt, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_geq() {
-        let e = binary(var_x(), Operator::Geq, var_y());
+        let e = bin(build::x(), Operator::Geq, build::y());
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_min() {
+        let e = bin(build::x(), Operator::Min, build::y());
         test_expr_range(&e, -4..=4);
     }
 
+    #[test]
+    fn test_jit_max() {
+        let e = bin(build::x(), Operator::Max, build::y());
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_min_max_literal() {
+        // x <? 2 and -3 >? y, exercising immediates on either side
+        let e1 = bin(build::x(), Operator::Min, lit(2));
+        let e2 = bin(lit(-3), Operator::Max, build::y());
+        test_expr_range(&e1, -4..=4);
+        test_expr_range(&e2, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_min_max_extremes() {
+        let e1 = bin(build::x(), Operator::Min, build::y());
+        let e2 = bin(build::x(), Operator::Max, build::y());
+        for &(x, y) in &[(i64::MIN, i64::MAX), (i64::MAX, i64::MIN), (i64::MIN, i64::MIN), (-1, 0)] {
+            assert!(compare_jit_naive(&e1, x, y));
+            assert!(compare_jit_naive(&e2, x, y));
+        }
+    }
+
+    #[test]
+    fn test_jit_saturating() {
+        let edges = [i64::MIN, i64::MIN + 1, -3_037_000_500, -2, -1, 0, 1, 2, 3_037_000_500, i64::MAX - 1, i64::MAX];
+        for op in [Operator::Add, Operator::Sub, Operator::Mul] {
+            let e = build::saturating(build::x(), op, build::y());
+            for &x in &edges {
+                for &y in &edges {
+                    assert!(compare_jit_naive(&e, x, y), "{:?} x={} y={}", op, x, y);
+                }
+            }
+        }
+        let e = build::saturating(build::x(), Operator::Add, lit(1));
+        assert_eq!(jit_compile_expr(&e).call(i64::MAX, 0).0, i64::MAX);
+    }
+
+    #[test]
+    fn test_jit_assign_reads_target_last() {
+        // The right side's x++ lands before x is read back for the update
+        let right = bin(build::y(), Operator::Add, un(Operator::PostInc, build::x()));
+        for op in [Operator::AddEq, Operator::SubEq, Operator::MulEq, Operator::DivEq, Operator::ModEq, Operator::BitShlEq] {
+            test_expr_range(&bin(build::x(), op, right.clone()), -4..=4);
+        }
+        // A nested division by 0 returns 0 from the whole function
+        let e = bin(bin(build::x(), Operator::Div, build::y()), Operator::Add, lit(100));
+        assert_eq!(jit_compile_expr(&e).call(7, 0).0, 0);
+        assert_eq!(jit_compile_expr(&bin(e, Operator::Comma, build::x())).call(7, 0).0, 0);
+    }
+
+    #[test]
+    fn test_jit_saturating_assign() {
+        let edges = [i64::MIN, -5, -1, 0, 1, 5, i64::MAX];
+        for op in [Operator::AddEq, Operator::SubEq, Operator::MulEq] {
+            // y*y stays live in a register while the saturating op needs temporaries
+            let e = bin(bin(build::y(), Operator::Mul, build::y()), Operator::Add, build::saturating(build::x(), op, build::y()));
+            for &x in &edges {
+                for &y in &edges {
+                    assert!(compare_jit_naive(&e, x, y), "{:?} x={} y={}", op, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_jit_euclidean() {
+        // Negative dividends and divisors are where Euclidean and truncating differ
+        let edges = [i64::MIN, i64::MIN + 1, -7, -6, -2, -1, 0, 1, 2, 6, 7, i64::MAX];
+        for op in [Operator::Div, Operator::Mod, Operator::DivEq, Operator::ModEq] {
+            // y*y stays live in a register across the division
+            let e = bin(bin(build::y(), Operator::Mul, build::y()), Operator::Add, build::euclidean(build::x(), op, build::y()));
+            for &x in &edges {
+                for &y in &edges {
+                    assert!(compare_jit_naive(&e, x, y), "{:?} x={} y={}", op, x, y);
+                }
+            }
+        }
+        for (x, y, quotient, remainder) in [(-7, 2, -4, 1), (-7, -2, 4, 1), (7, -2, -3, 1), (-6, 2, -3, 0)] {
+            let div = jit_compile_expr(&build::euclidean(build::x(), Operator::Div, build::y()));
+            let rem = jit_compile_expr(&build::euclidean(build::x(), Operator::Mod, build::y()));
+            assert_eq!(unsafe { div.func()(&mut { x }, &mut { y }) }, quotient, "{} / {}", x, y);
+            assert_eq!(unsafe { rem.func()(&mut { x }, &mut { y }) }, remainder, "{} % {}", x, y);
+        }
+    }
+
+    #[test]
+    fn test_jit_unsigned() {
+        // Negative values are huge as u64, so they flip every comparison
+        // and turn >> into a logical shift
+        let edges = [i64::MIN, i64::MIN + 1, -7, -2, -1, 0, 1, 2, 3, 7, 63, 64, i64::MAX];
+        let ops = [
+            Operator::Lt, Operator::Leq, Operator::Gt, Operator::Geq, Operator::Min, Operator::Max,
+            Operator::BitShr, Operator::Div, Operator::Mod, Operator::BitShrEq, Operator::DivEq, Operator::ModEq,
+        ];
+        for op in ops {
+            // y*y stays live in a register across the operation
+            let e = bin(bin(build::y(), Operator::Mul, build::y()), Operator::Add, build::unsigned(build::x(), op, build::y()));
+            for &x in &edges {
+                for &y in &edges {
+                    assert!(compare_jit_naive(&e, x, y), "{:?} x={} y={}", op, x, y);
+                }
+            }
+        }
+        let lt = jit_compile_expr(&build::unsigned(build::x(), Operator::Lt, build::y()));
+        let shr = jit_compile_expr(&build::unsigned(build::x(), Operator::BitShr, build::y()));
+        assert_eq!(unsafe { lt.func()(&mut { 1 }, &mut { -1 }) }, 1);
+        assert_eq!(unsafe { shr.func()(&mut { -1 }, &mut { 60 }) }, 15);
+    }
+
     // ==================== Logical Operations ====================
 
     #[test]
     fn test_jit_or() {
-        let e = binary(var_x(), Operator::Or, var_y());
+        let e = bin(build::x(), Operator::Or, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_and() {
-        let e = binary(var_x(), Operator::And, var_y());
+        let e = bin(build::x(), Operator::And, build::y());
         test_expr_range(&e, -4..=4);
     }
 
@@ -1402,89 +2672,260 @@ mod tests {
 
     #[test]
     fn test_jit_bitor() {
-        let e = binary(var_x(), Operator::BitOr, var_y());
+        let e = bin(build::x(), Operator::BitOr, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_bitxor() {
-        let e = binary(var_x(), Operator::BitXor, var_y());
+        let e = bin(build::x(), Operator::BitXor, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_bitand() {
-        let e = binary(var_x(), Operator::BitAnd, var_y());
+        let e = bin(build::x(), Operator::BitAnd, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_shl() {
-        let e = binary(var_x(), Operator::BitShl, lit(2));
+        let e = bin(build::x(), Operator::BitShl, lit(2));
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_shr() {
-        let e = binary(var_x(), Operator::BitShr, lit(1));
+        let e = bin(build::x(), Operator::BitShr, lit(1));
         test_expr_range(&e, -4..=4);
     }
 
+    #[test]
+    fn test_jit_lshr() {
+        // Negative x is where logical and arithmetic shifts differ
+        let e = bin(build::x(), Operator::LShr, build::y());
+        test_expr_range(&e, -4..=4);
+        for &x in &[i64::MIN, -1, -2, i64::MAX] {
+            for y in [0, 1, 63, 64, 65, -1] {
+                assert!(compare_jit_naive(&e, x, y), "x={} y={}", x, y);
+            }
+        }
+    }
+
     // ==================== Unary Operations ====================
 
     #[test]
     fn test_jit_neg() {
-        let e = unary(Operator::Neg, var_x());
+        let e = un(Operator::Neg, build::x());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_bitnot() {
-        let e = unary(Operator::BitNot, var_x());
+        let e = un(Operator::BitNot, build::x());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_not() {
-        let e = unary(Operator::Not, var_x());
+        let e = un(Operator::Not, build::x());
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_gcd() {
+        // y*y stays live in a register across the loop
+        let e = bin(bin(build::y(), Operator::Mul, build::y()), Operator::Add, bin(build::x(), Operator::Gcd, build::y()));
+        test_expr_range(&e, -12..=12);
+        let edges = [i64::MIN, i64::MIN + 1, -1, 0, 1, 6, 1 << 40, 3 << 40, i64::MAX];
+        for &x in &edges {
+            for &y in &edges {
+                assert!(compare_jit_naive(&e, x, y), "x={} y={}", x, y);
+            }
+        }
+        let gcd = jit_compile_expr(&bin(build::x(), Operator::Gcd, build::y()));
+        for (x, y, expected) in [(0, 9, 9), (9, 0, 9), (0, 0, 0), (-12, 18, 6), (i64::MIN, 0, i64::MIN)] {
+            assert_eq!(unsafe { gcd.func()(&mut { x }, &mut { y }) }, expected, "gcd({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn test_jit_popcount() {
+        // Negative x has all the high bits set
+        let e = bin(un(Operator::PopCount, build::x()), Operator::Sub, build::y());
         test_expr_range(&e, -4..=4);
+        for x in [i64::MIN, i64::MIN + 1, -1, -2, 0x5555_5555_5555_5555, i64::MAX, 1 << 40] {
+            assert!(compare_jit_naive(&e, x, 0), "x={}", x);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_jit_popcount_fallback() {
+        let e = un(Operator::PopCount, build::x());
+        let mut jit = Jit::new(4096);
+        jit.popcnt = false;
+        jit.compile(&e);
+        jit.finalize();
+        assert!(!jit.code_bytes().windows(3).any(|w| w[0] == 0xF3 && w[2] == 0x0F));
+        for x in [i64::MIN, -1, -2, -4, 0, 1, 3, 0x5555_5555_5555_5555, i64::MAX, 1 << 40] {
+            assert_eq!(unsafe { jit.func()(&mut { x }, &mut { 0 }) }, x.count_ones() as NumT, "x={}", x);
+        }
     }
 
     #[test]
     fn test_jit_parens() {
-        let e = unary(Operator::Parens, var_x());
+        let e = un(Operator::Parens, build::x());
+        test_expr_range(&e, -4..=4);
+    }
+
+    // ==================== Comma Operation ====================
+
+    #[test]
+    fn test_jit_comma() {
+        // x=y+1,y=x*2
+        let l = bin(build::x(), Operator::AssignEq, bin(build::y(), Operator::Add, lit(1)));
+        let r = bin(build::y(), Operator::AssignEq, bin(build::x(), Operator::Mul, lit(2)));
+        let e = bin(l, Operator::Comma, r);
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_comma_compound() {
+        // x+=y,y-=x
+        let l = bin(build::x(), Operator::AddEq, build::y());
+        let r = bin(build::y(), Operator::SubEq, build::x());
+        let e = bin(l, Operator::Comma, r);
+        test_expr_range(&e, -4..=4);
+    }
+
+    // ==================== Ternary Operation ====================
+
+    #[test]
+    fn test_jit_ternary() {
+        let e = ternary(build::x(), build::y(), lit(7));
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_ternary_comparison_cond() {
+        // x < y ? y - x : x * 2
+        let cond = bin(build::x(), Operator::Lt, build::y());
+        let then = bin(build::y(), Operator::Sub, build::x());
+        let other = bin(build::x(), Operator::Mul, lit(2));
+        let e = ternary(cond, then, other);
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_ternary_side_effects() {
+        // x ? ++y : y--
+        let inc = un(Operator::PreInc, build::y());
+        let dec = un(Operator::PostDec, build::y());
+        let e = ternary(build::x(), inc, dec);
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_ternary_nested() {
+        // x ? y : y ? 1 : 2
+        let inner = ternary(build::y(), lit(1), lit(2));
+        let e = ternary(build::x(), build::y(), inner);
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_ternary_as_operand() {
+        // (x ? x : y) + 1
+        let t = un(Operator::Parens, ternary(build::x(), build::x(), build::y()));
+        let e = bin(t, Operator::Add, lit(1));
+        test_expr_range(&e, -4..=4);
+    }
+
+    #[test]
+    fn test_jit_long_branches() {
+        // y + big + big + ... with 64-bit literals is well past 127 bytes of code,
+        // so branching over it needs the rel32 forms
+        let mut long = build::y();
+        for i in 0..16 {
+            long = bin(long, Operator::Add, lit(0x1234_5678_9ABC + i));
+        }
+        let e = ternary(build::x(), long.clone(), build::y());
+        test_expr_range(&e, -4..=4);
+        assert!(jit_compile_expr(&e).code_bytes().len() > 127);
+
+        let e = ternary(build::x(), build::y(), long.clone());
+        test_expr_range(&e, -4..=4);
+
+        let e = bin(build::x(), Operator::And, long);
         test_expr_range(&e, -4..=4);
     }
 
+    #[test]
+    fn test_jit_reuses_compiled_children() {
+        // The same trees built with every node compiled: the children's own
+        // code doesn't change how the parent compiles
+        let trees = |b: build::Builder| {
+            let div = b.bin(b.x(), Operator::Div, b.bin(b.y(), Operator::Sub, b.lit(1)));
+            let cond = b.ternary(b.bin(b.x(), Operator::And, b.y()), div.clone(), b.un(Operator::Neg, b.x()));
+            vec![
+                b.bin(div.clone(), Operator::Add, b.lit(7)),
+                b.bin(cond.clone(), Operator::Mul, div),
+                b.bin(b.x(), Operator::AssignEq, cond),
+            ]
+        };
+        for (plain, compiled) in trees(build::Builder::new(false)).iter().zip(trees(build::Builder::new(true))) {
+            assert!(compiled.left.as_ref().unwrap().jit.is_some());
+            let reused = jit_compile_expr(&compiled);
+            assert_eq!(reused.code_bytes(), jit_compile_expr(plain).code_bytes());
+            test_expr_range(&compiled, -4..=4);
+        }
+    }
+
     // ==================== Increment/Decrement Operations ====================
 
     #[test]
     fn test_jit_pre_inc() {
-        let e = unary(Operator::PreInc, var_x());
+        let e = un(Operator::PreInc, build::x());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_pre_dec() {
-        let e = unary(Operator::PreDec, var_x());
+        let e = un(Operator::PreDec, build::x());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_post_inc() {
-        let e = unary(Operator::PostInc, var_x());
+        let e = un(Operator::PostInc, build::x());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_post_dec() {
-        let e = unary(Operator::PostDec, var_x());
+        let e = un(Operator::PostDec, build::x());
         test_expr_range(&e, -4..=4);
     }
 
+    #[test]
+    fn test_jit_rejects_non_variable_target() {
+        // ++(x+1), (y*2)--, (x+1)=3, 2+=y: fatal, so return 0 and write nothing
+        let sum = bin(build::x(), Operator::Add, lit(1));
+        let exprs = [
+            un(Operator::PreInc, sum.clone()),
+            un(Operator::PostDec, bin(build::y(), Operator::Mul, lit(2))),
+            bin(sum, Operator::AssignEq, lit(3)),
+            bin(lit(2), Operator::AddEq, build::y()),
+        ];
+        for e in &exprs {
+            assert_eq!(jit_compile_expr(e).call(5, 7), (0, 5, 7));
+        }
+    }
+
     #[test]
     fn test_jit_pre_inc_y() {
-        let e = unary(Operator::PreInc, var_y());
+        let e = un(Operator::PreInc, build::y());
         test_expr_range(&e, -4..=4);
     }
 
@@ -1492,164 +2933,381 @@ mod tests {
 
     #[test]
     fn test_jit_assign_eq() {
-        let e = binary(var_x(), Operator::AssignEq, var_y());
+        let e = bin(build::x(), Operator::AssignEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_add_eq() {
-        let e = binary(var_x(), Operator::AddEq, var_y());
+        let e = bin(build::x(), Operator::AddEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_sub_eq() {
-        let e = binary(var_x(), Operator::SubEq, var_y());
+        let e = bin(build::x(), Operator::SubEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_mul_eq() {
-        let e = binary(var_x(), Operator::MulEq, var_y());
+        let e = bin(build::x(), Operator::MulEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_div_eq() {
-        let e = binary(var_x(), Operator::DivEq, var_y());
+        let e = bin(build::x(), Operator::DivEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_mod_eq() {
-        let e = binary(var_x(), Operator::ModEq, var_y());
+        let e = bin(build::x(), Operator::ModEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
+    // x op= y at the MIN / -1 overflow (and x / 0) must not trap, return 0 and
+    // leave x untouched, matching naive_eval's fatal path
+    fn check_div_eq_fatal(op: Operator, x_val: NumT, rhs: Arc<Expr>) {
+        let e = bin(build::x(), op, rhs);
+
+        let mut x_naive = x_val;
+        let mut y_naive = -1;
+        let mut fatal = false;
+        let naive_result = naive_eval(&e, &mut x_naive, &mut y_naive, &mut fatal);
+        assert!(fatal);
+        assert_eq!(naive_result, 0);
+        assert_eq!(x_naive, x_val);
+
+        let (jit_result, x_jit, y_jit) = jit_compile_expr(&e).call(x_val, -1);
+        assert_eq!(jit_result, naive_result, "{:?} result", op);
+        assert_eq!(x_jit, x_naive, "{:?} target", op);
+        assert_eq!(y_jit, y_naive);
+    }
+
+    #[test]
+    fn test_jit_div_eq_int_min() {
+        check_div_eq_fatal(Operator::DivEq, NumT::MIN, build::y());
+        check_div_eq_fatal(Operator::DivEq, NumT::MIN, lit(-1));
+        check_div_eq_fatal(Operator::DivEq, NumT::MIN, lit(0));
+        check_div_eq_fatal(Operator::DivEq, 7, lit(0));
+        for y_val in [NumT::MIN, -2, 1, 2, NumT::MAX] {
+            let e = bin(build::x(), Operator::DivEq, build::y());
+            assert!(compare_jit_naive(&e, NumT::MIN, y_val), "y={}", y_val);
+        }
+    }
+
+    #[test]
+    fn test_jit_mod_eq_int_min() {
+        check_div_eq_fatal(Operator::ModEq, NumT::MIN, build::y());
+        check_div_eq_fatal(Operator::ModEq, NumT::MIN, lit(-1));
+        check_div_eq_fatal(Operator::ModEq, NumT::MIN, lit(0));
+        check_div_eq_fatal(Operator::ModEq, 7, lit(0));
+        for y_val in [NumT::MIN, -2, 1, 2, NumT::MAX] {
+            let e = bin(build::x(), Operator::ModEq, build::y());
+            assert!(compare_jit_naive(&e, NumT::MIN, y_val), "y={}", y_val);
+        }
+    }
+
     #[test]
     fn test_jit_bitor_eq() {
-        let e = binary(var_x(), Operator::BitOrEq, var_y());
+        let e = bin(build::x(), Operator::BitOrEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_bitxor_eq() {
-        let e = binary(var_x(), Operator::BitXorEq, var_y());
+        let e = bin(build::x(), Operator::BitXorEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_bitand_eq() {
-        let e = binary(var_x(), Operator::BitAndEq, var_y());
+        let e = bin(build::x(), Operator::BitAndEq, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_shl_eq() {
-        let e = binary(var_x(), Operator::BitShlEq, lit(2));
+        let e = bin(build::x(), Operator::BitShlEq, lit(2));
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_shr_eq() {
-        let e = binary(var_x(), Operator::BitShrEq, lit(1));
+        let e = bin(build::x(), Operator::BitShrEq, lit(1));
         test_expr_range(&e, -4..=4);
     }
 
+    // A compound assignment's value is the target's new value, not its right side
+    #[test]
+    fn test_jit_compound_assign_value() {
+        let ops = [
+            Operator::BitOrEq, Operator::BitXorEq, Operator::BitAndEq, Operator::BitShlEq, Operator::BitShrEq,
+            Operator::AddEq, Operator::SubEq, Operator::MulEq, Operator::DivEq, Operator::ModEq,
+        ];
+        for op in ops {
+            let jit = jit_compile_expr(&bin(build::x(), op, build::y()));
+            for (x0, y0) in [(7, 3), (-9, 2), (12, 1)] {
+                let (value, x, _) = jit.call(x0, y0);
+                assert_eq!(value, x, "x={} y={} {:?}", x0, y0, op);
+            }
+        }
+    }
+
     // ==================== Power Operation ====================
 
     #[test]
     fn test_jit_pow() {
-        let e = binary(var_x(), Operator::Pow, var_y());
+        let e = bin(build::x(), Operator::Pow, build::y());
         test_expr_range(&e, -2..=4);
     }
 
+    // (+-1)**-n is +-1 like naive_eval, where other negative powers are 0
+    #[test]
+    fn test_jit_pow_unit_base_negative_exp() {
+        let e = bin(build::x(), Operator::Pow, build::y());
+        let jit = jit_compile_expr(&e);
+        assert_eq!(jit.call(1, -5).0, 1);
+        assert_eq!(jit.call(-1, -5).0, -1);
+        assert_eq!(jit.call(-1, -4).0, 1);
+        for exp in [-7, -2, -1, NumT::MIN] {
+            for base in [-1, 1, 2] {
+                assert!(compare_jit_naive(&e, base, exp), "{}**{}", base, exp);
+            }
+        }
+    }
+
     #[test]
     fn test_jit_pow_positive() {
-        let e = binary(lit(2), Operator::Pow, lit(10));
+        let e = bin(lit(2), Operator::Pow, lit(10));
         assert!(compare_jit_naive(&e, 0, 0));
     }
 
     #[test]
     fn test_jit_pow_zero_exp() {
-        let e = binary(var_x(), Operator::Pow, lit(0));
+        let e = bin(build::x(), Operator::Pow, lit(0));
         test_expr_range(&e, -4..=4);
     }
 
+    // Squaring must be fatal exactly where checked_pow is, (-2)**63 being
+    // MIN and 3**40 one too many, and return 0 before x is written
+    #[test]
+    fn test_jit_pow_by_squaring() {
+        let pow = bin(build::x(), Operator::Pow, build::y());
+        let assign = bin(build::x(), Operator::AssignEq, pow.clone());
+        let (jit_pow, jit_assign) = (jit_compile_expr(&pow), jit_compile_expr(&assign));
+        let bases = [-3, -2, -1, 0, 1, 2, 3, 7, i64::MIN, i64::MAX];
+        let exps = [i64::MIN, -3, -1, 0, 1, 2, 5, 21, 39, 40, 62, 63, 64, 100, u32::MAX as i64, u32::MAX as i64 + 1, i64::MAX];
+        for base in bases {
+            for exp in exps {
+                let mut fatal = false;
+                let r = naive_eval(&pow, &mut base.clone(), &mut exp.clone(), &mut fatal);
+                let (expected, written) = if fatal { (0, base) } else { (r, r) };
+                assert_eq!(jit_pow.call(base, exp), (expected, base, exp), "{}**{}", base, exp);
+                assert_eq!(jit_assign.call(base, exp), (expected, written, exp), "x={}**{}", base, exp);
+            }
+        }
+    }
+
     // ==================== Complex Expressions ====================
 
     #[test]
     fn test_jit_nested_add_mul() {
         // (x + y) * 2
-        let inner = Arc::new(binary(var_x(), Operator::Add, var_y()));
-        let e = binary(inner, Operator::Mul, lit(2));
+        let inner = bin(build::x(), Operator::Add, build::y());
+        let e = bin(inner, Operator::Mul, lit(2));
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_nested_sub_div() {
         // (x - y) / 2
-        let inner = Arc::new(binary(var_x(), Operator::Sub, var_y()));
-        let e = binary(inner, Operator::Div, lit(2));
+        let inner = bin(build::x(), Operator::Sub, build::y());
+        let e = bin(inner, Operator::Div, lit(2));
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_deeply_nested() {
         // ((x + 1) * 2) - y
-        let inner1 = Arc::new(binary(var_x(), Operator::Add, lit(1)));
-        let inner2 = Arc::new(binary(inner1, Operator::Mul, lit(2)));
-        let e = binary(inner2, Operator::Sub, var_y());
+        let inner1 = bin(build::x(), Operator::Add, lit(1));
+        let inner2 = bin(inner1, Operator::Mul, lit(2));
+        let e = bin(inner2, Operator::Sub, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_comparison_chain() {
         // (x < y) == (y > x)
-        let left = Arc::new(binary(var_x(), Operator::Lt, var_y()));
-        let right = Arc::new(binary(var_y(), Operator::Gt, var_x()));
-        let e = binary(left, Operator::Eq, right);
+        let left = bin(build::x(), Operator::Lt, build::y());
+        let right = bin(build::y(), Operator::Gt, build::x());
+        let e = bin(left, Operator::Eq, right);
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_mixed_ops() {
         // x + y * 2
-        let mul = Arc::new(binary(var_y(), Operator::Mul, lit(2)));
-        let e = binary(var_x(), Operator::Add, mul);
+        let mul = bin(build::y(), Operator::Mul, lit(2));
+        let e = bin(build::x(), Operator::Add, mul);
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_unary_in_binary() {
         // (-x) + y
-        let neg = Arc::new(unary(Operator::Neg, var_x()));
-        let e = binary(neg, Operator::Add, var_y());
+        let neg = un(Operator::Neg, build::x());
+        let e = bin(neg, Operator::Add, build::y());
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_logical_and_comparison() {
         // (x > 0) && (y > 0)
-        let left = Arc::new(binary(var_x(), Operator::Gt, lit(0)));
-        let right = Arc::new(binary(var_y(), Operator::Gt, lit(0)));
-        let e = binary(left, Operator::And, right);
+        let left = bin(build::x(), Operator::Gt, lit(0));
+        let right = bin(build::y(), Operator::Gt, lit(0));
+        let e = bin(left, Operator::And, right);
         test_expr_range(&e, -4..=4);
     }
 
     #[test]
     fn test_jit_bitwise_chain() {
         // (x | y) & 0xFF
-        let bitor = Arc::new(binary(var_x(), Operator::BitOr, var_y()));
-        let e = binary(bitor, Operator::BitAnd, lit(0xFF));
+        let bitor = bin(build::x(), Operator::BitOr, build::y());
+        let e = bin(bitor, Operator::BitAnd, lit(0xFF));
+        test_expr_range(&e, -4..=4);
+    }
+
+    // ==================== Reassociation ====================
+
+    /// Most registers the compiled code holds at once, given as many as it wants
+    fn registers_needed(e: &Expr) -> usize {
+        let alloc = regalloc::allocate(&ir::lower(e), usize::MAX);
+        alloc.locs.iter().map(|&loc| match loc { Loc::Reg(i) => i + 1, Loc::Slot(_) => 0 }).max().unwrap_or(0)
+    }
+
+    // x op (y op (x op (y op x))), five operands nested to the right
+    fn right_chain(op: Operator, operands: &[Arc<Expr>]) -> Arc<Expr> {
+        let (last, rest) = operands.split_last().unwrap();
+        rest.iter().rev().fold(last.clone(), |acc, o| bin(o.clone(), op, acc))
+    }
+
+    // ((x op y) op 3) op ..., the same operands nested to the left
+    fn left_chain(op: Operator, operands: &[Arc<Expr>]) -> Arc<Expr> {
+        let (first, rest) = operands.split_first().unwrap();
+        rest.iter().fold(first.clone(), |acc, o| bin(acc, op, o.clone()))
+    }
+
+    fn depth(e: &Expr) -> usize {
+        1 + [&e.left, &e.mid, &e.right].into_iter().flatten().map(|c| depth(c)).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_rebalance_balanced() {
+        let operands = [build::x(), build::y(), lit(3), build::x(), build::y()];
+        let e = right_chain(Operator::Add, &operands);
+        let rebalanced = rebalance(&e);
+        assert_eq!(crate::format_expression(&rebalanced, &['x', 'y']), "x+y+3+(x+y)");
+        assert_eq!((depth(&e), depth(&rebalanced)), (5, 4));
+        assert_eq!((registers_needed(&e), registers_needed(&rebalanced)), (5, 3));
+        // Either nesting comes out the same, and a balanced chain is left as it is
+        assert!(crate::structural_eq(&rebalance(&left_chain(Operator::Add, &operands)), &rebalanced));
+        assert!(crate::structural_eq(&rebalance(&rebalanced), &rebalanced));
+
+        // 16 operands nest 5 deep, in log2(16)+1 registers
+        let many: Vec<_> = (0..16).map(|i| operands[i % operands.len()].clone()).collect();
+        for e in [right_chain(Operator::Mul, &many), left_chain(Operator::Mul, &many)] {
+            let rebalanced = rebalance(&e);
+            assert_eq!((depth(&e), depth(&rebalanced)), (16, 5));
+            assert_eq!(registers_needed(&rebalanced), 5);
+            test_expr_range(&e, -2..=2);
+        }
+        assert_eq!(registers_needed(&right_chain(Operator::Mul, &many)), 16);
+
+        // Chains nested inside other operators are rebuilt too
+        let e = bin(lit(2), Operator::Mul, un(Operator::Neg, right_chain(Operator::BitXor, &operands)));
+        assert_eq!(registers_needed(&e), 6);
+        assert_eq!(registers_needed(&rebalance(&e)), 4);
+    }
+
+    #[test]
+    fn test_rebalance_preserves_semantics() {
+        // Past x86's four free registers before rebalancing
+        let side_effects = [un(Operator::PostInc, build::x()), build::y(), un(Operator::PreDec, build::y()), build::x(), lit(-2)];
+        for op in [Operator::Add, Operator::Mul, Operator::BitOr, Operator::BitXor, Operator::BitAnd, Operator::Or, Operator::And, Operator::Min, Operator::Max] {
+            for e in [right_chain(op, &side_effects), left_chain(op, &side_effects)] {
+                assert!(has_chain(&e));
+                test_expr_range(&e, -4..=4);
+                test_expr_range(&bin(build::x(), Operator::AssignEq, e), -4..=4);
+            }
+        }
+        // Wrapping arithmetic reassociates even across overflow
+        let e = right_chain(Operator::Add, &[lit(NumT::MAX), build::x(), lit(NumT::MAX), build::y(), lit(3)]);
         test_expr_range(&e, -4..=4);
     }
 
+    #[test]
+    fn test_rebalance_skips_saturating_and_mixed() {
+        let sat = build::saturating(build::x(), Operator::Add, build::saturating(build::y(), Operator::Add, lit(1)));
+        assert!(!has_chain(&sat));
+        // x+(y-1) isn't one chain
+        let mixed = bin(build::x(), Operator::Add, bin(build::y(), Operator::Sub, lit(1)));
+        assert!(!has_chain(&mixed));
+        let wrapping_over_sat = bin(build::x(), Operator::Add, build::saturating(build::y(), Operator::Add, lit(1)));
+        assert!(!has_chain(&wrapping_over_sat));
+        // An unsigned <? or >? over a signed one compares differently, e.g.
+        // (x<?y)<?5 unsigned over signed is 5 at x=-1, y=3, not 3
+        for op in [Operator::Min, Operator::Max] {
+            let signed_under = build::unsigned(bin(build::x(), op, build::y()), op, lit(5));
+            let unsigned_under = bin(build::unsigned(build::x(), op, build::y()), op, lit(5));
+            for e in [signed_under, unsigned_under] {
+                assert!(!has_chain(&e));
+                test_expr_range(&e, -4..=4);
+            }
+        }
+        let e = build::unsigned(bin(build::x(), Operator::Min, build::y()), Operator::Min, lit(5));
+        assert_eq!(compile_fn(&e)(-1, 3).0, 5);
+        // A chain all in one mode still reassociates
+        let all_unsigned = build::unsigned(build::unsigned(build::x(), Operator::Min, build::y()), Operator::Min, lit(5));
+        assert!(has_chain(&all_unsigned));
+        test_expr_range(&all_unsigned, -4..=4);
+    }
+
+    // ==================== Spilling ====================
+
+    // x op0 (y op1 (3 op2 (x ...))), `n` operands cycling through `ops`, none
+    // of which reassociate, so every operand is live at the innermost one
+    fn spilling_chain(n: usize, ops: &[Operator]) -> Arc<Expr> {
+        let operands = [build::x(), build::y(), lit(3), un(Operator::PostInc, build::x()), lit(-5)];
+        (0..n).rev().fold(build::y(), |acc, i| bin(operands[i % operands.len()].clone(), ops[i % ops.len()], acc))
+    }
+
+    #[test]
+    fn test_jit_spills_deep_chains() {
+        for ops in [&[Operator::Sub][..], &[Operator::Sub, Operator::BitShl, Operator::Lt], &[Operator::Div, Operator::Sub, Operator::Mod, Operator::Neq]] {
+            let e = spilling_chain(12, ops);
+            assert!(registers_needed(&e) > 7);
+            test_expr_range(&e, -4..=4);
+            // Spilled values around stores and branches
+            test_expr_range(&bin(build::y(), Operator::SubEq, e.clone()), -4..=4);
+            test_expr_range(&ternary(e.clone(), spilling_chain(9, ops), bin(build::x(), Operator::AssignEq, e.clone())), -4..=4);
+            test_expr_range(&bin(e.clone(), Operator::Comma, un(Operator::PreDec, e)), -4..=4);
+        }
+    }
+
+    #[test]
+    fn test_jit_very_deep_tree() {
+        let e = spilling_chain(200, &[Operator::Sub, Operator::BitXor, Operator::Gt]);
+        test_expr_range(&e, -2..=2);
+    }
+
     // ==================== Edge Cases ====================
 
     #[test]
     fn test_jit_div_by_zero() {
-        let e = binary(var_x(), Operator::Div, lit(0));
+        let e = bin(build::x(), Operator::Div, lit(0));
         let jit = jit_compile_expr(&e);
         let f = jit.func();
         let mut x = 10;
@@ -1661,7 +3319,7 @@ mod tests {
 
     #[test]
     fn test_jit_mod_by_zero() {
-        let e = binary(var_x(), Operator::Mod, lit(0));
+        let e = bin(build::x(), Operator::Mod, lit(0));
         let jit = jit_compile_expr(&e);
         let f = jit.func();
         let mut x = 10;
@@ -1674,9 +3332,9 @@ mod tests {
     #[test]
     fn test_jit_multiple_compilations() {
         // Test that we can compile multiple expressions
-        let e1 = binary(var_x(), Operator::Add, var_y());
-        let e2 = binary(var_x(), Operator::Mul, var_y());
-        let e3 = binary(var_x(), Operator::Sub, var_y());
+        let e1 = bin(build::x(), Operator::Add, build::y());
+        let e2 = bin(build::x(), Operator::Mul, build::y());
+        let e3 = bin(build::x(), Operator::Sub, build::y());
 
         let jit1 = jit_compile_expr(&e1);
         let jit2 = jit_compile_expr(&e2);
@@ -1696,7 +3354,7 @@ mod tests {
 
     #[test]
     fn test_jit_finalize_required() {
-        let e = binary(var_x(), Operator::Add, var_y());
+        let e = bin(build::x(), Operator::Add, build::y());
         let mut jit = Jit::new(4096);
         jit.compile(&e);
         // Not calling finalize should cause panic when calling func()
@@ -1711,7 +3369,7 @@ mod tests {
     #[test]
     #[should_panic(expected = "JIT not finalized")]
     fn test_jit_func_without_finalize_panics() {
-        let e = binary(var_x(), Operator::Add, var_y());
+        let e = bin(build::x(), Operator::Add, build::y());
         let mut jit = Jit::new(4096);
         jit.compile(&e);
         // This should panic because finalize() wasn't called
@@ -1,11 +1,64 @@
-use std::i64;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::cmp::Ordering;
+use std::fmt;
 use std::hash::{Hash, Hasher};
 
 pub mod jit;
+pub mod build;
+pub mod catalog;
+pub mod checkpoint;
+pub mod bytecode;
+pub mod c;
+pub mod config;
+pub mod context;
+pub mod dot;
+pub mod generate;
+pub mod ir;
+pub mod python;
+pub mod reference;
+pub mod regalloc;
+pub mod search;
+pub mod sequence;
+pub mod table;
+pub mod visit;
+
+pub use bytecode::{run_bytecode, to_bytecode, BytecodeError};
+pub use c::{to_c, C_PRELUDE};
+pub use catalog::dump_catalog;
+pub use checkpoint::{resume_from, save_checkpoint, Checkpoint, FoundPair};
+pub use config::{Arithmetic, AssignOp, BinaryOp, DivMode, IncDecOp, SearchConfig, UnaryOp};
+pub use context::{insert_canonical, Cache, Context};
+pub use dot::to_dot;
+pub use jit::compile_fn;
+pub use generate::{build_cache, make_expr, make_statement, make_ternary};
+pub use python::to_python;
+pub use search::{search_each, search_length, search_with, PairMatcher, SequenceMatcher};
+pub use sequence::{combined_step, find_recurrences, is_seed_robust, run_sequence, run_sequence_every, sequence_prefix, sequence_to_csv, step_pair, is_total, verify_extends, Recurrence, TransitionMode, MAX_PREFIX_TERMS};
+pub use table::{fit_table, search_table, TableMatcher};
+pub use visit::{map, substitute, visit};
 
 pub type NumT = i64;
 
+/// How a node computes beyond what its operator says. Generated nodes get
+/// theirs from the config (see `SearchConfig::op_mode`); each mode only
+/// applies to some operators, and every other node is `Plain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum Mode {
+    /// Wrapping arithmetic and a plain `**`
+    #[default]
+    Plain,
+    /// `+`, `-`, `*` and their assignments clamp at i64::MIN/MAX (see `Arithmetic`)
+    Saturating,
+    /// `/`, `%` and their assignments leave a remainder that's never
+    /// negative, rounding the quotient to match (see `DivMode`)
+    Euclidean,
+    /// Comparisons, `<?`, `>?`, `>>`, `/`, `%` and the assignment forms of
+    /// these read their operands as u64 (see `SearchConfig::unsigned`)
+    Unsigned,
+    /// `**` reduces modulo this, which is positive (see `SearchConfig::pow_modulus`)
+    PowMod(NumT),
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Operator {
@@ -21,6 +74,10 @@ pub enum Operator {
     MulEq = 0x08,
     DivEq = 0x09,
     ModEq = 0x0A,
+    // Sequence operator (evaluate left, yield right)
+    Comma = 0x10,
+    // Conditional operator (cond ? mid : right)
+    Ternary = 0x1F,
     // Binary operators
     Or = 0x20,
     And = 0x30,
@@ -33,14 +90,18 @@ pub enum Operator {
     Leq = 0x81,
     Gt = 0x82,
     Geq = 0x83,
+    Min = 0x84,  // <? (GNU C++ minimum operator)
+    Max = 0x85,  // >? (GNU C++ maximum operator)
     BitShl = 0x90,
     BitShr = 0x91,
+    LShr = 0x92, // >>> (logical shift right, as in JS)
     Add = 0xA0,
     Sub = 0xA1,
     Mul = 0xB0,
     Div = 0xB1,
     Mod = 0xB2,
     Pow = 0xB3,  // **
+    Gcd = 0xB4,  // %% (greatest common divisor, not C)
     // Unary operators
     Neg = 0xC0,
     BitNot = 0xC1,
@@ -48,6 +109,7 @@ pub enum Operator {
     // Pre-increment/decrement operators
     PreInc = 0xC3,
     PreDec = 0xC4,
+    PopCount = 0xC5, // # (number of set bits, not C)
     // Post-increment/decrement operators
     PostInc = 0xD0,
     PostDec = 0xD1,
@@ -60,6 +122,24 @@ pub enum Operator {
 }
 
 impl Operator {
+    /// Every operator, in discriminant order
+    pub const ALL: &'static [Operator] = &[
+        Operator::AssignEq, Operator::BitOrEq, Operator::BitXorEq, Operator::BitAndEq,
+        Operator::BitShlEq, Operator::BitShrEq, Operator::AddEq, Operator::SubEq, Operator::MulEq,
+        Operator::DivEq, Operator::ModEq, Operator::Comma, Operator::Ternary, Operator::Or,
+        Operator::And, Operator::BitOr, Operator::BitXor, Operator::BitAnd, Operator::Eq,
+        Operator::Neq, Operator::Lt, Operator::Leq, Operator::Gt, Operator::Geq, Operator::Min,
+        Operator::Max, Operator::BitShl, Operator::BitShr, Operator::LShr, Operator::Add, Operator::Sub,
+        Operator::Mul, Operator::Div, Operator::Mod, Operator::Pow, Operator::Gcd, Operator::Neg, Operator::BitNot,
+        Operator::Not, Operator::PreInc, Operator::PreDec, Operator::PopCount, Operator::PostInc, Operator::PostDec,
+        Operator::Parens, Operator::Var, Operator::VarY, Operator::Literal,
+    ];
+
+    /// `ALL` by value, e.g. to go through every operator's `symbol`
+    pub fn all() -> impl Iterator<Item = Operator> {
+        Operator::ALL.iter().copied()
+    }
+
     pub fn from_u8(n: u8) -> Option<Operator> {
         match n {
             0x00 => Some(Operator::AssignEq),
@@ -73,6 +153,8 @@ impl Operator {
             0x08 => Some(Operator::MulEq),
             0x09 => Some(Operator::DivEq),
             0x0A => Some(Operator::ModEq),
+            0x10 => Some(Operator::Comma),
+            0x1F => Some(Operator::Ternary),
             0x20 => Some(Operator::Or),
             0x30 => Some(Operator::And),
             0x40 => Some(Operator::BitOr),
@@ -84,19 +166,24 @@ impl Operator {
             0x81 => Some(Operator::Leq),
             0x82 => Some(Operator::Gt),
             0x83 => Some(Operator::Geq),
+            0x84 => Some(Operator::Min),
+            0x85 => Some(Operator::Max),
             0x90 => Some(Operator::BitShl),
             0x91 => Some(Operator::BitShr),
+            0x92 => Some(Operator::LShr),
             0xA0 => Some(Operator::Add),
             0xA1 => Some(Operator::Sub),
             0xB0 => Some(Operator::Mul),
             0xB1 => Some(Operator::Div),
             0xB2 => Some(Operator::Mod),
             0xB3 => Some(Operator::Pow),
+            0xB4 => Some(Operator::Gcd),
             0xC0 => Some(Operator::Neg),
             0xC1 => Some(Operator::BitNot),
             0xC2 => Some(Operator::Not),
             0xC3 => Some(Operator::PreInc),
             0xC4 => Some(Operator::PreDec),
+            0xC5 => Some(Operator::PopCount),
             0xD0 => Some(Operator::PostInc),
             0xD1 => Some(Operator::PostDec),
             0xE0 => Some(Operator::Parens),
@@ -106,156 +193,604 @@ impl Operator {
             _ => None,
         }
     }
+
+    /// The operator by its variant name, e.g. `"AddEq"`
+    pub fn from_name(name: &str) -> Option<Operator> {
+        Operator::all().find(|op| format!("{:?}", op) == name)
+    }
+
+    /// How the operator is spelled in C. Empty for variables and literals,
+    /// whose text depends on the node.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Operator::AssignEq => "=",
+            Operator::BitOrEq => "|=",
+            Operator::BitXorEq => "^=",
+            Operator::BitAndEq => "&=",
+            Operator::BitShlEq => "<<=",
+            Operator::BitShrEq => ">>=",
+            Operator::AddEq => "+=",
+            Operator::SubEq => "-=",
+            Operator::MulEq => "*=",
+            Operator::DivEq => "/=",
+            Operator::ModEq => "%=",
+            Operator::Comma => ",",
+            Operator::Ternary => "?:",
+            Operator::Or => "||",
+            Operator::And => "&&",
+            Operator::BitOr => "|",
+            Operator::BitXor => "^",
+            Operator::BitAnd => "&",
+            Operator::Eq => "==",
+            Operator::Neq => "!=",
+            Operator::Lt => "<",
+            Operator::Leq => "<=",
+            Operator::Gt => ">",
+            Operator::Geq => ">=",
+            Operator::Min => "<?",
+            Operator::Max => ">?",
+            Operator::BitShl => "<<",
+            Operator::BitShr => ">>",
+            Operator::LShr => ">>>",
+            Operator::Add => "+",
+            Operator::Sub => "-",
+            Operator::Mul => "*",
+            Operator::Div => "/",
+            Operator::Mod => "%",
+            Operator::Pow => "**",
+            Operator::Gcd => "%%",
+            Operator::Neg => "-",
+            Operator::BitNot => "~",
+            Operator::Not => "!",
+            Operator::PopCount => "#",
+            Operator::PreInc | Operator::PostInc => "++",
+            Operator::PreDec | Operator::PostDec => "--",
+            Operator::Parens => "()",
+            Operator::Var | Operator::VarY | Operator::Literal => "",
+        }
+    }
+}
+
+/// Whether `a op b` equals `b op a`. Only the values are swapped: operands
+/// with side effects (`x++`) or short-circuiting (`||`, `&&`) still evaluate
+/// left to right, so swapping those can change the result.
+pub fn is_commutative(op: Operator) -> bool {
+    matches!(
+        op,
+        Operator::Add | Operator::Mul | Operator::BitOr | Operator::BitXor | Operator::BitAnd
+            | Operator::Or | Operator::And | Operator::Eq | Operator::Neq | Operator::Min | Operator::Max
+            | Operator::Gcd
+    )
+}
+
+/// Whether `(a op b) op c` equals `a op (b op c)`, with the same caveat as
+/// `is_commutative`. Wrapping `+` and `*` are; a saturating node isn't.
+pub fn is_associative(op: Operator) -> bool {
+    matches!(
+        op,
+        Operator::Add | Operator::Mul | Operator::BitOr | Operator::BitXor | Operator::BitAnd
+            | Operator::Or | Operator::And | Operator::Min | Operator::Max | Operator::Gcd
+    )
+}
+
+/// What kind of node an operator makes, i.e. which children it takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCategory {
+    /// `x = e`, `x += e`, ...: an lvalue on the left
+    Assignment,
+    /// Infix operators, including `,` and `?:` (which also takes a mid)
+    Binary,
+    /// `-e`, `~e`, `!e`
+    Unary,
+    /// `++x`, `--x`, `x++`, `x--`
+    IncDec,
+    Paren,
+    /// Variables and literals, which have no children
+    Operand,
+}
+
+pub fn category(op: Operator) -> OpCategory {
+    match op {
+        _ if (op as u8) < Operator::Comma as u8 => OpCategory::Assignment,
+        Operator::Neg | Operator::BitNot | Operator::Not | Operator::PopCount => OpCategory::Unary,
+        Operator::PreInc | Operator::PreDec | Operator::PostInc | Operator::PostDec => OpCategory::IncDec,
+        Operator::Parens => OpCategory::Paren,
+        Operator::Var | Operator::VarY | Operator::Literal => OpCategory::Operand,
+        _ => OpCategory::Binary,
+    }
+}
+
+/// How tightly the operator binds, higher is tighter: the high nibble of
+/// its discriminant. Assignments are 0, `,` and `?:` share 1, the binary
+/// operators run 2 (`||`) to 11 (`*`), then prefix unary, postfix, parens
+/// and operands. Operators with the same class group the same way.
+pub fn precedence_class(op: Operator) -> u8 {
+    op as u8 >> 4
 }
 
-#[derive(Debug)]
 pub struct Expr {
     pub left: Option<Arc<Expr>>,
+    pub mid: Option<Arc<Expr>>, // Only used by Ternary
     pub right: Option<Arc<Expr>>,
-    pub literal: NumT,
+    pub literal: NumT, // Only read for Operator::Literal
+    pub mode: Mode,
     pub op: Operator,
     pub jit: Option<Arc<jit::Jit>>,
+    pub bytecode: Option<Arc<[u8]>>, // For statements the search runs without a Jit
 }
 
-impl PartialEq for Expr {
-    fn eq(&self, other: &Self) -> bool {
-        // Semantic equivalence check
-        let range = 4;
-        
-        // If JIT is available, use it
-        if let (Some(jit1), Some(jit2)) = (&self.jit, &other.jit) {
-            let f1 = jit1.func();
-            let f2 = jit2.func();
-            
-            for x_val in -range..=range {
-                for y_val in -range..=range {
-                     let mut x = x_val;
-                     let mut y = y_val;
-                     // JIT signature: fn(*mut i64, *mut i64) -> i64
-                     let r1 = unsafe { f1(&mut x, &mut y) };
-                     let x1 = x; let y1 = y;
-
-                     x = x_val; y = y_val;
-                     let r2 = unsafe { f2(&mut x, &mut y) };
-                     let x2 = x; let y2 = y;
-
-                     if r1 != r2 || x1 != x2 || y1 != y2 {
-                         return false;
-                     }
-                }
+/// `{:?}` is the formula and its root's op, with its mode unless that's
+/// `Plain`, e.g. `x+=y*2 (AddEq)` or `x+y (Add, Saturating)`. `{:#?}` is the
+/// tree, leaving out the compiled code. Only literals show their `literal`.
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !f.alternate() {
+            write!(f, "{} ({:?}", format_expression(self, &['x', 'y']), self.op)?;
+            if self.mode != Mode::Plain {
+                write!(f, ", {:?}", self.mode)?;
+            }
+            return write!(f, ")");
+        }
+        let mut s = f.debug_struct("Expr");
+        s.field("op", &self.op);
+        if self.op == Operator::Literal {
+            s.field("literal", &self.literal);
+        }
+        if self.mode != Mode::Plain {
+            s.field("mode", &self.mode);
+        }
+        for (name, child) in [("left", &self.left), ("mid", &self.mid), ("right", &self.right)] {
+            if let Some(child) = child {
+                s.field(name, child);
             }
-            return true;
         }
+        s.finish_non_exhaustive()
+    }
+}
 
-        // Fallback to naive
-        for x_val in -range..=range {
-            for y_val in -range..=range {
-                let mut x1 = x_val;
-                let mut y1 = y_val;
-                let mut f1 = false;
-                let r1 = naive_eval(self, &mut x1, &mut y1, &mut f1);
-
-                let mut x2 = x_val;
-                let mut y2 = y_val;
-                let mut f2 = false;
-                let r2 = naive_eval(other, &mut x2, &mut y2, &mut f2);
-
-                if r1 != r2 || x1 != x2 || y1 != y2 {
-                    return false;
-                }
+/// x and y each run over -GRID_RANGE..=GRID_RANGE for `fingerprint`,
+/// unless `set_grid` chose other points
+const GRID_RANGE: NumT = 4;
+
+static GRID: OnceLock<Vec<(NumT, NumT)>> = OnceLock::new();
+
+/// Fingerprint expressions from `points` instead of the default square, e.g.
+/// for ones that only differ for large or odd inputs. `==`, `Hash` and so
+/// every cache go through the fingerprint, and fingerprints over different
+/// grids can't be compared, so a run must set this once, before anything is
+/// compared. Err if the grid was already set or used, or `points` is empty.
+pub fn set_grid(points: Vec<(NumT, NumT)>) -> Result<(), String> {
+    if points.is_empty() {
+        return Err("the grid needs at least one point".to_string());
+    }
+    GRID.set(points).map_err(|_| "the grid was already set or used".to_string())
+}
+
+/// The starting (x, y) of each grid point: those given to `set_grid`, else
+/// -GRID_RANGE..=GRID_RANGE row by row
+pub fn sample_grid() -> &'static [(NumT, NumT)] {
+    GRID.get_or_init(|| grid_points_in(GRID_RANGE).collect())
+}
+
+fn grid_points() -> impl Iterator<Item = (NumT, NumT)> {
+    sample_grid().iter().copied()
+}
+
+/// Every (x, y) with both in -range..=range, row by row
+fn grid_points_in(range: NumT) -> impl Iterator<Item = (NumT, NumT)> {
+    (-range..=range).flat_map(move |x0| (-range..=range).map(move |y0| (x0, y0)))
+}
+
+/// What an expression does over the grid: its value, x and y after running
+/// from every point, and which points are fatal, hashed down to 192 bits.
+/// Two expressions that differ somewhere on the grid all but surely get
+/// different fingerprints, but it's only as discriminating as the grid: ones
+/// that agree at every point of `sample_grid` share one, whatever they do
+/// elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u64; 3]);
+
+/// 48 hex digits
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}{:016x}{:016x}", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+/// Odd multipliers for the three lanes, so each step is a bijection on its lane
+const LANE_MUL: [u64; 3] = [0x9E37_79B9_7F4A_7C15, 0xC2B2_AE3D_27D4_EB4F, 0x1656_67B1_9E37_79F9];
+
+/// The splitmix64 finalizer, so small neighbouring values spread over all bits
+fn mix(mut w: u64) -> u64 {
+    w = (w ^ (w >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    w = (w ^ (w >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    w ^ (w >> 31)
+}
+
+/// Fingerprint of `e` over the grid, run through its JIT, bytecode or naive_eval
+pub fn fingerprint(e: &Expr) -> Fingerprint {
+    fingerprint_over(e, sample_grid())
+}
+
+/// Fingerprint of `e` run from `points` rather than the grid
+pub fn fingerprint_over(e: &Expr, points: &[(NumT, NumT)]) -> Fingerprint {
+    let mut lanes = [0u64; 3];
+    let mut feed = |w: u64| {
+        let m = mix(w);
+        for (i, lane) in lanes.iter_mut().enumerate() {
+            *lane = (*lane ^ m.rotate_left(21 * i as u32)).wrapping_mul(LANE_MUL[i]).rotate_left(29);
+        }
+    };
+    // Each point starts with a tag, so a fatal one can't be mistaken for any
+    // value it might have left behind
+    for point in e.grid_over(points.iter().copied()) {
+        match point {
+            Some((r, x, y)) => {
+                feed(0);
+                feed(r as u64);
+                feed(x as u64);
+                feed(y as u64);
             }
+            None => feed(1),
         }
-        true
+    }
+    Fingerprint(lanes)
+}
+
+/// Semantic equivalence: the same fingerprint, so the same value, x and y at
+/// every point of the grid, and fatal at the same points
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        fingerprint(self) == fingerprint(other)
     }
 }
 
 impl Eq for Expr {}
 
+/// The first starting (x, y), row by row over -range..=range, where `a` and
+/// `b` end with a different value, x or y, or only one of them is fatal.
+/// None if they agree on all of it. With GRID_RANGE and the default grid,
+/// this finds where two expressions that aren't `==` part ways.
+pub fn first_difference(a: &Expr, b: &Expr, range: NumT) -> Option<(NumT, NumT)> {
+    let (pa, pb) = (a.grid_over(grid_points_in(range)), b.grid_over(grid_points_in(range)));
+    grid_points_in(range).zip(pa.zip(pb)).find(|(_, (pa, pb))| pa != pb).map(|(p, _)| p)
+}
+
+/// Shares the children and the compiled code. Both are immutable, and the
+/// code was built from this very node, so it stays right for the copy.
+/// Use `without_code` for a copy that's about to be edited.
+impl Clone for Expr {
+    fn clone(&self) -> Self {
+        Expr {
+            left: self.left.clone(),
+            mid: self.mid.clone(),
+            right: self.right.clone(),
+            literal: self.literal,
+            mode: self.mode,
+            op: self.op,
+            jit: self.jit.clone(),
+            bytecode: self.bytecode.clone(),
+        }
+    }
+}
+
+/// Whether `a` and `b` are the same tree: same ops, modes, literals and shape,
+/// whatever their compiled code. `==` only compares values over the grid.
+pub fn structural_eq(a: &Expr, b: &Expr) -> bool {
+    let same = |l: &Option<Arc<Expr>>, r: &Option<Arc<Expr>>| match (l, r) {
+        (Some(l), Some(r)) => Arc::ptr_eq(l, r) || structural_eq(l, r),
+        (None, None) => true,
+        _ => false,
+    };
+    a.op == b.op && a.mode == b.mode && a.literal == b.literal && same(&a.left, &b.left) && same(&a.mid, &b.mid) && same(&a.right, &b.right)
+}
+
+/// An expression keyed by its tree rather than its values, for sets and maps
+/// that should keep x+y and y+x apart. `Expr`'s own `==` and `Hash` are the
+/// semantic ones the search dedups with.
+#[derive(Debug, Clone)]
+pub struct StructuralExpr(pub Arc<Expr>);
+
+impl PartialEq for StructuralExpr {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || structural_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for StructuralExpr {}
+
+/// Ops in prefix order, with their modes and literals. Each op's arity is
+/// fixed, so that's enough to tell the shapes apart.
+impl Hash for StructuralExpr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        visit(&self.0, &mut |e| {
+            (e.op as u8).hash(state);
+            e.mode.hash(state);
+            e.literal.hash(state);
+        });
+    }
+}
+
+/// A total order on trees: fewer nodes first (so the literal -1 beats -1
+/// negated), then by op, mode, literal, and children left to right. Dedup keeps
+/// the least of each class, so which representative survives doesn't depend
+/// on the order expressions were generated or merged in.
+pub fn structural_cmp(a: &Expr, b: &Expr) -> Ordering {
+    let size = |e: &Expr| {
+        let mut n = 0;
+        visit(e, &mut |_| n += 1);
+        n
+    };
+    size(a).cmp(&size(b)).then_with(|| same_size_cmp(a, b))
+}
+
+fn same_size_cmp(a: &Expr, b: &Expr) -> Ordering {
+    let child = |l: &Option<Arc<Expr>>, r: &Option<Arc<Expr>>| match (l, r) {
+        (Some(l), Some(r)) if Arc::ptr_eq(l, r) => Ordering::Equal,
+        (Some(l), Some(r)) => structural_cmp(l, r),
+        _ => l.is_some().cmp(&r.is_some()),
+    };
+    (a.op as u8).cmp(&(b.op as u8))
+        .then(a.mode.cmp(&b.mode))
+        .then(a.literal.cmp(&b.literal))
+        .then_with(|| child(&a.left, &b.left))
+        .then_with(|| child(&a.mid, &b.mid))
+        .then_with(|| child(&a.right, &b.right))
+}
+
 impl Hash for Expr {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        let range = 4;
-        
-        if let Some(jit) = &self.jit {
-            let f = jit.func();
-            for x_val in -range..=range {
-                for y_val in -range..=range {
-                    let mut x = x_val;
-                    let mut y = y_val;
-                    let r = unsafe { f(&mut x, &mut y) };
-                    
-                    r.hash(state);
-                    x.hash(state);
-                    y.hash(state);
+        fingerprint(self).hash(state);
+    }
+}
+
+impl Expr {
+    /// A copy with no Jit or bytecode, so changing its op, literal or
+    /// children can't leave it running stale code
+    pub fn without_code(&self) -> Expr {
+        Expr { jit: None, bytecode: None, ..self.clone() }
+    }
+
+    pub fn is_assignment(&self) -> bool {
+        category(self.op) == OpCategory::Assignment
+    }
+
+    pub fn is_incdec(&self) -> bool {
+        category(self.op) == OpCategory::IncDec
+    }
+
+    /// Whether this node can be written to, i.e. is a variable
+    pub fn is_lvalue(&self) -> bool {
+        matches!(self.op, Operator::Var | Operator::VarY)
+    }
+
+    /// Whether `var` (`Var` or `VarY`) appears anywhere in the tree, read or written
+    pub fn uses_var(&self, var: Operator) -> bool {
+        self.op == var || [&self.left, &self.mid, &self.right].into_iter().flatten().any(|c| c.uses_var(var))
+    }
+
+    /// Whether an assignment or `++`/`--` anywhere in the tree writes `var`
+    pub fn writes_var(&self, var: Operator) -> bool {
+        let mut writes = false;
+        visit(self, &mut |e| {
+            let target = if e.is_assignment() { &e.left } else if e.is_incdec() { &e.right } else { &None };
+            writes |= target.as_ref().is_some_and(|t| t.op == var);
+        });
+        writes
+    }
+
+    /// The variables the tree mentions as a bitmask, one bit per variable by
+    /// the low nibble of its operator: 1 for `Var`, 2 for `VarY`
+    pub fn referenced_vars(&self) -> u8 {
+        let own = if self.is_lvalue() { 1 << (self.op as u8 & 0x0F) } else { 0 };
+        [&self.left, &self.mid, &self.right].into_iter().flatten().fold(own, |vars, c| vars | c.referenced_vars())
+    }
+
+    /// For assignments and ++/--, whether the written operand is not a variable.
+    /// Such nodes (e.g. `++(x+1)`) are fatal in naive_eval and the JIT.
+    pub fn has_bad_target(&self) -> bool {
+        let target = if self.is_assignment() {
+            &self.left
+        } else if self.is_incdec() {
+            &self.right
+        } else {
+            return false;
+        };
+        !target.as_ref().is_some_and(|t| t.is_lvalue())
+    }
+
+    /// The modulus of a `Pow` node in `Mode::PowMod`
+    pub fn pow_modulus(&self) -> Option<NumT> {
+        match self.mode {
+            Mode::PowMod(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a `Pow` that reduces modulo its `pow_modulus`
+    pub fn is_pow_mod(&self) -> bool {
+        self.pow_modulus().is_some()
+    }
+
+    /// Whether `op` clamps instead of wrapping in `Mode::Saturating`
+    pub fn can_saturate(op: Operator) -> bool {
+        matches!(op, Operator::Add | Operator::Sub | Operator::Mul | Operator::AddEq | Operator::SubEq | Operator::MulEq)
+    }
+
+    /// An arithmetic node in `Mode::Saturating` clamps at i64::MIN/MAX
+    pub fn is_saturating(&self) -> bool {
+        self.mode == Mode::Saturating
+    }
+
+    /// Whether `op` rounds the Euclidean way in `Mode::Euclidean`
+    pub fn can_be_euclidean(op: Operator) -> bool {
+        matches!(op, Operator::Div | Operator::Mod | Operator::DivEq | Operator::ModEq)
+    }
+
+    /// A `/` or `%` node in `Mode::Euclidean` leaves a remainder that's
+    /// never negative, rounding the quotient to match
+    pub fn is_euclidean(&self) -> bool {
+        self.mode == Mode::Euclidean
+    }
+
+    /// Whether `op` reads its operands as u64 in `Mode::Unsigned`
+    pub fn can_be_unsigned(op: Operator) -> bool {
+        matches!(
+            op,
+            Operator::Lt | Operator::Leq | Operator::Gt | Operator::Geq | Operator::Min | Operator::Max
+                | Operator::BitShr | Operator::Div | Operator::Mod
+                | Operator::BitShrEq | Operator::DivEq | Operator::ModEq
+        )
+    }
+
+    /// A node in `Mode::Unsigned` compares, shifts in zeros, or
+    /// divides as if both operands were u64. Only `/0` is fatal then.
+    pub fn is_unsigned(&self) -> bool {
+        self.mode == Mode::Unsigned
+    }
+
+    /// Whether the JIT can compile this tree; modular `Pow` is naive-only
+    pub fn is_jittable(&self) -> bool {
+        let mut jittable = true;
+        visit(self, &mut |e| jittable &= !e.is_pow_mod());
+        jittable
+    }
+
+    /// Whether some input could make this fatal: it divides, takes a
+    /// power or writes to a non-variable
+    fn can_be_fatal(&self) -> bool {
+        let mut fatal = false;
+        visit(self, &mut |e| {
+            fatal |= e.has_bad_target()
+                || matches!(e.op, Operator::Div | Operator::Mod | Operator::DivEq | Operator::ModEq | Operator::Pow);
+        });
+        fatal
+    }
+
+    /// The variable a statement's value ends up in: an assignment's target,
+    /// or that of the last statement in a comma
+    fn result_target(&self) -> Option<Operator> {
+        match self.op {
+            Operator::Comma => self.right.as_ref().and_then(|r| r.result_target()),
+            _ if self.is_assignment() && !self.has_bad_target() => self.left.as_ref().map(|t| t.op),
+            _ => None,
+        }
+    }
+
+    /// (value, x, y) after running from each of `grid_points`, or None where
+    /// that's fatal. The JIT's 0 and partial writes at a fatal point, and
+    /// whatever naive_eval carries on to compute, are all dropped, so the
+    /// fingerprint doesn't depend on which of them ran.
+    fn grid(&self) -> impl Iterator<Item = Option<(NumT, NumT, NumT)>> + '_ {
+        self.grid_over(grid_points())
+    }
+
+    /// `grid` from `points` rather than the equivalence grid
+    fn grid_over<'a>(
+        &'a self,
+        points: impl Iterator<Item = (NumT, NumT)> + 'a,
+    ) -> impl Iterator<Item = Option<(NumT, NumT, NumT)>> + 'a {
+        let jit = self.jit.as_ref().map(|jit| jit.func());
+        let can_be_fatal = jit.is_some() && self.can_be_fatal();
+        let target = self.result_target();
+        let naive = move |x: &mut NumT, y: &mut NumT| {
+            let mut fatal = false;
+            let r = naive_eval(self, x, y, &mut fatal);
+            (!fatal).then_some(r)
+        };
+
+        points.map(move |(x0, y0)| {
+            let (mut x, mut y) = (x0, y0);
+            let r = if let Some(f) = jit {
+                let r = unsafe { f(&mut x, &mut y) };
+                if r != 0 || !can_be_fatal {
+                    return Some((r, x, y));
                 }
+                // A fatal JIT run returns 0. A statement that finished leaves
+                // its value in its target, so a nonzero target means it bailed
+                // out; otherwise only naive_eval can tell.
+                let (mut nx, mut ny) = (x0, y0);
+                let fatal = match target {
+                    Some(Operator::Var) if x != 0 => true,
+                    Some(Operator::VarY) if y != 0 => true,
+                    _ => naive(&mut nx, &mut ny).is_none(),
+                };
+                if fatal { None } else { Some(0) }
+            } else if let Some(code) = &self.bytecode {
+                run_bytecode(code, &mut x, &mut y).expect("to_bytecode output is well-formed")
+            } else {
+                naive(&mut x, &mut y)
+            };
+            r.map(|r| (r, x, y))
+        })
+    }
+
+    /// The value this takes at every point of the equivalence grid (see `Fingerprint`),
+    /// if that is one constant, x and y are left alone and it's never fatal
+    pub fn grid_constant(&self) -> Option<NumT> {
+        let mut value = None;
+        for ((x0, y0), point) in grid_points().zip(self.grid()) {
+            let (r, x, y) = point?;
+            if x != x0 || y != y0 || value.is_some_and(|v| v != r) {
+                return None;
             }
-            return;
+            value = Some(r);
         }
+        value
+    }
+}
 
-        for x_val in -range..=range {
-            for y_val in -range..=range {
-                let mut x = x_val;
-                let mut y = y_val;
-                let mut fatal = false;
-                let r = naive_eval(self, &mut x, &mut y, &mut fatal);
-                
-                r.hash(state);
-                x.hash(state);
-                y.hash(state);
+fn write_node(out: &mut String, e: &Expr, var_names: &[char]) {
+    let symbol = match e.op {
+        // Written after the operand
+        Operator::PostInc | Operator::PostDec => "",
+        // Only the opening half goes before the children
+        Operator::Ternary => "?",
+        Operator::Parens => "(",
+        Operator::Literal => {
+            push_token(out, &e.literal.to_string());
+            return;
+        }
+        Operator::Var | Operator::VarY => {
+            let idx = (e.op as usize) & 0xF;
+            if idx < var_names.len() {
+                out.push(var_names[idx]);
             }
+            return;
         }
-    }
+        op => op.symbol(),
+    };
+    push_token(out, symbol);
 }
 
-impl Expr {
-    pub fn is_assignment(&self) -> bool {
-        (self.op as u8) < 0x10
+/// Append a token, with a space where it would otherwise merge into the lone
+/// `-` or `+` before it: `x- -5` and `x+ ++y`, not `x--5` and `x+++y`
+fn push_token(out: &mut String, token: &str) {
+    if let Some(sign @ ('-' | '+')) = token.chars().next() {
+        // An even run is all `--`/`++` tokens already, an odd one ends in a lone sign
+        let run = out.chars().rev().take_while(|&c| c == sign).count();
+        if run % 2 == 1 {
+            out.push(' ');
+        }
     }
+    out.push_str(token);
 }
 
-fn print_node(e: &Expr, var_names: &[char]) {
+/// The `+` or `-` that `e` prints starting with, if any. After the same sign
+/// it's spaced off (`x- -y`, `x+ ++y`), one char more than its parts add up to.
+pub(crate) fn leading_sign(e: &Expr) -> Option<char> {
     match e.op {
-        Operator::Or => print!("||"),
-        Operator::And => print!("&&"),
-        Operator::BitOr => print!("|"),
-        Operator::BitXor => print!("^"),
-        Operator::BitAnd => print!("&"),
-        Operator::Eq => print!("=="),
-        Operator::Neq => print!("!="),
-        Operator::Lt => print!("<"),
-        Operator::Leq => print!("<="),
-        Operator::Gt => print!(">"),
-        Operator::Geq => print!(">="),
-        Operator::BitShl => print!("<<"),
-        Operator::BitShr => print!(">>"),
-        Operator::Add => print!("+"),
-        Operator::Sub => print!("-"),
-        Operator::Mul => print!("*"),
-        Operator::Div => print!("/"),
-        Operator::Mod => print!("%"),
-        Operator::Pow => print!("**"),
-        Operator::Neg => print!("-"),
-        Operator::BitNot => print!("~"),
-        Operator::Not => print!("!"),
-        Operator::PreInc => print!("++"),
-        Operator::PreDec => print!("--"),
-        Operator::PostInc | Operator::PostDec => {}
-        Operator::Parens => print!("("),
-        Operator::Literal => print!("{}", e.literal),
-        Operator::AssignEq => print!("="),
-        Operator::BitOrEq => print!("|="),
-        Operator::BitXorEq => print!("^="),
-        Operator::BitAndEq => print!("&="),
-        Operator::BitShlEq => print!("<<="),
-        Operator::BitShrEq => print!(">>="),
-        Operator::AddEq => print!("+="),
-        Operator::SubEq => print!("-="),
-        Operator::MulEq => print!("*="),
-        Operator::DivEq => print!("/="),
-        Operator::ModEq => print!("%="),
-        Operator::Var | Operator::VarY => {
-            let idx = (e.op as usize) & 0xF;
-            if idx < var_names.len() {
-                print!("{}", var_names[idx]);
+        Operator::Neg | Operator::PreDec => Some('-'),
+        Operator::PreInc => Some('+'),
+        Operator::Literal => (e.literal < 0).then_some('-'),
+        Operator::Parens => e.right.as_deref().filter(|r| is_atomic(r.op)).and_then(leading_sign),
+        _ => {
+            let left = e.left.as_deref()?;
+            let negative_base = e.op == Operator::Pow && left.op == Operator::Literal && left.literal < 0;
+            if needs_parens(left.op, e.op, false) || negative_base {
+                None
+            } else {
+                leading_sign(left)
             }
         }
     }
@@ -263,30 +798,39 @@ fn print_node(e: &Expr, var_names: &[char]) {
 
 /// Check if operator needs parentheses when used as child of parent_op
 fn needs_parens(child_op: Operator, parent_op: Operator, is_right: bool) -> bool {
-    // Assignment operators don't need parens around their operands
+    // Assignment operators don't need parens around their operands, except a comma
     match parent_op {
         Operator::AssignEq | Operator::AddEq | Operator::SubEq | 
         Operator::MulEq | Operator::DivEq | Operator::ModEq |
         Operator::BitOrEq | Operator::BitXorEq | Operator::BitAndEq |
-        Operator::BitShlEq | Operator::BitShrEq => return false,
+        Operator::BitShlEq | Operator::BitShrEq => return child_op == Operator::Comma,
+        // Parens already supplies the pair
+        Operator::Parens => return false,
         _ => {}
     }
     
     // Get precedence levels (higher = binds tighter)
     let prec = |op: Operator| -> u8 {
         match op {
-            Operator::Or => 1,
-            Operator::And => 2,
-            Operator::BitOr => 3,
-            Operator::BitXor => 4,
-            Operator::BitAnd => 5,
-            Operator::Eq | Operator::Neq => 6,
-            Operator::Lt | Operator::Leq | Operator::Gt | Operator::Geq => 7,
-            Operator::BitShl | Operator::BitShr => 8,
-            Operator::Add | Operator::Sub => 9,
-            Operator::Mul | Operator::Div | Operator::Mod => 10,
-            Operator::Pow => 11,  // Highest binary precedence
-            _ => 100,  // Variables, literals, unary, assignment - don't need parens
+            Operator::Comma => 0,  // Lowest precedence
+            Operator::Ternary => 1,
+            Operator::AssignEq | Operator::AddEq | Operator::SubEq |
+            Operator::MulEq | Operator::DivEq | Operator::ModEq |
+            Operator::BitOrEq | Operator::BitXorEq | Operator::BitAndEq |
+            Operator::BitShlEq | Operator::BitShrEq => 1,
+            Operator::Or => 2,
+            Operator::And => 3,
+            Operator::BitOr => 4,
+            Operator::BitXor => 5,
+            Operator::BitAnd => 6,
+            Operator::Eq | Operator::Neq => 7,
+            Operator::Lt | Operator::Leq | Operator::Gt | Operator::Geq => 8,
+            Operator::Min | Operator::Max => 8,
+            Operator::BitShl | Operator::BitShr | Operator::LShr => 9,
+            Operator::Add | Operator::Sub => 10,
+            Operator::Mul | Operator::Div | Operator::Mod | Operator::Gcd => 11,
+            Operator::Pow => 12,  // Highest binary precedence
+            _ => 100,  // Variables, literals, unary - don't need parens
         }
     };
     
@@ -294,58 +838,220 @@ fn needs_parens(child_op: Operator, parent_op: Operator, is_right: bool) -> bool
     let parent_prec = prec(parent_op);
     
     // Need parens if child has lower precedence than parent
-    // For right-associative Pow, also need parens for same precedence on left
+    // For right-associative Pow and Ternary, also need parens for same precedence on left
     if child_prec < parent_prec {
         return true;
     }
-    if parent_op == Operator::Pow && !is_right && child_prec == parent_prec {
+    if matches!(parent_op, Operator::Pow | Operator::Ternary) && !is_right && child_prec == parent_prec {
+        return true;
+    }
+    // The rest group left to right, so x-(y+1) keeps its parens
+    if parent_op != Operator::Ternary && parent_prec < prec(Operator::Pow) && is_right && child_prec == parent_prec {
+        return true;
+    }
+    // -x**2 reads as -(x**2), so a prefix unary base keeps its parens
+    if parent_op == Operator::Pow && !is_right && is_prefix_unary(child_op) {
         return true;
     }
     false
 }
 
+fn is_prefix_unary(op: Operator) -> bool {
+    matches!(op, Operator::Neg | Operator::BitNot | Operator::Not | Operator::PopCount | Operator::PreInc | Operator::PreDec)
+}
+
+/// Unary results, variables, literals and parens never need wrapping
+fn is_atomic(op: Operator) -> bool {
+    precedence_class(op) >= precedence_class(Operator::Neg)
+}
+
+/// Length of the expression as printed, i.e. its golf score
+pub fn printed_len(e: &Expr) -> usize {
+    format_expression(e, &['x', 'y']).len()
+}
+
 pub fn print_expression(e: &Expr, var_names: &[char]) {
-    print_expr_with_parent(e, var_names, None, false);
+    print!("{}", format_expression(e, var_names));
 }
 
-fn print_expr_with_parent(e: &Expr, var_names: &[char], parent_op: Option<Operator>, is_right: bool) {
-    let wrap = parent_op.map(|p| needs_parens(e.op, p, is_right)).unwrap_or(false);
+/// The expression as source text, with the parentheses precedence requires
+pub fn format_expression(e: &Expr, var_names: &[char]) -> String {
+    let mut out = String::new();
+    write_expr(&mut out, e, var_names, None, false);
+    out
+}
+
+fn write_expr(out: &mut String, e: &Expr, var_names: &[char], parent_op: Option<Operator>, is_right: bool) {
+    // Parens around Parens or an atom adds nothing; the child still gets
+    // whatever parens its own position calls for
+    if e.op == Operator::Parens {
+        if let Some(inner) = e.right.as_ref().filter(|r| is_atomic(r.op)) {
+            return write_expr(out, inner, var_names, parent_op, is_right);
+        }
+    }
+
+    let wrap = parent_op.map(|p| {
+        // A negative literal is a leading minus too: (-5)**x
+        let negative_base = p == Operator::Pow && !is_right && e.op == Operator::Literal && e.literal < 0;
+        needs_parens(e.op, p, is_right) || negative_base
+    }).unwrap_or(false);
     
     if wrap {
-        print!("(");
+        out.push('(');
     }
-    
+
+    // A postfix operand is in `right` like any unary one; evaluation only
+    // reads it there, so one in `left` would print a different tree
+    if matches!(e.op, Operator::PostInc | Operator::PostDec) {
+        debug_assert!(e.left.is_none(), "{:?} takes its operand in right, not left", e.op);
+        if let Some(operand) = e.right.as_ref() {
+            write_expr(out, operand, var_names, Some(e.op), true);
+        }
+        out.push_str(e.op.symbol());
+        if wrap {
+            out.push(')');
+        }
+        return;
+    }
+
     if let Some(ref left) = e.left {
-        print_expr_with_parent(left, var_names, Some(e.op), false);
+        write_expr(out, left, var_names, Some(e.op), false);
     }
-    print_node(e, var_names);
-    if let Some(ref right) = e.right {
-        print_expr_with_parent(right, var_names, Some(e.op), true);
-        if e.op == Operator::Parens {
-            print!(")");
-        }
+    write_node(out, e, var_names);
+    if let Some(ref mid) = e.mid {
+        // Anything goes between '?' and ':', so treat it like a right operand
+        write_expr(out, mid, var_names, Some(e.op), true);
+        out.push(':');
     }
-    if e.op == Operator::PostInc {
-        print!("++");
+    if let Some(ref right) = e.right {
+        write_expr(out, right, var_names, Some(e.op), true);
     }
-    if e.op == Operator::PostDec {
-        print!("--");
+    if e.op == Operator::Parens {
+        out.push(')');
     }
     
     if wrap {
-        print!(")");
+        out.push(')');
+    }
+}
+
+/// `base**exp` for negative `exp`, None for the fatal `0**-n`
+fn pow_negative_exp(base: NumT, exp: NumT) -> Option<NumT> {
+    match base {
+        0 => None,
+        1 => Some(1),
+        -1 => Some(if exp % 2 == 0 { 1 } else { -1 }),
+        _ => Some(0),  // |base| > 1 with negative exponent -> 0 in integer math
+    }
+}
+
+/// `l op r` for a saturating node, clamped at i64::MIN/MAX
+pub(crate) fn saturating_op(op: Operator, l: NumT, r: NumT) -> NumT {
+    match op {
+        Operator::Add | Operator::AddEq => l.saturating_add(r),
+        Operator::Sub | Operator::SubEq => l.saturating_sub(r),
+        Operator::Mul | Operator::MulEq => l.saturating_mul(r),
+        _ => unreachable!("{:?} can't saturate", op),
+    }
+}
+
+/// `l op r` for a Euclidean node, None (fatal) wherever truncating `/` is:
+/// `r == 0` and `MIN / -1`
+pub(crate) fn euclidean_op(op: Operator, l: NumT, r: NumT) -> Option<NumT> {
+    match op {
+        Operator::Div | Operator::DivEq => l.checked_div_euclid(r),
+        Operator::Mod | Operator::ModEq => l.checked_rem_euclid(r),
+        _ => unreachable!("{:?} isn't a division", op),
+    }
+}
+
+/// `l op r` for an unsigned node, None (fatal) for `r == 0` in `/` and `%`
+pub(crate) fn unsigned_op(op: Operator, l: NumT, r: NumT) -> Option<NumT> {
+    let (ul, ur) = (l as u64, r as u64);
+    let v = match op {
+        Operator::Lt => (ul < ur) as u64,
+        Operator::Leq => (ul <= ur) as u64,
+        Operator::Gt => (ul > ur) as u64,
+        Operator::Geq => (ul >= ur) as u64,
+        Operator::Min => ul.min(ur),
+        Operator::Max => ul.max(ur),
+        Operator::BitShr | Operator::BitShrEq => ul.wrapping_shr(r as u32),
+        Operator::Div | Operator::DivEq => ul.checked_div(ur)?,
+        Operator::Mod | Operator::ModEq => ul.checked_rem(ur)?,
+        _ => unreachable!("{:?} can't be unsigned", op),
+    };
+    Some(v as NumT)
+}
+
+/// The greatest common divisor of |a| and |b|, with gcd(0, 0) = 0. The only
+/// result past i64::MAX is 2**63, from i64::MIN and 0 or itself, which wraps
+/// to i64::MIN like `-i64::MIN` does.
+pub fn gcd(a: NumT, b: NumT) -> NumT {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a as NumT
+}
+
+/// `base**exp % modulus` without overflow, agreeing with `(base**exp)%modulus`
+/// wherever the plain power fits. Negative exponents follow the plain `Pow`
+/// rules; None (fatal) for `0**-n` and a modulus that isn't positive.
+pub fn pow_mod(base: NumT, exp: NumT, modulus: NumT) -> Option<NumT> {
+    if modulus <= 0 {
+        return None;
+    }
+    if exp < 0 {
+        return pow_negative_exp(base, exp).map(|v| v % modulus);
+    }
+    // % truncates, so each partial product keeps the sign of the true power
+    let m = modulus as i128;
+    let mut result = 1 % m;
+    let mut base = base as i128 % m;
+    let mut exp = exp;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result * base % m;
+        }
+        base = base * base % m;
+        exp >>= 1;
     }
+    Some(result as NumT)
 }
 
+/// How deeply `naive_eval` nests before it gives up on a tree as fatal. The
+/// search's trees are never close; it's there so a hand-built chain can't
+/// overflow the stack.
+pub const MAX_EVAL_DEPTH: usize = 1000;
+
 pub fn naive_eval(e: &Expr, x: &mut NumT, y: &mut NumT, fatal: &mut bool) -> NumT {
+    naive_eval_limited(e, x, y, fatal, MAX_EVAL_DEPTH)
+}
+
+/// `naive_eval` with the depth cap set by the caller: a tree with more than
+/// `max_depth` nodes on some path is fatal (that path isn't evaluated past it)
+pub fn naive_eval_limited(e: &Expr, x: &mut NumT, y: &mut NumT, fatal: &mut bool, max_depth: usize) -> NumT {
+    if e.has_bad_target() || max_depth == 0 {
+        *fatal = true;
+        return 0;
+    }
+    let depth = max_depth - 1;
+
+    // Only the selected branch of a ternary may run (it can have side effects)
+    if e.op == Operator::Ternary {
+        let cond = naive_eval_limited(e.left.as_ref().unwrap(), x, y, fatal, depth);
+        let branch = if cond != 0 { &e.mid } else { &e.right };
+        return naive_eval_limited(branch.as_ref().unwrap(), x, y, fatal, depth);
+    }
+
     let mut l = 0;
     let mut r = 0;
     
     if let Some(ref left) = e.left {
-        l = naive_eval(left, x, y, fatal);
+        l = naive_eval_limited(left, x, y, fatal, depth);
     }
     if let Some(ref right) = e.right {
-        r = naive_eval(right, x, y, fatal);
+        r = naive_eval_limited(right, x, y, fatal, depth);
     }
 
     match e.op {
@@ -361,15 +1067,24 @@ pub fn naive_eval(e: &Expr, x: &mut NumT, y: &mut NumT, fatal: &mut bool) -> Num
             let target = if target_is_x { x } else { y };
             
             match e.op {
+                _ if e.is_saturating() => { *target = saturating_op(e.op, *target, r); *target }
+                _ if e.is_euclidean() => match euclidean_op(e.op, *target, r) {
+                    Some(v) => { *target = v; *target }
+                    None => { *fatal = true; 0 }
+                },
+                _ if e.is_unsigned() => match unsigned_op(e.op, *target, r) {
+                    Some(v) => { *target = v; *target }
+                    None => { *fatal = true; 0 }
+                },
                 Operator::AssignEq => { *target = r; *target }
                 Operator::BitOrEq => { *target |= r; *target }
                 Operator::BitXorEq => { *target ^= r; *target }
                 Operator::BitAndEq => { *target &= r; *target }
-                Operator::BitShlEq => { *target <<= r; *target }
-                Operator::BitShrEq => { *target >>= r; *target }
-                Operator::AddEq => { *target += r; *target }
-                Operator::SubEq => { *target -= r; *target }
-                Operator::MulEq => { *target *= r; *target }
+                Operator::BitShlEq => { *target = target.wrapping_shl(r as u32); *target }
+                Operator::BitShrEq => { *target = target.wrapping_shr(r as u32); *target }
+                Operator::AddEq => { *target = target.wrapping_add(r); *target }
+                Operator::SubEq => { *target = target.wrapping_sub(r); *target }
+                Operator::MulEq => { *target = target.wrapping_mul(r); *target }
                 Operator::DivEq => {
                     if r == 0 || (*target == i64::MIN && r == -1) {
                         *fatal = true;
@@ -391,6 +1106,8 @@ pub fn naive_eval(e: &Expr, x: &mut NumT, y: &mut NumT, fatal: &mut bool) -> Num
                 _ => unreachable!(),
             }
         }
+        Operator::Comma => r,
+        Operator::Ternary => unreachable!(),
         Operator::Or => if l != 0 || r != 0 { 1 } else { 0 },
         Operator::And => if l != 0 && r != 0 { 1 } else { 0 },
         Operator::BitOr => l | r,
@@ -398,12 +1115,19 @@ pub fn naive_eval(e: &Expr, x: &mut NumT, y: &mut NumT, fatal: &mut bool) -> Num
         Operator::BitAnd => l & r,
         Operator::Eq => if l == r { 1 } else { 0 },
         Operator::Neq => if l != r { 1 } else { 0 },
+        _ if e.is_unsigned() => unsigned_op(e.op, l, r).unwrap_or_else(|| { *fatal = true; 0 }),
         Operator::Lt => if l < r { 1 } else { 0 },
         Operator::Leq => if l <= r { 1 } else { 0 },
         Operator::Gt => if l > r { 1 } else { 0 },
         Operator::Geq => if l >= r { 1 } else { 0 },
-        Operator::BitShl => l << r,
-        Operator::BitShr => l >> r,
+        Operator::Min => l.min(r),
+        Operator::Max => l.max(r),
+        // Shift counts are taken mod 64, like the x86 and aarch64 shift instructions
+        Operator::BitShl => l.wrapping_shl(r as u32),
+        Operator::BitShr => l.wrapping_shr(r as u32),
+        Operator::LShr => (l as u64).wrapping_shr(r as u32) as NumT,
+        _ if e.is_saturating() => saturating_op(e.op, l, r),
+        _ if e.is_euclidean() => euclidean_op(e.op, l, r).unwrap_or_else(|| { *fatal = true; 0 }),
         Operator::Add => l.wrapping_add(r),
         Operator::Sub => l.wrapping_sub(r),
         Operator::Mul => l.wrapping_mul(r),
@@ -423,19 +1147,19 @@ pub fn naive_eval(e: &Expr, x: &mut NumT, y: &mut NumT, fatal: &mut bool) -> Num
                 l % r
             }
         }
+        Operator::Gcd => gcd(l, r),
         Operator::Pow => {
             // Handle negative exponents and overflow
-            if r < 0 {
-                if l == 0 {
+            if let Some(m) = e.pow_modulus() {
+                pow_mod(l, r, m).unwrap_or_else(|| {
                     *fatal = true;
                     0
-                } else if l == 1 {
-                    1
-                } else if l == -1 {
-                    if r % 2 == 0 { 1 } else { -1 }
-                } else {
-                    0  // |l| > 1 with negative exponent -> 0 in integer math
-                }
+                })
+            } else if r < 0 {
+                pow_negative_exp(l, r).unwrap_or_else(|| {
+                    *fatal = true;
+                    0
+                })
             } else if let Ok(exp) = u32::try_from(r) {
                 l.checked_pow(exp).unwrap_or_else(|| {
                     *fatal = true;
@@ -449,6 +1173,7 @@ pub fn naive_eval(e: &Expr, x: &mut NumT, y: &mut NumT, fatal: &mut bool) -> Num
         Operator::Neg => r.wrapping_neg(),
         Operator::BitNot => !r,
         Operator::Not => if r == 0 { 1 } else { 0 },
+        Operator::PopCount => r.count_ones() as NumT,
         Operator::PreInc => {
              let target_is_x = if let Some(ref right) = e.right {
                 right.op == Operator::Var
@@ -493,67 +1218,22 @@ pub fn naive_eval(e: &Expr, x: &mut NumT, y: &mut NumT, fatal: &mut bool) -> Num
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::build::{self, bin, lit, ternary, un};
 
-    // Helper function to create a literal expression
-    fn lit(val: NumT) -> Arc<Expr> {
-        Arc::new(Expr {
-            left: None,
-            right: None,
-            literal: val,
-            op: Operator::Literal,
-            jit: None,
-        })
-    }
-
-    // Helper function to create a variable x expression
-    fn var_x() -> Arc<Expr> {
-        Arc::new(Expr {
-            left: None,
-            right: None,
-            literal: 0,
-            op: Operator::Var,
-            jit: None,
-        })
-    }
-
-    // Helper function to create a variable y expression
-    fn var_y() -> Arc<Expr> {
-        Arc::new(Expr {
-            left: None,
-            right: None,
-            literal: 0,
-            op: Operator::VarY,
-            jit: None,
-        })
-    }
-
-    // Helper function to create a binary expression
-    fn binary(left: Arc<Expr>, op: Operator, right: Arc<Expr>) -> Expr {
-        Expr {
-            left: Some(left),
-            right: Some(right),
-            literal: 0,
-            op,
-            jit: None,
-        }
-    }
+    // ==================== Operator::from_u8 Tests ====================
 
-    // Helper function to create a unary expression
-    fn unary(op: Operator, right: Arc<Expr>) -> Expr {
-        Expr {
-            left: None,
-            right: Some(right),
-            literal: 0,
-            op,
-            jit: None,
-        }
+    #[test]
+    fn test_operator_from_name() {
+        assert_eq!(Operator::from_name("AddEq"), Some(Operator::AddEq));
+        assert_eq!(Operator::from_name("VarY"), Some(Operator::VarY));
+        assert_eq!(Operator::from_name("addeq"), None);
     }
 
-    // ==================== Operator::from_u8 Tests ====================
-
     #[test]
     fn test_operator_from_u8_valid() {
         assert_eq!(Operator::from_u8(0x00), Some(Operator::AssignEq));
+        assert_eq!(Operator::from_u8(0x10), Some(Operator::Comma));
+        assert_eq!(Operator::from_u8(0x1F), Some(Operator::Ternary));
         assert_eq!(Operator::from_u8(0x20), Some(Operator::Or));
         assert_eq!(Operator::from_u8(0x30), Some(Operator::And));
         assert_eq!(Operator::from_u8(0xA0), Some(Operator::Add));
@@ -568,18 +1248,129 @@ mod tests {
         assert_eq!(Operator::from_u8(0xFF), Some(Operator::Literal));
     }
 
+    #[test]
+    fn test_commutative_associative() {
+        let ops = Operator::all().filter(|op| (0x20..0xC0).contains(&(*op as u8)));
+        for op in ops {
+            let eval = |e: &Expr| naive_eval(e, &mut 0, &mut 0, &mut false);
+            for (a, b, c) in [(3, -7, 5), (0, 2, -1), (6, 6, 1)] {
+                if is_commutative(op) {
+                    assert_eq!(eval(&bin(lit(a), op, lit(b))), eval(&bin(lit(b), op, lit(a))), "{:?}", op);
+                }
+                if is_associative(op) {
+                    let left_deep = bin(bin(lit(a), op, lit(b)), op, lit(c));
+                    let right_deep = bin(lit(a), op, bin(lit(b), op, lit(c)));
+                    assert_eq!(eval(&left_deep), eval(&right_deep), "{:?}", op);
+                }
+            }
+        }
+        for op in [Operator::Sub, Operator::Div, Operator::Mod, Operator::Pow, Operator::BitShl, Operator::Lt] {
+            assert!(!is_commutative(op) && !is_associative(op), "{:?}", op);
+        }
+        assert!(is_commutative(Operator::Eq) && !is_associative(Operator::Eq));
+    }
+
+    #[test]
+    fn test_category_precedence_class() {
+        assert_eq!(category(Operator::ModEq), OpCategory::Assignment);
+        assert_eq!(category(Operator::Comma), OpCategory::Binary);
+        assert_eq!(category(Operator::Ternary), OpCategory::Binary);
+        assert_eq!(category(Operator::Pow), OpCategory::Binary);
+        assert_eq!(category(Operator::Not), OpCategory::Unary);
+        assert_eq!(category(Operator::PreDec), OpCategory::IncDec);
+        assert_eq!(category(Operator::PostInc), OpCategory::IncDec);
+        assert_eq!(category(Operator::Parens), OpCategory::Paren);
+        assert_eq!(category(Operator::VarY), OpCategory::Operand);
+
+        assert_eq!(precedence_class(Operator::Comma), precedence_class(Operator::Ternary));
+        assert_eq!(precedence_class(Operator::Lt), precedence_class(Operator::Max));
+        assert!(precedence_class(Operator::Add) < precedence_class(Operator::Mul));
+        assert!(precedence_class(Operator::Pow) < precedence_class(Operator::Neg));
+    }
+
+    #[test]
+    fn test_operator_discriminant_layout() {
+        let ops: Vec<Operator> = (0..=u8::MAX).filter_map(Operator::from_u8).collect();
+        assert_eq!(Operator::ALL, &ops[..]);
+        for n in 0..=u8::MAX {
+            if let Some(op) = Operator::from_u8(n) {
+                assert_eq!(op as u8, n, "{:?}", op);
+                assert_eq!(Operator::from_u8(op as u8), Some(op));
+                assert_eq!(Operator::from_name(&format!("{:?}", op)), Some(op));
+            }
+        }
+        for &op in &ops {
+            assert_eq!(category(op) == OpCategory::Assignment, (op as u8) < 0x10, "{:?}", op);
+            assert_eq!(category(op) == OpCategory::Operand, (op as u8) >= 0xF0, "{:?}", op);
+        }
+
+        // C's binary precedence, loosest first; the generator's pruning
+        // assumes the high nibbles rise in this order, equal within a row
+        let levels: [&[Operator]; 10] = [
+            &[Operator::Or],
+            &[Operator::And],
+            &[Operator::BitOr],
+            &[Operator::BitXor],
+            &[Operator::BitAnd],
+            &[Operator::Eq, Operator::Neq],
+            &[Operator::Lt, Operator::Leq, Operator::Gt, Operator::Geq, Operator::Min, Operator::Max],
+            &[Operator::BitShl, Operator::BitShr, Operator::LShr],
+            &[Operator::Add, Operator::Sub],
+            &[Operator::Mul, Operator::Div, Operator::Mod, Operator::Pow, Operator::Gcd],
+        ];
+        let binary = ops.iter().filter(|&&op| category(op) == OpCategory::Binary && (op as u8) >= 0x20);
+        assert_eq!(binary.count(), levels.iter().map(|l| l.len()).sum::<usize>());
+        let mut prev = precedence_class(Operator::Ternary);
+        for level in levels {
+            let class = precedence_class(level[0]);
+            assert!(class > prev, "{:?}", level);
+            for &op in level {
+                assert_eq!(category(op), OpCategory::Binary, "{:?}", op);
+                assert_eq!(precedence_class(op), class, "{:?}", op);
+            }
+            prev = class;
+        }
+        assert!(prev < precedence_class(Operator::Neg));
+
+        assert_eq!(Operator::Var as u8 & 0xF0, Operator::VarY as u8 & 0xF0);
+        assert_ne!(Operator::Var as u8, Operator::VarY as u8);
+    }
+
     #[test]
     fn test_operator_from_u8_invalid() {
-        assert_eq!(Operator::from_u8(0x10), None);
+        assert_eq!(Operator::from_u8(0x11), None);
         assert_eq!(Operator::from_u8(0x15), None);
         assert_eq!(Operator::from_u8(0xFE), None);
     }
 
+    #[test]
+    fn test_eval_depth_limit() {
+        // x+1+1 nests 3 deep down its left side
+        let e = bin(bin(build::x(), Operator::Add, lit(1)), Operator::Add, lit(1));
+        let mut fatal = false;
+        assert_eq!(naive_eval_limited(&e, &mut 5, &mut 0, &mut fatal, 3), 7);
+        assert!(!fatal);
+        naive_eval_limited(&e, &mut 5, &mut 0, &mut fatal, 2);
+        assert!(fatal);
+
+        // The default cap: x and MAX_EVAL_DEPTH-1 additions still run, one more doesn't
+        let mut chain = build::x();
+        for _ in 1..MAX_EVAL_DEPTH {
+            chain = bin(chain, Operator::Add, lit(1));
+        }
+        let (mut x, mut fatal) = (0, false);
+        assert_eq!(naive_eval(&chain, &mut x, &mut 0, &mut fatal), MAX_EVAL_DEPTH as NumT - 1);
+        assert!(!fatal);
+        let chain = bin(chain, Operator::Add, lit(1));
+        naive_eval(&chain, &mut x, &mut 0, &mut fatal);
+        assert!(fatal);
+    }
+
     // ==================== Arithmetic Operations Tests ====================
 
     #[test]
     fn test_eval_add() {
-        let e = binary(lit(10), Operator::Add, lit(20));
+        let e = bin(lit(10), Operator::Add, lit(20));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -589,7 +1380,7 @@ mod tests {
 
     #[test]
     fn test_eval_sub() {
-        let e = binary(lit(30), Operator::Sub, lit(10));
+        let e = bin(lit(30), Operator::Sub, lit(10));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -599,7 +1390,7 @@ mod tests {
 
     #[test]
     fn test_eval_mul() {
-        let e = binary(lit(6), Operator::Mul, lit(7));
+        let e = bin(lit(6), Operator::Mul, lit(7));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -609,7 +1400,7 @@ mod tests {
 
     #[test]
     fn test_eval_div() {
-        let e = binary(lit(42), Operator::Div, lit(6));
+        let e = bin(lit(42), Operator::Div, lit(6));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -619,7 +1410,7 @@ mod tests {
 
     #[test]
     fn test_eval_div_by_zero() {
-        let e = binary(lit(10), Operator::Div, lit(0));
+        let e = bin(lit(10), Operator::Div, lit(0));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -631,7 +1422,7 @@ mod tests {
     #[test]
     fn test_eval_div_overflow() {
         // i64::MIN / -1 causes overflow
-        let e = binary(lit(i64::MIN), Operator::Div, lit(-1));
+        let e = bin(lit(i64::MIN), Operator::Div, lit(-1));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -642,7 +1433,7 @@ mod tests {
 
     #[test]
     fn test_eval_mod() {
-        let e = binary(lit(17), Operator::Mod, lit(5));
+        let e = bin(lit(17), Operator::Mod, lit(5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -652,7 +1443,7 @@ mod tests {
 
     #[test]
     fn test_eval_mod_by_zero() {
-        let e = binary(lit(10), Operator::Mod, lit(0));
+        let e = bin(lit(10), Operator::Mod, lit(0));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -665,7 +1456,7 @@ mod tests {
 
     #[test]
     fn test_eval_pow_positive() {
-        let e = binary(lit(2), Operator::Pow, lit(10));
+        let e = bin(lit(2), Operator::Pow, lit(10));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -675,7 +1466,7 @@ mod tests {
 
     #[test]
     fn test_eval_pow_zero_exponent() {
-        let e = binary(lit(5), Operator::Pow, lit(0));
+        let e = bin(lit(5), Operator::Pow, lit(0));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -686,7 +1477,7 @@ mod tests {
     #[test]
     fn test_eval_pow_negative_exponent() {
         // 2^-3 should be 0 in integer math
-        let e = binary(lit(2), Operator::Pow, lit(-3));
+        let e = bin(lit(2), Operator::Pow, lit(-3));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -697,7 +1488,7 @@ mod tests {
     #[test]
     fn test_eval_pow_one_negative_exponent() {
         // 1^-5 = 1
-        let e = binary(lit(1), Operator::Pow, lit(-5));
+        let e = bin(lit(1), Operator::Pow, lit(-5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -708,7 +1499,7 @@ mod tests {
     #[test]
     fn test_eval_pow_minus_one_even_exponent() {
         // (-1)^-4 = 1
-        let e = binary(lit(-1), Operator::Pow, lit(-4));
+        let e = bin(lit(-1), Operator::Pow, lit(-4));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -719,7 +1510,7 @@ mod tests {
     #[test]
     fn test_eval_pow_minus_one_odd_exponent() {
         // (-1)^-3 = -1
-        let e = binary(lit(-1), Operator::Pow, lit(-3));
+        let e = bin(lit(-1), Operator::Pow, lit(-3));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -727,11 +1518,70 @@ mod tests {
         assert!(!fatal);
     }
 
+    #[test]
+    fn test_pow_mod_matches_plain_pow() {
+        for base in -6..=6 {
+            for exp in -3..=12 {
+                for m in 1..=10 {
+                    let mut fatal = false;
+                    let plain = naive_eval(&bin(lit(base), Operator::Pow, lit(exp)), &mut 0, &mut 0, &mut fatal);
+                    let expected = if fatal { None } else { Some(plain % m) };
+                    assert_eq!(pow_mod(base, exp, m), expected, "{}**{} % {}", base, exp, m);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_pow_mod_no_overflow() {
+        assert_eq!(pow_mod(3, 1_000_000_000_000, 1_000_000_007), Some(570188345));
+        // Products of residues this large only fit in 128 bits
+        assert_eq!(pow_mod(-7, 1_000_000_000_000_001, (1 << 62) + 135), Some(-2613181691645475146));
+    }
+
+    #[test]
+    fn test_pow_mod_fatal() {
+        assert_eq!(pow_mod(2, 3, 0), None);
+        assert_eq!(pow_mod(2, 3, -5), None);
+        assert_eq!(pow_mod(0, -1, 5), None);
+    }
+
+    #[test]
+    fn test_eval_pow_mod_node() {
+        // x**y overflows as a plain Pow but not mod 1000
+        let e = build::pow_mod(build::x(), build::y(), 1000);
+        let (mut x, mut y) = (7, 100);
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 1);
+        assert!(!fatal);
+        assert!(!e.is_jittable());
+        assert!(bin(build::x(), Operator::Pow, build::y()).is_jittable());
+    }
+
+    #[test]
+    fn test_eval_saturating() {
+        let (mut x, mut y) = (i64::MAX, i64::MIN);
+        let mut fatal = false;
+        let e = build::saturating(build::x(), Operator::Add, lit(1));
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), i64::MAX);
+        let e = build::saturating(build::y(), Operator::Sub, lit(1));
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), i64::MIN);
+        let e = build::saturating(build::x(), Operator::Mul, lit(-2));
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), i64::MIN);
+        // The assignment stores the clamped value
+        let e = build::saturating(build::x(), Operator::MulEq, build::x());
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), i64::MAX);
+        assert_eq!(x, i64::MAX);
+        assert!(!fatal);
+        // Unmarked nodes still wrap
+        assert_eq!(naive_eval(&bin(build::x(), Operator::Add, lit(1)), &mut x, &mut y, &mut fatal), i64::MIN);
+    }
+
     // ==================== Comparison Operations Tests ====================
 
     #[test]
     fn test_eval_eq_true() {
-        let e = binary(lit(5), Operator::Eq, lit(5));
+        let e = bin(lit(5), Operator::Eq, lit(5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -740,7 +1590,7 @@ mod tests {
 
     #[test]
     fn test_eval_eq_false() {
-        let e = binary(lit(5), Operator::Eq, lit(3));
+        let e = bin(lit(5), Operator::Eq, lit(3));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -749,7 +1599,7 @@ mod tests {
 
     #[test]
     fn test_eval_neq() {
-        let e = binary(lit(5), Operator::Neq, lit(3));
+        let e = bin(lit(5), Operator::Neq, lit(3));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -758,7 +1608,7 @@ mod tests {
 
     #[test]
     fn test_eval_lt() {
-        let e = binary(lit(3), Operator::Lt, lit(5));
+        let e = bin(lit(3), Operator::Lt, lit(5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -767,8 +1617,8 @@ mod tests {
 
     #[test]
     fn test_eval_leq() {
-        let e1 = binary(lit(3), Operator::Leq, lit(5));
-        let e2 = binary(lit(5), Operator::Leq, lit(5));
+        let e1 = bin(lit(3), Operator::Leq, lit(5));
+        let e2 = bin(lit(5), Operator::Leq, lit(5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -778,7 +1628,7 @@ mod tests {
 
     #[test]
     fn test_eval_gt() {
-        let e = binary(lit(5), Operator::Gt, lit(3));
+        let e = bin(lit(5), Operator::Gt, lit(3));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -787,8 +1637,8 @@ mod tests {
 
     #[test]
     fn test_eval_geq() {
-        let e1 = binary(lit(5), Operator::Geq, lit(3));
-        let e2 = binary(lit(5), Operator::Geq, lit(5));
+        let e1 = bin(lit(5), Operator::Geq, lit(3));
+        let e2 = bin(lit(5), Operator::Geq, lit(5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -796,14 +1646,56 @@ mod tests {
         assert_eq!(naive_eval(&e2, &mut x, &mut y, &mut fatal), 1);
     }
 
+    #[test]
+    fn test_eval_min() {
+        let e = bin(build::x(), Operator::Min, build::y());
+        let mut x = 3;
+        let mut y = -7;
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), -7);
+        y = 5;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 3);
+        assert!(!fatal);
+    }
+
+    #[test]
+    fn test_eval_max() {
+        let e = bin(build::x(), Operator::Max, build::y());
+        let mut x = 3;
+        let mut y = -7;
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 3);
+        y = 5;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 5);
+        assert!(!fatal);
+    }
+
+    #[test]
+    fn test_gcd() {
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(-12, 18), 6);
+        assert_eq!(gcd(12, -18), 6);
+        assert_eq!(gcd(0, 7), 7);
+        assert_eq!(gcd(-7, 0), 7);
+        assert_eq!(gcd(0, 0), 0);
+        assert_eq!(gcd(NumT::MIN, 6), 2);
+        assert_eq!(gcd(NumT::MIN, NumT::MAX), 1);
+        assert_eq!(gcd(NumT::MIN, 0), NumT::MIN);
+        let mut fatal = false;
+        let e = bin(build::x(), Operator::Gcd, lit(4));
+        assert_eq!(naive_eval(&e, &mut -10, &mut 0, &mut fatal), 2);
+        assert!(!fatal);
+        assert_eq!(format_expression(&bin(e, Operator::Mul, build::y()), &['x', 'y']), "x%%4*y");
+    }
+
     // ==================== Logical Operations Tests ====================
 
     #[test]
     fn test_eval_or() {
-        let e1 = binary(lit(0), Operator::Or, lit(0));
-        let e2 = binary(lit(1), Operator::Or, lit(0));
-        let e3 = binary(lit(0), Operator::Or, lit(1));
-        let e4 = binary(lit(1), Operator::Or, lit(1));
+        let e1 = bin(lit(0), Operator::Or, lit(0));
+        let e2 = bin(lit(1), Operator::Or, lit(0));
+        let e3 = bin(lit(0), Operator::Or, lit(1));
+        let e4 = bin(lit(1), Operator::Or, lit(1));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -815,10 +1707,10 @@ mod tests {
 
     #[test]
     fn test_eval_and() {
-        let e1 = binary(lit(0), Operator::And, lit(0));
-        let e2 = binary(lit(1), Operator::And, lit(0));
-        let e3 = binary(lit(0), Operator::And, lit(1));
-        let e4 = binary(lit(1), Operator::And, lit(1));
+        let e1 = bin(lit(0), Operator::And, lit(0));
+        let e2 = bin(lit(1), Operator::And, lit(0));
+        let e3 = bin(lit(0), Operator::And, lit(1));
+        let e4 = bin(lit(1), Operator::And, lit(1));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -832,7 +1724,7 @@ mod tests {
 
     #[test]
     fn test_eval_bitor() {
-        let e = binary(lit(0b1010), Operator::BitOr, lit(0b1100));
+        let e = bin(lit(0b1010), Operator::BitOr, lit(0b1100));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -841,7 +1733,7 @@ mod tests {
 
     #[test]
     fn test_eval_bitxor() {
-        let e = binary(lit(0b1010), Operator::BitXor, lit(0b1100));
+        let e = bin(lit(0b1010), Operator::BitXor, lit(0b1100));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -850,7 +1742,7 @@ mod tests {
 
     #[test]
     fn test_eval_bitand() {
-        let e = binary(lit(0b1010), Operator::BitAnd, lit(0b1100));
+        let e = bin(lit(0b1010), Operator::BitAnd, lit(0b1100));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -859,7 +1751,7 @@ mod tests {
 
     #[test]
     fn test_eval_shl() {
-        let e = binary(lit(1), Operator::BitShl, lit(4));
+        let e = bin(lit(1), Operator::BitShl, lit(4));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -868,18 +1760,39 @@ mod tests {
 
     #[test]
     fn test_eval_shr() {
-        let e = binary(lit(16), Operator::BitShr, lit(2));
+        let e = bin(lit(16), Operator::BitShr, lit(2));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
         assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 4);
     }
 
+    #[test]
+    fn test_eval_lshr() {
+        // Zeros shift in where >> copies the sign bit; the count is taken mod 64
+        let mut fatal = false;
+        assert_eq!(naive_eval(&bin(lit(-16), Operator::LShr, lit(2)), &mut 0, &mut 0, &mut fatal), (u64::MAX >> 2) as NumT - 3);
+        assert_eq!(naive_eval(&bin(lit(-16), Operator::BitShr, lit(2)), &mut 0, &mut 0, &mut fatal), -4);
+        assert_eq!(naive_eval(&bin(lit(-1), Operator::LShr, lit(127)), &mut 0, &mut 0, &mut fatal), 1);
+        assert_eq!(format_expression(&bin(build::x(), Operator::LShr, lit(3)), &['x', 'y']), "x>>>3");
+        assert!(!fatal);
+    }
+
     // ==================== Unary Operations Tests ====================
 
+    #[test]
+    fn test_eval_popcount() {
+        let mut fatal = false;
+        assert_eq!(naive_eval(&un(Operator::PopCount, lit(0b1011)), &mut 0, &mut 0, &mut fatal), 3);
+        assert_eq!(naive_eval(&un(Operator::PopCount, lit(-1)), &mut 0, &mut 0, &mut fatal), 64);
+        assert_eq!(naive_eval(&un(Operator::PopCount, lit(NumT::MIN)), &mut 0, &mut 0, &mut fatal), 1);
+        assert_eq!(format_expression(&un(Operator::PopCount, un(Operator::Neg, build::x())), &['x', 'y']), "#-x");
+        assert!(!fatal);
+    }
+
     #[test]
     fn test_eval_neg() {
-        let e = unary(Operator::Neg, lit(5));
+        let e = un(Operator::Neg, lit(5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -888,7 +1801,7 @@ mod tests {
 
     #[test]
     fn test_eval_bitnot() {
-        let e = unary(Operator::BitNot, lit(0));
+        let e = un(Operator::BitNot, lit(0));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -897,8 +1810,8 @@ mod tests {
 
     #[test]
     fn test_eval_not() {
-        let e1 = unary(Operator::Not, lit(0));
-        let e2 = unary(Operator::Not, lit(5));
+        let e1 = un(Operator::Not, lit(0));
+        let e2 = un(Operator::Not, lit(5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -908,18 +1821,92 @@ mod tests {
 
     #[test]
     fn test_eval_parens() {
-        let e = unary(Operator::Parens, lit(42));
+        let e = un(Operator::Parens, lit(42));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
         assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 42);
     }
 
+    // ==================== Ternary Tests ====================
+
+    #[test]
+    fn test_eval_ternary() {
+        let e = ternary(build::x(), lit(10), lit(20));
+        let mut x = 3;
+        let mut y = 0;
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 10);
+        x = 0;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 20);
+        assert!(!fatal);
+    }
+
+    #[test]
+    fn test_eval_ternary_only_taken_branch() {
+        // x ? ++y : --y
+        let inc = un(Operator::PreInc, build::y());
+        let dec = un(Operator::PreDec, build::y());
+        let e = ternary(build::x(), inc, dec);
+        let mut x = 1;
+        let mut y = 5;
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 6);
+        assert_eq!(y, 6);
+        x = 0;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 5);
+        assert_eq!(y, 5);
+    }
+
+    #[test]
+    fn test_eval_ternary_fatal_branch_not_taken() {
+        // x ? 1 : 1/y must not trip on y == 0 when x is set
+        let div = bin(lit(1), Operator::Div, build::y());
+        let e = ternary(build::x(), lit(1), div);
+        let mut x = 1;
+        let mut y = 0;
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 1);
+        assert!(!fatal);
+    }
+
+    // ==================== Comma Tests ====================
+
+    #[test]
+    fn test_eval_comma() {
+        // x=y+1,y=x*2 runs both assignments in order and yields the second
+        let l = bin(build::x(), Operator::AssignEq, bin(build::y(), Operator::Add, lit(1)));
+        let r = bin(build::y(), Operator::AssignEq, bin(build::x(), Operator::Mul, lit(2)));
+        let e = bin(l, Operator::Comma, r);
+        let mut x = 0;
+        let mut y = 3;
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 8);
+        assert_eq!(x, 4);
+        assert_eq!(y, 8);
+        assert!(!fatal);
+    }
+
+    #[test]
+    fn test_comma_is_not_assignment() {
+        let e = bin(build::x(), Operator::Comma, build::y());
+        assert!(!e.is_assignment());
+    }
+
+    #[test]
+    fn test_needs_parens_comma() {
+        assert!(needs_parens(Operator::Comma, Operator::Add, false));
+        assert!(needs_parens(Operator::Comma, Operator::Ternary, true));
+        assert!(needs_parens(Operator::Comma, Operator::AssignEq, true));
+        assert!(!needs_parens(Operator::AssignEq, Operator::Comma, false));
+        assert!(!needs_parens(Operator::Comma, Operator::Comma, false));
+    }
+
     // ==================== Variable Tests ====================
 
     #[test]
     fn test_eval_var() {
-        let e = binary(var_x(), Operator::Add, var_y());
+        let e = bin(build::x(), Operator::Add, build::y());
         let mut x = 10;
         let mut y = 20;
         let mut fatal = false;
@@ -928,7 +1915,7 @@ mod tests {
 
     #[test]
     fn test_eval_var_mul() {
-        let e = binary(var_x(), Operator::Mul, var_y());
+        let e = bin(build::x(), Operator::Mul, build::y());
         let mut x = 3;
         let mut y = 7;
         let mut fatal = false;
@@ -939,7 +1926,7 @@ mod tests {
 
     #[test]
     fn test_assignment_eq() {
-        let e = binary(var_x(), Operator::AssignEq, lit(5));
+        let e = bin(build::x(), Operator::AssignEq, lit(5));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -949,7 +1936,7 @@ mod tests {
 
     #[test]
     fn test_assignment_add_eq() {
-        let e = binary(var_x(), Operator::AddEq, lit(3));
+        let e = bin(build::x(), Operator::AddEq, lit(3));
         let mut x = 10;
         let mut y = 0;
         let mut fatal = false;
@@ -959,7 +1946,7 @@ mod tests {
 
     #[test]
     fn test_assignment_sub_eq() {
-        let e = binary(var_x(), Operator::SubEq, lit(3));
+        let e = bin(build::x(), Operator::SubEq, lit(3));
         let mut x = 10;
         let mut y = 0;
         let mut fatal = false;
@@ -969,7 +1956,7 @@ mod tests {
 
     #[test]
     fn test_assignment_mul_eq() {
-        let e = binary(var_x(), Operator::MulEq, lit(3));
+        let e = bin(build::x(), Operator::MulEq, lit(3));
         let mut x = 10;
         let mut y = 0;
         let mut fatal = false;
@@ -979,7 +1966,7 @@ mod tests {
 
     #[test]
     fn test_assignment_div_eq() {
-        let e = binary(var_x(), Operator::DivEq, lit(2));
+        let e = bin(build::x(), Operator::DivEq, lit(2));
         let mut x = 10;
         let mut y = 0;
         let mut fatal = false;
@@ -989,7 +1976,7 @@ mod tests {
 
     #[test]
     fn test_assignment_mod_eq() {
-        let e = binary(var_x(), Operator::ModEq, lit(3));
+        let e = bin(build::x(), Operator::ModEq, lit(3));
         let mut x = 10;
         let mut y = 0;
         let mut fatal = false;
@@ -999,7 +1986,7 @@ mod tests {
 
     #[test]
     fn test_assignment_bitor_eq() {
-        let e = binary(var_x(), Operator::BitOrEq, lit(0b0011));
+        let e = bin(build::x(), Operator::BitOrEq, lit(0b0011));
         let mut x = 0b1100;
         let mut y = 0;
         let mut fatal = false;
@@ -1009,7 +1996,7 @@ mod tests {
 
     #[test]
     fn test_assignment_bitand_eq() {
-        let e = binary(var_x(), Operator::BitAndEq, lit(0b0011));
+        let e = bin(build::x(), Operator::BitAndEq, lit(0b0011));
         let mut x = 0b1111;
         let mut y = 0;
         let mut fatal = false;
@@ -1019,7 +2006,7 @@ mod tests {
 
     #[test]
     fn test_assignment_bitxor_eq() {
-        let e = binary(var_x(), Operator::BitXorEq, lit(0b0011));
+        let e = bin(build::x(), Operator::BitXorEq, lit(0b0011));
         let mut x = 0b1111;
         let mut y = 0;
         let mut fatal = false;
@@ -1029,7 +2016,7 @@ mod tests {
 
     #[test]
     fn test_assignment_shl_eq() {
-        let e = binary(var_x(), Operator::BitShlEq, lit(2));
+        let e = bin(build::x(), Operator::BitShlEq, lit(2));
         let mut x = 4;
         let mut y = 0;
         let mut fatal = false;
@@ -1039,7 +2026,7 @@ mod tests {
 
     #[test]
     fn test_assignment_shr_eq() {
-        let e = binary(var_x(), Operator::BitShrEq, lit(2));
+        let e = bin(build::x(), Operator::BitShrEq, lit(2));
         let mut x = 16;
         let mut y = 0;
         let mut fatal = false;
@@ -1049,7 +2036,7 @@ mod tests {
 
     #[test]
     fn test_assignment_to_y() {
-        let e = binary(var_y(), Operator::AssignEq, lit(42));
+        let e = bin(build::y(), Operator::AssignEq, lit(42));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -1061,7 +2048,7 @@ mod tests {
 
     #[test]
     fn test_pre_inc() {
-        let e = unary(Operator::PreInc, var_x());
+        let e = un(Operator::PreInc, build::x());
         let mut x = 5;
         let mut y = 0;
         let mut fatal = false;
@@ -1071,7 +2058,7 @@ mod tests {
 
     #[test]
     fn test_pre_dec() {
-        let e = unary(Operator::PreDec, var_x());
+        let e = un(Operator::PreDec, build::x());
         let mut x = 5;
         let mut y = 0;
         let mut fatal = false;
@@ -1081,7 +2068,7 @@ mod tests {
 
     #[test]
     fn test_post_inc() {
-        let e = unary(Operator::PostInc, var_x());
+        let e = un(Operator::PostInc, build::x());
         let mut x = 5;
         let mut y = 0;
         let mut fatal = false;
@@ -1091,7 +2078,7 @@ mod tests {
 
     #[test]
     fn test_post_dec() {
-        let e = unary(Operator::PostDec, var_x());
+        let e = un(Operator::PostDec, build::x());
         let mut x = 5;
         let mut y = 0;
         let mut fatal = false;
@@ -1101,7 +2088,7 @@ mod tests {
 
     #[test]
     fn test_pre_inc_y() {
-        let e = unary(Operator::PreInc, var_y());
+        let e = un(Operator::PreInc, build::y());
         let mut x = 0;
         let mut y = 10;
         let mut fatal = false;
@@ -1109,13 +2096,36 @@ mod tests {
         assert_eq!(y, 11);
     }
 
+    #[test]
+    fn test_pre_inc_non_variable_is_fatal() {
+        // ++(x+1) has nothing to write to
+        let e = un(Operator::PreInc, bin(build::x(), Operator::Add, lit(1)));
+        let mut x = 3;
+        let mut y = 4;
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 0);
+        assert!(fatal);
+        assert_eq!((x, y), (3, 4));
+    }
+
+    #[test]
+    fn test_assign_to_non_variable_is_fatal() {
+        let e = bin(lit(2), Operator::AddEq, build::y());
+        let mut x = 3;
+        let mut y = 4;
+        let mut fatal = false;
+        assert_eq!(naive_eval(&e, &mut x, &mut y, &mut fatal), 0);
+        assert!(fatal);
+        assert_eq!((x, y), (3, 4));
+    }
+
     // ==================== Complex Expression Tests ====================
 
     #[test]
     fn test_nested_expression() {
         // (x + y) * 2
-        let inner = Arc::new(binary(var_x(), Operator::Add, var_y()));
-        let e = binary(inner, Operator::Mul, lit(2));
+        let inner = bin(build::x(), Operator::Add, build::y());
+        let e = bin(inner, Operator::Mul, lit(2));
         let mut x = 3;
         let mut y = 5;
         let mut fatal = false;
@@ -1125,9 +2135,9 @@ mod tests {
     #[test]
     fn test_deeply_nested() {
         // ((x + 1) * 2) - 3
-        let inner1 = Arc::new(binary(var_x(), Operator::Add, lit(1)));
-        let inner2 = Arc::new(binary(inner1, Operator::Mul, lit(2)));
-        let e = binary(inner2, Operator::Sub, lit(3));
+        let inner1 = bin(build::x(), Operator::Add, lit(1));
+        let inner2 = bin(inner1, Operator::Mul, lit(2));
+        let e = bin(inner2, Operator::Sub, lit(3));
         let mut x = 5;
         let mut y = 0;
         let mut fatal = false;
@@ -1138,32 +2148,414 @@ mod tests {
 
     #[test]
     fn test_is_assignment() {
-        let assign = binary(var_x(), Operator::AssignEq, lit(5));
-        let add_eq = binary(var_x(), Operator::AddEq, lit(5));
-        let add = binary(var_x(), Operator::Add, lit(5));
+        let assign = bin(build::x(), Operator::AssignEq, lit(5));
+        let add_eq = bin(build::x(), Operator::AddEq, lit(5));
+        let add = bin(build::x(), Operator::Add, lit(5));
         
         assert!(assign.is_assignment());
         assert!(add_eq.is_assignment());
         assert!(!add.is_assignment());
     }
 
+    #[test]
+    fn test_referenced_vars() {
+        let x_plus_y = bin(build::x(), Operator::Add, build::y());
+        for (e, vars) in [(build::x(), 1), (build::y(), 2), (x_plus_y, 3), (lit(5), 0)] {
+            assert_eq!(e.referenced_vars(), vars, "{}", format_expression(&e, &['x', 'y']));
+            assert_eq!(e.uses_var(Operator::Var), vars & 1 != 0);
+            assert_eq!(e.uses_var(Operator::VarY), vars & 2 != 0);
+        }
+        // Written but not read still counts
+        let e = bin(build::y(), Operator::AssignEq, ternary(build::x(), lit(1), lit(2)));
+        assert_eq!(e.referenced_vars(), 3);
+    }
+
+    #[test]
+    fn test_grid_constant() {
+        for b in [build::Builder::new(false), build::Builder::new(true)] {
+            assert_eq!(b.bin(b.x(), Operator::Sub, b.x()).grid_constant(), Some(0));
+            assert_eq!(b.bin(b.x(), Operator::BitAnd, b.un(Operator::BitNot, b.x())).grid_constant(), Some(0));
+            assert_eq!(b.bin(b.bin(b.y(), Operator::Mul, b.lit(0)), Operator::Add, b.lit(2)).grid_constant(), Some(2));
+            assert_eq!(b.lit(3).grid_constant(), Some(3));
+            assert_eq!(b.bin(b.x(), Operator::Add, b.lit(1)).grid_constant(), None);
+            // Constant result, but writes x
+            assert_eq!(b.bin(b.bin(b.x(), Operator::AssignEq, b.lit(1)), Operator::Mul, b.lit(0)).grid_constant(), None);
+            // 0 wherever it isn't fatal
+            assert_eq!(b.bin(b.bin(b.x(), Operator::Sub, b.x()), Operator::Div, b.y()).grid_constant(), None);
+        }
+    }
+
+    #[test]
+    fn test_expr_debug() {
+        let mut e = bin(build::x(), Operator::AddEq, bin(build::y(), Operator::Mul, lit(2)));
+        Arc::get_mut(&mut e).unwrap().jit = Some(Arc::new(jit::jit_compile_expr(&e)));
+        assert_eq!(format!("{:?}", e), "x+=y*2 (AddEq)");
+        let tree = format!("{:#?}", e);
+        assert!(tree.starts_with("Expr {\n    op: AddEq,\n    left: Expr {\n        op: Var,"), "{}", tree);
+        assert!(tree.contains("op: Mul") && tree.contains("literal: 2") && !tree.contains("mid") && !tree.contains("Jit"), "{}", tree);
+        let e = build::saturating(build::x(), Operator::Add, lit(-1));
+        assert_eq!(format!("{:?}", e), "x+-1 (Add, Saturating)");
+        assert!(format!("{:#?}", e).starts_with("Expr {\n    op: Add,\n    mode: Saturating,"));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let (naive, jit) = (build::Builder::new(false), build::Builder::new(true));
+        let x_plus_x = |b: &build::Builder| b.bin(b.x(), Operator::Add, b.x());
+        let x_times_2 = |b: &build::Builder| b.bin(b.x(), Operator::Mul, b.lit(2));
+        // Same function, whichever way it's written or run
+        assert_eq!(fingerprint(&x_plus_x(&naive)), fingerprint(&x_times_2(&jit)));
+        assert_eq!(fingerprint(&x_plus_x(&jit)), fingerprint(&x_plus_x(&naive)));
+        assert_ne!(fingerprint(&x_plus_x(&naive)), fingerprint(&naive.bin(naive.x(), Operator::Add, naive.y())));
+        // Same value, but only one of them writes x
+        let zero = naive.bin(naive.x(), Operator::Sub, naive.x());
+        assert_ne!(fingerprint(&zero), fingerprint(&naive.bin(naive.x(), Operator::AssignEq, naive.lit(0))));
+        // Hash and == go through it
+        let mut cache = Cache::default();
+        cache.insert(x_plus_x(&jit));
+        assert!(cache.contains(&x_times_2(&naive)));
+    }
+
+    #[test]
+    fn test_structural_expr_keys() {
+        let (naive, jit) = (build::Builder::new(false), build::Builder::new(true));
+        let x_plus_y = naive.bin(naive.x(), Operator::Add, naive.y());
+        let y_plus_x = naive.bin(naive.y(), Operator::Add, naive.x());
+        let mut trees = hashbrown::HashSet::new();
+        trees.insert(StructuralExpr(x_plus_y.clone()));
+        trees.insert(StructuralExpr(y_plus_x.clone()));
+        // The same tree again, compiled this time
+        trees.insert(StructuralExpr(jit.bin(jit.x(), Operator::Add, jit.y())));
+        assert_eq!(trees.len(), 2);
+        let values: Cache = [x_plus_y, y_plus_x].into_iter().collect();
+        assert_eq!(values.len(), 1);
+        // Same ops in a different shape
+        assert_ne!(
+            StructuralExpr(naive.bin(naive.bin(naive.x(), Operator::Sub, naive.y()), Operator::Sub, naive.x())),
+            StructuralExpr(naive.bin(naive.x(), Operator::Sub, naive.bin(naive.y(), Operator::Sub, naive.x()))),
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_fatal_points() {
+        for b in [build::Builder::new(false), build::Builder::new(true)] {
+            let over = |n: Arc<Expr>, k| b.bin(n, Operator::Div, b.un(Operator::Parens, b.bin(b.y(), Operator::Sub, b.lit(k))));
+            assert_ne!(fingerprint(&over(b.x(), 1)), fingerprint(&over(b.x(), 2)));
+            assert_eq!(fingerprint(&over(b.x(), 1)), fingerprint(&over(b.x(), 1)));
+            // 0 wherever they aren't fatal, so only where they're fatal tells
+            // them apart
+            let zero = || b.bin(b.x(), Operator::Sub, b.x());
+            assert_ne!(fingerprint(&over(zero(), 1)), fingerprint(&over(zero(), 2)));
+            assert_ne!(fingerprint(&over(zero(), 1)), fingerprint(&b.lit(0)));
+        }
+    }
+
+    #[test]
+    fn test_sample_grid() {
+        let b = build::Builder::new(true);
+        // x%8 is x on the default square but not at x=8
+        let (mod8, x) = (b.bin(b.x(), Operator::Mod, b.lit(8)), b.x());
+        assert_eq!(fingerprint_over(&mod8, &grid_points_in(GRID_RANGE).collect::<Vec<_>>()), fingerprint(&x));
+        assert_eq!(fingerprint_over(&mod8, &[(0, 0), (3, 1)]), fingerprint_over(&x, &[(0, 0), (3, 1)]));
+        assert_ne!(fingerprint_over(&mod8, &[(0, 0), (8, 1)]), fingerprint_over(&x, &[(0, 0), (8, 1)]));
+        // Tests share the grid, so it's only ever the default here, and
+        // fixed once it's been used
+        assert_eq!(sample_grid().len(), 81);
+        assert!(set_grid(vec![(8, 1)]).is_err());
+        assert!(set_grid(Vec::new()).is_err());
+        assert_eq!(sample_grid().len(), 81);
+    }
+
+    #[test]
+    fn test_first_difference() {
+        for b in [build::Builder::new(false), build::Builder::new(true)] {
+            let over = |k| b.bin(b.x(), Operator::Div, b.un(Operator::Parens, b.bin(b.y(), Operator::Sub, b.lit(k))));
+            // -4/(-3-1) is 1, -4/(-3-2) is 0
+            assert_eq!(first_difference(&over(1), &over(2), GRID_RANGE), Some((-4, -3)));
+            assert_eq!(first_difference(&over(1), &over(1), GRID_RANGE), None);
+            let x_plus_x = b.bin(b.x(), Operator::Add, b.x());
+            assert_eq!(first_difference(&x_plus_x, &b.bin(b.x(), Operator::Mul, b.lit(2)), GRID_RANGE), None);
+            // Same value, but only one of them writes x
+            let zero = b.bin(b.x(), Operator::Sub, b.x());
+            assert_eq!(first_difference(&zero, &b.bin(b.x(), Operator::AssignEq, b.lit(0)), GRID_RANGE), Some((-4, -4)));
+            // x%8 is x until |x| reaches 8, past the equivalence grid
+            let mod8 = b.bin(b.x(), Operator::Mod, b.lit(8));
+            assert_eq!(first_difference(&mod8, &b.x(), GRID_RANGE), None);
+            assert_eq!(first_difference(&mod8, &b.x(), 8), Some((-8, -8)));
+        }
+    }
+
+    #[test]
+    fn test_grid_fatal_points_agree_across_backends() {
+        use std::hash::BuildHasher;
+        let state = hashbrown::DefaultHashBuilder::default();
+        let hash = |e: &Expr| state.hash_one(e);
+        let b = build::Builder::new(true);
+        // The JIT stops at y=0 with x as it was, naive_eval goes on to write 5
+        let stop_early = b.bin(b.bin(b.x(), Operator::DivEq, b.y()), Operator::Comma, b.bin(b.x(), Operator::AssignEq, b.lit(5)));
+        // A real 0 next to the fatal points: x=x%y at x=0
+        let zero = b.bin(b.x(), Operator::ModEq, b.y());
+        let pow = b.bin(b.x(), Operator::AssignEq, b.bin(b.y(), Operator::Pow, b.x()));
+        for e in [stop_early, zero, pow] {
+            assert!(e.jit.is_some());
+            let naive = e.without_code();
+            let code = Expr { bytecode: Some(to_bytecode(&e).into()), ..e.without_code() };
+            for other in [&naive, &code] {
+                assert_eq!(*e, *other, "{}", format_expression(&e, &['x', 'y']));
+                assert_eq!(hash(&e), hash(other), "{}", format_expression(&e, &['x', 'y']));
+            }
+        }
+    }
+
+    #[test]
+    fn test_grid_fatal_differs_from_zero() {
+        // x/y and y?x/y:0 only differ where y=0, which one is fatal at
+        let b = build::Builder::new(true);
+        let div = b.bin(b.x(), Operator::Div, b.y());
+        let guarded = b.ternary(b.y(), div.clone(), b.lit(0));
+        assert_ne!(*div, *guarded);
+        assert_ne!(div.without_code(), guarded.without_code());
+        assert_ne!(*b.bin(b.x(), Operator::AssignEq, div), *b.bin(b.x(), Operator::AssignEq, guarded));
+    }
+
+    #[test]
+    fn test_has_bad_target() {
+        assert!(!un(Operator::PostInc, build::y()).has_bad_target());
+        assert!(!bin(build::x(), Operator::MulEq, lit(2)).has_bad_target());
+        assert!(un(Operator::PreDec, lit(5)).has_bad_target());
+        assert!(bin(un(Operator::Parens, build::x()), Operator::AssignEq, lit(1)).has_bad_target());
+        assert!(!bin(lit(1), Operator::Add, lit(2)).has_bad_target());
+    }
+
+    // ==================== Printing Tests ====================
+
+    fn fmt(e: &Expr) -> String {
+        format_expression(e, &['x', 'y'])
+    }
+
+    #[test]
+    fn test_format_nested_parens() {
+        let e = un(Operator::Parens, un(Operator::Parens, bin(build::x(), Operator::Add, lit(1))));
+        assert_eq!(fmt(&e), "(x+1)");
+    }
+
+    #[test]
+    fn test_format_parens_assignment() {
+        let e = un(Operator::Parens, bin(build::x(), Operator::AssignEq, lit(5)));
+        assert_eq!(fmt(&e), "(x=5)");
+    }
+
+    #[test]
+    fn test_format_parens_unary() {
+        let e = un(Operator::Parens, un(Operator::Neg, build::x()));
+        assert_eq!(fmt(&e), "-x");
+        let e = bin(build::y(), Operator::Mul, un(Operator::Parens, un(Operator::PostInc, build::x())));
+        assert_eq!(fmt(&e), "y*x++");
+    }
+
+    #[test]
+    fn test_format_postfix_operand_in_right() {
+        for (op, text) in [(Operator::PostInc, "x++"), (Operator::PostDec, "x--")] {
+            assert_eq!(fmt(&un(op, build::x())), text);
+            assert_eq!(fmt(&bin(build::y(), Operator::Sub, un(op, build::x()))), format!("y-{}", text));
+            assert_eq!(fmt(&bin(un(op, build::x()), Operator::Mul, lit(2))), format!("{}*2", text));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "PostInc takes its operand in right, not left")]
+    fn test_format_postfix_operand_in_left_rejected() {
+        // naive_eval would find no target here and call it fatal
+        let on_left = Expr { left: Some(build::x()), mid: None, right: None, literal: 0, mode: Mode::Plain, op: Operator::PostInc, jit: None, bytecode: None };
+        fmt(&on_left);
+    }
+
+    #[test]
+    fn test_format_parens_atom() {
+        assert_eq!(fmt(&un(Operator::Parens, build::x())), "x");
+        assert_eq!(fmt(&bin(lit(2), Operator::Add, un(Operator::Parens, lit(3)))), "2+3");
+    }
+
+    #[test]
+    fn test_format_pow_unary_base() {
+        let e = bin(un(Operator::Neg, build::x()), Operator::Pow, lit(2));
+        assert_eq!(fmt(&e), "(-x)**2");
+        let e = bin(un(Operator::Parens, un(Operator::Neg, build::x())), Operator::Pow, lit(2));
+        assert_eq!(fmt(&e), "(-x)**2");
+        let e = bin(lit(2), Operator::Pow, un(Operator::Neg, build::x()));
+        assert_eq!(fmt(&e), "2**-x");
+    }
+
+    #[test]
+    fn test_format_negative_literal() {
+        assert_eq!(fmt(&lit(-5)), "-5");
+        assert_eq!(fmt(&bin(build::x(), Operator::Mul, lit(-5))), "x*-5");
+        assert_eq!(fmt(&bin(build::x(), Operator::SubEq, lit(-5))), "x-=-5");
+        assert_eq!(fmt(&bin(lit(-5), Operator::Pow, build::x())), "(-5)**x");
+        assert_eq!(fmt(&bin(build::x(), Operator::Pow, lit(-5))), "x**-5");
+    }
+
+    #[test]
+    fn test_format_sign_tokens_dont_merge() {
+        // x - -5 must not read as x-- 5
+        assert_eq!(fmt(&bin(build::x(), Operator::Sub, lit(-5))), "x- -5");
+        assert_eq!(fmt(&un(Operator::Neg, lit(-5))), "- -5");
+        assert_eq!(fmt(&bin(build::x(), Operator::Sub, un(Operator::Neg, build::y()))), "x- -y");
+        assert_eq!(fmt(&bin(build::x(), Operator::Add, un(Operator::PreInc, build::y()))), "x+ ++y");
+        assert_eq!(fmt(&un(Operator::Neg, un(Operator::PreDec, build::y()))), "- --y");
+        // x-- -5 already tokenizes as intended without the space
+        assert_eq!(fmt(&bin(un(Operator::PostDec, build::x()), Operator::Sub, lit(5))), "x---5");
+        assert_eq!(fmt(&bin(un(Operator::PostDec, build::x()), Operator::Sub, lit(-5))), "x--- -5");
+        assert_eq!(fmt(&bin(build::x(), Operator::Add, lit(-5))), "x+-5");
+    }
+
+    #[test]
+    fn test_clone_shares_code() {
+        let b = build::Builder::new(true);
+        let e = b.bin(b.x(), Operator::AddEq, b.bin(b.y(), Operator::Mul, b.lit(2)));
+        let copy = (*e).clone();
+        assert!(Arc::ptr_eq(copy.jit.as_ref().unwrap(), e.jit.as_ref().unwrap()));
+        assert!(Arc::ptr_eq(copy.right.as_ref().unwrap(), e.right.as_ref().unwrap()));
+        assert!(structural_eq(&copy, &e));
+
+        let mut edited = e.without_code();
+        assert!(edited.jit.is_none() && edited.bytecode.is_none());
+        edited.op = Operator::SubEq;
+        let (mut x, mut y, mut fatal) = (5, 3, false);
+        assert_eq!(naive_eval(&edited, &mut x, &mut y, &mut fatal), -1);
+    }
+
+    #[test]
+    fn test_structural_eq() {
+        let b = build::Builder::new(true);
+        let sum = |l, r| bin(l, Operator::Add, r);
+        let left = sum(sum(build::x(), build::y()), lit(1));
+        // Same shape, one side compiled
+        assert!(structural_eq(&left, &b.bin(b.bin(b.x(), Operator::Add, b.y()), Operator::Add, b.lit(1))));
+        // (x+y)+1 and x+(y+1) agree everywhere but aren't the same tree
+        let right = sum(build::x(), sum(build::y(), lit(1)));
+        assert_eq!(*left, *right);
+        assert!(!structural_eq(&left, &right));
+        assert!(!structural_eq(&lit(1), &lit(2)));
+        assert!(!structural_eq(&un(Operator::Neg, build::x()), &un(Operator::BitNot, build::x())));
+        assert!(!structural_eq(&un(Operator::Parens, left.clone()), &left));
+        assert!(structural_eq(
+            &ternary(build::x(), lit(2), build::y()),
+            &ternary(build::x(), lit(2), build::y()),
+        ));
+        assert!(!structural_eq(
+            &ternary(build::x(), lit(2), build::y()),
+            &ternary(build::x(), build::y(), lit(2)),
+        ));
+    }
+
+    #[test]
+    fn test_structural_cmp() {
+        let sum = |l, r| bin(l, Operator::Add, r);
+        let xy = sum(build::x(), build::y());
+        assert_eq!(structural_cmp(&xy, &sum(build::x(), build::y())), Ordering::Equal);
+        assert_eq!(structural_cmp(&xy, &sum(build::y(), build::x())), Ordering::Less);
+        // Size first, then op, then literal, then children
+        assert_eq!(structural_cmp(&lit(-1), &un(Operator::Neg, lit(1))), Ordering::Less);
+        assert_eq!(structural_cmp(&xy, &bin(build::x(), Operator::Sub, build::y())), Ordering::Less);
+        assert_eq!(structural_cmp(&lit(3), &lit(-1)), Ordering::Greater);
+        assert_eq!(structural_cmp(&sum(xy.clone(), lit(1)), &sum(xy, lit(2))), Ordering::Less);
+    }
+
+    #[test]
+    fn test_symbol_len_matches_printed_width() {
+        // What the generator counts for an operator is what it prints as
+        for op in Operator::all() {
+            let width = op.symbol().len();
+            let (e, operands) = match op as u8 {
+                0x20..=0xB3 => (bin(build::x(), op, build::y()), 2),
+                _ if op.symbol().is_empty() || matches!(op, Operator::Comma | Operator::Ternary | Operator::Parens) => continue,
+                0x00..=0x0F => (bin(build::x(), op, build::y()), 2),
+                _ => (un(op, build::x()), 1),
+            };
+            assert_eq!(printed_len(&e), operands + width, "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn test_generated_lengths_match_printed() {
+        let config = SearchConfig { use_ternary: true, use_min_max: true, ..SearchConfig::default() };
+        let ctx = build_cache(&config, 5);
+        for n in 1..=5 {
+            for e in ctx.expressions(n).unwrap() {
+                assert_eq!(printed_len(e), n, "{}", format_expression(e, &['x', 'y']));
+            }
+            // A statement's length leaves out its target
+            for s in ctx.statements(n).unwrap() {
+                assert_eq!(printed_len(s), n + 1, "{}", format_expression(s, &['x', 'y']));
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_right_operand_same_precedence() {
+        let fmt = |e: &Expr| format_expression(e, &['x', 'y']);
+        let sum = bin(build::y(), Operator::Add, lit(1));
+        assert_eq!(fmt(&bin(build::x(), Operator::Sub, sum.clone())), "x-(y+1)");
+        assert_eq!(fmt(&bin(sum.clone(), Operator::Sub, build::x())), "y+1-x");
+        assert_eq!(fmt(&bin(build::x(), Operator::Div, bin(build::y(), Operator::Mul, lit(2)))), "x/(y*2)");
+        // ** groups right to left
+        assert_eq!(fmt(&bin(build::x(), Operator::Pow, bin(build::y(), Operator::Pow, lit(2)))), "x**y**2");
+    }
+
+    #[test]
+    fn test_printed_len_minimal_parens() {
+        let sum = bin(build::y(), Operator::Add, lit(1));
+        // x*(y+1), spelled with and without an explicit Parens node
+        assert_eq!(printed_len(&bin(build::x(), Operator::Mul, sum.clone())), 7);
+        assert_eq!(printed_len(&bin(build::x(), Operator::Mul, un(Operator::Parens, sum.clone()))), 7);
+        assert_eq!(printed_len(&bin(build::x(), Operator::Mul, un(Operator::Parens, un(Operator::Parens, sum.clone())))), 7);
+        // Lower-precedence children of + need none
+        assert_eq!(printed_len(&bin(build::x(), Operator::Add, bin(build::y(), Operator::Mul, lit(2)))), 5);
+        assert_eq!(printed_len(&un(Operator::Neg, sum)), 6);
+        assert_eq!(printed_len(&un(Operator::Parens, un(Operator::Not, build::x()))), 2);
+    }
+
+    #[test]
+    fn test_format_parens_balanced_in_operand() {
+        // (x=5)+1, whether or not the tree spells out the Parens
+        let assign = bin(build::x(), Operator::AssignEq, lit(5));
+        let e = bin(un(Operator::Parens, assign.clone()), Operator::Add, lit(1));
+        assert_eq!(fmt(&e), "(x=5)+1");
+        let e = bin(assign, Operator::Add, lit(1));
+        assert_eq!(fmt(&e), "(x=5)+1");
+    }
+
+    #[test]
+    fn test_format_post_inc_and_ternary() {
+        let e = build::ternary(un(Operator::PostInc, build::y()), lit(1), un(Operator::Neg, build::x()));
+        assert_eq!(fmt(&e), "y++?1:-x");
+    }
+
     // ==================== Expression Equality Tests ====================
 
     #[test]
     fn test_expr_equality_same_literal() {
         let e1 = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: 5,
+            mode: Mode::Plain,
             op: Operator::Literal,
             jit: None,
+            bytecode: None,
         };
         let e2 = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: 5,
+            mode: Mode::Plain,
             op: Operator::Literal,
             jit: None,
+            bytecode: None,
         };
         assert_eq!(e1, e2);
     }
@@ -1172,17 +2564,23 @@ mod tests {
     fn test_expr_equality_different_literal() {
         let e1 = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: 5,
+            mode: Mode::Plain,
             op: Operator::Literal,
             jit: None,
+            bytecode: None,
         };
         let e2 = Expr {
             left: None,
+            mid: None,
             right: None,
             literal: 10,
+            mode: Mode::Plain,
             op: Operator::Literal,
             jit: None,
+            bytecode: None,
         };
         assert_ne!(e1, e2);
     }
@@ -1190,36 +2588,22 @@ mod tests {
     #[test]
     fn test_expr_equality_semantically_equal() {
         // x + 0 should equal x for semantic equality
-        let e1 = binary(var_x(), Operator::Add, lit(0));
-        let e2 = Expr {
-            left: None,
-            right: None,
-            literal: 0,
-            op: Operator::Var,
-            jit: None,
-        };
-        assert_eq!(e1, e2);
+        let e1 = bin(build::x(), Operator::Add, lit(0));
+        assert_eq!(e1, build::x());
     }
 
     #[test]
     fn test_expr_equality_mul_by_one() {
         // x * 1 should equal x
-        let e1 = binary(var_x(), Operator::Mul, lit(1));
-        let e2 = Expr {
-            left: None,
-            right: None,
-            literal: 0,
-            op: Operator::Var,
-            jit: None,
-        };
-        assert_eq!(e1, e2);
+        let e1 = bin(build::x(), Operator::Mul, lit(1));
+        assert_eq!(e1, build::x());
     }
 
     // ==================== Wrapping Arithmetic Tests ====================
 
     #[test]
     fn test_wrapping_add() {
-        let e = binary(lit(i64::MAX), Operator::Add, lit(1));
+        let e = bin(lit(i64::MAX), Operator::Add, lit(1));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -1229,7 +2613,7 @@ mod tests {
 
     #[test]
     fn test_wrapping_sub() {
-        let e = binary(lit(i64::MIN), Operator::Sub, lit(1));
+        let e = bin(lit(i64::MIN), Operator::Sub, lit(1));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -1239,7 +2623,7 @@ mod tests {
 
     #[test]
     fn test_wrapping_neg() {
-        let e = unary(Operator::Neg, lit(i64::MIN));
+        let e = un(Operator::Neg, lit(i64::MIN));
         let mut x = 0;
         let mut y = 0;
         let mut fatal = false;
@@ -4,456 +4,757 @@
 
 mod params;
 
-use expr_rust::{Expr, Operator, print_expression, NumT, naive_eval, jit};
+use expr_rust::{Context, Expr, SearchConfig, make_statement, format_expression, printed_len, NumT, naive_eval, run_bytecode};
+use expr_rust::{resume_from, save_checkpoint, search_length, FoundPair, PairMatcher};
+use expr_rust::{sample_grid, set_grid, visit, Operator};
+use expr_rust::{combined_step, find_recurrences, is_seed_robust, is_total, run_sequence_every, to_bytecode, Recurrence, TableMatcher, TransitionMode};
 use hashbrown::{HashMap, HashSet};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::path::Path;
 use std::time::Instant;
 use rayon::prelude::*;
 
 use params::*;
 
-type Cache = HashSet<Arc<Expr>>;
+// =============================================================================
+// EVALUATION
+// =============================================================================
 
-struct Context {
-    expressions: HashMap<usize, Cache>,
-    statements: HashMap<usize, Cache>,
-    var_expressions: Vec<Arc<Expr>>,
+/// Run a statement as machine code if it was compiled, else as bytecode
+/// (USE_JIT off, or modular Pow), else by walking the tree. False if fatal.
+#[inline]
+fn eval_statement(e: &Expr, x: &mut NumT, y: &mut NumT) -> bool {
+    if let Some(jit) = &e.jit {
+        unsafe { jit.func()(x, y) };
+        true
+    } else if let Some(code) = &e.bytecode {
+        run_bytecode(code, x, y).expect("to_bytecode output is well-formed").is_some()
+    } else {
+        let mut fatal = false;
+        naive_eval(e, x, y, &mut fatal);
+        !fatal
+    }
 }
 
-impl Context {
-    fn new() -> Self {
-        Self {
-            expressions: HashMap::new(),
-            statements: HashMap::new(),
-            var_expressions: Vec::new(),
+/// One step of `e_x; e_y` under `mode` (see `TransitionMode`), false if fatal
+#[inline]
+fn step(e_x: &Expr, e_y: &Expr, x: &mut NumT, y: &mut NumT, mode: TransitionMode) -> bool {
+    match mode {
+        TransitionMode::Sequential => eval_statement(e_x, x, y) && eval_statement(e_y, y, x),
+        TransitionMode::Simultaneous => {
+            let (mut x_of_x, mut y_of_x) = (*x, *y);
+            let (mut y_of_y, mut x_of_y) = (*y, *x);
+            let ok = eval_statement(e_x, &mut x_of_x, &mut y_of_x) && eval_statement(e_y, &mut y_of_y, &mut x_of_y);
+            (*x, *y) = (x_of_x, y_of_y);
+            ok
         }
     }
 }
 
-// =============================================================================
-// EXPRESSION CONSTRUCTION
-// =============================================================================
+/// Test a pair with specific initial values, returns true if matches ANSWER
+fn test_pair_with_init(e_x: &Expr, e_y: &Expr, init_x: NumT, init_y: NumT) -> bool {
+    test_pair_with_matcher(e_x, e_y, init_x, init_y, Matcher::new())
+}
 
-fn make_expr(left: Option<Arc<Expr>>, right: Option<Arc<Expr>>, literal: NumT, op: Operator) -> Expr {
-    let mut e = Expr { left, right, literal, op, jit: None };
-    if USE_JIT {
-        let jit = jit::jit_compile_expr(&e);
-        e.jit = Some(Arc::new(jit));
+fn test_pair_with_matcher(e_x: &Expr, e_y: &Expr, init_x: NumT, init_y: NumT, mut matcher: Matcher) -> bool {
+    let mut x = init_x;
+    let mut y = init_y;
+
+    for (i, _) in ANSWER.iter().enumerate() {
+        for _ in 0..STEPS_PER_TERM {
+            if !(step(e_x, e_y, &mut x, &mut y, TRANSITION) && matcher.allows(x, y)) {
+                return false;
+            }
+        }
+        if !matcher.match_one(i, x, y) {
+            return false;
+        }
     }
-    e
+    matcher.match_final(e_x, e_y)
 }
 
-fn cache_expression(cache: &mut Cache, e: Expr) {
-    cache.insert(Arc::new(e));
+/// The recurrences ANSWER follows, for REQUIRE_SEED_ROBUST
+static RECURRENCES: LazyLock<Vec<Recurrence>> = LazyLock::new(|| find_recurrences(ANSWER));
+
+/// The seeds from params.rs, INIT_PAIRS or the range rectangle
+static INITS: LazyLock<Vec<(NumT, NumT)>> = LazyLock::new(init_pairs);
+
+/// Whether a matching pair passes REQUIRE_SEED_ROBUST (always, when it's off)
+fn is_robust(e_x: &Expr, e_y: &Expr) -> bool {
+    !REQUIRE_SEED_ROBUST || is_seed_robust(e_x, e_y, ROBUST_INITS, &RECURRENCES, ANSWER.len(), STEPS_PER_TERM, TRANSITION)
 }
 
-fn make_statement(var: &Arc<Expr>, expr: &Arc<Expr>, op: Operator) -> Expr {
-    make_expr(Some(var.clone()), Some(expr.clone()), -1, op)
+/// Whether a matching pair passes REQUIRE_TOTAL (always, when it's off)
+fn is_total_on_inits(e_x: &Expr, e_y: &Expr) -> bool {
+    !REQUIRE_TOTAL || is_total(e_x, e_y, &INITS, ANSWER.len(), STEPS_PER_TERM, TRANSITION)
 }
 
-// =============================================================================
-// EVALUATION
-// =============================================================================
+/// Test a pair from each seed in INITS, returns Some((init_x, init_y)) for the first that matches.
+/// Past PARALLEL_INIT_THRESHOLD seeds they're tried in parallel, still reporting the first.
+fn test_pair(e_x: &Expr, e_y: &Expr) -> Option<(NumT, NumT)> {
+    let matches = |&(init_x, init_y): &(NumT, NumT)| test_pair_with_init(e_x, e_y, init_x, init_y);
+    let found = if USE_MULTITHREAD && INITS.len() > PARALLEL_INIT_THRESHOLD {
+        INITS.par_iter().copied().find_first(matches)
+    } else {
+        INITS.iter().copied().find(matches)
+    }?;
+    (is_robust(e_x, e_y) && is_total_on_inits(e_x, e_y)).then_some(found)
+}
 
-#[inline]
-fn eval_naive(e_x: &Expr, e_y: &Expr, x: &mut NumT, y: &mut NumT) -> bool {
-    let mut fatal = false;
-    naive_eval(e_x, x, y, &mut fatal);
-    if fatal { return false; }
-    naive_eval(e_y, y, x, &mut fatal);
-    !fatal
+/// Like `test_pair`, but every seed in INITS the pair matches from, in
+/// INITS order, so the first is the one `test_pair` reports. Empty if none.
+fn test_pair_all(e_x: &Expr, e_y: &Expr) -> Vec<(NumT, NumT)> {
+    let matches = |&(init_x, init_y): &(NumT, NumT)| test_pair_with_init(e_x, e_y, init_x, init_y);
+    let found: Vec<(NumT, NumT)> = if USE_MULTITHREAD && INITS.len() > PARALLEL_INIT_THRESHOLD {
+        INITS.par_iter().copied().filter(matches).collect()
+    } else {
+        INITS.iter().copied().filter(matches).collect()
+    };
+    if found.is_empty() || !is_robust(e_x, e_y) || !is_total_on_inits(e_x, e_y) {
+        return Vec::new();
+    }
+    found
 }
 
-#[inline]
-fn eval_jit(e_x: &Expr, e_y: &Expr, x: &mut NumT, y: &mut NumT) -> bool {
-    let f_x = e_x.jit.as_ref().map(|j| j.func()).expect("No JIT for e_x");
-    let f_y = e_y.jit.as_ref().map(|j| j.func()).expect("No JIT for e_y");
-    unsafe {
-        f_x(x, y);
-        f_y(y, x);
-    }
-    true
+/// The library search's view of params.rs: ANSWER from INITS through
+/// Matcher, run as compiled, behind the REQUIRE_* filters and SYMMETRIC
+struct ParamsMatcher;
+
+impl PairMatcher for ParamsMatcher {
+    fn first_match(&self, e_x: &Expr, e_y: &Expr) -> Option<(NumT, NumT)> {
+        test_pair(e_x, e_y)
+    }
+
+    fn symmetric(&self) -> bool {
+        SYMMETRIC
+    }
 }
 
-/// Test a pair with specific initial values, returns true if matches ANSWER
-fn test_pair_with_init(e_x: &Expr, e_y: &Expr, init_x: NumT, init_y: NumT) -> bool {
-    let mut x = init_x;
-    let mut y = init_y;
-    let mut matcher = Matcher::new();
-    
-    for (i, _) in ANSWER.iter().enumerate() {
-        let ok = if USE_JIT {
-            eval_jit(e_x, e_y, &mut x, &mut y)
-        } else {
-            eval_naive(e_x, e_y, &mut x, &mut y)
-        };
-        
-        if !ok || !matcher.match_one(i, x) {
-            return false;
+fn format_result(e_x: &Expr, e_y: &Expr, init_x: NumT, init_y: NumT) -> String {
+    let mut line = format!("x={}, y={} : {}; {}", init_x, init_y, format_expression(e_x, &['x', 'y']), format_expression(e_y, &['y', 'x']));
+    // The terms the Matcher saw, as proof of the match
+    if PRINT_SEQUENCE {
+        if let Some(terms) = run_sequence_every(e_x, e_y, init_x, init_y, ANSWER.len(), STEPS_PER_TERM, TRANSITION) {
+            let terms: Vec<String> = terms.iter().map(|t| t.to_string()).collect();
+            line += &format!(" -> {}", terms.join(", "));
         }
     }
-    matcher.match_final(e_x, e_y)
+    line
 }
 
-/// Test a pair against all initial value combinations, returns Some((init_x, init_y)) if found
-fn test_pair(e_x: &Expr, e_y: &Expr) -> Option<(NumT, NumT)> {
-    for init_x in INIT_X_MIN..=INIT_X_MAX {
-        for init_y in INIT_Y_MIN..=INIT_Y_MAX {
-            if test_pair_with_init(e_x, e_y, init_x, init_y) {
-                return Some((init_x, init_y));
-            }
+/// For SHORTEST_PER_CLASS: the step and the init values, so matches that step
+/// alike from the same start share a class. A sequential step is one program
+/// (see `combined_step`); a simultaneous one is both statements, as each
+/// runs from the same x and y
+type Class = (Vec<Arc<Expr>>, NumT, NumT);
+
+fn step_class(e_x: &Expr, e_y: &Expr) -> Vec<Arc<Expr>> {
+    match TRANSITION {
+        TransitionMode::Sequential => vec![combined_step(e_x, e_y)],
+        TransitionMode::Simultaneous => vec![Arc::new(e_x.clone()), Arc::new(e_y.clone())],
+    }
+}
+
+/// A match: the length it was found at, its sort key for SORT_SOLUTIONS
+/// (total printed length, then the statements' bytecode, then the init
+/// values), its class, unless it's a pure expression the pair itself, and
+/// with FIND_ALL_INITS every seed it matches from
+struct Solution {
+    n: usize,
+    key: (usize, Vec<u8>, Vec<u8>, NumT, NumT),
+    class: Class,
+    line: String,
+    pair: Option<FoundPair>,
+    seeds: Vec<(NumT, NumT)>,
+}
+
+impl Solution {
+    fn of_pair(pair: FoundPair) -> Self {
+        let FoundPair { length, ref e_x, ref e_y, init_x, init_y } = pair;
+        Self {
+            n: length,
+            key: (printed_len(e_x) + printed_len(e_y), to_bytecode(e_x), to_bytecode(e_y), init_x, init_y),
+            class: (step_class(e_x, e_y), init_x, init_y),
+            line: format_result(e_x, e_y, init_x, init_y),
+            seeds: if FIND_ALL_INITS { test_pair_all(e_x, e_y) } else { Vec::new() },
+            pair: Some(pair),
         }
     }
-    None
 }
 
-fn print_result(e_x: &Expr, e_y: &Expr, init_x: NumT, init_y: NumT) {
-    use std::io::Write;
-    let stdout = std::io::stdout();
-    let mut lock = stdout.lock();
-    write!(lock, "x={}, y={} : ", init_x, init_y).unwrap();
-    drop(lock);
-    
-    print_expression(e_x, &['x', 'y']);
-    print!("; ");
-    print_expression(e_y, &['y', 'x']);
-    println!();
+/// Number of matching pairs found, indexed by the longer statement's length,
+/// and the matches waiting for `flush` when they're held back
+struct Solutions {
+    counts: Vec<AtomicUsize>,
+    pending: Mutex<Vec<Solution>>,
+    /// Shortest total printed length reported so far per class
+    shortest: Mutex<HashMap<Class, usize>>,
+    /// Stop once this many distinct matches are found (MAX_SOLUTIONS)
+    max_solutions: Option<usize>,
+    /// Distinct matches let through so far, for `max_solutions`
+    found: AtomicUsize,
+    /// Classes among `pending`, to tell a distinct match from another member
+    pending_classes: Mutex<HashSet<Class>>,
+    /// Each reported pair, for CHECKPOINT
+    reported: Mutex<Vec<FoundPair>>,
+    /// Set once the last of the `max_solutions` slots is taken, to end the cached search
+    stop: AtomicBool,
 }
 
-fn gen_expressions(ctx: &mut Context, n: usize) {
-    let mut en = HashSet::new();
+impl Solutions {
+    fn new(max_len: usize, max_solutions: Option<usize>) -> Self {
+        Self {
+            counts: (0..=max_len).map(|_| AtomicUsize::new(0)).collect(),
+            pending: Mutex::new(Vec::new()),
+            shortest: Mutex::new(HashMap::new()),
+            max_solutions,
+            found: AtomicUsize::new(0),
+            pending_classes: Mutex::new(HashSet::new()),
+            reported: Mutex::new(Vec::new()),
+            stop: AtomicBool::new(false),
+        }
+    }
 
-    // Length 1: variables and literals
-    if n == 1 {
-        let vars = [Operator::Var, Operator::VarY];
-        for &op in &vars {
-            let e = make_expr(None, None, -1, op);
-            let arc = Arc::new(e);
-            en.insert(arc.clone());
-            ctx.var_expressions.push(arc);
+    /// Tally a match as reported, printing it unless COUNT_ONLY is set
+    fn report(&self, solution: Solution) {
+        self.counts[solution.n].fetch_add(1, Ordering::Relaxed);
+        if !COUNT_ONLY {
+            if solution.seeds.is_empty() {
+                println!("{}", solution.line);
+            } else {
+                let seeds: Vec<String> = solution.seeds.iter().map(|(x, y)| format!("({}, {})", x, y)).collect();
+                println!("{} | seeds: {}", solution.line, seeds.join(" "));
+            }
         }
-        
-        for &lit in LITERALS {
-            cache_expression(&mut en, make_expr(None, None, lit, Operator::Literal));
+        if let Some(pair) = solution.pair.filter(|_| CHECKPOINT.is_some()) {
+            self.reported.lock().unwrap().push(pair);
         }
     }
 
-    // Length 3: increment/decrement operators
-    if n == 3 {
-        if let Some(exprs_1) = ctx.expressions.get(&1) {
-            for e_r in exprs_1 {
-                if e_r.op != Operator::Literal {
-                    for incdec in INCDEC_OPERATORS {
-                        cache_expression(&mut en, make_expr(None, Some(e_r.clone()), -1, incdec.op));
-                    }
-                }
+    /// Report again the pairs a checkpoint was saved with, each the
+    /// shortest of its class so far
+    fn restore(&self, pairs: Vec<FoundPair>) {
+        self.found.fetch_add(pairs.len(), Ordering::Relaxed);
+        let mut shortest = self.shortest.lock().unwrap();
+        for pair in pairs {
+            let solution = Solution::of_pair(pair);
+            if SHORTEST_PER_CLASS {
+                shortest.insert(solution.class.clone(), solution.key.0);
             }
+            self.report(solution);
         }
     }
 
-    // Generate binary expressions
-    {
-        let expressions_ref = &ctx.expressions;
-
-        // Generate binary ops for a given length split
-        let gen_binary_ops = |n_l: usize, op_len: usize| -> Vec<Expr> {
-            let mut local_exprs = Vec::new();
-            let n_r = n.saturating_sub(n_l + op_len);
-            if n_r < 1 { return local_exprs; }
-            
-            if let (Some(exprs_l), Some(exprs_r)) = (expressions_ref.get(&n_l), expressions_ref.get(&n_r)) {
-                for e_l in exprs_l {
-                    for e_r in exprs_r {
-                        if PRUNE_CONST_EXPR && e_l.op == Operator::Literal && e_r.op == Operator::Literal {
-                            continue;
-                        }
-
-                        let op_l_val = e_l.op as u8;
-                        let op_r_val = e_r.op as u8;
-
-                        // Check each enabled binary operator
-                        for bin_op in binary_ops_by_len(op_len) {
-                            // Apply precedence rules based on operator type
-                            let can_apply = match bin_op.op {
-                                Operator::BitOr => op_l_val >= 0x40 && op_r_val >= 0x50,
-                                Operator::BitXor => op_l_val >= 0x50 && op_r_val >= 0x60,
-                                Operator::BitAnd => op_l_val >= 0x60 && op_r_val >= 0x70,
-                                Operator::Eq | Operator::Neq => op_l_val >= 0x70 && op_r_val >= 0x80,
-                                Operator::Lt | Operator::Gt => op_l_val >= 0x80 && op_r_val >= 0x90,
-                                Operator::Leq | Operator::Geq => op_l_val >= 0x80 && op_r_val >= 0x90,
-                                Operator::BitShl | Operator::BitShr => op_l_val >= 0x90 && op_r_val >= 0xA0,
-                                Operator::Add | Operator::Sub => op_l_val >= 0xA0 && op_r_val >= 0xB0,
-                                Operator::Mul | Operator::Div | Operator::Mod | Operator::Pow => {
-                                    op_l_val >= 0xB0 && op_r_val >= 0xC0 && e_r.literal != 1
-                                }
-                                _ => false,
-                            };
-                            
-                            if can_apply {
-                                local_exprs.push(make_expr(Some(e_l.clone()), Some(e_r.clone()), -1, bin_op.op));
-                            }
-                        }
-                    }
-                }
-            }
-            local_exprs
-        };
+    /// Whether `max_solutions` distinct matches were found, so the search
+    /// can stop scheduling work
+    fn is_full(&self) -> bool {
+        self.max_solutions.is_some_and(|max| self.found.load(Ordering::Relaxed) >= max)
+    }
 
-        // 1-byte operators
-        if n > 2 {
-            let new_exprs: Vec<Expr> = if USE_MULTITHREAD {
-                (1..(n - 1)).into_par_iter().flat_map(|n_l| gen_binary_ops(n_l, 1)).collect()
-            } else {
-                (1..(n - 1)).flat_map(|n_l| gen_binary_ops(n_l, 1)).collect()
-            };
+    /// Take one of the `max_solutions` slots for a distinct match, false if none are left
+    fn claim(&self) -> bool {
+        let Some(max) = self.max_solutions else { return true };
+        let claimed = self.found.fetch_add(1, Ordering::Relaxed);
+        if claimed + 1 >= max {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+        claimed < max
+    }
 
-            if USE_MULTITHREAD {
-                en.par_extend(new_exprs.into_par_iter().map(Arc::new));
-            } else {
-                en.extend(new_exprs.into_iter().map(Arc::new));
-            }
+    /// Whether a held-back match would be reported as a class of its own,
+    /// rather than lose to a shorter member or share a pending one's slot
+    fn is_distinct(&self, solution: &Solution, pending_classes: &HashSet<Class>) -> bool {
+        if !SHORTEST_PER_CLASS {
+            return true;
         }
+        let reported = self.shortest.lock().unwrap().get(&solution.class).is_some_and(|&best| best <= solution.key.0);
+        !reported && !pending_classes.contains(&solution.class)
+    }
 
-        // 2-byte operators
-        if n > 3 {
-            let new_exprs: Vec<Expr> = if USE_MULTITHREAD {
-                (1..(n - 2)).into_par_iter().flat_map(|n_l| gen_binary_ops(n_l, 2)).collect()
-            } else {
-                (1..(n - 2)).flat_map(|n_l| gen_binary_ops(n_l, 2)).collect()
-            };
+    /// Tally a matching pair, printing it unless COUNT_ONLY is set. This is
+    /// the library search's `on_solution` callback.
+    fn record(&self, pair: &FoundPair) {
+        self.emit(Solution::of_pair(pair.clone()));
+    }
 
-            if USE_MULTITHREAD {
-                en.par_extend(new_exprs.into_par_iter().map(Arc::new));
-            } else {
-                en.extend(new_exprs.into_iter().map(Arc::new));
-            }
-        }
+    /// Tally a matching pure expression, for EXPR_ONLY
+    fn record_expr(&self, n: usize, e: &Expr) {
+        self.emit(Solution {
+            n,
+            key: (printed_len(e), to_bytecode(e), Vec::new(), 0, 0),
+            class: (vec![Arc::new(e.clone())], 0, 0),
+            line: format_expression(e, &['x', 'y']),
+            pair: None,
+            seeds: Vec::new(),
+        });
+    }
 
-        // Unary operators
-        if n > 1 {
-            if let Some(exprs_r) = expressions_ref.get(&(n - 1)) {
-                for e_r in exprs_r {
-                    if (e_r.op as u8) >= 0xC0 {
-                        for unary_op in UNARY_OPERATORS {
-                            cache_expression(&mut en, make_expr(None, Some(e_r.clone()), -1, unary_op.op));
-                        }
+    fn emit(&self, solution: Solution) {
+        if SORT_SOLUTIONS || SHORTEST_PER_CLASS {
+            if self.max_solutions.is_some() {
+                // Held while claiming, so two members of a new class can't both take a slot
+                let mut pending_classes = self.pending_classes.lock().unwrap();
+                if self.is_distinct(&solution, &pending_classes) {
+                    if !self.claim() {
+                        return;
+                    }
+                    if SHORTEST_PER_CLASS {
+                        pending_classes.insert(solution.class.clone());
                     }
                 }
             }
-        }
-
-        // Parentheses
-        if USE_PARENS && n > 2 {
-            if let Some(exprs_r) = expressions_ref.get(&(n - 2)) {
-                for e_r in exprs_r {
-                    cache_expression(&mut en, make_expr(None, Some(e_r.clone()), -1, Operator::Parens));
-                }
+            self.pending.lock().unwrap().push(solution);
+        } else {
+            if self.claim() {
+                self.report(solution);
             }
         }
     }
-    
-    ctx.expressions.insert(n, en);
-}
-
-// =============================================================================
-// STATEMENT GENERATION
-// =============================================================================
-
-fn gen_statements(ctx: &mut Context, n: usize) {
-    if ctx.expressions.get(&n).is_none() {
-        return;
-    }
-    
-    let mut sn = HashSet::new();
-    let e_l = if !ctx.var_expressions.is_empty() { 
-        ctx.var_expressions[0].clone() 
-    } else { 
-        return; 
-    };
 
-    let expressions_ref = &ctx.expressions;
-
-    // Generate statements for each assignment operator length
-    for assign_op in ASSIGN_OPERATORS {
-        let expr_len = n.saturating_sub(assign_op.len);
-        if expr_len < 1 { continue; }
-        
-        if let Some(exprs_r) = expressions_ref.get(&expr_len) {
-            if USE_MULTITHREAD && exprs_r.len() > 100 && assign_op.len == 1 {
-                // Parallel for large sets with 1-byte operator
-                let new_stmts: Vec<Expr> = exprs_r.par_iter().map(|e_r| {
-                    make_expr(Some(e_l.clone()), Some(e_r.clone()), -1, assign_op.op)
-                }).collect();
-                sn.par_extend(new_stmts.into_par_iter().map(Arc::new));
-            } else {
-                for e_r in exprs_r {
-                    cache_expression(&mut sn, make_expr(Some(e_l.clone()), Some(e_r.clone()), -1, assign_op.op));
-                }
+    /// Tally and print the held-back matches in order, with SHORTEST_PER_CLASS
+    /// dropping any whose class was already reported at the same or a shorter
+    /// length; call once a length is searched
+    fn flush(&self) {
+        let mut pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        self.pending_classes.lock().unwrap().clear();
+        pending.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut shortest = self.shortest.lock().unwrap();
+        for solution in pending {
+            if SHORTEST_PER_CLASS {
+                let len = solution.key.0;
+                match shortest.get(&solution.class) {
+                    Some(&best) if best <= len => continue,
+                    _ => shortest.insert(solution.class.clone(), len),
+                };
             }
+            self.report(solution);
         }
     }
 
-    ctx.statements.insert(n, sn);
+    fn count(&self, n: usize) -> usize {
+        self.counts[n].load(Ordering::Relaxed)
+    }
 }
 
 // =============================================================================
 // SEARCH FUNCTIONS
 // =============================================================================
 
-fn dfs_search(ctx: &Context, target_n: usize) {
-    let var_x = &ctx.var_expressions[0];
-    
-    // Generate statements of length target_n on-the-fly
-    let gen_stmts_for_expr = |expr: &Arc<Expr>, expr_len: usize| -> Vec<Expr> {
-        let mut stmts = Vec::new();
-        
-        for assign_op in ASSIGN_OPERATORS {
-            if expr_len + assign_op.len == target_n {
-                stmts.push(make_statement(var_x, expr, assign_op.op));
+/// Total `op_cost` of a tree, for ordering the DFS
+fn tree_cost(e: &Expr) -> u32 {
+    let mut cost = 0;
+    visit(e, &mut |n| cost += op_cost(n.op));
+    cost
+}
+
+/// A DFS statement: one from the cache, or `x op= expr` built when it's tried
+#[derive(Clone, Copy)]
+enum Candidate<'a> {
+    Cached(&'a Arc<Expr>),
+    Assign(&'a Arc<Expr>, Operator),
+}
+
+/// Search lengths past the cache. The x statements are built on the fly;
+/// both sides are tried cheapest first by `op_cost`, so simple solutions
+/// surface early, though every pair of the length is still tested.
+fn dfs_search(ctx: &Context, config: &SearchConfig, target_n: usize, solutions: &Solutions) {
+    let var_x = &ctx.var_expressions()[0];
+
+    // The statements x op= expr of length target_n
+    let assigns: Vec<(u32, Candidate)> = (1..=MAX_CACHE_LENGTH)
+        .filter_map(|len| ctx.expressions(len).map(|exprs| (len, exprs)))
+        .flat_map(|(len, exprs)| exprs.iter().map(move |expr| (len, expr)))
+        .flat_map(|(len, expr)| {
+            let cost = tree_cost(expr);
+            config.assign_operators.iter()
+                .filter(move |assign_op| len + assign_op.len == target_n)
+                .map(move |assign_op| (cost + op_cost(assign_op.op), Candidate::Assign(expr, assign_op.op)))
+        })
+        .collect();
+
+    // y is also tried with every cached statement
+    let cached = (1..=MAX_CACHE_LENGTH)
+        .filter_map(|len| ctx.statements(len))
+        .flat_map(|s| s.iter())
+        .map(|stmt| (tree_cost(stmt), Candidate::Cached(stmt)));
+
+    // Stable sorts, so equal costs keep the generation order
+    let mut stmts_x = assigns.clone();
+    stmts_x.sort_by_key(|&(cost, _)| cost);
+    let mut stmts_y: Vec<(u32, Candidate)> = cached.chain(assigns).collect();
+    stmts_y.sort_by_key(|&(cost, _)| cost);
+
+    let build = |candidate: Candidate| match candidate {
+        Candidate::Cached(stmt) => stmt.clone(),
+        Candidate::Assign(expr, op) => Arc::new(make_statement(config, var_x, expr, op)),
+    };
+    let search_x = |&(_, candidate): &(u32, Candidate)| {
+        if solutions.is_full() {
+            return;
+        }
+        let stmt_x = build(candidate);
+        let test = |e_y: Arc<Expr>| {
+            if let Some((init_x, init_y)) = ParamsMatcher.first_match(&stmt_x, &e_y) {
+                solutions.record(&FoundPair { length: target_n, e_x: stmt_x.clone(), e_y, init_x, init_y });
             }
+        };
+        if ParamsMatcher.symmetric() {
+            test(stmt_x.clone());
+            return;
+        }
+        for &(_, candidate) in &stmts_y {
+            test(build(candidate));
         }
-        
-        stmts
     };
-    
-    // Collect all cached statements for y
-    let cached_stmts_y: Vec<&Arc<Expr>> = (1..=MAX_CACHE_LENGTH)
-        .filter_map(|len| ctx.statements.get(&len))
-        .flat_map(|s| s.iter())
-        .collect();
-    
+
     if USE_MULTITHREAD {
-        (1..=MAX_CACHE_LENGTH).into_par_iter().for_each(|expr_len| {
-            if let Some(exprs) = ctx.expressions.get(&expr_len) {
-                exprs.par_iter().for_each(|expr| {
-                    let stmts_x = gen_stmts_for_expr(expr, expr_len);
-                    for stmt_x in &stmts_x {
-                        for stmt_y in &cached_stmts_y {
-                            if let Some((ix, iy)) = test_pair(stmt_x, stmt_y) {
-                                print_result(stmt_x, stmt_y, ix, iy);
-                            }
-                        }
-                        
-                        for y_expr_len in 1..=MAX_CACHE_LENGTH {
-                            if let Some(y_exprs) = ctx.expressions.get(&y_expr_len) {
-                                for y_expr in y_exprs {
-                                    let stmts_y = gen_stmts_for_expr(y_expr, y_expr_len);
-                                    for stmt_y in &stmts_y {
-                                        if let Some((ix, iy)) = test_pair(stmt_x, stmt_y) {
-                                            print_result(stmt_x, stmt_y, ix, iy);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                });
-            }
-        });
+        stmts_x.par_iter().for_each(search_x);
     } else {
-        for expr_len in 1..=MAX_CACHE_LENGTH {
-            if let Some(exprs) = ctx.expressions.get(&expr_len) {
-                for expr in exprs {
-                    let stmts_x = gen_stmts_for_expr(expr, expr_len);
-                    for stmt_x in &stmts_x {
-                        for stmt_y in &cached_stmts_y {
-                            if let Some((ix, iy)) = test_pair(stmt_x, stmt_y) {
-                                print_result(stmt_x, stmt_y, ix, iy);
-                            }
-                        }
-                        
-                        for y_expr_len in 1..=MAX_CACHE_LENGTH {
-                            if let Some(y_exprs) = ctx.expressions.get(&y_expr_len) {
-                                for y_expr in y_exprs {
-                                    let stmts_y = gen_stmts_for_expr(y_expr, y_expr_len);
-                                    for stmt_y in &stmts_y {
-                                        if let Some((ix, iy)) = test_pair(stmt_x, stmt_y) {
-                                            print_result(stmt_x, stmt_y, ix, iy);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        stmts_x.iter().for_each(search_x);
     }
 }
 
-fn search_cached(ctx: &Context, max_n: usize) {
+static TABLE: LazyLock<TableMatcher> = LazyLock::new(|| TableMatcher::new(EXPR_ROWS.to_vec()));
+
+/// Whether `e` passes RowMatcher at every EXPR_ROWS row
+fn test_expr(e: &Expr) -> bool {
+    let mut matcher = RowMatcher;
+    TABLE.matches_by(e, |i, value| matcher.match_row(i, value))
+}
+
+/// EXPR_ONLY: test each cached expression of length n on its own
+fn search_expressions(ctx: &Context, n: usize, solutions: &Solutions) {
+    let Some(exprs) = ctx.expressions(n) else { return };
     if USE_MULTITHREAD {
-        (1..=max_n.min(MAX_CACHE_LENGTH)).into_par_iter().for_each(|n_x| {
-            if let Some(stmts_x) = ctx.statements.get(&n_x) {
-                stmts_x.par_iter().for_each(|e_x| {
-                    for n_y in 1..=max_n.min(MAX_CACHE_LENGTH) {
-                        if let Some(stmts_y) = ctx.statements.get(&n_y) {
-                            for e_y in stmts_y {
-                                if let Some((ix, iy)) = test_pair(e_x, e_y) {
-                                    print_result(e_x, e_y, ix, iy);
-                                }
-                            }
-                        }
-                    }
-                });
-            }
-        });
+        exprs.par_iter().filter(|e| !solutions.is_full() && test_expr(e)).for_each(|e| solutions.record_expr(n, e));
     } else {
-        for n_x in 1..=max_n.min(MAX_CACHE_LENGTH) {
-            if let Some(stmts_x) = ctx.statements.get(&n_x) {
-                for e_x in stmts_x {
-                    for n_y in 1..=max_n.min(MAX_CACHE_LENGTH) {
-                        if let Some(stmts_y) = ctx.statements.get(&n_y) {
-                            for e_y in stmts_y {
-                                if let Some((ix, iy)) = test_pair(e_x, e_y) {
-                                    print_result(e_x, e_y, ix, iy);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        exprs.iter().filter(|e| !solutions.is_full() && test_expr(e)).for_each(|e| solutions.record_expr(n, e));
     }
 }
 
+/// How many pairs `search_cached` tests at length n: |stmts_x| * |stmts_y|
+/// over the lengths where the longer of the two is n, or with SYMMETRIC
+/// one per statement of length n
+fn new_pairs(ctx: &Context, n: usize) -> usize {
+    let count = |len: usize| ctx.statements(len).map(|s| s.len()).unwrap_or(0);
+    if SYMMETRIC {
+        return count(n);
+    }
+    let shorter: usize = (1..n).map(count).sum();
+    count(n) * (count(n) + 2 * shorter)
+}
+
+/// Test every cached pair new at length n, through the library's search
+/// with ParamsMatcher; each match is recorded as it's found. Without
+/// USE_MULTITHREAD the pool has one thread, so that's in cache order.
+fn search_cached(ctx: &Context, n: usize, solutions: &Solutions) {
+    search_length(ctx, &ParamsMatcher, n, &solutions.stop, |pair| solutions.record(pair));
+}
+
 // =============================================================================
 // MAIN
 // =============================================================================
 
+/// The config from params.rs, with operators switched by `--enable NAME` and
+/// `--disable NAME` arguments (names as in `Operator`, e.g. `--disable ModEq`)
+fn config_from_args() -> Result<SearchConfig, String> {
+    let mut config = search_config();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let enabled = match arg.as_str() {
+            "--enable" => true,
+            "--disable" => false,
+            _ => return Err(format!("unknown argument {}", arg)),
+        };
+        let name = args.next().ok_or_else(|| format!("{} needs an operator name", arg))?;
+        config.set_operator_enabled(&name, enabled)?;
+    }
+    config.validate()?;
+    Ok(config)
+}
+
 fn main() {
+    if let Some(points) = GRID_POINTS {
+        if let Err(err) = set_grid(points.to_vec()) {
+            eprintln!("Error: GRID_POINTS: {}", err);
+            std::process::exit(1);
+        }
+    }
+    let config = config_from_args().unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
+
+    // All parallel work runs in this pool rather than rayon's global one (0 threads = rayon default, 1 without USE_MULTITHREAD)
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(if USE_MULTITHREAD { THREADS } else { 1 })
+        .build()
+        .expect("Failed to build thread pool");
+
     println!("Expression Search");
     println!("=================");
-    println!("Target: {:?}", ANSWER);
-    println!("Init: x=[{}..={}], y=[{}..={}]", INIT_X_MIN, INIT_X_MAX, INIT_Y_MIN, INIT_Y_MAX);
+    if EXPR_ONLY {
+        println!("Target rows (x, y, expected): {:?}", EXPR_ROWS);
+    } else {
+        println!("Target: {:?}", ANSWER);
+        if INIT_PAIRS.is_empty() {
+            println!("Init: x=[{}..={}], y=[{}..={}]", INIT_X_MIN, INIT_X_MAX, INIT_Y_MIN, INIT_Y_MAX);
+        } else {
+            println!("Init: (x, y) in {:?}", INIT_PAIRS);
+        }
+        if STEPS_PER_TERM > 1 {
+            println!("Steps per term: {}", STEPS_PER_TERM);
+        }
+        if TRANSITION == TransitionMode::Simultaneous {
+            println!("Transition: simultaneous, both statements read the same x and y");
+        }
+    }
     println!("Max length: {}, Cache length: {}", MAX_LENGTH, MAX_CACHE_LENGTH);
-    println!("JIT: {}, Multithread: {}", USE_JIT, USE_MULTITHREAD);
+    println!("JIT: {}, Multithread: {}, Threads: {}", USE_JIT, USE_MULTITHREAD, pool.current_num_threads());
+    println!("Count only: {}", COUNT_ONLY);
+    if DRY_RUN {
+        println!("Dry run: only generating, nothing is searched");
+    }
+    println!("Shortest per class: {}", SHORTEST_PER_CLASS);
+    if GRID_POINTS.is_some() {
+        println!("Equivalence grid: {} points", sample_grid().len());
+    }
+    if SYMMETRIC && !EXPR_ONLY {
+        println!("Symmetric: only pairs where y's statement mirrors x's");
+    }
+    if REQUIRE_TOTAL && !EXPR_ONLY {
+        println!("Total: pairs must never be fatal from any seed");
+    }
+    if REQUIRE_SEED_ROBUST && RECURRENCES.is_empty() {
+        println!("Warning: REQUIRE_SEED_ROBUST is set but the target follows no recurrence, nothing will be reported");
+    }
     println!("Binary ops: {}, Unary ops: {}, Assign ops: {}", 
-             BINARY_OPERATORS.len(), UNARY_OPERATORS.len(), ASSIGN_OPERATORS.len());
+             config.binary_operators.len(), config.unary_operators.len(), config.assign_operators.len());
     println!();
 
+    pool.install(|| run_search(&config));
+}
+
+fn run_search(config: &SearchConfig) {
     let start = Instant::now();
     let mut ctx = Context::new();
+    let solutions = Solutions::new(MAX_LENGTH.max(MAX_CACHE_LENGTH), MAX_SOLUTIONS);
+
+    if EXPR_ONLY {
+        for n in (1..=MAX_CACHE_LENGTH).take_while(|_| !solutions.is_full()) {
+            println!("Finding length {}...", n);
+            ctx.gen_expressions(config, n);
+            let expr_count = ctx.expressions(n).map(|s| s.len()).unwrap_or(0);
+            if DRY_RUN {
+                println!("  {} expressions", expr_count);
+            } else {
+                search_expressions(&ctx, n, &solutions);
+                solutions.flush();
+                println!("  {} expressions, {} solutions", expr_count, solutions.count(n));
+            }
+            println!("  time: {:.3}s", start.elapsed().as_secs_f64());
+        }
+        if DRY_RUN {
+            println!("\nDry run done! Total time: {:.3}s", start.elapsed().as_secs_f64());
+            return;
+        }
+        print_histogram(&solutions, MAX_CACHE_LENGTH);
+        println!("\nDone! Total time: {:.3}s", start.elapsed().as_secs_f64());
+        return;
+    }
+
+    // With CHECKPOINT, pick up after the last length a previous run finished
+    let first = match CHECKPOINT.filter(|path| !DRY_RUN && Path::new(path).exists()) {
+        Some(path) => {
+            let checkpoint = resume_from(Path::new(path), config, &checkpoint_target()).unwrap_or_else(|err| {
+                eprintln!("Error: can't resume from {}: {}", path, err);
+                std::process::exit(1);
+            });
+            println!("Resuming from {} after length {}", path, checkpoint.length);
+            ctx = checkpoint.ctx;
+            solutions.restore(checkpoint.solutions);
+            checkpoint.length + 1
+        }
+        None => 1,
+    };
+    let save = |ctx: &Context, n: usize| {
+        if let Some(path) = CHECKPOINT {
+            let reported = solutions.reported.lock().unwrap();
+            if let Err(err) = save_checkpoint(Path::new(path), config, &checkpoint_target(), ctx, n, &reported) {
+                eprintln!("Warning: can't save checkpoint to {}: {}", path, err);
+            }
+        }
+    };
 
     // Phase 1: Generate and cache expressions up to MAX_CACHE_LENGTH
-    for n in 1..=MAX_CACHE_LENGTH {
+    let mut total_pairs = 0;
+    for n in (first..=MAX_CACHE_LENGTH).take_while(|_| !solutions.is_full()) {
         println!("Finding length {}...", n);
-        gen_expressions(&mut ctx, n);
-        gen_statements(&mut ctx, n);
-        search_cached(&ctx, n);
-
-        let expr_count = ctx.expressions.get(&n).map(|s| s.len()).unwrap_or(0);
-        let stmt_count = ctx.statements.get(&n).map(|s| s.len()).unwrap_or(0);
-        println!("  {} expressions, {} statements", expr_count, stmt_count);
+        ctx.gen_expressions(config, n);
+        ctx.gen_statements(config, n);
+
+        let expr_count = ctx.expressions(n).map(|s| s.len()).unwrap_or(0);
+        let stmt_count = ctx.statements(n).map(|s| s.len()).unwrap_or(0);
+        if DRY_RUN {
+            let pairs = new_pairs(&ctx, n);
+            total_pairs += pairs;
+            println!("  {} expressions, {} statements, {} pairs to test", expr_count, stmt_count, pairs);
+        } else {
+            search_cached(&ctx, n, &solutions);
+            solutions.flush();
+            save(&ctx, n);
+            println!("  {} expressions, {} statements, {} solutions", expr_count, stmt_count, solutions.count(n));
+        }
+        if PRUNE_GRID_CONST {
+            println!("  {} disguised constants pruned", ctx.pruned(n));
+        }
         println!("  time: {:.3}s", start.elapsed().as_secs_f64());
     }
     
+    if DRY_RUN {
+        let inits = INITS.len();
+        println!("\nDry run: {} pairs up to length {}, each tried from up to {} init values", total_pairs, MAX_CACHE_LENGTH, inits);
+        if MAX_LENGTH > MAX_CACHE_LENGTH {
+            println!("Lengths {}-{} are searched by DFS and not estimated", MAX_CACHE_LENGTH + 1, MAX_LENGTH);
+        }
+        println!("\nDry run done! Total time: {:.3}s", start.elapsed().as_secs_f64());
+        return;
+    }
+
     // Phase 2: DFS search for lengths beyond MAX_CACHE_LENGTH
-    for n in (MAX_CACHE_LENGTH + 1)..=MAX_LENGTH {
+    for n in (first.max(MAX_CACHE_LENGTH + 1)..=MAX_LENGTH).take_while(|_| !solutions.is_full()) {
         println!("Finding length {}-{} (DFS)...", n, MAX_LENGTH);
-        dfs_search(&ctx, n);
+        dfs_search(&ctx, config, n, &solutions);
+        solutions.flush();
+        save(&ctx, n);
+        println!("  {} solutions", solutions.count(n));
         println!("  time: {:.3}s", start.elapsed().as_secs_f64());
     }
     
+    print_histogram(&solutions, MAX_LENGTH.max(MAX_CACHE_LENGTH));
     println!("\nDone! Total time: {:.3}s", start.elapsed().as_secs_f64());
 }
+
+/// What a checkpoint's solutions depend on besides the generator config.
+/// Matcher and RowMatcher are code, so changes to them aren't caught.
+fn checkpoint_target() -> String {
+    format!("{:?} {:?} {} {} {} {:?}", ANSWER, *INITS, STEPS_PER_TERM, MAX_CACHE_LENGTH, SYMMETRIC, TRANSITION)
+}
+
+fn print_histogram(solutions: &Solutions, max_len: usize) {
+    if solutions.is_full() {
+        println!("\nStopped at MAX_SOLUTIONS ({})", MAX_SOLUTIONS.unwrap_or(0));
+    }
+    println!("\nSolutions per length:");
+    for n in 1..=max_len {
+        println!("  {:>3}: {}", n, solutions.count(n));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expr_rust::build::{bin, x, y};
+    use expr_rust::structural_eq;
+
+    #[test]
+    fn test_max_solutions() {
+        // ANSWER has more than five distinct solutions by length 5
+        let config = search_config();
+        let mut ctx = Context::new();
+        let solutions = Solutions::new(5, Some(5));
+        for n in (1..=5).take_while(|_| !solutions.is_full()) {
+            ctx.gen_expressions(&config, n);
+            ctx.gen_statements(&config, n);
+            search_cached(&ctx, n, &solutions);
+            solutions.flush();
+        }
+        assert!(solutions.is_full() && solutions.stop.load(Ordering::Relaxed));
+        assert_eq!((1..=5).map(|n| solutions.count(n)).sum::<usize>(), 5);
+    }
+
+    #[test]
+    fn test_forbidden_values() {
+        // Fibonacci from x=0, y=1: x+=y; y=x-y, with y 0 after the first step
+        let e_x = bin(x(), Operator::AddEq, y());
+        let e_y = bin(x(), Operator::AssignEq, bin(y(), Operator::Sub, x()));
+        let run = |matcher| test_pair_with_matcher(&e_x, &e_y, 0, 1, matcher);
+        assert!(run(Matcher::new()));
+        assert!(run(Matcher::with_forbidden(&[4], &[-1])));
+        assert!(!run(Matcher::with_forbidden(&[], &[0])));
+        // Even a value ANSWER has, once x takes it
+        assert!(!run(Matcher::with_forbidden(&[13], &[])));
+    }
+
+    #[test]
+    fn test_step_transition_modes() {
+        // x+=y; y=x: sequentially y copies the new x, simultaneously the old one
+        let b = expr_rust::build::Builder::new(USE_JIT);
+        let e_x = b.bin(b.x(), Operator::AddEq, b.y());
+        let e_y = b.bin(b.x(), Operator::AssignEq, b.y());
+        for (mode, expected) in [(TransitionMode::Sequential, (2, 2)), (TransitionMode::Simultaneous, (2, 1))] {
+            let (mut x, mut y) = (1, 1);
+            assert!(step(&e_x, &e_y, &mut x, &mut y, mode));
+            assert_eq!((x, y), expected, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_require_total() {
+        // x=x/y; y=y: matches a constant 1 from (1, 1), but the rectangle has y=0 seeds
+        let e_x = bin(x(), Operator::AssignEq, bin(x(), Operator::Div, y()));
+        let e_y = bin(x(), Operator::AssignEq, x());
+        assert!(INITS.iter().any(|&(_, iy)| iy == 0));
+        assert!(!is_total(&e_x, &e_y, &INITS, ANSWER.len(), STEPS_PER_TERM, TRANSITION));
+        assert!(is_total(&e_x, &e_y, &[(1, 1), (5, -1)], ANSWER.len(), STEPS_PER_TERM, TRANSITION));
+        if REQUIRE_TOTAL {
+            assert!(!is_total_on_inits(&e_x, &e_y));
+        }
+        // Fibonacci never divides, so it's total
+        let e_x = bin(x(), Operator::AddEq, y());
+        let e_y = bin(x(), Operator::AssignEq, bin(y(), Operator::Sub, x()));
+        assert!(is_total(&e_x, &e_y, &INITS, ANSWER.len(), STEPS_PER_TERM, TRANSITION));
+        assert!(is_total_on_inits(&e_x, &e_y));
+    }
+
+    #[test]
+    fn test_pair_all_seeds() {
+        let config = search_config();
+        let mut ctx = Context::new();
+        for n in 1..=4 {
+            ctx.gen_expressions(&config, n);
+            ctx.gen_statements(&config, n);
+        }
+        let stmts: Vec<&Arc<Expr>> = (1..=4).filter_map(|len| ctx.statements(len)).flatten().collect();
+        let mut matched = 0;
+        for e_x in &stmts {
+            for e_y in &stmts {
+                let all = test_pair_all(e_x, e_y);
+                assert_eq!(all.first().copied(), test_pair(e_x, e_y));
+                let expected: Vec<_> = INITS.iter().copied().filter(|&(ix, iy)| test_pair_with_init(e_x, e_y, ix, iy)).collect();
+                if !all.is_empty() {
+                    assert_eq!(all, expected);
+                    matched += 1;
+                }
+            }
+        }
+        assert!(matched > 0);
+    }
+
+    #[test]
+    fn test_symmetric_partner_is_itself() {
+        // The symmetric search relies on no two cached statements being the same tree
+        let config = search_config();
+        let mut ctx = Context::new();
+        for n in 1..=4 {
+            ctx.gen_expressions(&config, n);
+            ctx.gen_statements(&config, n);
+            let stmts: Vec<&Arc<Expr>> = (1..=n).filter_map(|len| ctx.statements(len)).flatten().collect();
+            for e in ctx.statements(n).into_iter().flatten() {
+                let partners = stmts.iter().filter(|s| structural_eq(s, e)).count();
+                assert_eq!(partners, 1, "{}", format_expression(e, &['x', 'y']));
+            }
+        }
+    }
+}
@@ -1,35 +1,186 @@
-use expr_rust::{NumT, Operator, Expr};
+use expr_rust::{Arithmetic, DivMode, NumT, Operator, Expr, SearchConfig, TransitionMode};
+pub use expr_rust::{AssignOp, BinaryOp, IncDecOp, UnaryOp};
+use std::sync::Arc;
 
 pub const USE_JIT: bool = true;
+pub const VERIFY_JIT: bool = false; // Check each compiled statement against naive_eval over the grid, panicking on a mismatch
+// The (x, y) points expressions are compared at, e.g. Some(&[(0, 0), (7, 1),
+// (100, -3)]) for ones that only differ at large inputs. None is the square
+// -4..=4 by -4..=4. Caches and checkpoints are only valid for their own grid
+pub const GRID_POINTS: Option<&[(NumT, NumT)]> = None;
 
 pub const ANSWER: &[NumT] = &[1,1,2,3,5,8,13,21,34,55,89,144];
 
+// Updates (x; y) applied per ANSWER term, for generators with several phases per term
+pub const STEPS_PER_TERM: usize = 1;
+const _: () = assert!(STEPS_PER_TERM > 0, "STEPS_PER_TERM must be at least 1");
+// Sequential runs x's statement, then y's with the new x. Simultaneous runs
+// both from the same (x, y) and keeps each one's write to its own variable,
+// like (x, y) = (f(x, y), g(x, y)) in a textbook recurrence
+pub const TRANSITION: TransitionMode = TransitionMode::Sequential;
+
 pub const INIT_X_MIN: NumT = -1;
 pub const INIT_X_MAX: NumT = 1;
 pub const INIT_Y_MIN: NumT = -1;
 pub const INIT_Y_MAX: NumT = 1;
+// Exact (x, y) seeds to try, in order, e.g. &[(0, 1), (1, 1)]; empty tries
+// the whole INIT_X_MIN..=INIT_X_MAX by INIT_Y_MIN..=INIT_Y_MAX rectangle
+pub const INIT_PAIRS: &[(NumT, NumT)] = &[];
+// With more seeds than this, each pair tries them in parallel, for large seed
+// sets or an expensive Matcher. Below it the serial loop is cheaper
+pub const PARALLEL_INIT_THRESHOLD: usize = 64;
+
+/// The seeds each pair is tried from: INIT_PAIRS, or the range rectangle x-major
+pub fn init_pairs() -> Vec<(NumT, NumT)> {
+    if !INIT_PAIRS.is_empty() {
+        return INIT_PAIRS.to_vec();
+    }
+    (INIT_X_MIN..=INIT_X_MAX)
+        .flat_map(|init_x| (INIT_Y_MIN..=INIT_Y_MAX).map(move |init_y| (init_x, init_y)))
+        .collect()
+}
 
 pub const MAX_LENGTH: usize = 10;
 pub const MAX_CACHE_LENGTH: usize = 7;
+
+/// How unlikely an operator makes a solution. Lengths past MAX_CACHE_LENGTH
+/// try the statements with the lowest total cost first; every pair is still
+/// tested, so this only changes which solutions turn up early
+pub fn op_cost(op: Operator) -> u32 {
+    match op {
+        Operator::Var | Operator::VarY | Operator::Literal => 0,
+        Operator::Add | Operator::Sub | Operator::Mul | Operator::AddEq | Operator::SubEq | Operator::AssignEq => 1,
+        Operator::Div | Operator::Mod | Operator::Pow | Operator::DivEq | Operator::ModEq => 4,
+        _ => 2,
+    }
+}
 pub const USE_MULTITHREAD: bool = true;
-pub const LITERALS: &[NumT] = &[1, 2, 3];
+pub const THREADS: usize = 0; // Size of the search's thread pool, 0 = rayon default
+pub const LITERALS: &[NumT] = &[1, 2, 3]; // Negatives like -1 are fine, each costs its printed length
 pub const USE_PARENS: bool = true;
-pub const PRUNE_CONST_EXPR: bool = true; // Skip constant-only expressions since we can easily find them
+// Without parens, still build x-(y-z) and x*(y/z): a right operand of the
+// operator's own class in parens. No effect with USE_PARENS, which covers these
+pub const RIGHT_GROUPINGS: bool = false;
+pub const USE_TERNARY: bool = true;
+pub const USE_MIN_MAX: bool = false; // Enables the non-C <? and >? operators
+pub const USE_LSHR: bool = false; // Enables >>>, a logical shift right like JavaScript's
+pub const USE_POPCOUNT: bool = false; // Enables the non-C #x, the number of set bits in x
+pub const USE_GCD: bool = false; // Enables the non-C x%%y, the greatest common divisor
+pub const USE_COMMA: bool = false; // Statements like x=a,y=b that update both variables
+// Keep the two recurrences apart: x's statement never writes y (no y++ or
+// ,y=... in it) and y's never writes x. Overrides USE_COMMA
+pub const OWN_VAR_ONLY: bool = false;
+// Skip constant-only expressions since we can easily find them. Ignored with
+// EXPR_ONLY, where f(x, y)=42 may be the answer
+pub const PRUNE_CONST_EXPR: bool = true;
+pub const PRUNE_GRID_CONST: bool = false; // Also skip ones like x-x that are a literal in disguise
+pub const MAX_LITERAL_ABS: Option<NumT> = None; // E.g. Some(100) skips anything with a constant part like 99*99 beyond it
+
+/// Expressions or statements to leave out of the search, with everything
+/// equivalent to them over the grid, e.g. to skip what an earlier run covered:
+/// `vec![bin(x(), Operator::Add, lit(1))]` (from `expr_rust::build`) drops
+/// x+1, 1+x and x- -1, along with what would be built on them
+pub fn exclude() -> Vec<Arc<Expr>> {
+    Vec::new()
+}
+
+/// Hand-written expressions to cache alongside the generated ones at their
+/// printed length, so longer expressions are built on them too, e.g. a magic
+/// constant: `vec![lit(1337)]`
+pub fn seed_expressions() -> Vec<Arc<Expr>> {
+    Vec::new()
+}
+pub const POW_MODULUS: Option<NumT> = None; // Pow computes a**b%m, for modular sequences (JIT falls back to naive)
+pub const ARITHMETIC: Arithmetic = Arithmetic::Wrapping; // Or Saturating: +, -, * clamp at i64::MIN/MAX
+pub const DIV_MODE: DivMode = DivMode::Truncating; // Or Euclidean: % is never negative, / rounds to match
+pub const UNSIGNED: bool = false; // Comparisons, <?, >?, >>, / and % treat values as u64, e.g. for hashes
+pub const COUNT_ONLY: bool = false; // Only tally solutions per length, without printing each one
+pub const PRINT_SEQUENCE: bool = false; // Follow each solution with the ANSWER.len() terms it generates
+pub const FIND_ALL_INITS: bool = false; // Also list every seed a reported pair matches from, not just the first
+// Only generate each cached length and report how many expressions,
+// statements and pairs it has, to size up a run before starting it
+pub const DRY_RUN: bool = false;
+// Stop once this many distinct solutions (after SHORTEST_PER_CLASS) are found,
+// e.g. Some(50); None searches every length exhaustively
+pub const MAX_SOLUTIONS: Option<usize> = None;
+// Save progress to this file after each length, e.g. Some("search.ckpt"), and
+// resume from it when it exists. It must be from the same config and target
+pub const CHECKPOINT: Option<&str> = None;
+// Only test pairs whose two statements are the same tree, e.g. x+=y; y+=x,
+// for targets known to follow a symmetric recurrence. The y statement runs
+// with the variables swapped, so its x is y and its y is x
+pub const SYMMETRIC: bool = false;
+// Print each length's solutions sorted (by length, bytecode, then init) once
+// it's searched, rather than as threads find them, so runs can be diffed
+pub const SORT_SOLUTIONS: bool = false;
+// Of matches whose steps behave identically over the equivalence grid (same
+// sequence, same post-state, e.g. x+=1 and x+=1|0), report only the shortest.
+// False keeps them all
+pub const SHORTEST_PER_CLASS: bool = true;
 
-pub struct Matcher {}
+// Only report pairs that, from each of ROBUST_INITS too, follow a linear
+// recurrence ANSWER follows (same differences, ratios or e.g. Fibonacci rule)
+pub const REQUIRE_SEED_ROBUST: bool = false;
+pub const ROBUST_INITS: &[(NumT, NumT)] = &[(2, 3), (5, -1), (-4, 7)];
+
+// Only report pairs that run ANSWER's length without a fatal step (/0, an
+// overflowing **, ...) from every seed in INIT_PAIRS or the range rectangle,
+// not just the one they matched from
+pub const REQUIRE_TOTAL: bool = false;
+
+/// Extra check on a pair that matched the whole sequence, e.g.
+/// `Some(|e_x, e_y| expr_rust::printed_len(e_x) + expr_rust::printed_len(e_y) <= 12)`
+pub const FINAL_CHECK: Option<FinalCheck> = None;
+
+pub type FinalCheck = fn(&Expr, &Expr) -> bool;
+
+// Values x and y must never take after any step, within a term too, e.g.
+// &[0] for a sentinel. A pair that passes through one fails, even if it
+// goes on to match ANSWER
+pub const FORBID_X: &[NumT] = &[];
+pub const FORBID_Y: &[NumT] = &[];
+
+// Search for a pure expression f(x, y) instead of a statement pair: every
+// cached expression (up to MAX_CACHE_LENGTH) runs once per row, no recurrence
+pub const EXPR_ONLY: bool = false;
+pub const EXPR_ROWS: &[(NumT, NumT, NumT)] = &[(1, 2, 3), (4, 1, 5), (-2, 3, 1)]; // (x, y, expected)
+
+pub struct Matcher {
+    final_check: Option<FinalCheck>,
+    forbid_x: &'static [NumT],
+    forbid_y: &'static [NumT],
+}
 
 impl Matcher {
     pub fn new() -> Self {
-        Self {}
+        Self { final_check: FINAL_CHECK, forbid_x: FORBID_X, forbid_y: FORBID_Y }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_final_check(final_check: FinalCheck) -> Self {
+        Self { final_check: Some(final_check), ..Self::new() }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_forbidden(forbid_x: &'static [NumT], forbid_y: &'static [NumT]) -> Self {
+        Self { forbid_x, forbid_y, ..Self::new() }
     }
 
+    /// Called after every step, even between terms; false if x or y is forbidden
     #[inline]
-    pub fn match_one(&mut self, index: usize, output: NumT) -> bool {
-        output == ANSWER[index]
+    pub fn allows(&self, x: NumT, y: NumT) -> bool {
+        !self.forbid_x.contains(&x) && !self.forbid_y.contains(&y)
     }
 
-    pub fn match_final(self, _e_x: &Expr, _e_y: &Expr) -> bool {
-        true
+    /// Called after each step with the full state; x is the output
+    #[inline]
+    pub fn match_one(&mut self, index: usize, x: NumT, _y: NumT) -> bool {
+        x == ANSWER[index]
+    }
+
+    /// Called once every match_one has passed
+    pub fn match_final(self, e_x: &Expr, e_y: &Expr) -> bool {
+        self.final_check.is_none_or(|check| check(e_x, e_y))
     }
 }
 
@@ -39,26 +190,15 @@ impl Default for Matcher {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct BinaryOp {
-    pub op: Operator,
-    pub len: usize,
-}
-
-#[derive(Clone, Copy)]
-pub struct UnaryOp {
-    pub op: Operator,
-}
+/// Matching logic for EXPR_ONLY
+pub struct RowMatcher;
 
-#[derive(Clone, Copy)]
-pub struct AssignOp {
-    pub op: Operator,
-    pub len: usize,
-}
-
-#[derive(Clone, Copy)]
-pub struct IncDecOp {
-    pub op: Operator,
+impl RowMatcher {
+    /// Called with the expression's value at row `index` of EXPR_ROWS
+    #[inline]
+    pub fn match_row(&mut self, index: usize, value: NumT) -> bool {
+        value == EXPR_ROWS[index].2
+    }
 }
 
 #[rustfmt::skip]
@@ -82,6 +222,10 @@ pub const BINARY_OPERATORS: &[BinaryOp] = &[
     // BinaryOp { op: Operator::Pow, len: 2 },
     BinaryOp { op: Operator::Or, len: 2 },
     BinaryOp { op: Operator::And, len: 2 },
+    BinaryOp { op: Operator::Min, len: 2 },
+    BinaryOp { op: Operator::Max, len: 2 },
+    BinaryOp { op: Operator::LShr, len: 3 },
+    BinaryOp { op: Operator::Gcd, len: 2 },
 ];
 
 #[rustfmt::skip]
@@ -89,6 +233,7 @@ pub const UNARY_OPERATORS: &[UnaryOp] = &[
     UnaryOp { op: Operator::Neg },
     UnaryOp { op: Operator::BitNot },
     UnaryOp { op: Operator::Not },
+    UnaryOp { op: Operator::PopCount },
 ];
 
 #[rustfmt::skip]
@@ -114,6 +259,79 @@ pub const INCDEC_OPERATORS: &[IncDecOp] = &[
     IncDecOp { op: Operator::PostDec },
 ];
 
-pub fn binary_ops_by_len(len: usize) -> impl Iterator<Item = &'static BinaryOp> {
-    BINARY_OPERATORS.iter().filter(move |o| o.len == len)
+/// The generator settings above, in the form the library takes
+pub fn search_config() -> SearchConfig {
+    SearchConfig {
+        use_jit: USE_JIT,
+        verify_jit: VERIFY_JIT,
+        use_multithread: USE_MULTITHREAD,
+        literals: LITERALS.to_vec(),
+        use_parens: USE_PARENS,
+        right_groupings: RIGHT_GROUPINGS,
+        use_ternary: USE_TERNARY,
+        use_min_max: USE_MIN_MAX,
+        use_lshr: USE_LSHR,
+        use_popcount: USE_POPCOUNT,
+        use_gcd: USE_GCD,
+        use_comma: USE_COMMA,
+        own_var_only: OWN_VAR_ONLY,
+        prune_const_expr: PRUNE_CONST_EXPR && !EXPR_ONLY,
+        prune_grid_const: PRUNE_GRID_CONST,
+        max_literal_abs: MAX_LITERAL_ABS,
+        exclude: exclude(),
+        seed_expressions: seed_expressions(),
+        pow_modulus: POW_MODULUS,
+        arithmetic: ARITHMETIC,
+        div_mode: DIV_MODE,
+        unsigned: UNSIGNED,
+        binary_operators: BINARY_OPERATORS.to_vec(),
+        unary_operators: UNARY_OPERATORS.to_vec(),
+        assign_operators: ASSIGN_OPERATORS.to_vec(),
+        incdec_operators: INCDEC_OPERATORS.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expr_rust::build::{bin, x, y};
+    use expr_rust::printed_len;
+
+    #[test]
+    fn test_row_matcher() {
+        let mut matcher = RowMatcher;
+        for (i, &(_, _, expected)) in EXPR_ROWS.iter().enumerate() {
+            assert!(matcher.match_row(i, expected));
+            assert!(!matcher.match_row(i, expected + 1));
+        }
+    }
+
+    #[test]
+    fn test_init_pairs() {
+        let pairs = init_pairs();
+        if INIT_PAIRS.is_empty() {
+            let width = (INIT_Y_MAX - INIT_Y_MIN + 1) as usize;
+            assert_eq!(pairs.len(), (INIT_X_MAX - INIT_X_MIN + 1) as usize * width);
+            assert_eq!(pairs[0], (INIT_X_MIN, INIT_Y_MIN));
+            assert_eq!(pairs.last(), Some(&(INIT_X_MAX, INIT_Y_MAX)));
+            // x-major, like the nested loops it replaces
+            assert!(pairs.windows(2).all(|w| w[0] < w[1]));
+        } else {
+            assert_eq!(pairs, INIT_PAIRS);
+        }
+    }
+
+    #[test]
+    fn test_operator_tables_valid() {
+        assert_eq!(search_config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_match_final_length_budget() {
+        // Fibonacci from x=0, y=1: x+=y; y=x-y
+        let e_x = bin(x(), Operator::AddEq, y());
+        let e_y = bin(x(), Operator::AssignEq, bin(y(), Operator::Sub, x()));
+        assert!(Matcher::with_final_check(|a, b| printed_len(a) + printed_len(b) <= 9).match_final(&e_x, &e_y));
+        assert!(!Matcher::with_final_check(|a, b| printed_len(a) + printed_len(b) <= 8).match_final(&e_x, &e_y));
+    }
 }
@@ -0,0 +1,460 @@
+//! Exporting a found pair as a standalone Python script.
+//!
+//! Python ints don't wrap, `//` and `%` floor rather than truncate, and it has
+//! no `++` or assignments inside expressions, so each statement is lowered to
+//! a function of plain Python statements. The emitted helpers reproduce the
+//! crate's semantics: `wrap` for 64-bit wraparound (`clamp` where saturating),
+//! `idiv`/`imod` truncating toward zero with `%` taking the dividend's sign
+//! (`ediv`/`emod` where Euclidean, `udiv`/`umod` where unsigned, with `u`
+//! reinterpreting a value as `u64` for the other unsigned operators), and
+//! `Fatal` raised wherever `naive_eval` would flag the step as fatal, which
+//! ends the sequence there like `run_sequence` does.
+
+use crate::{format_expression, Expr, NumT, Operator, TransitionMode};
+
+const HELPERS: &str = r#"import math
+
+
+class Fatal(Exception):
+    pass
+
+
+def wrap(v):
+    return (v + 2**63) % 2**64 - 2**63
+
+
+def clamp(v):
+    return max(-2**63, min(v, 2**63 - 1))
+
+
+def idiv(a, b):
+    # C division truncates toward zero, where // floors
+    if b == 0 or (a == -2**63 and b == -1):
+        raise Fatal
+    q = abs(a) // abs(b)
+    return q if (a < 0) == (b < 0) else -q
+
+
+def imod(a, b):
+    # Takes the sign of a, where Python's % takes the sign of b
+    return a - b * idiv(a, b)
+
+
+def emod(a, b):
+    # Euclidean: never negative, whatever the signs
+    if b == 0 or (a == -2**63 and b == -1):
+        raise Fatal
+    return a % abs(b)
+
+
+def ediv(a, b):
+    return (a - emod(a, b)) // b
+
+
+def u(v):
+    return v % 2**64
+
+
+def udiv(a, b):
+    if b == 0:
+        raise Fatal
+    return wrap(u(a) // u(b))
+
+
+def umod(a, b):
+    if b == 0:
+        raise Fatal
+    return wrap(u(a) % u(b))
+
+
+def ipow(a, b):
+    if b < 0:
+        if a == 0:
+            raise Fatal
+        return 1 if a == 1 else (1 - 2 * (b % 2) if a == -1 else 0)
+    if b > 2**32 - 1 or (abs(a) > 1 and b > 63):
+        raise Fatal
+    v = a**b
+    if wrap(v) != v:
+        raise Fatal
+    return v
+
+
+def ipow_mod(a, b, m):
+    if b < 0:
+        return imod(ipow(a, b), m)
+    v = pow(abs(a), b, m)
+    return -v if a < 0 and b % 2 == 1 else v
+"#;
+
+/// A script that runs `e_x; e_y` from `init_x, init_y` for `terms` terms of
+/// `steps_per_term` steps taken by `mode`, and prints x after each, the same
+/// terms `run_sequence_every` returns.
+pub fn to_python(
+    e_x: &Expr,
+    e_y: &Expr,
+    init_x: NumT,
+    init_y: NumT,
+    terms: usize,
+    steps_per_term: usize,
+    mode: TransitionMode,
+) -> String {
+    let mut out = String::new();
+    out.push_str(&format!(
+        "# x={}, y={} : {}; {}\n\n",
+        init_x,
+        init_y,
+        format_expression(e_x, &['x', 'y']),
+        format_expression(e_y, &['y', 'x'])
+    ));
+    out.push_str(HELPERS);
+    out.push_str("\n\n");
+    out.push_str(&step_function("step_x", e_x, ["x", "y"]));
+    out.push_str("\n\n");
+    out.push_str(&step_function("step_y", e_y, ["y", "x"]));
+    // Simultaneously, each keeps only its own variable's new value
+    let step = match mode {
+        TransitionMode::Sequential => "x, y = step_x(x, y)\n            x, y = step_y(x, y)",
+        TransitionMode::Simultaneous => "x, y = step_x(x, y)[0], step_y(x, y)[1]",
+    };
+    out.push_str(&format!(
+        "\n\nx, y = {}, {}\nfor _ in range({}):\n    try:\n        for _ in range({}):\n            {}\n    except Fatal:\n        break\n    print(x)\n",
+        init_x, init_y, terms, steps_per_term, step
+    ));
+    out
+}
+
+/// `def name(x, y)` running `e` with `Var` and `VarY` bound to `vars`
+fn step_function(name: &str, e: &Expr, vars: [&'static str; 2]) -> String {
+    let mut w = Lowering { lines: Vec::new(), indent: 1, temps: 0, vars };
+    w.lower(e);
+    let mut out = format!("def {}(x, y):\n", name);
+    for line in &w.lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("    return x, y\n");
+    out
+}
+
+struct Lowering {
+    lines: Vec<String>,
+    indent: usize,
+    temps: usize,
+    vars: [&'static str; 2],
+}
+
+impl Lowering {
+    fn line(&mut self, s: String) {
+        self.lines.push(format!("{}{}", "    ".repeat(self.indent), s));
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("t{}", self.temps)
+    }
+
+    /// Store `value` in a fresh temporary, so later writes to x or y can't change it
+    fn bind(&mut self, value: String) -> String {
+        let t = self.temp();
+        self.line(format!("{} = {}", t, value));
+        t
+    }
+
+    fn var(&self, e: &Expr) -> &'static str {
+        self.vars[(e.op as usize) & 0xF]
+    }
+
+    /// Emit the statements evaluating `e`, in `naive_eval`'s order, and return
+    /// the name holding its value
+    fn lower(&mut self, e: &Expr) -> String {
+        if e.has_bad_target() {
+            self.line("raise Fatal".to_string());
+            return "0".to_string();
+        }
+
+        match e.op {
+            Operator::Literal => return e.literal.to_string(),
+            Operator::Var | Operator::VarY => {
+                let v = self.var(e);
+                return self.bind(v.to_string());
+            }
+            Operator::Ternary => {
+                let cond = self.lower(e.left.as_ref().unwrap());
+                let t = self.temp();
+                self.line(format!("if {} != 0:", cond));
+                self.indent += 1;
+                let then = self.lower(e.mid.as_ref().unwrap());
+                self.line(format!("{} = {}", t, then));
+                self.indent -= 1;
+                self.line("else:".to_string());
+                self.indent += 1;
+                let other = self.lower(e.right.as_ref().unwrap());
+                self.line(format!("{} = {}", t, other));
+                self.indent -= 1;
+                return t;
+            }
+            _ => {}
+        }
+
+        if e.is_assignment() {
+            let target = self.var(e.left.as_ref().unwrap());
+            let r = self.lower(e.right.as_ref().unwrap());
+            let wrap = if e.is_saturating() { "clamp" } else { "wrap" };
+            let (div, rem) = division(e);
+            let value = match e.op {
+                Operator::BitShrEq if e.is_unsigned() => format!("wrap(u({}) >> ({} & 63))", target, r),
+                Operator::AssignEq => r,
+                Operator::BitOrEq => format!("{} | {}", target, r),
+                Operator::BitXorEq => format!("{} ^ {}", target, r),
+                Operator::BitAndEq => format!("{} & {}", target, r),
+                Operator::BitShlEq => format!("wrap({} << ({} & 63))", target, r),
+                Operator::BitShrEq => format!("{} >> ({} & 63)", target, r),
+                Operator::AddEq => format!("{}({} + {})", wrap, target, r),
+                Operator::SubEq => format!("{}({} - {})", wrap, target, r),
+                Operator::MulEq => format!("{}({} * {})", wrap, target, r),
+                Operator::DivEq => format!("{}({}, {})", div, target, r),
+                Operator::ModEq => format!("{}({}, {})", rem, target, r),
+                _ => unreachable!(),
+            };
+            self.line(format!("{} = {}", target, value));
+            return self.bind(target.to_string());
+        }
+
+        if e.is_incdec() {
+            let target = self.var(e.right.as_ref().unwrap());
+            let step = if matches!(e.op, Operator::PreInc | Operator::PostInc) { "+" } else { "-" };
+            let update = format!("{} = wrap({} {} 1)", target, target, step);
+            return if matches!(e.op, Operator::PreInc | Operator::PreDec) {
+                self.line(update);
+                self.bind(target.to_string())
+            } else {
+                let t = self.bind(target.to_string());
+                self.line(update);
+                t
+            };
+        }
+
+        let l = e.left.as_ref().map(|left| self.lower(left)).unwrap_or_default();
+        let r = e.right.as_ref().map(|right| self.lower(right)).unwrap_or_default();
+        let wrap = if e.is_saturating() { "clamp" } else { "wrap" };
+        let (div, rem) = division(e);
+        let value = match e.op {
+            Operator::Comma | Operator::Parens => return r,
+            Operator::Lt if e.is_unsigned() => format!("int(u({}) < u({}))", l, r),
+            Operator::Leq if e.is_unsigned() => format!("int(u({}) <= u({}))", l, r),
+            Operator::Gt if e.is_unsigned() => format!("int(u({}) > u({}))", l, r),
+            Operator::Geq if e.is_unsigned() => format!("int(u({}) >= u({}))", l, r),
+            Operator::Min if e.is_unsigned() => format!("wrap(min(u({}), u({})))", l, r),
+            Operator::Max if e.is_unsigned() => format!("wrap(max(u({}), u({})))", l, r),
+            Operator::BitShr if e.is_unsigned() => format!("wrap(u({}) >> ({} & 63))", l, r),
+            Operator::Or => format!("int({} != 0 or {} != 0)", l, r),
+            Operator::And => format!("int({} != 0 and {} != 0)", l, r),
+            Operator::BitOr => format!("{} | {}", l, r),
+            Operator::BitXor => format!("{} ^ {}", l, r),
+            Operator::BitAnd => format!("{} & {}", l, r),
+            Operator::Eq => format!("int({} == {})", l, r),
+            Operator::Neq => format!("int({} != {})", l, r),
+            Operator::Lt => format!("int({} < {})", l, r),
+            Operator::Leq => format!("int({} <= {})", l, r),
+            Operator::Gt => format!("int({} > {})", l, r),
+            Operator::Geq => format!("int({} >= {})", l, r),
+            Operator::Min => format!("min({}, {})", l, r),
+            Operator::Max => format!("max({}, {})", l, r),
+            Operator::BitShl => format!("wrap({} << ({} & 63))", l, r),
+            Operator::BitShr => format!("{} >> ({} & 63)", l, r),
+            Operator::LShr => format!("wrap(u({}) >> ({} & 63))", l, r),
+            Operator::Add => format!("{}({} + {})", wrap, l, r),
+            Operator::Sub => format!("{}({} - {})", wrap, l, r),
+            Operator::Mul => format!("{}({} * {})", wrap, l, r),
+            Operator::Div => format!("{}({}, {})", div, l, r),
+            Operator::Mod => format!("{}({}, {})", rem, l, r),
+            Operator::Pow => match e.pow_modulus() {
+                Some(m) => format!("ipow_mod({}, {}, {})", l, r, m),
+                None => format!("ipow({}, {})", l, r),
+            },
+            Operator::Gcd => format!("wrap(math.gcd({}, {}))", l, r),
+            Operator::Neg => format!("wrap(-{})", r),
+            Operator::BitNot => format!("~{}", r),
+            Operator::Not => format!("int({} == 0)", r),
+            Operator::PopCount => format!("bin(u({})).count('1')", r),
+            _ => unreachable!("{:?} is handled above", e.op),
+        };
+        self.bind(value)
+    }
+}
+
+/// The helpers a `/` or `%` node (or its assignment) lowers to
+fn division(e: &Expr) -> (&'static str, &'static str) {
+    if e.is_euclidean() {
+        ("ediv", "emod")
+    } else if e.is_unsigned() {
+        ("udiv", "umod")
+    } else {
+        ("idiv", "imod")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{bin, euclidean, lit, pow_mod, saturating, ternary, un, unsigned, x, y};
+    use crate::run_sequence_every;
+    use std::process::Command;
+    use std::sync::Arc;
+
+    /// The terms the script prints, or None without a python3 to run it
+    fn run_python(script: &str) -> Option<Vec<NumT>> {
+        let output = Command::new("python3").arg("-c").arg(script).output().ok()?;
+        assert!(output.status.success(), "{}\n{}", script, String::from_utf8_lossy(&output.stderr));
+        Some(String::from_utf8(output.stdout).unwrap().lines().map(|l| l.parse().unwrap()).collect())
+    }
+
+    fn check_matches_run_sequence(e_x: &Arc<Expr>, e_y: &Arc<Expr>, init_x: NumT, init_y: NumT, steps: usize) {
+        check_matches_every(e_x, e_y, init_x, init_y, steps, 1, TransitionMode::Sequential);
+    }
+
+    fn check_matches_every(
+        e_x: &Arc<Expr>,
+        e_y: &Arc<Expr>,
+        init_x: NumT,
+        init_y: NumT,
+        terms: usize,
+        steps_per_term: usize,
+        mode: TransitionMode,
+    ) {
+        let script = to_python(e_x, e_y, init_x, init_y, terms, steps_per_term, mode);
+        let Some(printed) = run_python(&script) else {
+            eprintln!("python3 not found, skipping");
+            return;
+        };
+        // A fatal step ends the script's output early, where run_sequence_every gives None
+        let expected = (1..=terms)
+            .map_while(|n| run_sequence_every(e_x, e_y, init_x, init_y, n, steps_per_term, mode).map(|t| t[n - 1]))
+            .collect::<Vec<_>>();
+        assert_eq!(printed, expected, "{}", script);
+    }
+
+    #[test]
+    fn test_to_python_shape() {
+        let script = to_python(&bin(x(), Operator::AddEq, y()), &bin(x(), Operator::AssignEq, bin(y(), Operator::Sub, x())), 0, 1, 12, 1, TransitionMode::Sequential);
+        assert!(script.starts_with("# x=0, y=1 : x+=y; y=x-y\n"));
+        assert!(script.contains("def idiv(a, b):"));
+        assert!(script.contains("for _ in range(12):"));
+    }
+
+    #[test]
+    fn test_to_python_fibonacci() {
+        let e_x = bin(x(), Operator::AddEq, y());
+        let e_y = bin(x(), Operator::AssignEq, bin(y(), Operator::Sub, x()));
+        check_matches_run_sequence(&e_x, &e_y, 0, 1, 12);
+    }
+
+    #[test]
+    fn test_to_python_steps_per_term_and_mode() {
+        // Simultaneously, x+=y; y=x is (x, y) = (x+y, x); sequentially y sees the new x
+        let e_x = bin(x(), Operator::AddEq, y());
+        let e_y = bin(x(), Operator::AssignEq, y());
+        for mode in [TransitionMode::Sequential, TransitionMode::Simultaneous] {
+            check_matches_every(&e_x, &e_y, 0, 1, 10, 3, mode);
+        }
+        // y/=x-5 is fatal on the fifth step, midway through the third term
+        let e_x = bin(x(), Operator::AddEq, lit(1));
+        let e_y = bin(x(), Operator::DivEq, bin(y(), Operator::Sub, lit(5)));
+        assert_eq!(run_sequence_every(&e_x, &e_y, 1, 1, 3, 2, TransitionMode::Simultaneous), None);
+        check_matches_every(&e_x, &e_y, 1, 1, 6, 2, TransitionMode::Simultaneous);
+    }
+
+    #[test]
+    fn test_to_python_truncating_division() {
+        // Negative operands are where // and % differ from C
+        let e_x = bin(x(), Operator::AssignEq, bin(bin(x(), Operator::Div, lit(-3)), Operator::Sub, bin(y(), Operator::Mod, lit(4))));
+        let e_y = bin(x(), Operator::SubEq, lit(7));
+        check_matches_run_sequence(&e_x, &e_y, -20, 5, 10);
+        let e_x = bin(x(), Operator::ModEq, y());
+        let e_y = bin(x(), Operator::DivEq, lit(-2));
+        check_matches_run_sequence(&e_x, &e_y, 100, -9, 6);
+    }
+
+    #[test]
+    fn test_to_python_fatal_ends_sequence() {
+        // y reaches 0 on the third step, so x/=y is fatal on the fourth
+        let e_x = bin(x(), Operator::DivEq, y());
+        let e_y = un(Operator::PreDec, x());
+        check_matches_run_sequence(&e_x, &e_y, 1000, 3, 6);
+    }
+
+    #[test]
+    fn test_to_python_side_effects_and_wrapping() {
+        // x+(x=5) reads x before the assignment; ternary only runs one branch
+        let e_x = bin(x(), Operator::AssignEq, bin(x(), Operator::Add, un(Operator::Parens, bin(x(), Operator::AssignEq, lit(5)))));
+        let e_y = ternary(bin(y(), Operator::And, un(Operator::PostInc, x())), un(Operator::PreInc, x()), bin(x(), Operator::MulEq, lit(3)));
+        check_matches_run_sequence(&e_x, &e_y, 1, 0, 8);
+        // Overflow wraps, shifts take their count mod 64
+        let e_x = bin(x(), Operator::MulEq, bin(y(), Operator::BitShl, lit(65)));
+        let e_y = bin(x(), Operator::AssignEq, bin(un(Operator::Neg, x()), Operator::BitXor, un(Operator::BitNot, y())));
+        check_matches_run_sequence(&e_x, &e_y, 3, 1234567, 12);
+    }
+
+    #[test]
+    fn test_to_python_saturating() {
+        // x doubles until it sticks at MAX, y runs down to MIN
+        let e_x = saturating(x(), Operator::MulEq, lit(2));
+        let e_y = saturating(y(), Operator::SubEq, saturating(x(), Operator::Mul, x()));
+        check_matches_run_sequence(&e_x, &e_y, 3, 0, 70);
+    }
+
+    #[test]
+    fn test_to_python_euclidean() {
+        // y alternates sign and is never 0, so x's divisions hit every sign combination
+        let e_y = bin(x(), Operator::AssignEq, bin(lit(1), Operator::Sub, bin(x(), Operator::Mul, lit(3))));
+        let e_x = bin(x(), Operator::AssignEq, bin(euclidean(x(), Operator::Div, y()), Operator::Sub, euclidean(x(), Operator::Mod, lit(3))));
+        check_matches_run_sequence(&e_x, &e_y, -50, 2, 12);
+        let e_x = bin(euclidean(x(), Operator::DivEq, y()), Operator::Comma, bin(euclidean(x(), Operator::ModEq, lit(-7)), Operator::Sub, lit(100)));
+        check_matches_run_sequence(&e_x, &e_y, -500, 2, 12);
+    }
+
+    #[test]
+    fn test_to_python_pow() {
+        let e_x = bin(x(), Operator::AssignEq, bin(y(), Operator::Pow, x()));
+        let e_y = bin(x(), Operator::SubEq, lit(1));
+        check_matches_run_sequence(&e_x, &e_y, 2, 3, 6);
+        let e_x = bin(x(), Operator::AssignEq, pow_mod(bin(y(), Operator::Sub, lit(9)), bin(x(), Operator::Add, lit(40)), 97));
+        let e_y = bin(x(), Operator::AddEq, x());
+        check_matches_run_sequence(&e_x, &e_y, 2, 1, 10);
+    }
+
+    #[test]
+    fn test_to_python_unsigned() {
+        // x swings between large positive and negative values, which is where
+        // the unsigned operators part ways with the signed ones
+        let e_x = bin(x(), Operator::AssignEq, bin(bin(x(), Operator::Mul, lit(-7)), Operator::Add, unsigned(x(), Operator::Lt, y())));
+        let e_y = bin(x(), Operator::AssignEq, bin(unsigned(y(), Operator::Max, x()), Operator::Sub, bin(unsigned(y(), Operator::Div, lit(3)), Operator::BitXor, unsigned(x(), Operator::BitShr, lit(3)))));
+        check_matches_run_sequence(&e_x, &e_y, 5, -9, 20);
+        let e_x = bin(unsigned(x(), Operator::ModEq, y()), Operator::Comma, bin(x(), Operator::SubEq, unsigned(y(), Operator::Min, lit(-2))));
+        let e_y = bin(unsigned(x(), Operator::DivEq, lit(-3)), Operator::Add, unsigned(x(), Operator::BitShrEq, lit(1)));
+        check_matches_run_sequence(&e_x, &e_y, 1000, -13, 12);
+    }
+
+    #[test]
+    fn test_to_python_lshr() {
+        let e_x = bin(x(), Operator::AssignEq, bin(bin(x(), Operator::LShr, y()), Operator::Sub, lit(1000)));
+        let e_y = bin(x(), Operator::AddEq, lit(7));
+        check_matches_run_sequence(&e_x, &e_y, -5, 60, 10);
+    }
+
+    #[test]
+    fn test_to_python_popcount() {
+        // A000120 from 0, with negative y
+        let e_x = bin(x(), Operator::AssignEq, bin(un(Operator::PopCount, y()), Operator::Add, un(Operator::PopCount, x())));
+        let e_y = bin(x(), Operator::SubEq, lit(3));
+        check_matches_run_sequence(&e_x, &e_y, 0, 5, 10);
+    }
+
+    #[test]
+    fn test_to_python_gcd() {
+        let e_x = bin(x(), Operator::AssignEq, bin(bin(x(), Operator::Gcd, y()), Operator::Add, y()));
+        let e_y = bin(x(), Operator::MulEq, lit(-3));
+        check_matches_run_sequence(&e_x, &e_y, 12, 18, 12);
+    }
+}
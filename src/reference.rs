@@ -0,0 +1,179 @@
+//! A reference entry for every operator: what it does, in one line, and how a
+//! plain node computes its value from its operands' values. `minimal_expr`
+//! builds the smallest tree that runs each one. Tests check `naive_eval`
+//! against the table and each JIT backend against `naive_eval` on those
+//! trees, over `EXTREMES`. The match in `reference` has no catch-all, so a new
+//! operator doesn't compile until it has an entry, and with it those tests.
+//!
+//! Flavored nodes (saturating, Euclidean, unsigned) aren't separate operators.
+//! Their semantics are in `saturating_op`, `euclidean_op` and `unsigned_op`.
+
+use crate::build::{bin, lit, ternary, un, x, y};
+use crate::{category, gcd, Expr, NumT, OpCategory, Operator};
+use std::sync::Arc;
+
+/// How a node's value follows from its operands'
+#[derive(Clone, Copy)]
+pub enum Semantics {
+    /// From the values of its left and right operand, None where that's
+    /// fatal. An assignment gets its target's old value and its right side,
+    /// and yields the target's new value. Unary operators and `++`/`--` get
+    /// their operand as the right; `++`/`--` store `x±1` either way.
+    Values(fn(NumT, NumT) -> Option<NumT>),
+    /// `?:` runs only the branch its condition picks, and takes its value
+    Branch,
+    /// Variables and literals read x, y or the node's literal
+    Operand,
+}
+
+/// An operator's reference entry
+#[derive(Clone, Copy)]
+pub struct OpReference {
+    pub op: Operator,
+    pub description: &'static str,
+    pub semantics: Semantics,
+}
+
+/// Values that find the edge cases: zero and ±1, shift counts around 64,
+/// the i32 bounds and the i64 ones
+pub const EXTREMES: &[NumT] = &[
+    NumT::MIN, NumT::MIN + 1, i32::MIN as NumT, -64, -3, -2, -1, 0, 1, 2, 3, 5, 63, 64, 65,
+    i32::MAX as NumT, 0x5555_5555_5555_5555, NumT::MAX - 1, NumT::MAX,
+];
+
+fn div(l: NumT, r: NumT) -> Option<NumT> {
+    l.checked_div(r)
+}
+
+fn rem(l: NumT, r: NumT) -> Option<NumT> {
+    l.checked_rem(r)
+}
+
+fn pow(l: NumT, r: NumT) -> Option<NumT> {
+    match r {
+        // 1/l**-r truncated, so 0 unless l is ±1
+        ..0 => match l {
+            0 => None,
+            1 => Some(1),
+            -1 => Some(if r % 2 == 0 { 1 } else { -1 }),
+            _ => Some(0),
+        },
+        _ => u32::try_from(r).ok().and_then(|r| l.checked_pow(r)),
+    }
+}
+
+/// The reference entry for `op`
+pub fn reference(op: Operator) -> OpReference {
+    use Semantics::{Branch, Operand, Values};
+    let (description, semantics) = match op {
+        Operator::AssignEq => ("x = e stores e in x", Values(|_, r| Some(r))),
+        Operator::BitOrEq => ("x |= e", Values(|l, r| Some(l | r))),
+        Operator::BitXorEq => ("x ^= e", Values(|l, r| Some(l ^ r))),
+        Operator::BitAndEq => ("x &= e", Values(|l, r| Some(l & r))),
+        Operator::BitShlEq => ("x <<= e, shifting by e mod 64", Values(|l, r| Some(l.wrapping_shl(r as u32)))),
+        Operator::BitShrEq => ("x >>= e, shifting in the sign bit by e mod 64", Values(|l, r| Some(l.wrapping_shr(r as u32)))),
+        Operator::AddEq => ("x += e, wrapping", Values(|l, r| Some(l.wrapping_add(r)))),
+        Operator::SubEq => ("x -= e, wrapping", Values(|l, r| Some(l.wrapping_sub(r)))),
+        Operator::MulEq => ("x *= e, wrapping", Values(|l, r| Some(l.wrapping_mul(r)))),
+        Operator::DivEq => ("x /= e, truncating; fatal for /0 and MIN/-1", Values(div)),
+        Operator::ModEq => ("x %= e, taking the dividend's sign; fatal for %0 and MIN%-1", Values(rem)),
+        Operator::Comma => ("a, b runs a then b, and takes b", Values(|_, r| Some(r))),
+        Operator::Ternary => ("c ? a : b runs a if c is nonzero, else b", Branch),
+        Operator::Or => ("a || b, 1 if either is nonzero; both always run", Values(|l, r| Some((l != 0 || r != 0) as NumT))),
+        Operator::And => ("a && b, 1 if both are nonzero; both always run", Values(|l, r| Some((l != 0 && r != 0) as NumT))),
+        Operator::BitOr => ("bitwise or", Values(|l, r| Some(l | r))),
+        Operator::BitXor => ("bitwise exclusive or", Values(|l, r| Some(l ^ r))),
+        Operator::BitAnd => ("bitwise and", Values(|l, r| Some(l & r))),
+        Operator::Eq => ("1 if equal, else 0", Values(|l, r| Some((l == r) as NumT))),
+        Operator::Neq => ("1 if not equal, else 0", Values(|l, r| Some((l != r) as NumT))),
+        Operator::Lt => ("1 if less, signed", Values(|l, r| Some((l < r) as NumT))),
+        Operator::Leq => ("1 if less or equal, signed", Values(|l, r| Some((l <= r) as NumT))),
+        Operator::Gt => ("1 if greater, signed", Values(|l, r| Some((l > r) as NumT))),
+        Operator::Geq => ("1 if greater or equal, signed", Values(|l, r| Some((l >= r) as NumT))),
+        Operator::Min => ("the smaller, signed", Values(|l, r| Some(l.min(r)))),
+        Operator::Max => ("the larger, signed", Values(|l, r| Some(l.max(r)))),
+        Operator::BitShl => ("shift left by b mod 64", Values(|l, r| Some(l.wrapping_shl(r as u32)))),
+        Operator::BitShr => ("shift right by b mod 64, copying the sign bit", Values(|l, r| Some(l.wrapping_shr(r as u32)))),
+        Operator::LShr => ("shift right by b mod 64, shifting in zeros", Values(|l, r| Some((l as u64).wrapping_shr(r as u32) as NumT))),
+        Operator::Add => ("wrapping sum", Values(|l, r| Some(l.wrapping_add(r)))),
+        Operator::Sub => ("wrapping difference", Values(|l, r| Some(l.wrapping_sub(r)))),
+        Operator::Mul => ("wrapping product", Values(|l, r| Some(l.wrapping_mul(r)))),
+        Operator::Div => ("quotient truncated toward 0; fatal for /0 and MIN/-1", Values(div)),
+        Operator::Mod => ("remainder with the dividend's sign; fatal for %0 and MIN%-1", Values(rem)),
+        Operator::Pow => ("a to the power b, truncated for b < 0; fatal on overflow and 0**-n", Values(pow)),
+        Operator::Gcd => ("greatest common divisor, never negative", Values(|l, r| Some(gcd(l, r)))),
+        Operator::Neg => ("wrapping negation", Values(|_, r| Some(r.wrapping_neg()))),
+        Operator::BitNot => ("bitwise not", Values(|_, r| Some(!r))),
+        Operator::Not => ("1 if zero, else 0", Values(|_, r| Some((r == 0) as NumT))),
+        Operator::PopCount => ("number of set bits", Values(|_, r| Some(r.count_ones() as NumT))),
+        Operator::PreInc => ("++x adds 1 to x and takes the new value", Values(|_, r| Some(r.wrapping_add(1)))),
+        Operator::PreDec => ("--x subtracts 1 from x and takes the new value", Values(|_, r| Some(r.wrapping_sub(1)))),
+        Operator::PostInc => ("x++ adds 1 to x and takes the old value", Values(|_, r| Some(r))),
+        Operator::PostDec => ("x-- subtracts 1 from x and takes the old value", Values(|_, r| Some(r))),
+        Operator::Parens => ("(e) takes e", Values(|_, r| Some(r))),
+        Operator::Var => ("the variable x", Operand),
+        Operator::VarY => ("the variable y", Operand),
+        Operator::Literal => ("the node's literal", Operand),
+    };
+    OpReference { op, description, semantics }
+}
+
+/// The smallest tree that runs `op`: `op` applied to x, and to y on the
+/// right. `?:` picks between `y++` and `--y`, so running both would show.
+pub fn minimal_expr(op: Operator) -> Arc<Expr> {
+    match op {
+        Operator::Ternary => ternary(x(), un(Operator::PostInc, y()), un(Operator::PreDec, y())),
+        Operator::Var => x(),
+        Operator::VarY => y(),
+        Operator::Literal => lit(NumT::MIN),
+        _ if matches!(category(op), OpCategory::Assignment | OpCategory::Binary) => bin(x(), op, y()),
+        _ => un(op, x()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naive_eval;
+
+    #[test]
+    fn test_reference_matches_naive_eval() {
+        for op in Operator::all() {
+            let Semantics::Values(eval) = reference(op).semantics else { continue };
+            let e = minimal_expr(op);
+            for &x0 in EXTREMES {
+                for &y0 in EXTREMES {
+                    let (mut x, mut y, mut fatal) = (x0, y0, false);
+                    let r = naive_eval(&e, &mut x, &mut y, &mut fatal);
+                    // Unary operators take x as their right operand
+                    let (l, r0) = if e.left.is_some() { (x0, y0) } else { (0, x0) };
+                    let expected = eval(l, r0);
+                    assert_eq!((!fatal).then_some(r), expected, "{:?} at x={}, y={}", op, x0, y0);
+                    if fatal {
+                        continue;
+                    }
+                    let x_after = match category(op) {
+                        OpCategory::Assignment => r,
+                        OpCategory::IncDec => x0.wrapping_add(if matches!(op, Operator::PreInc | Operator::PostInc) { 1 } else { -1 }),
+                        _ => x0,
+                    };
+                    assert_eq!((x, y), (x_after, y0), "{:?} at x={}, y={}", op, x0, y0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reference_covers_every_operator() {
+        for op in Operator::all() {
+            let entry = reference(op);
+            assert_eq!(entry.op, op);
+            assert!(!entry.description.is_empty());
+            assert!(minimal_expr(op).is_jittable());
+        }
+        // The branch not taken doesn't run
+        let (mut x, mut y, mut fatal) = (0, 5, false);
+        assert_eq!(naive_eval(&minimal_expr(Operator::Ternary), &mut x, &mut y, &mut fatal), 4);
+        assert_eq!((x, y), (0, 4));
+    }
+}
@@ -0,0 +1,170 @@
+//! Placing the IR's virtual registers in machine registers and stack slots.
+//!
+//! This is a linear scan over live ranges. A virtual register is live from
+//! the first instruction that mentions it to the last. Going through the
+//! ranges by where they start, each takes a machine register no live range
+//! holds. When none is left, whichever range ends last, the new one or a
+//! live one, spends its whole life in a stack slot instead.
+//!
+//! The IR only branches forward, and nothing computed before a `?:` is read
+//! inside one of its branches. So a range from first to last mention covers
+//! every path a value is live on. The value of the `?:` itself is live over
+//! the branch that doesn't write it too, which only costs a register.
+
+use crate::ir::{vreg_count, IrInst, VReg};
+
+/// Where a virtual register lives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Loc {
+    /// The backend's i-th allocatable register
+    Reg(usize),
+    /// The i-th 8-byte stack slot
+    Slot(usize),
+}
+
+/// Where each virtual register lives, and how many stack slots that takes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    pub locs: Vec<Loc>,
+    pub slots: usize,
+}
+
+/// The first and last instruction mentioning each virtual register, None
+/// for one no instruction mentions (the value of a fatal node left of `,`)
+pub fn live_ranges(code: &[IrInst]) -> Vec<Option<(usize, usize)>> {
+    let mut ranges = vec![None; vreg_count(code)];
+    for (i, inst) in code.iter().enumerate() {
+        for v in inst.reads().chain(inst.writes()) {
+            ranges[v].get_or_insert((i, i)).1 = i;
+        }
+    }
+    ranges
+}
+
+/// Place the virtual registers of `code` in `regs` machine registers,
+/// spilling the rest. A `BinOp` may get its left operand's register, never
+/// its right one's; registers nothing mentions are put in `Reg(0)`.
+pub fn allocate(code: &[IrInst], regs: usize) -> Allocation {
+    assert!(regs > 0, "nothing to allocate into");
+    let ranges = live_ranges(code);
+    let mut order: Vec<VReg> = (0..ranges.len()).filter(|&v| ranges[v].is_some()).collect();
+    order.sort_by_key(|&v| ranges[v].unwrap().0);
+
+    let end = |v: VReg| ranges[v].unwrap().1;
+    let mut locs = vec![Loc::Reg(0); ranges.len()];
+    let mut holders: Vec<Option<VReg>> = Vec::new(); // What each machine register holds
+    let mut slots = 0;
+    for v in order {
+        let start = ranges[v].unwrap().0;
+        for holder in holders.iter_mut() {
+            if holder.is_some_and(|h| end(h) < start) {
+                *holder = None;
+            }
+        }
+        // A value computed from one that dies here takes over its register,
+        // so the backend needn't copy it
+        let from = match code[start] {
+            IrInst::BinOp { dst, lhs, .. } if dst == v => Some(lhs),
+            IrInst::UnOp { dst, src, .. } | IrInst::Copy { dst, src } if dst == v => Some(src),
+            _ => None,
+        };
+        let hint = from.filter(|&s| end(s) == start).and_then(|s| holders.iter().position(|&h| h == Some(s)));
+        let reg = hint.or_else(|| holders.iter().position(Option::is_none)).or_else(|| {
+            (holders.len() < regs).then(|| {
+                holders.push(None);
+                holders.len() - 1
+            })
+        });
+        match reg {
+            Some(r) => {
+                holders[r] = Some(v);
+                locs[v] = Loc::Reg(r);
+            }
+            None => {
+                // Operands of the instruction at `start` end there, so they're never the victim
+                let (r, victim) = holders.iter().map(|h| h.unwrap()).enumerate().max_by_key(|&(_, h)| end(h)).unwrap();
+                if end(victim) > end(v) {
+                    locs[victim] = Loc::Slot(slots);
+                    holders[r] = Some(v);
+                    locs[v] = Loc::Reg(r);
+                } else {
+                    locs[v] = Loc::Slot(slots);
+                }
+                slots += 1;
+            }
+        }
+    }
+    Allocation { locs, slots }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{bin, lit, ternary, un, x, y};
+    use crate::ir::lower;
+    use crate::{Expr, Operator};
+    use std::sync::Arc;
+
+    // x - (y - (x - ...)), `n` operands deep, so all of them are live at once
+    fn right_nested(n: usize) -> Arc<Expr> {
+        (1..n).fold(x(), |acc, i| bin(if i % 2 == 0 { x() } else { y() }, Operator::Sub, acc))
+    }
+
+    // No two values share a register while both are live, and no BinOp
+    // writes its right operand's register
+    fn check(code: &[IrInst], alloc: &Allocation) {
+        let ranges = live_ranges(code);
+        for a in 0..ranges.len() {
+            for b in a + 1..ranges.len() {
+                let (Some((sa, ea)), Some((sb, eb))) = (ranges[a], ranges[b]) else { continue };
+                if alloc.locs[a] == alloc.locs[b] {
+                    assert!(ea <= sb || eb <= sa, "v{} and v{} overlap in {:?}", a, b, alloc.locs[a]);
+                }
+            }
+        }
+        for inst in code {
+            if let IrInst::BinOp { dst, rhs, .. } = *inst {
+                assert!(alloc.locs[dst] != alloc.locs[rhs] || matches!(alloc.locs[dst], Loc::Slot(_)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_live_ranges() {
+        // Load x, Load y, Add, Return
+        let code = lower(&bin(x(), Operator::Add, y()));
+        assert_eq!(live_ranges(&code), [Some((0, 2)), Some((1, 2)), Some((2, 3))]);
+        // A fatal node's value that `,` drops is never mentioned
+        let code = lower(&bin(un(Operator::PreInc, lit(1)), Operator::Comma, y()));
+        assert_eq!(live_ranges(&code), [None, Some((1, 2))]);
+    }
+
+    #[test]
+    fn test_allocate_takes_left_register() {
+        let code = lower(&bin(bin(x(), Operator::Mul, y()), Operator::Sub, lit(3)));
+        let alloc = allocate(&code, 4);
+        assert_eq!(alloc.locs, [Loc::Reg(0), Loc::Reg(1), Loc::Reg(0), Loc::Reg(1), Loc::Reg(0)]);
+        assert_eq!(alloc.slots, 0);
+    }
+
+    #[test]
+    fn test_allocate_spills() {
+        let code = lower(&right_nested(9));
+        let alloc = allocate(&code, 4);
+        check(&code, &alloc);
+        // Nine operands are live at once in four registers
+        assert_eq!(alloc.slots, 5);
+        assert_eq!(allocate(&code, 9).slots, 0);
+        check(&code, &allocate(&code, 1));
+    }
+
+    #[test]
+    fn test_allocate_ternary() {
+        let deep = right_nested(6);
+        let e = bin(deep.clone(), Operator::Add, ternary(x(), deep.clone(), bin(y(), Operator::AssignEq, deep)));
+        let code = lower(&e);
+        for regs in 1..=8 {
+            check(&code, &allocate(&code, regs));
+        }
+    }
+}
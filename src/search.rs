@@ -0,0 +1,227 @@
+//! Searching a built cache for pairs that generate a target sequence.
+//!
+//! The caches only depend on the generator config, not on the target, so one
+//! `Context` from `build_cache` can be searched for any number of targets.
+//! What a pair is tested against is a `PairMatcher`: `SequenceMatcher` for
+//! a plain target, or the binary's own matcher with its filters.
+
+use crate::{step_pair, structural_cmp, Context, Expr, FoundPair, NumT, TransitionMode};
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Which pairs a search reports, and from which seed
+pub trait PairMatcher: Sync {
+    /// The seed a matching `e_x; e_y` is reported with, None if it's no match
+    fn first_match(&self, e_x: &Expr, e_y: &Expr) -> Option<(NumT, NumT)>;
+
+    /// Whether only pairs of a statement with itself are tried, as for the
+    /// binary's SYMMETRIC
+    fn symmetric(&self) -> bool {
+        false
+    }
+}
+
+/// A target sequence and the seeds to try, like the binary's `ANSWER` and
+/// init range. Term i is x after `steps_per_term` steps per term, taken by
+/// `mode`. Pairs are run with naive_eval.
+#[derive(Debug, Clone)]
+pub struct SequenceMatcher {
+    pub target: Vec<NumT>,
+    pub inits: Vec<(NumT, NumT)>,
+    pub steps_per_term: usize,
+    pub mode: TransitionMode,
+    pub symmetric: bool,
+}
+
+impl SequenceMatcher {
+    /// One step per term, taken sequentially, over every pair
+    pub fn new(target: Vec<NumT>, inits: Vec<(NumT, NumT)>) -> Self {
+        Self { target, inits, steps_per_term: 1, mode: TransitionMode::default(), symmetric: false }
+    }
+
+    /// Whether `e_x; e_y` generates the target from `(init_x, init_y)`. A
+    /// fatal step is a miss.
+    pub fn matches_from(&self, e_x: &Expr, e_y: &Expr, init_x: NumT, init_y: NumT) -> bool {
+        let (mut x, mut y) = (init_x, init_y);
+        self.target.iter().all(|&term| {
+            (0..self.steps_per_term).all(|_| step_pair(e_x, e_y, &mut x, &mut y, self.mode)) && x == term
+        })
+    }
+
+}
+
+impl PairMatcher for SequenceMatcher {
+    /// The first seed `e_x; e_y` generates the target from
+    fn first_match(&self, e_x: &Expr, e_y: &Expr) -> Option<(NumT, NumT)> {
+        self.inits.iter().copied().find(|&(ix, iy)| self.matches_from(e_x, e_y, ix, iy))
+    }
+
+    fn symmetric(&self) -> bool {
+        self.symmetric
+    }
+}
+
+/// Every pair of cached statements that generates the matcher's target, with
+/// the first seed it does from. A pair's length is its longer statement's, as
+/// in the binary's cached phase. Pairs come shortest first, then in
+/// `structural_cmp` order of e_x and e_y, however the threads ran.
+pub fn search_with(ctx: &Context, matcher: &impl PairMatcher) -> Vec<FoundPair> {
+    let found = Mutex::new(Vec::new());
+    search_each(ctx, matcher, &AtomicBool::new(false), |pair| found.lock().unwrap().push(pair.clone()));
+    let mut found = found.into_inner().unwrap();
+    found.sort_by(|a, b| {
+        a.length.cmp(&b.length)
+            .then_with(|| structural_cmp(&a.e_x, &b.e_x))
+            .then_with(|| structural_cmp(&a.e_y, &b.e_y))
+    });
+    found
+}
+
+/// Like `search_with`, but each match goes to `on_solution` as soon as it's
+/// found, on whichever thread found it, rather than into a Vec. Lengths are
+/// still searched shortest first. Nothing is locked around the call, so
+/// `on_solution` handles its own sharing.
+///
+/// Setting `stop`, from `on_solution` or elsewhere, ends the search: threads
+/// give up at the next pair they try. A call already under way on another
+/// thread still finishes, so `on_solution` may see a match or two after it.
+pub fn search_each(ctx: &Context, matcher: &impl PairMatcher, stop: &AtomicBool, on_solution: impl Fn(&FoundPair) + Sync) {
+    let max_len = (1..).take_while(|&n| ctx.expressions(n).is_some()).last().unwrap_or(0);
+    for n in 1..=max_len {
+        search_length(ctx, matcher, n, stop, &on_solution);
+    }
+}
+
+/// `search_each` for the pairs of length `n` alone, those with a statement
+/// of length n and none longer; the shorter ones are left to earlier
+/// lengths. Statements are taken in cache order, so on a single thread
+/// matches come in that order too.
+pub fn search_length(ctx: &Context, matcher: &impl PairMatcher, n: usize, stop: &AtomicBool, on_solution: impl Fn(&FoundPair) + Sync) {
+    let report = |e_x: &Arc<Expr>, e_y: &Arc<Expr>| {
+        if let Some((init_x, init_y)) = matcher.first_match(e_x, e_y) {
+            on_solution(&FoundPair { length: n, e_x: e_x.clone(), e_y: e_y.clone(), init_x, init_y });
+        }
+    };
+    if matcher.symmetric() {
+        // The cache keeps one statement per semantic class, so a statement
+        // is the only one in it `structural_eq` to itself
+        if let Some(stmts) = ctx.statements(n) {
+            stmts.par_iter().filter(|_| !stop.load(Ordering::Relaxed)).for_each(|e| report(e, e));
+        }
+        return;
+    }
+    for n_x in 1..=n {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(stmts_x) = ctx.statements(n_x) {
+            stmts_x.par_iter().for_each(|e_x| partners(ctx, stop, n, n_x).for_each(|e_y| report(e_x, e_y)));
+        }
+    }
+}
+
+/// The statements that make a pair of length `n` with one of length `n_x`
+/// on the left, until `stop` is set
+fn partners<'a>(ctx: &'a Context, stop: &'a AtomicBool, n: usize, n_x: usize) -> impl Iterator<Item = &'a Arc<Expr>> + 'a {
+    let n_y_from = if n_x == n { 1 } else { n };
+    let stmts_y = (n_y_from..=n).filter_map(|n_y| ctx.statements(n_y)).flatten();
+    stmts_y.take_while(|_| !stop.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{bin, x, y};
+    use crate::{build_cache, format_expression, run_sequence_every, Operator, SearchConfig};
+    use std::sync::atomic::AtomicUsize;
+
+    fn seeds() -> Vec<(NumT, NumT)> {
+        (-2..=2).flat_map(|ix| (-2..=2).map(move |iy| (ix, iy))).collect()
+    }
+
+    fn show(p: &FoundPair) -> String {
+        format!("{}; {}", format_expression(&p.e_x, &['x', 'y']), format_expression(&p.e_y, &['y', 'x']))
+    }
+
+    #[test]
+    fn test_one_cache_many_targets() {
+        let ctx = build_cache(&SearchConfig::default(), 4);
+        let targets: [&[NumT]; 3] = [
+            &[1, 1, 2, 3, 5, 8, 13, 21, 34, 55],
+            &[2, 4, 8, 16, 32, 64, 128, 256],
+            &[1, 2, 3, 4, 5, 6, 7, 8],
+        ];
+        let expected = ["x+=y; y=x-y", "x+=x; y=y", "x+=1; y=y"];
+        for (target, expected) in targets.iter().zip(expected) {
+            let found = search_with(&ctx, &SequenceMatcher::new(target.to_vec(), seeds()));
+            assert!(found.iter().any(|p| show(p) == expected), "{} not found", expected);
+            assert!(found.windows(2).all(|w| w[0].length <= w[1].length));
+            for p in &found {
+                let terms = run_sequence_every(&p.e_x, &p.e_y, p.init_x, p.init_y, target.len(), 1, TransitionMode::Sequential);
+                assert_eq!(terms.as_deref(), Some(*target));
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_each_streams_and_stops() {
+        let ctx = build_cache(&SearchConfig::default(), 4);
+        let matcher = SequenceMatcher::new(vec![2, 4, 8, 16, 32, 64, 128, 256], seeds());
+        let all = search_with(&ctx, &matcher);
+
+        let streamed = Mutex::new(Vec::new());
+        search_each(&ctx, &matcher, &AtomicBool::new(false), |pair| streamed.lock().unwrap().push(show(pair)));
+        let mut streamed = streamed.into_inner().unwrap();
+        let mut expected: Vec<String> = all.iter().map(show).collect();
+        streamed.sort();
+        expected.sort();
+        assert_eq!(streamed, expected);
+
+        // The callback stops the search at the third match, and only calls
+        // already under way on other threads get in after it
+        let (stop, calls) = (AtomicBool::new(false), AtomicUsize::new(0));
+        search_each(&ctx, &matcher, &stop, |_| {
+            if calls.fetch_add(1, Ordering::Relaxed) + 1 == 3 {
+                stop.store(true, Ordering::Relaxed);
+            }
+        });
+        let calls = calls.into_inner();
+        assert!((3..3 + rayon::current_num_threads()).contains(&calls), "{} calls", calls);
+    }
+
+    #[test]
+    fn test_search_length_and_symmetric() {
+        let ctx = build_cache(&SearchConfig::default(), 4);
+        let mut matcher = SequenceMatcher::new(vec![2, 4, 8, 16, 32, 64, 128, 256], seeds());
+        let all = search_with(&ctx, &matcher);
+        let at_3 = Mutex::new(Vec::new());
+        search_length(&ctx, &matcher, 3, &AtomicBool::new(false), |pair| at_3.lock().unwrap().push(show(pair)));
+        let mut at_3 = at_3.into_inner().unwrap();
+        at_3.sort();
+        let mut expected: Vec<String> = all.iter().filter(|p| p.length == 3).map(show).collect();
+        expected.sort();
+        assert_eq!(at_3, expected);
+
+        // x+=x doubles, and so does y+=y as e_y
+        matcher.symmetric = true;
+        let symmetric = search_with(&ctx, &matcher);
+        assert!(!symmetric.is_empty());
+        assert!(symmetric.iter().all(|p| Arc::ptr_eq(&p.e_x, &p.e_y)));
+        assert!(symmetric.iter().any(|p| show(p) == "x+=x; y+=y"));
+    }
+
+    #[test]
+    fn test_matcher_steps_and_mode() {
+        // x+=y; y=x from (1, 0): simultaneous steps give the Fibonacci
+        // numbers, sequential ones double. e_y sees y as its x.
+        let (e_x, e_y) = (bin(x(), Operator::AddEq, y()), bin(x(), Operator::AssignEq, y()));
+        let mut matcher = SequenceMatcher::new(vec![1, 2, 3, 5, 8], vec![(0, 0), (1, 0)]);
+        assert_eq!(matcher.first_match(&e_x, &e_y), None);
+        matcher.mode = TransitionMode::Simultaneous;
+        assert_eq!(matcher.first_match(&e_x, &e_y), Some((1, 0)));
+        matcher.steps_per_term = 2;
+        matcher.target = vec![2, 5, 13];
+        assert_eq!(matcher.first_match(&e_x, &e_y), Some((1, 0)));
+    }
+}
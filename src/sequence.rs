@@ -0,0 +1,415 @@
+//! Running a statement pair as the sequence it generates.
+
+use crate::build::{bin, x, y};
+use crate::visit::map;
+use crate::{naive_eval, Expr, NumT, Operator};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+/// How one step of a pair updates x and y
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransitionMode {
+    /// `e_x` runs, then `e_y` sees the x it wrote
+    #[default]
+    Sequential,
+    /// Both run from the same (x, y), then x takes what `e_x` wrote to it
+    /// and y what `e_y` wrote to it, like `(x, y) = (f(x, y), g(x, y))`.
+    /// Either one's write to the other's variable is dropped.
+    Simultaneous,
+}
+
+/// One step of `e_x; e_y` from (x, y) under `mode`. False if it's fatal,
+/// leaving x and y partly updated.
+pub fn step_pair(e_x: &Expr, e_y: &Expr, x: &mut NumT, y: &mut NumT, mode: TransitionMode) -> bool {
+    let mut fatal = false;
+    match mode {
+        TransitionMode::Sequential => {
+            naive_eval(e_x, x, y, &mut fatal);
+            naive_eval(e_y, y, x, &mut fatal);
+        }
+        TransitionMode::Simultaneous => {
+            let (mut x_of_x, mut y_of_x) = (*x, *y);
+            let (mut y_of_y, mut x_of_y) = (*y, *x);
+            naive_eval(e_x, &mut x_of_x, &mut y_of_x, &mut fatal);
+            naive_eval(e_y, &mut y_of_y, &mut x_of_y, &mut fatal);
+            (*x, *y) = (x_of_x, y_of_y);
+        }
+    }
+    !fatal
+}
+
+/// The value of x after each of `steps` steps of `e_x; e_y`, the same
+/// sequence the search matches against. None if a step is fatal.
+pub fn run_sequence(e_x: &Expr, e_y: &Expr, init_x: NumT, init_y: NumT, steps: usize) -> Option<Vec<NumT>> {
+    run_sequence_every(e_x, e_y, init_x, init_y, steps, 1, TransitionMode::Sequential)
+}
+
+/// Most terms `sequence_prefix` runs for, as a pair may never go fatal
+pub const MAX_PREFIX_TERMS: usize = 1000;
+
+/// The terms `e_x; e_y` produces before its first fatal step, up to
+/// MAX_PREFIX_TERMS, showing how far a candidate stays well-defined. Terms
+/// are `steps_per_term` steps apart, taken by `mode`, as in `run_sequence_every`.
+/// Walks the tree, as the JIT doesn't report fatal steps.
+pub fn sequence_prefix(
+    e_x: &Expr,
+    e_y: &Expr,
+    init_x: NumT,
+    init_y: NumT,
+    steps_per_term: usize,
+    mode: TransitionMode,
+) -> Vec<NumT> {
+    let mut x = init_x;
+    let mut y = init_y;
+    let mut out = Vec::new();
+
+    while out.len() < MAX_PREFIX_TERMS {
+        if !(0..steps_per_term).all(|_| step_pair(e_x, e_y, &mut x, &mut y, mode)) {
+            break;
+        }
+        out.push(x);
+    }
+    out
+}
+
+/// Like `run_sequence`, but x is only read every `steps_per_term` steps,
+/// for generators that take several updates per term, and steps go by `mode`
+pub fn run_sequence_every(
+    e_x: &Expr,
+    e_y: &Expr,
+    init_x: NumT,
+    init_y: NumT,
+    terms: usize,
+    steps_per_term: usize,
+    mode: TransitionMode,
+) -> Option<Vec<NumT>> {
+    let mut x = init_x;
+    let mut y = init_y;
+    let mut out = Vec::with_capacity(terms);
+
+    for _ in 0..terms {
+        for _ in 0..steps_per_term {
+            if !step_pair(e_x, e_y, &mut x, &mut y, mode) {
+                return None;
+            }
+        }
+        out.push(x);
+    }
+    Some(out)
+}
+
+/// Write `step,x,y` CSV rows for `terms` terms of `e_x; e_y`, each
+/// `steps_per_term` steps taken by `mode`, after a header and a step 0 row
+/// with the init values. Rows are numbered by step, and a fatal step ends
+/// it with a `step,fatal,fatal` row.
+#[allow(clippy::too_many_arguments)]
+pub fn sequence_to_csv<W: Write>(
+    e_x: &Expr,
+    e_y: &Expr,
+    init_x: NumT,
+    init_y: NumT,
+    terms: usize,
+    steps_per_term: usize,
+    mode: TransitionMode,
+    writer: &mut W,
+) -> io::Result<()> {
+    let mut x = init_x;
+    let mut y = init_y;
+    let mut step = 0;
+    writeln!(writer, "step,x,y")?;
+    writeln!(writer, "0,{},{}", x, y)?;
+    for _ in 0..terms {
+        for _ in 0..steps_per_term {
+            step += 1;
+            if !step_pair(e_x, e_y, &mut x, &mut y, mode) {
+                return writeln!(writer, "{},fatal,fatal", step);
+            }
+        }
+        writeln!(writer, "{},{},{}", step, x, y)?;
+    }
+    Ok(())
+}
+
+/// One step of `e_x; e_y` as a single program, `e_x, e_y'` where `e_y'` is
+/// `e_y` with x and y swapped back. Two pairs whose steps compare equal (over
+/// the equivalence grid, result and post-state) generate the same sequences.
+pub fn combined_step(e_x: &Expr, e_y: &Expr) -> Arc<Expr> {
+    let e_y = map(e_y, &|n: &Expr| match n.op {
+        Operator::Var => Some(y()),
+        Operator::VarY => Some(x()),
+        _ => None,
+    });
+    bin(Arc::new(e_x.clone()), Operator::Comma, e_y)
+}
+
+/// Run a found pair `extra_steps` past `target`. If the first `target.len()`
+/// terms still match, returns the extra terms so they can be checked against
+/// the intended sequence; None if the pair doesn't reproduce `target`.
+pub fn verify_extends(
+    e_x: &Expr,
+    e_y: &Expr,
+    init_x: NumT,
+    init_y: NumT,
+    target: &[NumT],
+    extra_steps: usize,
+) -> Option<Vec<NumT>> {
+    let mut terms = run_sequence(e_x, e_y, init_x, init_y, target.len() + extra_steps)?;
+    if terms[..target.len()] != *target {
+        return None;
+    }
+    Some(terms.split_off(target.len()))
+}
+
+/// `t[i] = a*t[i-1] + b*t[i-2] + c`, i.e. fixed differences (`a=1, c=d`),
+/// ratios (`a=r`) or a second-order rule like Fibonacci (`a=b=1`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recurrence {
+    pub a: NumT,
+    pub b: NumT,
+    pub c: NumT,
+}
+
+impl Recurrence {
+    pub fn holds(&self, terms: &[NumT]) -> bool {
+        terms.windows(3).all(|w| {
+            w[2] == self.a.wrapping_mul(w[1]).wrapping_add(self.b.wrapping_mul(w[0])).wrapping_add(self.c)
+        })
+    }
+}
+
+/// Every recurrence with coefficients in -4..=4 that `target` satisfies.
+/// Empty for targets under 3 terms, which no recurrence can be checked on.
+pub fn find_recurrences(target: &[NumT]) -> Vec<Recurrence> {
+    if target.len() < 3 {
+        return Vec::new();
+    }
+    let range = -4..=4;
+    let mut found = Vec::new();
+    for a in range.clone() {
+        for b in range.clone() {
+            for c in range.clone() {
+                let r = Recurrence { a, b, c };
+                if r.holds(target) {
+                    found.push(r);
+                }
+            }
+        }
+    }
+    found
+}
+
+/// Whether the pair follows one of `recurrences` from every seed in `seeds`
+/// for `steps` terms (each `steps_per_term` steps apart, taken by `mode`),
+/// rather than matching only from the seed it was found with. False if a
+/// run is fatal or `recurrences` is empty.
+pub fn is_seed_robust(
+    e_x: &Expr,
+    e_y: &Expr,
+    seeds: &[(NumT, NumT)],
+    recurrences: &[Recurrence],
+    steps: usize,
+    steps_per_term: usize,
+    mode: TransitionMode,
+) -> bool {
+    let Some(runs) = seeds.iter()
+        .map(|&(init_x, init_y)| run_sequence_every(e_x, e_y, init_x, init_y, steps, steps_per_term, mode))
+        .collect::<Option<Vec<_>>>() else {
+        return false;
+    };
+    recurrences.iter().any(|r| runs.iter().all(|terms| r.holds(terms)))
+}
+
+/// Whether the pair runs for `terms` terms (each `steps_per_term` steps
+/// apart, taken by `mode`) from every seed in `seeds` without a fatal step,
+/// so it's well-defined over the whole seed space, not only where it matched.
+/// Walks the trees, as the JIT doesn't report fatal steps.
+pub fn is_total(
+    e_x: &Expr,
+    e_y: &Expr,
+    seeds: &[(NumT, NumT)],
+    terms: usize,
+    steps_per_term: usize,
+    mode: TransitionMode,
+) -> bool {
+    seeds.iter().all(|&(init_x, init_y)| run_sequence_every(e_x, e_y, init_x, init_y, terms, steps_per_term, mode).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::lit;
+
+    const FIB: &[NumT] = &[1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144];
+    const SEQ: TransitionMode = TransitionMode::Sequential;
+    const SIM: TransitionMode = TransitionMode::Simultaneous;
+
+    // x+=y; y=x-y, which from x=0, y=1 gives the Fibonacci numbers
+    fn fib_pair() -> (Arc<Expr>, Arc<Expr>) {
+        (bin(x(), Operator::AddEq, y()), bin(x(), Operator::AssignEq, bin(y(), Operator::Sub, x())))
+    }
+
+    #[test]
+    fn test_run_sequence() {
+        let (e_x, e_y) = fib_pair();
+        assert_eq!(run_sequence(&e_x, &e_y, 0, 1, 6), Some(vec![1, 1, 2, 3, 5, 8]));
+        assert_eq!(run_sequence(&e_x, &e_y, 0, 1, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn test_run_sequence_fatal() {
+        // x/=y with y=0
+        let e_x = bin(x(), Operator::DivEq, y());
+        let e_y = bin(x(), Operator::AssignEq, lit(0));
+        assert_eq!(run_sequence(&e_x, &e_y, 1, 1, 1), Some(vec![1]));
+        assert_eq!(run_sequence(&e_x, &e_y, 1, 1, 2), None);
+    }
+
+    #[test]
+    fn test_run_sequence_every() {
+        let (e_x, e_y) = fib_pair();
+        // Every other Fibonacci number
+        assert_eq!(run_sequence_every(&e_x, &e_y, 0, 1, 5, 2, SEQ), Some(vec![1, 3, 8, 21, 55]));
+        assert_eq!(run_sequence_every(&e_x, &e_y, 0, 1, 5, 1, SEQ), run_sequence(&e_x, &e_y, 0, 1, 5));
+        let terms = run_sequence_every(&e_x, &e_y, 0, 1, 6, 2, SEQ).unwrap();
+        assert!(is_seed_robust(&e_x, &e_y, &[(2, 3), (5, -1)], &find_recurrences(&terms), 6, 2, SEQ));
+    }
+
+    #[test]
+    fn test_simultaneous_transition() {
+        // x+=y; y=x: (x, y) = (x+y, x) is Fibonacci, but sequentially y
+        // copies the new x and x doubles
+        let e_x = bin(x(), Operator::AddEq, y());
+        let e_y = bin(x(), Operator::AssignEq, y());
+        assert_eq!(run_sequence_every(&e_x, &e_y, 0, 1, 6, 1, SIM), Some(vec![1, 1, 2, 3, 5, 8]));
+        assert_eq!(run_sequence(&e_x, &e_y, 0, 1, 6), Some(vec![1, 2, 4, 8, 16, 32]));
+
+        // Each keeps only its own variable's write: x's y=0 and y's x=9 are dropped
+        let e_x = bin(bin(x(), Operator::AddEq, lit(1)), Operator::Comma, bin(y(), Operator::AssignEq, lit(0)));
+        let e_y = bin(bin(x(), Operator::AddEq, y()), Operator::Comma, bin(y(), Operator::AssignEq, lit(9)));
+        let (mut x0, mut y0) = (2, 5);
+        assert!(step_pair(&e_x, &e_y, &mut x0, &mut y0, SIM));
+        assert_eq!((x0, y0), (3, 7));
+
+        // Fatal if either is, even though neither sees the other's result
+        let e_y = bin(x(), Operator::DivEq, y());
+        assert!(!step_pair(&e_x, &e_y, &mut 0, &mut 1, SIM));
+    }
+
+    #[test]
+    fn test_sequence_to_csv() {
+        let (e_x, e_y) = fib_pair();
+        let mut out = Vec::new();
+        sequence_to_csv(&e_x, &e_y, 0, 1, 3, 1, TransitionMode::Sequential, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "step,x,y\n0,0,1\n1,1,0\n2,1,1\n3,2,1\n");
+
+        let e_x = bin(x(), Operator::DivEq, y());
+        let e_y = bin(x(), Operator::AssignEq, lit(0));
+        let mut out = Vec::new();
+        sequence_to_csv(&e_x, &e_y, 1, 1, 5, 1, TransitionMode::Sequential, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "step,x,y\n0,1,1\n1,1,0\n2,fatal,fatal\n");
+
+        // Two steps a row, simultaneous: (x, y) = (x+y, x)
+        let (e_x, e_y) = (bin(x(), Operator::AddEq, y()), bin(x(), Operator::AssignEq, y()));
+        let mut out = Vec::new();
+        sequence_to_csv(&e_x, &e_y, 0, 1, 2, 2, TransitionMode::Simultaneous, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "step,x,y\n0,0,1\n2,1,1\n4,3,2\n");
+    }
+
+    #[test]
+    fn test_sequence_prefix() {
+        // x-=1; y/=x: fatal on the step x reaches 0
+        let e_x = bin(x(), Operator::SubEq, lit(1));
+        let e_y = bin(x(), Operator::DivEq, y());
+        let seq = TransitionMode::Sequential;
+        assert_eq!(sequence_prefix(&e_x, &e_y, 4, 1, 1, seq), vec![3, 2, 1]);
+        assert_eq!(sequence_prefix(&e_x, &e_y, 1, 1, 1, seq), vec![]);
+        // Two steps a term: the term after 2 would take x through 0
+        assert_eq!(sequence_prefix(&e_x, &e_y, 6, 1, 2, seq), vec![4, 2]);
+
+        // Never fatal, so it runs to the cap
+        let (e_x, e_y) = fib_pair();
+        let terms = sequence_prefix(&e_x, &e_y, 0, 1, 1, seq);
+        assert_eq!(terms.len(), MAX_PREFIX_TERMS);
+        assert_eq!(terms[..FIB.len()], *FIB);
+        let every = run_sequence_every(&e_x, &e_y, 0, 1, 10, 3, TransitionMode::Simultaneous).unwrap();
+        assert_eq!(sequence_prefix(&e_x, &e_y, 0, 1, 3, TransitionMode::Simultaneous)[..10], *every);
+    }
+
+    #[test]
+    fn test_verify_extends() {
+        let (e_x, e_y) = fib_pair();
+        assert_eq!(verify_extends(&e_x, &e_y, 0, 1, FIB, 3), Some(vec![233, 377, 610]));
+        assert_eq!(verify_extends(&e_x, &e_y, 0, 1, FIB, 0), Some(vec![]));
+        // Wrong seed, so the target itself doesn't match
+        assert_eq!(verify_extends(&e_x, &e_y, 1, 0, FIB, 3), None);
+    }
+
+    #[test]
+    fn test_combined_step() {
+        let (e_x, e_y) = fib_pair();
+        let step = combined_step(&e_x, &e_y);
+        for (init_x, init_y) in [(0, 1), (3, -2), (7, 5)] {
+            let (mut x, mut y, mut fatal) = (init_x, init_y, false);
+            naive_eval(&step, &mut x, &mut y, &mut fatal);
+            assert_eq!(run_sequence(&e_x, &e_y, init_x, init_y, 1), Some(vec![x]));
+            assert_eq!(y, init_x);
+        }
+
+        // x+=1 and x-=-1 step alike; x+=2 doesn't
+        let e_y = bin(x(), Operator::AssignEq, x());
+        let step = |e_x: Arc<Expr>| combined_step(&e_x, &e_y);
+        assert_eq!(step(bin(x(), Operator::AddEq, lit(1))), step(bin(x(), Operator::SubEq, lit(-1))));
+        assert_ne!(step(bin(x(), Operator::AddEq, lit(1))), step(bin(x(), Operator::AddEq, lit(2))));
+    }
+
+    #[test]
+    fn test_is_total() {
+        // x=x/y; y=y+0: fine from any seed but one with y=0
+        let e_x = bin(x(), Operator::AssignEq, bin(x(), Operator::Div, y()));
+        let e_y = bin(x(), Operator::AddEq, lit(0));
+        let rectangle: Vec<(NumT, NumT)> = (-1..=1).flat_map(|ix| (-1..=1).map(move |iy| (ix, iy))).collect();
+        assert!(is_total(&e_x, &e_y, &[(5, 1), (-3, -1), (0, 2)], 6, 1, SEQ));
+        assert!(!is_total(&e_x, &e_y, &rectangle, 6, 1, SEQ));
+        // Even though it matches from seeds with y=1
+        assert_eq!(run_sequence(&e_x, &e_y, 1, 1, 3), Some(vec![1, 1, 1]));
+
+        let (e_x, e_y) = fib_pair();
+        assert!(is_total(&e_x, &e_y, &rectangle, FIB.len(), 1, SEQ));
+        // Fatal only on a later step: y=x%y reaches 0
+        let e_y = bin(x(), Operator::AssignEq, bin(y(), Operator::Mod, x()));
+        assert!(!is_total(&e_x, &e_y, &[(1, 1)], 3, 1, SEQ));
+        assert!(is_total(&e_x, &e_y, &[(1, 1)], 1, 1, SEQ));
+    }
+
+    #[test]
+    fn test_find_recurrences() {
+        assert_eq!(find_recurrences(FIB), vec![Recurrence { a: 1, b: 1, c: 0 }]);
+        assert!(find_recurrences(&[3, 5, 7, 9]).contains(&Recurrence { a: 1, b: 0, c: 2 }));
+        assert!(find_recurrences(&[1, 2, 5, 26, 677]).is_empty());
+        assert!(find_recurrences(&[1, 2]).is_empty());
+    }
+
+    #[test]
+    fn test_seed_robust() {
+        let seeds = [(2, 3), (5, -1), (-4, 7)];
+        let (e_x, e_y) = fib_pair();
+        assert!(is_seed_robust(&e_x, &e_y, &seeds, &find_recurrences(FIB), FIB.len(), 1, SEQ));
+
+        // x=x*x+1 gives 1,2,5 from x=0, but nothing linear from other seeds
+        let e_x = bin(x(), Operator::AssignEq, bin(bin(x(), Operator::Mul, x()), Operator::Add, lit(1)));
+        let e_y = bin(x(), Operator::AssignEq, x());
+        let target = [1, 2, 5];
+        assert_eq!(run_sequence(&e_x, &e_y, 0, 0, 3).as_deref(), Some(&target[..]));
+        assert!(!is_seed_robust(&e_x, &e_y, &seeds, &find_recurrences(&target), 5, 1, SEQ));
+    }
+
+    #[test]
+    fn test_seed_robust_fatal_run() {
+        // x/=y, y stays put: fatal from any seed with y=0
+        let e_x = bin(x(), Operator::DivEq, y());
+        let e_y = bin(x(), Operator::AssignEq, x());
+        let constant = find_recurrences(&[1, 1, 1]);
+        assert!(is_seed_robust(&e_x, &e_y, &[(1, 1), (5, 1)], &constant, 4, 1, SEQ));
+        assert!(!is_seed_robust(&e_x, &e_y, &[(1, 1), (5, 0)], &constant, 4, 1, SEQ));
+    }
+}
@@ -0,0 +1,94 @@
+//! Fitting a pure expression to a table of points.
+
+use crate::{naive_eval, Context, Expr, NumT, SearchConfig};
+use std::sync::Arc;
+
+/// Rows of `(x, y, expected)` a pure expression `f(x, y)` must reproduce
+#[derive(Debug, Clone)]
+pub struct TableMatcher {
+    pub rows: Vec<(NumT, NumT, NumT)>,
+}
+
+impl TableMatcher {
+    pub fn new(rows: Vec<(NumT, NumT, NumT)>) -> Self {
+        Self { rows }
+    }
+
+    /// Whether `e` gives every row's expected value. A fatal row is a miss.
+    pub fn matches(&self, e: &Expr) -> bool {
+        self.matches_by(e, |i, value| value == self.rows[i].2)
+    }
+
+    /// Like `matches`, with `accept(row, value)` deciding each row instead
+    pub fn matches_by(&self, e: &Expr, mut accept: impl FnMut(usize, NumT) -> bool) -> bool {
+        self.rows.iter().enumerate().all(|(i, &(x0, y0, _))| {
+            let (mut x, mut y, mut fatal) = (x0, y0, false);
+            let value = naive_eval(e, &mut x, &mut y, &mut fatal);
+            !fatal && accept(i, value)
+        })
+    }
+}
+
+/// The cached expressions of length `n` that fit the table
+pub fn search_table(ctx: &Context, matcher: &TableMatcher, n: usize) -> Vec<Arc<Expr>> {
+    ctx.expressions(n)
+        .map(|exprs| exprs.iter().filter(|e| matcher.matches(e)).cloned().collect())
+        .unwrap_or_default()
+}
+
+/// The shortest length up to `max_len` with an expression fitting the table,
+/// and every fitting expression of that length. Constant expressions like
+/// 2*3 are kept whatever `prune_const_expr` says, as one may be the fit.
+pub fn fit_table(config: &SearchConfig, matcher: &TableMatcher, max_len: usize) -> Option<(usize, Vec<Arc<Expr>>)> {
+    let config = SearchConfig { prune_const_expr: false, ..config.clone() };
+    let mut ctx = Context::new();
+    for n in 1..=max_len {
+        ctx.gen_expressions(&config, n);
+        let found = search_table(&ctx, matcher, n);
+        if !found.is_empty() {
+            return Some((n, found));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{bin, lit, x, y};
+    use crate::Operator;
+
+    #[test]
+    fn test_fit_table_recovers_formula() {
+        let points = [(2, 3), (-1, 4), (0, 5), (3, 3), (5, -2), (-4, -4)];
+        let matcher = TableMatcher::new(points.iter().map(|&(x, y)| (x, y, x * y + 1)).collect());
+        let (n, found) = fit_table(&SearchConfig::default(), &matcher, 5).unwrap();
+        assert_eq!(n, 5);
+        let target = bin(bin(x(), Operator::Mul, y()), Operator::Add, lit(1));
+        assert!(found.iter().any(|e| **e == *target));
+    }
+
+    #[test]
+    fn test_fit_table_keeps_constants() {
+        // 6 isn't a literal, but 2*3 is the shortest way to write it
+        let matcher = TableMatcher::new(vec![(1, 2, 6), (-3, 0, 6), (4, 4, 6)]);
+        let (n, found) = fit_table(&SearchConfig::default(), &matcher, 3).unwrap();
+        assert_eq!(n, 3);
+        assert!(found.iter().any(|e| **e == *bin(lit(2), Operator::Mul, lit(3))));
+    }
+
+    #[test]
+    fn test_table_fatal_row_is_miss() {
+        // x/y fits every row but the one dividing by zero
+        let e = bin(x(), Operator::Div, y());
+        assert!(TableMatcher::new(vec![(6, 3, 2), (7, -1, -7)]).matches(&e));
+        assert!(!TableMatcher::new(vec![(6, 3, 2), (1, 0, 0)]).matches(&e));
+    }
+
+    #[test]
+    fn test_table_no_fit() {
+        // No expression of length 3 or less is x*x*x*x+y*y*y
+        let matcher = TableMatcher::new((1..=4).map(|x: NumT| (x, 2 * x, x.pow(4) + 8 * x.pow(3))).collect());
+        assert!(fit_table(&SearchConfig::default(), &matcher, 3).is_none());
+    }
+}
@@ -0,0 +1,152 @@
+//! Generic traversals over expression trees.
+
+use crate::{Expr, Operator};
+use std::sync::Arc;
+
+/// Call `f` on every node of `e` in pre-order (node, left, mid, right)
+pub fn visit<F: FnMut(&Expr)>(e: &Expr, f: &mut F) {
+    f(e);
+    for child in [&e.left, &e.mid, &e.right].into_iter().flatten() {
+        visit(child, f);
+    }
+}
+
+/// Rebuild `e` bottom-up, replacing each node for which `f` returns `Some`.
+///
+/// `f` sees a node after its children were mapped. Unchanged subtrees are
+/// shared with the input (JIT code included); rebuilt nodes have no JIT.
+pub fn map<F: Fn(&Expr) -> Option<Arc<Expr>>>(e: &Expr, f: &F) -> Arc<Expr> {
+    match map_children(e, f) {
+        Some(rebuilt) => f(&rebuilt).unwrap_or_else(|| Arc::new(rebuilt)),
+        None => f(e).unwrap_or_else(|| Arc::new(e.clone())),
+    }
+}
+
+fn map_arc<F: Fn(&Expr) -> Option<Arc<Expr>>>(e: &Arc<Expr>, f: &F) -> Arc<Expr> {
+    match map_children(e, f) {
+        Some(rebuilt) => f(&rebuilt).unwrap_or_else(|| Arc::new(rebuilt)),
+        None => f(e).unwrap_or_else(|| e.clone()),
+    }
+}
+
+/// `e` with every `var` leaf (`Var` or `VarY`) replaced by `replacement`,
+/// sharing everything else. None if `var` is written by an assignment or
+/// `++`/`--` and `replacement` isn't a variable that could be written instead.
+pub fn substitute(e: &Expr, var: Operator, replacement: &Arc<Expr>) -> Option<Arc<Expr>> {
+    if !e.uses_var(var) {
+        return Some(Arc::new(e.clone()));
+    }
+    let mut assigned = false;
+    visit(e, &mut |n: &Expr| {
+        let target = if n.is_assignment() { &n.left } else if n.is_incdec() { &n.right } else { &None };
+        assigned |= target.as_ref().is_some_and(|t| t.op == var);
+    });
+    if assigned && !replacement.is_lvalue() {
+        return None;
+    }
+    Some(map(e, &|n: &Expr| (n.op == var).then(|| replacement.clone())))
+}
+
+// The node with mapped children, or None if no child changed
+fn map_children<F: Fn(&Expr) -> Option<Arc<Expr>>>(e: &Expr, f: &F) -> Option<Expr> {
+    let map_child = |c: &Option<Arc<Expr>>| c.as_ref().map(|c| map_arc(c, f));
+    let left = map_child(&e.left);
+    let mid = map_child(&e.mid);
+    let right = map_child(&e.right);
+
+    let same = |old: &Option<Arc<Expr>>, new: &Option<Arc<Expr>>| match (old, new) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        _ => true,
+    };
+    if same(&e.left, &left) && same(&e.mid, &mid) && same(&e.right, &right) {
+        return None;
+    }
+    Some(Expr { left, mid, right, literal: e.literal, mode: e.mode, op: e.op, jit: None, bytecode: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{self, bin, lit, un};
+    use crate::{format_expression, naive_eval};
+
+    #[test]
+    fn test_visit_pre_order() {
+        // -(x+2)
+        let e = un(Operator::Neg, bin(build::x(), Operator::Add, lit(2)));
+        let mut ops = Vec::new();
+        visit(&e, &mut |n: &Expr| ops.push(n.op));
+        assert_eq!(ops, [Operator::Neg, Operator::Add, Operator::Var, Operator::Literal]);
+    }
+
+    #[test]
+    fn test_visit_ternary_mid() {
+        let e = build::ternary(build::x(), lit(1), lit(2));
+        let mut literals = Vec::new();
+        visit(&e, &mut |n: &Expr| if n.op == Operator::Literal { literals.push(n.literal) });
+        assert_eq!(literals, [1, 2]);
+    }
+
+    #[test]
+    fn test_map_constant_fold() {
+        // (2*3)+x -> 6+x
+        let e = bin(bin(lit(2), Operator::Mul, lit(3)), Operator::Add, build::x());
+        let fold = |n: &Expr| match (&n.left, &n.right) {
+            (Some(l), Some(r)) if l.op == Operator::Literal && r.op == Operator::Literal => {
+                let (mut x, mut y, mut fatal) = (0, 0, false);
+                let v = naive_eval(n, &mut x, &mut y, &mut fatal);
+                (!fatal).then(|| lit(v))
+            }
+            _ => None,
+        };
+        let folded = map(&e, &fold);
+        assert_eq!(folded.op, Operator::Add);
+        assert_eq!(folded.left.as_ref().unwrap().literal, 6);
+        assert!(Arc::ptr_eq(folded.right.as_ref().unwrap(), e.right.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_map_identity_shares_subtrees() {
+        let e = bin(un(Operator::Neg, build::x()), Operator::Sub, build::y());
+        let same = map(&e, &|_: &Expr| None);
+        assert!(Arc::ptr_eq(same.left.as_ref().unwrap(), e.left.as_ref().unwrap()));
+        assert!(Arc::ptr_eq(same.right.as_ref().unwrap(), e.right.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_map_replaces_variable() {
+        // x+x with x -> y
+        let e = bin(build::x(), Operator::Add, build::x());
+        let swapped = map(&e, &|n: &Expr| (n.op == Operator::Var).then(build::y));
+        let mut ops = Vec::new();
+        visit(&swapped, &mut |n: &Expr| ops.push(n.op));
+        assert_eq!(ops, [Operator::Add, Operator::VarY, Operator::VarY]);
+        assert!(swapped.jit.is_none());
+    }
+
+    #[test]
+    fn test_substitute_square() {
+        // x*x with x := y+1
+        let square = bin(build::x(), Operator::Mul, build::x());
+        let y1 = bin(build::y(), Operator::Add, lit(1));
+        let e = substitute(&square, Operator::Var, &y1).unwrap();
+        assert_eq!(format_expression(&e, &['x', 'y']), "(y+1)*(y+1)");
+        let expected = bin(un(Operator::Parens, y1.clone()), Operator::Mul, un(Operator::Parens, y1.clone()));
+        assert_eq!(*e, *expected);
+        assert!(Arc::ptr_eq(e.left.as_ref().unwrap(), &y1));
+    }
+
+    #[test]
+    fn test_substitute_assigned_variable() {
+        // x+=x*2: x can become y, but not y+1
+        let e = bin(build::x(), Operator::AddEq, bin(build::x(), Operator::Mul, lit(2)));
+        let y1 = bin(build::y(), Operator::Add, lit(1));
+        assert!(substitute(&e, Operator::Var, &y1).is_none());
+        let renamed = substitute(&e, Operator::Var, &build::y()).unwrap();
+        assert_eq!(format_expression(&renamed, &['x', 'y']), "y+=y*2");
+        // Only y is incremented, so x is free to replace
+        let inc = bin(un(Operator::PreInc, build::y()), Operator::Sub, build::x());
+        assert!(substitute(&inc, Operator::VarY, &y1).is_none());
+        assert_eq!(format_expression(&substitute(&inc, Operator::Var, &y1).unwrap(), &['x', 'y']), "++y-(y+1)");
+    }
+}
@@ -0,0 +1,160 @@
+//! The crate's semantics checked against a C compiler: random expressions are
+//! exported with `to_c`, built with `cc` and run over `EXTREMES` by
+//! `EXTREMES`, and each result has to match `naive_eval`'s.
+//!
+//! Ignored by default, as it needs a C compiler. Run it with
+//! `cargo test --test c_semantics -- --ignored`.
+
+use expr_rust::build::{self, bin, lit, ternary, un, x, y};
+use expr_rust::reference::EXTREMES;
+use expr_rust::{category, naive_eval, to_c, Expr, NumT, OpCategory, Operator, C_PRELUDE};
+use std::fmt::Write;
+use std::process::Command;
+use std::sync::Arc;
+
+const EXPRESSIONS: usize = 400;
+const MAX_DEPTH: usize = 4;
+const LITERALS: &[NumT] = &[0, 1, -1, 2, 3, 7, -64, 63, 64, 65, NumT::MIN, NumT::MAX];
+
+/// xorshift64, so a failure reproduces from the same seed
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+
+    fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())].clone()
+    }
+}
+
+fn leaf(rng: &mut Rng) -> Arc<Expr> {
+    match rng.below(3) {
+        0 => x(),
+        1 => y(),
+        _ => lit(rng.pick(LITERALS)),
+    }
+}
+
+/// An assignment or `++` target: mostly x or y, now and then something
+/// else, which is fatal
+fn target(rng: &mut Rng) -> Arc<Expr> {
+    if rng.below(10) == 0 { lit(1) } else if rng.below(2) == 0 { x() } else { y() }
+}
+
+/// `l op r`, saturating, Euclidean, unsigned or modular now and then where `op` can be
+fn flavored(rng: &mut Rng, l: Arc<Expr>, op: Operator, r: Arc<Expr>) -> Arc<Expr> {
+    if rng.below(3) > 0 {
+        return bin(l, op, r);
+    }
+    if Expr::can_saturate(op) {
+        build::saturating(l, op, r)
+    } else if Expr::can_be_euclidean(op) && rng.below(2) == 0 {
+        build::euclidean(l, op, r)
+    } else if Expr::can_be_unsigned(op) {
+        build::unsigned(l, op, r)
+    } else if op == Operator::Pow {
+        build::pow_mod(l, r, rng.pick(&[1, 2, 7, 97, NumT::MAX]))
+    } else {
+        bin(l, op, r)
+    }
+}
+
+fn random_expr(rng: &mut Rng, depth: usize) -> Arc<Expr> {
+    if depth == 0 || rng.below(5) == 0 {
+        return leaf(rng);
+    }
+    let ops: Vec<Operator> = Operator::all().filter(|&op| category(op) != OpCategory::Operand).collect();
+    let op = rng.pick(&ops);
+    if op == Operator::Ternary {
+        let cond = random_expr(rng, depth - 1);
+        return ternary(cond, random_expr(rng, depth - 1), random_expr(rng, depth - 1));
+    }
+    match category(op) {
+        OpCategory::Assignment => {
+            let (t, r) = (target(rng), random_expr(rng, depth - 1));
+            flavored(rng, t, op, r)
+        }
+        OpCategory::Binary => {
+            let (l, r) = (random_expr(rng, depth - 1), random_expr(rng, depth - 1));
+            flavored(rng, l, op, r)
+        }
+        OpCategory::IncDec => un(op, target(rng)),
+        _ => un(op, random_expr(rng, depth - 1)),
+    }
+}
+
+/// What one run prints: `fatal`, or the value and the final x and y
+fn expected(e: &Expr, x0: NumT, y0: NumT) -> String {
+    let (mut x, mut y, mut fatal) = (x0, y0, false);
+    let r = naive_eval(e, &mut x, &mut y, &mut fatal);
+    if fatal { "fatal".to_string() } else { format!("{} {} {}", r, x, y) }
+}
+
+/// A program running each of `exprs` from every pair of EXTREMES, a line per run
+fn program(exprs: &[Arc<Expr>]) -> String {
+    let mut c = format!("{}\n#include <stdio.h>\n\n", C_PRELUDE);
+    for (i, e) in exprs.iter().enumerate() {
+        c += &to_c(e, &format!("f{}", i));
+        c += "\n";
+    }
+    let grid: Vec<String> = EXTREMES.iter().map(|&v| if v == NumT::MIN { "INT64_MIN".to_string() } else { format!("INT64_C({})", v) }).collect();
+    let fns: Vec<String> = (0..exprs.len()).map(|i| format!("f{}", i)).collect();
+    write!(
+        c,
+        "static const int64_t grid[] = {{{}}};\n\
+         static int64_t (*const fns[])(int64_t *, int64_t *, int *) = {{{}}};\n\n\
+         int main(void) {{\n    \
+             for (size_t f = 0; f < sizeof fns / sizeof *fns; f++)\n        \
+             for (size_t i = 0; i < sizeof grid / sizeof *grid; i++)\n            \
+             for (size_t j = 0; j < sizeof grid / sizeof *grid; j++) {{\n                \
+                 int64_t x = grid[i], y = grid[j];\n                \
+                 int fatal = 0;\n                \
+                 int64_t r = fns[f](&x, &y, &fatal);\n                \
+                 if (fatal) puts(\"fatal\");\n                \
+                 else printf(\"%lld %lld %lld\\n\", (long long)r, (long long)x, (long long)y);\n            \
+             }}\n    \
+             return 0;\n\
+         }}\n",
+        grid.join(", "),
+        fns.join(", ")
+    )
+    .unwrap();
+    c
+}
+
+#[test]
+#[ignore = "needs a C compiler, run with --ignored"]
+fn test_matches_c_compiler() {
+    if Command::new("cc").arg("--version").output().is_err() {
+        eprintln!("cc not found, skipping");
+        return;
+    }
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    let exprs: Vec<Arc<Expr>> = (0..EXPRESSIONS).map(|_| random_expr(&mut rng, MAX_DEPTH)).collect();
+
+    let dir = std::env::temp_dir().join(format!("expr_c_semantics_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (source, binary) = (dir.join("exprs.c"), dir.join("exprs"));
+    std::fs::write(&source, program(&exprs)).unwrap();
+    let built = Command::new("cc").args(["-std=c99", "-O1", "-o"]).arg(&binary).arg(&source).output().unwrap();
+    assert!(built.status.success(), "{}", String::from_utf8_lossy(&built.stderr));
+    let run = Command::new(&binary).output().unwrap();
+    assert!(run.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8(run.stdout).unwrap();
+    let mut lines = stdout.lines();
+    for e in &exprs {
+        for &x0 in EXTREMES {
+            for &y0 in EXTREMES {
+                let line = lines.next().expect("C output ended early");
+                assert_eq!(line, expected(e, x0, y0), "{} at x={}, y={}", expr_rust::format_expression(e, &['x', 'y']), x0, y0);
+            }
+        }
+    }
+}